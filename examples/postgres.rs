@@ -3,6 +3,7 @@
 //! This example demonstrates:
 //! - PostgreSQL backend connection
 //! - Multi-agent concurrent access
+//! - Per-agent filesystem isolation
 //! - Async operations at scale
 //!
 //! Prerequisites:
@@ -30,7 +31,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("1. Connecting to PostgreSQL...");
     println!("   URL: {}", database_url.replace(|c: char| c == ':' && c.is_ascii_digit(), ":****"));
 
-    let backend = SqlBackend::postgres(database_url).await?;
+    let backend = SqlBackend::postgres(database_url.clone()).await?;
     let agent_fs = AgentFS::new(Box::new(backend), "postgres-agent", "/agent").await?;
     println!("   ✓ Connected successfully\n");
 
@@ -67,6 +68,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     println!();
 
+    // Each agent id gets its own volume in the shared database
+    println!("3b. Isolated Agent Volumes:");
+    let other_backend = SqlBackend::postgres(database_url).await?;
+    let other_agent = AgentFS::new(Box::new(other_backend), "postgres-reviewer", "/agent").await?;
+    let visible = other_agent.fs.exists("/concurrent/agent-0/output.txt").await?;
+    println!("   ✓ postgres-reviewer sees postgres-agent's files: {}\n", visible);
+
     // Demonstrate shared KV store across "agents"
     println!("4. Shared Key-Value Store:");
 
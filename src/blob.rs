@@ -0,0 +1,235 @@
+//! Large-object offload
+//!
+//! Multi-hundred-megabyte files bloat database backups and slow down
+//! Postgres. With a blob store configured (see
+//! [`DbFileSystem::with_blob_store`]), content above a size threshold is
+//! written to the store instead, keyed by its SHA-256 hash, and the inode
+//! only records the hash. Reads and copies resolve the reference
+//! transparently, and identical large files are stored once. Blobs no file
+//! refers to any more are deleted by garbage collection.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::DbFileSystem;
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Storage for large file contents, addressed by the hex SHA-256 hash of
+/// the content
+///
+/// Implement this for object stores such as S3; [`LocalBlobStore`] keeps
+/// blobs in a host directory.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Store `data` under `hash`
+    ///
+    /// Storing an existing blob again must refresh its `created` time:
+    /// garbage collection leaves young blobs alone, as a file may be about
+    /// to refer to them.
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<()>;
+
+    /// Fetch a blob, or `None` if it doesn't exist
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Delete a blob; deleting a missing blob is not an error
+    async fn delete(&self, hash: &str) -> Result<()>;
+
+    /// List all stored blobs
+    async fn list(&self) -> Result<Vec<BlobInfo>>;
+}
+
+/// A stored blob, returned by [`BlobStore::list`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobInfo {
+    /// Hex SHA-256 hash of the content
+    pub hash: String,
+    /// Size in bytes
+    pub size: u64,
+    /// When the blob was stored
+    pub created: SystemTime,
+}
+
+/// Hex SHA-256 hash of `data`
+pub fn blob_hash(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Blob store keeping each blob as a file in a host directory
+///
+/// Blobs are spread over subdirectories named after the first two hex
+/// digits of their hash, and written through a temporary file so a crash
+/// never leaves a partial blob behind.
+#[derive(Debug, Clone)]
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    /// Use `root` as the blob directory, creating it if needed
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    /// The blob directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn blob_path(&self, hash: &str) -> Result<PathBuf> {
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(AgentFsError::InvalidPath(format!("Invalid blob hash: {}", hash)));
+        }
+        Ok(self.root.join(&hash[..2]).join(hash))
+    }
+
+    async fn blocking<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce() -> Result<R> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(|e| AgentFsError::Other(Box::new(e)))?
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, hash: &str, data: &[u8]) -> Result<()> {
+        let path = self.blob_path(hash)?;
+        let data = data.to_vec();
+        self.blocking(move || {
+            if path.exists() {
+                // Refresh the time so gc sees the blob as freshly stored
+                std::fs::File::options().append(true).open(&path)?.set_modified(SystemTime::now())?;
+                return Ok(());
+            }
+            let dir = path.parent().expect("blob paths have a parent");
+            std::fs::create_dir_all(dir)?;
+            let tmp = dir.join(format!(".{}.tmp", uuid::Uuid::new_v4()));
+            std::fs::write(&tmp, &data)?;
+            std::fs::rename(&tmp, &path).inspect_err(|_| {
+                let _ = std::fs::remove_file(&tmp);
+            })?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.blob_path(hash)?;
+        self.blocking(move || match std::fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        })
+        .await
+    }
+
+    async fn delete(&self, hash: &str) -> Result<()> {
+        let path = self.blob_path(hash)?;
+        self.blocking(move || match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<BlobInfo>> {
+        let root = self.root.clone();
+        self.blocking(move || {
+            let mut blobs = Vec::new();
+            for dir in std::fs::read_dir(&root)? {
+                let dir = dir?;
+                if !dir.file_type()?.is_dir() {
+                    continue;
+                }
+                for entry in std::fs::read_dir(dir.path())? {
+                    let entry = entry?;
+                    let hash = entry.file_name().to_string_lossy().to_string();
+                    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        continue;
+                    }
+                    let metadata = entry.metadata()?;
+                    blobs.push(BlobInfo { hash, size: metadata.len(), created: metadata.modified()? });
+                }
+            }
+            blobs.sort_by(|a, b| a.hash.cmp(&b.hash));
+            Ok(blobs)
+        })
+        .await
+    }
+}
+
+impl DbFileSystem {
+    /// Hash of the blob holding the data owned by `ino`, if it was offloaded
+    pub(crate) async fn blob_ref(&self, ino: i64) -> Result<Option<String>> {
+        self.ensure_schema().await?;
+        let query = format!("SELECT blob_hash FROM fs_inode WHERE ino = {}", ino);
+        let result = self.db.query(&query, vec![]).await?;
+        Ok(result
+            .rows
+            .first()
+            .and_then(|row| self.extract_string_opt(row, "blob_hash"))
+            .filter(|hash| !hash.is_empty()))
+    }
+
+    /// Content of an offloaded blob
+    pub(crate) async fn read_blob(&self, hash: &str) -> Result<Vec<u8>> {
+        let Some(store) = self.blob_store() else {
+            return Err(AgentFsError::Io(std::io::Error::other(format!(
+                "File content is in blob {}, but no blob store is configured",
+                hash
+            ))));
+        };
+        store.get(hash).await?.ok_or_else(|| {
+            AgentFsError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Blob {} is missing from the blob store", hash),
+            ))
+        })
+    }
+
+    /// Offload `content` to the blob store if it is above the threshold,
+    /// returning its hash
+    pub(crate) async fn offload(&self, content: &[u8]) -> Result<Option<String>> {
+        let Some(store) = self.blob_store() else {
+            return Ok(None);
+        };
+        if content.len() as u64 <= self.blob_threshold() {
+            return Ok(None);
+        }
+        let hash = blob_hash(content);
+        store.put(&hash, content).await?;
+        Ok(Some(hash))
+    }
+
+    /// Stored blobs that no inode refers to and that are older than `grace`
+    ///
+    /// A blob is stored before the inode refers to it, so younger blobs may
+    /// belong to a write in progress.
+    pub(crate) async fn find_orphan_blobs(&self, grace: Duration) -> Result<Vec<BlobInfo>> {
+        let Some(store) = self.blob_store() else {
+            return Ok(Vec::new());
+        };
+        let blobs = store.list().await?;
+        let result = self
+            .db
+            .query("SELECT DISTINCT blob_hash FROM fs_inode WHERE blob_hash IS NOT NULL", vec![])
+            .await?;
+        let referenced: std::collections::HashSet<String> = result
+            .rows
+            .iter()
+            .filter_map(|row| self.extract_string_opt(row, "blob_hash"))
+            .collect();
+        let now = SystemTime::now();
+        Ok(blobs
+            .into_iter()
+            .filter(|blob| !referenced.contains(&blob.hash))
+            .filter(|blob| now.duration_since(blob.created).unwrap_or_default() >= grace)
+            .collect())
+    }
+}
@@ -0,0 +1,210 @@
+//! Copying files and directory trees
+//!
+//! Copies don't duplicate file content. A copied inode records the inode
+//! whose data it shares (`data_ino`), and the first write to either side
+//! gives the writer data of its own, so copying a template workspace is
+//! near-instant and storage only grows where the copies diverge. Mode,
+//! timestamps, content metadata, symlink targets and extended attributes
+//! are copied along.
+
+use crate::error::{AgentFsError, Result};
+use crate::expiry::unexpired;
+use crate::filesystem::{DbFileSystem, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, Stats};
+use crate::path::child_path;
+use crate::permissions::{R_OK, W_OK, X_OK};
+use crate::watch::FsEventKind;
+
+impl DbFileSystem {
+    /// Copy a file, sharing its data; see [`FileSystem::copy`](crate::FileSystem::copy)
+    pub(crate) async fn copy_file(&self, src: &str, dst: &str) -> Result<()> {
+        let src = self.validate_and_normalize_path(src)?;
+        let dst = self.validate_and_normalize_path(dst)?;
+        let (src_ino, _) = self
+            .resolve(&src, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(src.clone()))?;
+        let stats = self
+            .stat_inode(src_ino)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(src.clone()))?;
+        if stats.is_directory() {
+            return Err(AgentFsError::IsADirectory(src));
+        }
+        self.check_access(src_ino, R_OK, &src).await?;
+        if dst == "/" {
+            return Err(AgentFsError::IsADirectory(dst));
+        }
+
+        let (parent_ino, name, existing, canonical) = self.resolve_for_write(&dst).await?;
+        match existing {
+            Some(ino) if ino == src_ino => return Ok(()),
+            Some(ino) => {
+                if self.is_directory(ino).await? {
+                    return Err(AgentFsError::IsADirectory(canonical));
+                }
+                self.check_access(ino, W_OK, &canonical).await?;
+
+                // Overwrite in place, like cp: the target keeps its inode,
+                // mode and owner, and now shares the source's data
+                let owner = self.data_owner(src_ino).await?;
+                let old_size = self.apparent_size(ino).await?;
+                let mut freed = 0;
+                if self.data_owner(ino).await? != owner {
+                    freed = self.replaced_bytes(ino).await?;
+                    self.detach_data(ino).await?;
+                    self.delete_data(ino).await?;
+                    let query = format!("UPDATE fs_inode SET data_ino = {} WHERE ino = {}", owner, ino);
+                    self.db.query(&query, vec![]).await?;
+                }
+                self.copy_index(src_ino, ino).await?;
+
+                let (now, nsec) = Self::now_timespec();
+                let query = format!(
+                    "UPDATE fs_inode SET size = {}, mtime = {now}, mtime_nsec = {nsec}, ctime = {now}, ctime_nsec = {nsec}, \
+                     version = version + 1, {} WHERE ino = {}",
+                    stats.size,
+                    copied_content_columns(&stats),
+                    ino
+                );
+                self.db.query(&query, vec![]).await?;
+                self.adjust_usage(-freed, stats.size - old_size, 0).await?;
+                self.record_change(FsEventKind::Modified, &canonical).await?;
+            }
+            None => {
+                self.check_access(parent_ino, W_OK | X_OK, &canonical).await?;
+                self.link_copy(src_ino, parent_ino, &name).await?;
+                self.touch_dir(parent_ino).await?;
+                self.record_change(FsEventKind::Created, &canonical).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Recursively copy a directory, sharing file data; see
+    /// [`FileSystem::copy_dir`](crate::FileSystem::copy_dir)
+    pub(crate) async fn copy_tree(&self, src: &str, dst: &str) -> Result<()> {
+        let src = self.validate_and_normalize_path(src)?;
+        let dst = self.validate_and_normalize_path(dst)?;
+        let (src_ino, src_canonical) = self
+            .resolve(&src, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(src.clone()))?;
+        if !self.is_directory(src_ino).await? {
+            return Err(AgentFsError::NotADirectory(src));
+        }
+        if dst == "/" {
+            return Err(AgentFsError::PathExists(dst));
+        }
+
+        let (parent_ino, name, parent_path) = self.resolve_parent(&dst).await?;
+        let dst_canonical = child_path(&parent_path, &name);
+        if self.lookup_for_write(parent_ino, &name).await?.is_some() {
+            return Err(AgentFsError::PathExists(dst));
+        }
+        if src_canonical == "/" || dst_canonical.starts_with(&format!("{}/", src_canonical)) {
+            return Err(AgentFsError::InvalidPath(format!("Cannot copy {} into itself", src)));
+        }
+        self.check_access(parent_ino, W_OK | X_OK, &dst).await?;
+        self.check_access(src_ino, R_OK | X_OK, &src).await?;
+
+        let root = self.link_copy(src_ino, parent_ino, &name).await?;
+        self.touch_dir(parent_ino).await?;
+        self.record_change(FsEventKind::Created, &dst_canonical).await?;
+
+        let mut pending = vec![(src_ino, root, src_canonical, dst_canonical)];
+        while let Some((from_ino, to_ino, from_path, to_path)) = pending.pop() {
+            let query = format!(
+                "SELECT d.name, d.ino FROM fs_dentry d JOIN fs_inode i ON i.ino = d.ino WHERE d.parent_ino = {} AND {} \
+                 ORDER BY d.name",
+                from_ino,
+                unexpired("i")
+            );
+            let result = self.db.query(&query, vec![]).await?;
+
+            for row in &result.rows {
+                let Some(name) = self.extract_string_opt(row, "name") else {
+                    continue;
+                };
+                let child = self.extract_i64(row, "ino")?;
+                let from_child = child_path(&from_path, &name);
+                let to_child = child_path(&to_path, &name);
+
+                let mode = self.inode_mode(child).await?.unwrap_or(0) & S_IFMT;
+                match mode {
+                    S_IFDIR => self.check_access(child, R_OK | X_OK, &from_child).await?,
+                    S_IFLNK => {}
+                    _ => self.check_access(child, R_OK, &from_child).await?,
+                }
+
+                let copy = self.link_copy(child, to_ino, &name).await?;
+                self.record_change(FsEventKind::Created, &to_child).await?;
+                if mode == S_IFDIR {
+                    pending.push((child, copy, from_child, to_child));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a copy of inode `src_ino` as `name` in `parent_ino`
+    ///
+    /// Regular files share the source's data. Ownership is kept unless
+    /// the copy is made by a non-root user, who owns the copy instead.
+    async fn link_copy(&self, src_ino: i64, parent_ino: i64, name: &str) -> Result<i64> {
+        self.ensure_schema().await?;
+        self.check_quota(name, 0, 1).await?;
+        let (uid, gid) = match self.credentials() {
+            Some(creds) if creds.uid != 0 => {
+                let (uid, gid) = self.creator();
+                (uid.to_string(), gid.to_string())
+            }
+            _ => ("uid".to_string(), "gid".to_string()),
+        };
+        let (now, nsec) = Self::now_timespec();
+        let root_ino = self.root_ino;
+        let query = format!(
+            "INSERT INTO fs_inode (mode, uid, gid, size, atime, mtime, ctime, atime_nsec, mtime_nsec, ctime_nsec, \
+             content_type, line_count, encoding, data_ino, root_ino) \
+             SELECT mode, {uid}, {gid}, size, atime, mtime, {now}, atime_nsec, mtime_nsec, {nsec}, \
+             content_type, line_count, encoding, \
+             CASE WHEN (mode & {S_IFMT}) = {S_IFREG} THEN COALESCE(data_ino, ino) END, {root_ino} \
+             FROM fs_inode WHERE ino = {src_ino}"
+        );
+        self.db.query(&query, vec![]).await?;
+        let ino = self.inserted_ino().await?;
+        let size = self.apparent_size(ino).await?;
+        self.adjust_usage(0, size, 1).await?;
+
+        let query = format!(
+            "INSERT INTO fs_symlink (ino, target) SELECT {}, target FROM fs_symlink WHERE ino = {}",
+            ino, src_ino
+        );
+        self.db.query(&query, vec![]).await?;
+        self.copy_xattrs(src_ino, ino).await?;
+        self.copy_index(src_ino, ino).await?;
+
+        if let Err(e) = self.create_dentry(parent_ino, name, ino).await {
+            self.db.query(&format!("DELETE FROM fs_symlink WHERE ino = {}", ino), vec![]).await?;
+            self.db.query(&format!("DELETE FROM fs_inode WHERE ino = {}", ino), vec![]).await?;
+            self.adjust_usage(0, -size, -1).await?;
+            self.delete_xattrs(ino).await?;
+            self.drop_index(ino).await?;
+            return Err(e);
+        }
+        Ok(ino)
+    }
+}
+
+/// `SET` clause copying the content metadata of `stats`
+fn copied_content_columns(stats: &Stats) -> String {
+    let text = |value: Option<&str>| match value {
+        Some(v) => format!("'{}'", v.replace('\'', "''")),
+        None => "NULL".to_string(),
+    };
+    format!(
+        "content_type = {}, line_count = {}, encoding = {}",
+        text(stats.content_type.as_deref()),
+        stats.line_count.map_or("NULL".to_string(), |n| n.to_string()),
+        text(stats.encoding.as_deref())
+    )
+}
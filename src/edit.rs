@@ -0,0 +1,206 @@
+//! Line-oriented text editing
+//!
+//! Code-editing agents think in lines: "show lines 40-80", "replace lines
+//! 52-55", "insert after line 10". These helpers edit text files that way,
+//! with lines counted from 1 and ranges inclusive, plus
+//! [`DbFileSystem::str_replace`] for edits anchored on a unique string.
+//!
+//! Every edit is a compare-and-swap, like the JSON helpers: a concurrent
+//! write makes it start over on the new content rather than overwrite it.
+//! An edit that doesn't fit the file fails with `AgentFsError::InvalidEdit`
+//! and leaves the file untouched. Successful edits return an
+//! [`EditPreview`] showing the edited lines with some context, so the
+//! agent can check the result without reading the file again.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::{DbFileSystem, FileSystem};
+use std::ops::{Range, RangeInclusive};
+
+/// Lines of context shown before and after an edit
+const PREVIEW_CONTEXT: usize = 3;
+
+/// Result of an edit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditPreview {
+    /// First line of the edited region, counting from 1
+    pub start_line: usize,
+    /// Last line of the edited region; `start_line - 1` if the edit only
+    /// removed lines
+    pub end_line: usize,
+    /// Number of lines in the file after the edit
+    pub total_lines: usize,
+    /// The edited region and its surroundings, numbered like `cat -n`
+    pub snippet: String,
+}
+
+impl DbFileSystem {
+    /// Read lines `range` of a text file, without their line endings
+    ///
+    /// Lines past the end of the file are left out.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let lines = agent_fs.fs.read_lines("/src/main.rs", 40..=80).await?;
+    /// ```
+    pub async fn read_lines(&self, path: &str, range: RangeInclusive<usize>) -> Result<Vec<String>> {
+        if *range.start() == 0 {
+            return Err(AgentFsError::InvalidEdit("line numbers start at 1".to_string()));
+        }
+        let content = self
+            .read_file(path)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(path.to_string()))?;
+        let text = as_text(path, &content)?;
+        Ok(split_lines(text)
+            .into_iter()
+            .skip(range.start() - 1)
+            .take(range.end().saturating_sub(range.start() - 1))
+            .map(|line| line.trim_end_matches(['\n', '\r']).to_string())
+            .collect())
+    }
+
+    /// Replace lines `start` to `end` with `text`
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let preview = agent_fs.fs.replace_lines("/src/main.rs", 52, 55, "    run()?;\n").await?;
+    /// println!("{}", preview.snippet);
+    /// ```
+    pub async fn replace_lines(&self, path: &str, start: usize, end: usize, text: &str) -> Result<EditPreview> {
+        self.edit_lines(path, |len| {
+            check_range(start, end, len)?;
+            Ok((start - 1..end, text.to_string()))
+        })
+        .await
+    }
+
+    /// Insert `text` after line `after`, or at the top of the file if
+    /// `after` is 0
+    pub async fn insert_lines(&self, path: &str, after: usize, text: &str) -> Result<EditPreview> {
+        self.edit_lines(path, |len| {
+            if after > len {
+                return Err(AgentFsError::InvalidEdit(format!(
+                    "cannot insert after line {} of a {}-line file",
+                    after, len
+                )));
+            }
+            Ok((after..after, text.to_string()))
+        })
+        .await
+    }
+
+    /// Delete lines `start` to `end`
+    pub async fn delete_lines(&self, path: &str, start: usize, end: usize) -> Result<EditPreview> {
+        self.edit_lines(path, |len| {
+            check_range(start, end, len)?;
+            Ok((start - 1..end, String::new()))
+        })
+        .await
+    }
+
+    /// Replace the only occurrence of `old` with `new`
+    ///
+    /// Fails if `old` occurs zero or several times; include more
+    /// surrounding text to make it unique.
+    pub async fn str_replace(&self, path: &str, old: &str, new: &str) -> Result<EditPreview> {
+        if old.is_empty() {
+            return Err(AgentFsError::InvalidEdit("the string to replace is empty".to_string()));
+        }
+        self.update_file(path, |content| {
+            let text = as_text(path, content)?;
+            let mut matches = text.match_indices(old);
+            let (offset, _) = matches
+                .next()
+                .ok_or_else(|| AgentFsError::InvalidEdit(format!("{:?} does not occur in {}", old, path)))?;
+            let count = 1 + matches.count();
+            if count > 1 {
+                return Err(AgentFsError::InvalidEdit(format!(
+                    "{:?} occurs {} times in {}",
+                    old, count, path
+                )));
+            }
+
+            let edited = format!("{}{}{}", &text[..offset], new, &text[offset + old.len()..]);
+            let start_line = text[..offset].matches('\n').count() + 1;
+            let end_line = start_line + new.trim_end_matches('\n').matches('\n').count();
+            let preview = preview(&edited, start_line, end_line);
+            Ok((edited.into_bytes(), preview))
+        })
+        .await
+    }
+
+    /// Replace a range of line indexes, chosen by `edit` from the number
+    /// of lines in the file, with new text
+    async fn edit_lines<F>(&self, path: &str, edit: F) -> Result<EditPreview>
+    where
+        F: Fn(usize) -> Result<(Range<usize>, String)>,
+    {
+        self.update_file(path, |content| {
+            let text = as_text(path, content)?;
+            let mut lines: Vec<String> = split_lines(text).into_iter().map(str::to_string).collect();
+            let (range, mut replacement) = edit(lines.len())?;
+
+            // Keep the line structure: the new text ends with a line ending,
+            // unless it replaces a last line that had none
+            let unterminated = !text.is_empty() && !text.ends_with('\n');
+            let replaces_last_line = unterminated && range.end == lines.len();
+            if !(replacement.is_empty() || replacement.ends_with('\n') || replaces_last_line) {
+                replacement.push_str(line_ending(text));
+            }
+            if unterminated
+                && range.start == lines.len()
+                && let Some(last) = lines.last_mut()
+            {
+                last.push_str(line_ending(text));
+            }
+
+            let start = range.start;
+            let inserted: Vec<String> = split_lines(&replacement).into_iter().map(str::to_string).collect();
+            let end = start + inserted.len();
+            lines.splice(range, inserted);
+            let edited = lines.concat();
+            let preview = preview(&edited, start + 1, end);
+            Ok((edited.into_bytes(), preview))
+        })
+        .await
+    }
+}
+
+/// Content as UTF-8 text
+fn as_text<'a>(path: &str, content: &'a [u8]) -> Result<&'a str> {
+    std::str::from_utf8(content).map_err(|_| AgentFsError::InvalidEdit(format!("{} is not UTF-8 text", path)))
+}
+
+/// Split text into lines, each keeping its line ending
+fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+/// The line ending used by a text, `\n` unless it uses `\r\n`
+fn line_ending(text: &str) -> &'static str {
+    if text.contains("\r\n") { "\r\n" } else { "\n" }
+}
+
+fn check_range(start: usize, end: usize, len: usize) -> Result<()> {
+    if start == 0 || start > end || end > len {
+        return Err(AgentFsError::InvalidEdit(format!(
+            "lines {}-{} are not within the file's {} lines",
+            start, end, len
+        )));
+    }
+    Ok(())
+}
+
+/// Preview of the edited lines `start_line..=end_line` of `text`
+fn preview(text: &str, start_line: usize, end_line: usize) -> EditPreview {
+    let lines = split_lines(text);
+    let first = start_line.saturating_sub(PREVIEW_CONTEXT).max(1);
+    let last = (end_line.max(start_line) + PREVIEW_CONTEXT).min(lines.len());
+    let snippet = (first..=last)
+        .map(|number| format!("{:>6}\t{}", number, lines[number - 1].trim_end_matches(['\n', '\r'])))
+        .collect::<Vec<_>>()
+        .join("\n");
+    EditPreview { start_line, end_line, total_lines: lines.len(), snippet }
+}
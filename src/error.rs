@@ -20,9 +20,59 @@ pub enum AgentFsError {
     #[error("Invalid path: {0}")]
     InvalidPath(String),
 
+    #[error("Not a directory: {0}")]
+    NotADirectory(String),
+
+    #[error("Is a directory: {0}")]
+    IsADirectory(String),
+
+    #[error("Directory not empty: {0}")]
+    DirectoryNotEmpty(String),
+
+    #[error("Not a symbolic link: {0}")]
+    NotASymlink(String),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
     #[error("Path traversal attempt: {0}")]
     PathTraversal(String),
 
+    #[error("Too many levels of symbolic links: {0}")]
+    SymlinkLoop(String),
+
+    #[error("Path is locked: {0}")]
+    Locked(String),
+
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Read-only filesystem: {0}")]
+    ReadOnly(String),
+
+    #[error("Cross-device link: {0}")]
+    CrossDevice(String),
+
+    #[error("Invalid edit: {0}")]
+    InvalidEdit(String),
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("No embedder configured")]
+    NoEmbedder,
+
+    #[error("{cause}, and rolling back failed: {}", failures.join("; "))]
+    PatchRolledBackPartially {
+        /// Why the patch failed
+        cause: Box<AgentFsError>,
+        /// The changes that could not be reverted, and why
+        failures: Vec<String>,
+    },
+
     #[error("Database error: {0}")]
     Database(#[from] agentdb::AgentDbError),
 
@@ -35,3 +85,50 @@ pub enum AgentFsError {
     #[error(transparent)]
     Other(#[from] Box<dyn std::error::Error + Send + Sync>),
 }
+
+impl AgentFsError {
+    /// The POSIX error number matching this error (Linux numbering)
+    ///
+    /// For FUSE and other POSIX consumers. Errors without a direct POSIX
+    /// equivalent map to `EIO`.
+    pub fn errno(&self) -> i32 {
+        const ENOENT: i32 = 2;
+        const EIO: i32 = 5;
+        const EAGAIN: i32 = 11;
+        const EACCES: i32 = 13;
+        const EEXIST: i32 = 17;
+        const EXDEV: i32 = 18;
+        const ENOTDIR: i32 = 20;
+        const EISDIR: i32 = 21;
+        const EINVAL: i32 = 22;
+        const EROFS: i32 = 30;
+        const ENOTEMPTY: i32 = 39;
+        const ELOOP: i32 = 40;
+        const EOPNOTSUPP: i32 = 95;
+        const EDQUOT: i32 = 122;
+
+        match self {
+            AgentFsError::FileNotFound(_) | AgentFsError::DirectoryNotFound(_) => ENOENT,
+            AgentFsError::PathExists(_) => EEXIST,
+            AgentFsError::InvalidPath(_)
+            | AgentFsError::NotASymlink(_)
+            | AgentFsError::InvalidEdit(_)
+            | AgentFsError::InvalidArgument(_) => EINVAL,
+            AgentFsError::NotADirectory(_) => ENOTDIR,
+            AgentFsError::IsADirectory(_) => EISDIR,
+            AgentFsError::DirectoryNotEmpty(_) => ENOTEMPTY,
+            AgentFsError::PathTraversal(_) | AgentFsError::PermissionDenied(_) => EACCES,
+            AgentFsError::SymlinkLoop(_) => ELOOP,
+            AgentFsError::Locked(_) | AgentFsError::PreconditionFailed(_) => EAGAIN,
+            AgentFsError::QuotaExceeded(_) => EDQUOT,
+            AgentFsError::ReadOnly(_) => EROFS,
+            AgentFsError::CrossDevice(_) => EXDEV,
+            AgentFsError::NoEmbedder => EOPNOTSUPP,
+            AgentFsError::Io(e) => e.raw_os_error().unwrap_or(EIO),
+            AgentFsError::Database(_)
+            | AgentFsError::Serialization(_)
+            | AgentFsError::PatchRolledBackPartially { .. }
+            | AgentFsError::Other(_) => EIO,
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Expiring entries
+//!
+//! Scratch files, caches and downloaded pages can be given an expiry time,
+//! stored on the inode. Once it has passed the entry is invisible: lookups
+//! and directory listings skip it, and changes that reuse its name reap it
+//! on the spot. Reads never write, so expired entries are otherwise
+//! reclaimed by [`DbFileSystem::sweep_expired`], which
+//! [`AgentFS::spawn_sweeper`](crate::AgentFS::spawn_sweeper) runs
+//! periodically in the background.
+
+use crate::AgentFS;
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::{DbFileSystem, WriteOptions};
+use crate::lock::now_millis;
+use crate::path::child_path;
+use crate::permissions::W_OK;
+use crate::usage::subtree;
+use crate::watch::FsEventKind;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+/// Unix time in milliseconds of an expiry time, which must fit in an
+/// `i64`; times before the epoch count as the epoch
+fn expiry_millis(at: SystemTime) -> Result<i64> {
+    let millis = at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+    i64::try_from(millis).map_err(|_| AgentFsError::InvalidArgument(format!("expiry time {:?} is too late", at)))
+}
+
+/// SQL condition holding for inodes (aliased `alias`) that haven't expired
+pub(crate) fn unexpired(alias: &str) -> String {
+    format!("({alias}.expires_at IS NULL OR {alias}.expires_at > {})", now_millis())
+}
+
+impl AgentFS {
+    /// Reclaim expired entries in the background every `interval`
+    ///
+    /// Errors are ignored and retried on the next run. Abort the returned
+    /// handle to stop sweeping.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let sweeper = agent_fs.spawn_sweeper(Duration::from_secs(30));
+    /// // ...
+    /// sweeper.abort();
+    /// ```
+    pub fn spawn_sweeper(&self, interval: Duration) -> JoinHandle<()> {
+        let fs = self.fs.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let _ = fs.sweep_expired().await;
+            }
+        })
+    }
+}
+
+impl DbFileSystem {
+    /// Write a file that disappears once `ttl` has passed
+    ///
+    /// The content and its expiry are set together. Fails with
+    /// `AgentFsError::InvalidArgument` if the expiry time would overflow.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// agent_fs.fs.write_file_with_ttl("/cache/page.html", &html, Duration::from_secs(3600)).await?;
+    /// ```
+    pub async fn write_file_with_ttl(&self, path: &str, content: &[u8], ttl: Duration) -> Result<()> {
+        let at = SystemTime::now()
+            .checked_add(ttl)
+            .ok_or_else(|| AgentFsError::InvalidArgument(format!("TTL of {:?} is too long", ttl)))?;
+        let expires_at = expiry_millis(at)?;
+
+        let path = self.validate_and_normalize_path(path)?;
+        if path == "/" {
+            return Err(AgentFsError::IsADirectory(path));
+        }
+        let (parent_ino, name, existing, canonical) = self.resolve_for_write(&path).await?;
+        let options = WriteOptions { expires_at: Some(expires_at), ..Default::default() };
+        self.write_entry(parent_ino, &name, existing, &canonical, content, options).await
+    }
+
+    /// Set when an entry expires, or make it permanent again with `None`
+    ///
+    /// Symlinks are followed. Expiring a directory takes everything in it
+    /// along.
+    pub async fn set_expiry(&self, path: &str, at: Option<SystemTime>) -> Result<()> {
+        self.ensure_schema().await?;
+        let path = self.validate_and_normalize_path(path)?;
+        let (ino, _) = self
+            .resolve(&path, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(path.clone()))?;
+        if ino == self.root_ino {
+            return Err(AgentFsError::InvalidPath("Cannot expire root directory".to_string()));
+        }
+        self.check_access(ino, W_OK, &path).await?;
+
+        let expires_at = match at {
+            Some(at) => expiry_millis(at)?.to_string(),
+            None => "NULL".to_string(),
+        };
+        let (now, nsec) = Self::now_timespec();
+        let query = format!(
+            "UPDATE fs_inode SET expires_at = {expires_at}, ctime = {now}, ctime_nsec = {nsec} WHERE ino = {ino}"
+        );
+        self.db.query(&query, vec![]).await?;
+        self.record_inode_change(FsEventKind::AttributesChanged, &path, Some(ino)).await
+    }
+
+    /// When an entry expires, if ever
+    pub async fn expiry(&self, path: &str) -> Result<Option<SystemTime>> {
+        self.ensure_schema().await?;
+        let path = self.validate_and_normalize_path(path)?;
+        let (ino, _) = self
+            .resolve(&path, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(path.clone()))?;
+        // The SQL backend reads integers back through 32 bits, truncating
+        // millisecond timestamps, so read seconds and milliseconds apart
+        let query = format!(
+            "SELECT expires_at / 1000 AS secs, expires_at % 1000 AS millis FROM fs_inode \
+             WHERE ino = {} AND expires_at IS NOT NULL",
+            ino
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        let Some(row) = result.rows.first() else {
+            return Ok(None);
+        };
+        let secs = self.extract_i64(row, "secs")?.max(0) as u64;
+        let millis = self.extract_i64(row, "millis")?.max(0) as u64;
+        Ok(Some(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis)))
+    }
+
+    /// Delete every expired entry in the volume, returning how many were
+    /// reclaimed
+    pub async fn sweep_expired(&self) -> Result<usize> {
+        self.ensure_schema().await?;
+        let query = format!(
+            "SELECT d.parent_ino, d.name, d.ino FROM fs_dentry d JOIN fs_inode i ON i.ino = d.ino \
+             WHERE NOT {} AND d.ino IN {} ORDER BY d.id",
+            unexpired("i"),
+            subtree(self.root_ino)
+        );
+        let result = self.db.query(&query, vec![]).await?;
+
+        let mut reclaimed = 0;
+        for row in &result.rows {
+            let parent_ino = self.extract_i64(row, "parent_ino")?;
+            let ino = self.extract_i64(row, "ino")?;
+            let Some(name) = self.extract_string_opt(row, "name") else {
+                continue;
+            };
+            // Gone with an expired directory swept earlier
+            let Some(parent_path) = self.inode_path(parent_ino).await? else {
+                continue;
+            };
+            self.reap(parent_ino, &name, ino).await?;
+            self.touch_dir(parent_ino).await?;
+            self.record_change(FsEventKind::Removed, &child_path(&parent_path, &name)).await?;
+            reclaimed += 1;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Delete the expired entries of a directory
+    pub(crate) async fn reap_expired_children(&self, ino: i64) -> Result<()> {
+        let query = format!(
+            "SELECT d.name, d.ino FROM fs_dentry d JOIN fs_inode i ON i.ino = d.ino WHERE d.parent_ino = {} AND NOT {}",
+            ino,
+            unexpired("i")
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        for row in &result.rows {
+            if let Some(name) = self.extract_string_opt(row, "name") {
+                self.reap(ino, &name, self.extract_i64(row, "ino")?).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unlink the entry `name` of `parent_ino`, and everything below it,
+    /// deleting inodes that are no longer linked anywhere
+    pub(crate) async fn reap(&self, parent_ino: i64, name: &str, ino: i64) -> Result<()> {
+        let mut pending = vec![(parent_ino, name.to_string(), ino)];
+        while let Some((parent_ino, name, ino)) = pending.pop() {
+            let query = format!("SELECT name, ino FROM fs_dentry WHERE parent_ino = {}", ino);
+            let result = self.db.query(&query, vec![]).await?;
+            for row in &result.rows {
+                if let Some(child) = self.extract_string_opt(row, "name") {
+                    pending.push((ino, child, self.extract_i64(row, "ino")?));
+                }
+            }
+
+            let query = format!(
+                "DELETE FROM fs_dentry WHERE parent_ino = {} AND name = '{}'",
+                parent_ino,
+                name.replace('\'', "''")
+            );
+            self.db.query(&query, vec![]).await?;
+            self.purge_unlinked(ino).await?;
+        }
+        Ok(())
+    }
+}
@@ -3,12 +3,21 @@
 //! Based on the Agent Filesystem Specification (SPEC.md).
 //! Uses inode/dentry design for Unix-like filesystem semantics.
 
+use crate::blob::BlobStore;
 use crate::error::{AgentFsError, Result};
+use crate::expiry::unexpired;
+use crate::mime::ContentInfo;
+use crate::path::{PathPolicy, child_path};
+use crate::permissions::{Credentials, R_OK, W_OK, X_OK};
+use crate::semantic::{DEFAULT_ANN_THRESHOLD, Embedder, VectorIndex};
+use crate::usage::Quota;
+use crate::watch::FsEventKind;
 use agentdb::AgentDB;
 use async_trait::async_trait;
-use std::path::Path;
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{OnceCell, watch};
 
 // File type constants for mode field
 pub const S_IFMT: u32 = 0o170000;   // File type mask
@@ -22,6 +31,62 @@ pub const DEFAULT_DIR_MODE: u32 = S_IFDIR | 0o755;  // Directory, rwxr-xr-x
 
 pub const ROOT_INO: i64 = 1;
 
+/// Maximum number of symlinks followed while resolving a single path
+pub const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// Attempts at a read-modify-write before giving up on concurrent writers
+const UPDATE_ATTEMPTS: usize = 8;
+
+/// Key of the KV entry holding the data owned by an inode, for data
+/// written before each write got its own entry
+pub(crate) fn data_key(ino: i64) -> String {
+    format!("__fs_data:{}:0", ino)
+}
+
+/// Key of a new KV entry for data owned by an inode
+///
+/// The time-ordered UUID records when the entry was created, so garbage
+/// collection can spare entries a write is still about to refer to.
+fn fresh_data_key(ino: i64) -> String {
+    format!("__fs_data:{}:{}", ino, uuid::Uuid::now_v7().simple())
+}
+
+/// When the entry `key` was created, in Unix milliseconds, if its key
+/// records it
+pub(crate) fn data_key_created(key: &str) -> Option<i64> {
+    let id = key.rsplit(':').next()?;
+    let (secs, nanos) = uuid::Uuid::try_parse(id).ok()?.get_timestamp()?.to_unix();
+    Some(secs as i64 * 1000 + nanos as i64 / 1_000_000)
+}
+
+/// Conditions and attributes of a write besides its content
+#[derive(Default)]
+pub(crate) struct WriteOptions<'a> {
+    /// Condition the file must meet for the write to happen
+    pub(crate) precondition: Option<&'a Precondition>,
+    /// Expiry time in Unix milliseconds to set along with the content,
+    /// `None` to keep the current one
+    pub(crate) expires_at: Option<i64>,
+}
+
+/// Where staged content lives until an inode refers to it
+struct StagedData {
+    blob_hash: Option<String>,
+    data_ref: Option<String>,
+}
+
+impl StagedData {
+    /// `fs_inode` assignments that make the staged content current
+    fn columns(&self) -> String {
+        let quote = |value: &Option<String>| value.as_ref().map_or("NULL".to_string(), |v| format!("'{}'", v));
+        format!("blob_hash = {}, data_ref = {}", quote(&self.blob_hash), quote(&self.data_ref))
+    }
+}
+
+/// `fs_inode` columns read by `build_stats`
+const STATS_COLUMNS: &str = "ino, mode, uid, gid, size, atime, mtime, ctime, atime_nsec, mtime_nsec, ctime_nsec, \
+     version, content_type, line_count, encoding";
+
 /// File statistics
 #[derive(Debug, Clone)]
 pub struct Stats {
@@ -34,6 +99,20 @@ pub struct Stats {
     pub atime: i64,
     pub mtime: i64,
     pub ctime: i64,
+    /// Nanoseconds within `atime`
+    pub atime_nsec: u32,
+    /// Nanoseconds within `mtime`
+    pub mtime_nsec: u32,
+    /// Nanoseconds within `ctime`
+    pub ctime_nsec: u32,
+    /// Content version, incremented on every write
+    pub version: i64,
+    /// MIME type detected on write, for regular files
+    pub content_type: Option<String>,
+    /// Number of lines, for text files
+    pub line_count: Option<i64>,
+    /// Text encoding, for text files
+    pub encoding: Option<String>,
 }
 
 impl Stats {
@@ -48,6 +127,61 @@ impl Stats {
     pub fn is_symlink(&self) -> bool {
         (self.mode & S_IFMT) == S_IFLNK
     }
+
+    /// Last access time in nanoseconds since the Unix epoch
+    pub fn atime_ns(&self) -> i64 {
+        self.atime * 1_000_000_000 + self.atime_nsec as i64
+    }
+
+    /// Last modification time in nanoseconds since the Unix epoch
+    pub fn mtime_ns(&self) -> i64 {
+        self.mtime * 1_000_000_000 + self.mtime_nsec as i64
+    }
+
+    /// Last status change time in nanoseconds since the Unix epoch
+    pub fn ctime_ns(&self) -> i64 {
+        self.ctime * 1_000_000_000 + self.ctime_nsec as i64
+    }
+
+    /// Opaque tag identifying this version of the file's content
+    ///
+    /// Changes whenever the file is written, and differs between a file
+    /// and one recreated at the same path. Pass it to
+    /// [`Precondition::IfMatch`] to write only if nobody else has.
+    pub fn etag(&self) -> String {
+        format!("{}-{}", self.ino, self.version)
+    }
+}
+
+/// When reads update a file's access time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AtimePolicy {
+    /// Never update atime
+    NoAtime,
+    /// Update atime only if it is older than mtime or ctime, or more than
+    /// a day old (the Linux default)
+    #[default]
+    Relatime,
+    /// Update atime on every read
+    StrictAtime,
+}
+
+/// A directory entry with its stats, returned by [`DbFileSystem::readdir_plus`]
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub stats: Stats,
+}
+
+/// Condition checked atomically by [`DbFileSystem::write_file_if`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Precondition {
+    /// The file exists and its ETag is still the given one
+    IfMatch(String),
+    /// The file doesn't exist yet (create-only, like `O_EXCL`)
+    IfNoneMatch,
+    /// The file exists and wasn't modified after the given Unix timestamp
+    IfUnmodifiedSince(i64),
 }
 
 /// Filesystem trait for agent file operations
@@ -84,68 +218,257 @@ pub trait FileSystem: Send + Sync {
 
     /// Read the target of a symbolic link
     async fn readlink(&self, path: &str) -> Result<Option<String>>;
+
+    /// Rename a file, directory or symlink
+    ///
+    /// An existing destination is replaced, unless it is a non-empty
+    /// directory or its type doesn't match the source.
+    async fn rename(&self, from: &str, to: &str) -> Result<()>;
+
+    /// Copy a file, following a symlink at `src`
+    ///
+    /// An existing file at `dst` is overwritten. This default reads and
+    /// rewrites the content; `DbFileSystem` and `MemFileSystem` instead let
+    /// the copy share the source's data until either file is written.
+    async fn copy(&self, src: &str, dst: &str) -> Result<()> {
+        let stats = self
+            .stat(src)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(src.to_string()))?;
+        if stats.is_directory() {
+            return Err(AgentFsError::IsADirectory(src.to_string()));
+        }
+        let content = self
+            .read_file(src)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(src.to_string()))?;
+        self.write_file(dst, &content).await
+    }
+
+    /// Recursively copy a directory to `dst`, which must not exist yet
+    ///
+    /// Symlinks inside the tree are copied as symlinks, not followed.
+    async fn copy_dir(&self, src: &str, dst: &str) -> Result<()> {
+        let stats = self
+            .stat(src)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(src.to_string()))?;
+        if !stats.is_directory() {
+            return Err(AgentFsError::NotADirectory(src.to_string()));
+        }
+        if self.lstat(dst).await?.is_some() {
+            return Err(AgentFsError::PathExists(dst.to_string()));
+        }
+        let (src_norm, dst_norm) = (crate::path::normalize(src), crate::path::normalize(dst));
+        if dst_norm.starts_with(&format!("{}/", src_norm.trim_end_matches('/'))) {
+            return Err(AgentFsError::InvalidPath(format!("Cannot copy {} into itself", src)));
+        }
+
+        self.mkdir(dst).await?;
+        // Never descend into the copy, even if it is reachable through a symlink
+        let copy_ino = self.stat(dst).await?.map(|stats| stats.ino);
+
+        let mut pending = vec![(src_norm, dst_norm)];
+        while let Some((from, to)) = pending.pop() {
+            for name in self.readdir(&from).await?.unwrap_or_default() {
+                let from_child = child_path(&from, &name);
+                let to_child = child_path(&to, &name);
+                let Some(entry) = self.lstat(&from_child).await? else {
+                    continue;
+                };
+
+                if entry.is_symlink() {
+                    if let Some(target) = self.readlink(&from_child).await? {
+                        self.symlink(&target, &to_child).await?;
+                    }
+                } else if entry.is_directory() {
+                    if Some(entry.ino) == copy_ino {
+                        continue;
+                    }
+                    self.mkdir(&to_child).await?;
+                    pending.push((from_child, to_child));
+                } else {
+                    self.copy(&from_child, &to_child).await?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Database-backed filesystem implementation
 #[derive(Clone)]
 pub struct DbFileSystem {
-    db: Arc<Box<dyn AgentDB>>,
+    pub(crate) db: Arc<Box<dyn AgentDB>>,
     mount_path: String,
+    schema: Arc<OnceCell<()>>,
+    pub(crate) changes: Arc<watch::Sender<u64>>,
+    atime_policy: AtimePolicy,
+    path_policy: PathPolicy,
+    pub(crate) credentials: Option<Arc<Credentials>>,
+    quota: Option<Quota>,
+    pub(crate) root_ino: i64,
+    trash: bool,
+    blob_store: Option<Arc<dyn BlobStore>>,
+    blob_threshold: u64,
+    embedder: Option<Arc<dyn Embedder>>,
+    pub(crate) vector_index: Arc<tokio::sync::Mutex<Option<VectorIndex>>>,
+    ann_threshold: usize,
+    pub(crate) agent_id: Option<String>,
+    pub(crate) tool_call_id: Option<i64>,
 }
 
 impl DbFileSystem {
     /// Create a new database-backed filesystem
+    ///
+    /// The filesystem is the database's original tree, rooted at inode 1.
+    /// Use [`DbFileSystem::open_volume`] to give each agent a tree of its own.
     pub fn new(db: Arc<Box<dyn AgentDB>>, mount_path: String) -> Self {
-        Self { db, mount_path }
+        Self {
+            db,
+            mount_path,
+            schema: Arc::new(OnceCell::new()),
+            changes: Arc::new(watch::channel(0).0),
+            atime_policy: AtimePolicy::default(),
+            path_policy: PathPolicy::default(),
+            credentials: None,
+            quota: None,
+            root_ino: ROOT_INO,
+            trash: false,
+            blob_store: None,
+            blob_threshold: 0,
+            embedder: None,
+            vector_index: Arc::new(tokio::sync::Mutex::new(None)),
+            ann_threshold: DEFAULT_ANN_THRESHOLD,
+            agent_id: None,
+            tool_call_id: None,
+        }
     }
 
-    /// Normalize a path
-    fn normalize_path(&self, path: &str) -> String {
-        let normalized = path.trim_end_matches('/');
-        let normalized = if normalized.is_empty() {
-            "/"
-        } else if normalized.starts_with('/') {
-            normalized
-        } else {
-            return format!("/{}", normalized);
-        };
+    /// Set the rules paths are validated against
+    pub fn with_path_policy(mut self, policy: PathPolicy) -> Self {
+        self.path_policy = policy;
+        self
+    }
 
-        // Handle . and .. components
-        let components: Vec<&str> = normalized.split('/').filter(|s| !s.is_empty()).collect();
-        let mut result = Vec::new();
+    /// Set when reads update access times
+    pub fn with_atime_policy(mut self, policy: AtimePolicy) -> Self {
+        self.atime_policy = policy;
+        self
+    }
 
-        for component in components {
-            match component {
-                "." => continue,
-                ".." => {
-                    if !result.is_empty() {
-                        result.pop();
-                    }
-                }
-                _ => result.push(component),
-            }
-        }
+    /// Limit how much the filesystem may store
+    ///
+    /// Writes that would exceed the quota fail with
+    /// `AgentFsError::QuotaExceeded`.
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = Some(quota);
+        self
+    }
 
-        if result.is_empty() {
-            "/".to_string()
-        } else {
-            format!("/{}", result.join("/"))
-        }
+    /// The quota writes are checked against, if any
+    pub fn quota(&self) -> Option<Quota> {
+        self.quota
+    }
+
+    /// Store file content larger than `threshold` bytes in `store`
+    ///
+    /// The inode then only keeps the content's hash. Files written before
+    /// the store was configured stay in the database.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let blobs = Arc::new(LocalBlobStore::new("/var/lib/agentfs/blobs")?);
+    /// let fs = agent_fs.fs.clone().with_blob_store(blobs, 16 * 1024 * 1024);
+    /// ```
+    pub fn with_blob_store(mut self, store: Arc<dyn BlobStore>, threshold: u64) -> Self {
+        self.blob_store = Some(store);
+        self.blob_threshold = threshold;
+        self
+    }
+
+    /// The store large file content is offloaded to, if any
+    pub fn blob_store(&self) -> Option<&Arc<dyn BlobStore>> {
+        self.blob_store.as_ref()
+    }
+
+    /// Size above which file content is offloaded to the blob store
+    pub fn blob_threshold(&self) -> u64 {
+        self.blob_threshold
+    }
+
+    /// Index text files for [`DbFileSystem::semantic_search`] as they are
+    /// written, using `embedder`
+    pub fn with_embedder(mut self, embedder: Arc<dyn Embedder>) -> Self {
+        self.embedder = Some(embedder);
+        self
+    }
+
+    /// The embedder text files are indexed with, if any
+    pub fn embedder(&self) -> Option<&Arc<dyn Embedder>> {
+        self.embedder.as_ref()
+    }
+
+    /// Make [`DbFileSystem::semantic_search`] use the approximate HNSW
+    /// index when a search covers more than `chunks` chunks, and scan them
+    /// all otherwise
+    ///
+    /// 0 always uses the index, `usize::MAX` never does.
+    pub fn with_ann_threshold(mut self, chunks: usize) -> Self {
+        self.ann_threshold = chunks;
+        self
+    }
+
+    /// Number of chunks above which semantic search is approximate
+    pub fn ann_threshold(&self) -> usize {
+        self.ann_threshold
+    }
+
+    /// Make `remove` move entries to the trash instead of deleting them
+    ///
+    /// Trashed entries can be listed with [`DbFileSystem::list_trash`] and
+    /// put back with [`DbFileSystem::restore`]; their storage is only
+    /// freed by [`DbFileSystem::empty_trash`].
+    pub fn with_trash(mut self, enabled: bool) -> Self {
+        self.trash = enabled;
+        self
+    }
+
+    /// Whether `remove` moves entries to the trash
+    pub fn trash_enabled(&self) -> bool {
+        self.trash
+    }
+
+    /// Create the AgentFS-owned tables on first use
+    pub(crate) async fn ensure_schema(&self) -> Result<()> {
+        self.schema
+            .get_or_try_init(|| crate::schema::migrate(self.db.as_ref().as_ref()))
+            .await?;
+        Ok(())
+    }
+
+    /// Normalize a path
+    fn normalize_path(&self, path: &str) -> String {
+        crate::path::normalize(path)
     }
 
     /// Validate and normalize a path, ensuring it's within the mount point
     ///
     /// This enforces path sandboxing by:
     /// 1. Treating all paths as relative to the mount point
-    /// 2. Normalizing the path (resolving . and ..)
-    /// 3. Ensuring no path traversal escapes the mount point
+    /// 2. Checking names against the [`PathPolicy`]
+    /// 3. Normalizing the path (resolving . and ..)
+    /// 4. Ensuring no path traversal escapes the mount point
     ///
     /// # Security
     ///
     /// This prevents directory traversal attacks by ensuring all paths
     /// are treated as relative to the mount point (e.g., /agent), even if
     /// they start with /. Attempts to traverse outside the mount point
-    /// (e.g., /../../../etc/passwd) are prevented by normalization.
+    /// (e.g., /../../../etc/passwd) are prevented by normalization, or
+    /// rejected with `AgentFsError::PathTraversal` if the path policy
+    /// says so.
     ///
     /// # Path Interpretation
     ///
@@ -162,291 +485,882 @@ impl DbFileSystem {
     /// - "/agent/foo" -> Ok("/foo")
     /// - "foo" -> Ok("/foo")
     /// - "/../../../etc/passwd" -> Ok("/") (normalized, traversal prevented)
-    fn validate_and_normalize_path(&self, path: &str) -> Result<String> {
-        let mount_prefix = self.mount_path.trim_end_matches('/');
-
-        // Check if path explicitly includes the mount point prefix
-        let path_to_normalize = if path.starts_with(&format!("{}/", mount_prefix)) {
-            // Path explicitly includes mount point, strip it
-            &path[mount_prefix.len()..]
-        } else if path == mount_prefix {
-            // Path is exactly the mount point
-            "/"
-        } else {
-            // Treat path as relative to mount point (even if it starts with /)
-            path
-        };
-
-        // Normalize the path (this handles .. and . components)
-        let normalized = self.normalize_path(path_to_normalize);
-
-        // The normalized path is now the internal path, already secured
-        // by the normalization process which prevents escaping the root
-        Ok(normalized)
+    pub(crate) fn validate_and_normalize_path(&self, path: &str) -> Result<String> {
+        crate::path::validate_and_normalize(&self.mount_path, &self.path_policy, path)
     }
 
     /// Split path into components
     fn split_path(&self, path: &str) -> Vec<String> {
-        let normalized = self.normalize_path(path);
-        if normalized == "/" {
-            return vec![];
-        }
-        normalized
-            .split('/')
-            .filter(|p| !p.is_empty())
-            .map(|s| s.to_string())
-            .collect()
+        crate::path::split(path)
     }
 
     /// Get current Unix timestamp
-    fn now() -> i64 {
+    pub(crate) fn now() -> i64 {
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs() as i64
     }
 
+    /// Get current time as Unix seconds and nanoseconds within the second
+    pub(crate) fn now_timespec() -> (i64, u32) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        (now.as_secs() as i64, now.subsec_nanos())
+    }
+
+    /// Set mtime and ctime of a directory whose entries changed
+    pub(crate) async fn touch_dir(&self, ino: i64) -> Result<()> {
+        let (secs, nsec) = Self::now_timespec();
+        let query = format!(
+            "UPDATE fs_inode SET mtime = {secs}, mtime_nsec = {nsec}, ctime = {secs}, ctime_nsec = {nsec} WHERE ino = {ino}"
+        );
+        self.db.query(&query, vec![]).await?;
+        Ok(())
+    }
+
+    /// Record an access to an inode according to the atime policy
+    async fn touch_atime(&self, ino: i64) -> Result<()> {
+        let (secs, nsec) = Self::now_timespec();
+        let condition = match self.atime_policy {
+            AtimePolicy::NoAtime => return Ok(()),
+            AtimePolicy::Relatime => format!(
+                " AND (atime < mtime OR (atime = mtime AND atime_nsec <= mtime_nsec) \
+                 OR atime < ctime OR (atime = ctime AND atime_nsec <= ctime_nsec) OR atime < {})",
+                secs - 24 * 60 * 60
+            ),
+            AtimePolicy::StrictAtime => String::new(),
+        };
+        self.ensure_schema().await?;
+        let query = format!("UPDATE fs_inode SET atime = {secs}, atime_nsec = {nsec} WHERE ino = {ino}{condition}");
+        self.db.query(&query, vec![]).await?;
+        Ok(())
+    }
+
     /// Get link count for an inode
     async fn get_link_count(&self, ino: i64) -> Result<u32> {
         let query = format!("SELECT COUNT(*) as count FROM fs_dentry WHERE ino = {}", ino);
         let result = self.db.query(&query, vec![]).await?;
 
-        if let Some(row) = result.rows.first() {
-            if let Some(count_val) = row.get("count") {
-                let count_bytes = count_val.as_bytes();
-                let count_str = String::from_utf8_lossy(count_bytes);
-                return Ok(count_str.parse().unwrap_or(0));
+        if let Some(row) = result.rows.first()
+            && let Some(count_val) = row.get("count")
+        {
+            let count_bytes = count_val.as_bytes();
+            let count_str = String::from_utf8_lossy(count_bytes);
+            return Ok(count_str.parse().unwrap_or(0));
+        }
+        Ok(0)
+    }
+
+    /// Delete an inode and its data once no entry or trash item refers to it
+    pub(crate) async fn purge_unlinked(&self, ino: i64) -> Result<()> {
+        if self.get_link_count(ino).await? > 0 || self.is_trashed(ino).await? {
+            return Ok(());
+        }
+
+        let freed = self.replaced_bytes(ino).await?;
+        let size = self.apparent_size(ino).await?;
+
+        // Delete data chunks, unless copies still share them
+        self.detach_data(ino).await?;
+        self.delete_data(ino).await?;
+
+        // Delete symlink if exists
+        let query = format!("DELETE FROM fs_symlink WHERE ino = {}", ino);
+        self.db.query(&query, vec![]).await?;
+
+        // Delete inode
+        let query = format!("DELETE FROM fs_inode WHERE ino = {}", ino);
+        self.db.query(&query, vec![]).await?;
+        self.adjust_usage(-freed, -size, -1).await?;
+
+        self.drop_index(ino).await?;
+        self.delete_xattrs(ino).await
+    }
+
+    /// Find the entry `name` in a directory, ignoring it if it has expired
+    ///
+    /// Nothing is written, so lookups work on read-only databases.
+    pub(crate) async fn lookup(&self, parent_ino: i64, name: &str) -> Result<Option<i64>> {
+        Ok(self.find_entry(parent_ino, name).await?.filter(|&(_, expired)| !expired).map(|(ino, _)| ino))
+    }
+
+    /// Find the entry `name` in a directory about to be changed, reaping it
+    /// if it has expired so the name can be reused
+    pub(crate) async fn lookup_for_write(&self, parent_ino: i64, name: &str) -> Result<Option<i64>> {
+        match self.find_entry(parent_ino, name).await? {
+            Some((ino, true)) => {
+                self.reap(parent_ino, name, ino).await?;
+                Ok(None)
+            }
+            entry => Ok(entry.map(|(ino, _)| ino)),
+        }
+    }
+
+    /// The inode of the entry `name` in a directory, and whether it has
+    /// expired
+    async fn find_entry(&self, parent_ino: i64, name: &str) -> Result<Option<(i64, bool)>> {
+        self.ensure_schema().await?;
+        let query = format!(
+            "SELECT d.ino, CASE WHEN {} THEN 0 ELSE 1 END AS expired FROM fs_dentry d LEFT JOIN fs_inode i ON i.ino = d.ino \
+             WHERE d.parent_ino = {} AND d.name = '{}'",
+            unexpired("i"),
+            parent_ino,
+            name.replace('\'', "''")
+        );
+        let result = self.db.query(&query, vec![]).await?;
+
+        let Some(row) = result.rows.first() else {
+            return Ok(None);
+        };
+        Ok(Some((self.extract_i64(row, "ino")?, self.extract_i64(row, "expired")? == 1)))
+    }
+
+    /// Get the mode of an inode
+    pub(crate) async fn inode_mode(&self, ino: i64) -> Result<Option<u32>> {
+        let query = format!("SELECT mode FROM fs_inode WHERE ino = {}", ino);
+        let result = self.db.query(&query, vec![]).await?;
+
+        match result.rows.first() {
+            Some(row) => Ok(Some(self.extract_u32(row, "mode")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Check whether an inode is a directory
+    pub(crate) async fn is_directory(&self, ino: i64) -> Result<bool> {
+        Ok(self.inode_mode(ino).await?.is_some_and(|mode| (mode & S_IFMT) == S_IFDIR))
+    }
+
+    /// Read the target stored for a symlink inode
+    async fn symlink_target(&self, ino: i64) -> Result<Option<String>> {
+        let query = format!("SELECT target FROM fs_symlink WHERE ino = {}", ino);
+        let result = self.db.query(&query, vec![]).await?;
+
+        if let Some(row) = result.rows.first()
+            && let Some(target_val) = row.get("target")
+        {
+            let target = String::from_utf8_lossy(target_val.as_bytes()).to_string();
+            return Ok(Some(target));
+        }
+        Ok(None)
+    }
+
+    /// Resolve a path to an inode number, following symlinks
+    ///
+    /// Symlinks in intermediate components are always followed; the final
+    /// component is followed only with `follow_last` (`stat` vs `lstat`).
+    /// Returns the inode together with the canonical, symlink-free path.
+    ///
+    /// # Security
+    ///
+    /// Resolution happens entirely inside the mount: absolute symlink
+    /// targets are interpreted relative to the mount root, and `..` stops at
+    /// the root, so no target can lead outside the sandbox. At most
+    /// `MAX_SYMLINK_DEPTH` symlinks are followed per path, which turns
+    /// symlink loops into `AgentFsError::SymlinkLoop`.
+    pub(crate) async fn resolve(&self, path: &str, follow_last: bool) -> Result<Option<(i64, String)>> {
+        let mut pending: VecDeque<String> = self.split_path(path).into();
+        let mut resolved: Vec<(String, i64)> = Vec::new();
+        let mut links_followed = 0;
+
+        while let Some(name) = pending.pop_front() {
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    resolved.pop();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let parent_ino = resolved.last().map_or(self.root_ino, |(_, ino)| *ino);
+            self.check_access(parent_ino, X_OK, path).await?;
+            let Some(ino) = self.lookup(parent_ino, &name).await? else {
+                return Ok(None);
+            };
+            let Some(mode) = self.inode_mode(ino).await? else {
+                return Ok(None);
+            };
+            let is_last = pending.is_empty();
+
+            if (mode & S_IFMT) == S_IFLNK && (follow_last || !is_last) {
+                links_followed += 1;
+                if links_followed > MAX_SYMLINK_DEPTH {
+                    return Err(AgentFsError::SymlinkLoop(path.to_string()));
+                }
+
+                let mut target = self
+                    .symlink_target(ino)
+                    .await?
+                    .ok_or_else(|| AgentFsError::InvalidPath("Symlink has no target".to_string()))?;
+                if target.starts_with('/') {
+                    resolved.clear();
+                    target = self.validate_and_normalize_path(&target)?;
+                }
+                for component in target.split('/').filter(|c| !c.is_empty()).rev() {
+                    pending.push_front(component.to_string());
+                }
+                continue;
+            }
+
+            if !is_last && (mode & S_IFMT) != S_IFDIR {
+                return Err(AgentFsError::NotADirectory(path.to_string()));
+            }
+            resolved.push((name, ino));
+        }
+
+        let ino = resolved.last().map_or(self.root_ino, |(_, ino)| *ino);
+        let names: Vec<&str> = resolved.iter().map(|(name, _)| name.as_str()).collect();
+        Ok(Some((ino, format!("/{}", names.join("/")))))
+    }
+
+    /// Resolve a path to an inode number without following a final symlink
+    pub(crate) async fn resolve_path(&self, path: &str) -> Result<Option<i64>> {
+        Ok(self.resolve(path, false).await?.map(|(ino, _)| ino))
+    }
+
+    /// Resolve the parent directory of a path
+    ///
+    /// Returns the parent inode, the final name and the canonical parent path.
+    pub(crate) async fn resolve_parent(&self, path: &str) -> Result<(i64, String, String)> {
+        let components = self.split_path(path);
+        let name = components
+            .last()
+            .cloned()
+            .ok_or_else(|| AgentFsError::InvalidPath("Root directory has no parent".to_string()))?;
+
+        let parent_path = format!("/{}", components[..components.len() - 1].join("/"));
+        let (parent_ino, parent_canonical) = self
+            .resolve(&parent_path, true)
+            .await?
+            .ok_or_else(|| AgentFsError::DirectoryNotFound(parent_path.clone()))?;
+        if !self.is_directory(parent_ino).await? {
+            return Err(AgentFsError::NotADirectory(parent_path));
+        }
+        self.check_access(parent_ino, X_OK, path).await?;
+
+        Ok((parent_ino, name, parent_canonical))
+    }
+
+    /// Find where a write to `path` lands
+    ///
+    /// Like `open(O_CREAT)`, a symlink in the final component is followed,
+    /// even when it dangles, so the write goes to its target. Returns the
+    /// parent inode, the final name, the existing inode (if any) and the
+    /// canonical path.
+    pub(crate) async fn resolve_for_write(&self, path: &str) -> Result<(i64, String, Option<i64>, String)> {
+        let mut current = path.to_string();
+        for _ in 0..=MAX_SYMLINK_DEPTH {
+            let (parent_ino, name, parent_path) = self.resolve_parent(&current).await?;
+            let existing = self.lookup_for_write(parent_ino, &name).await?;
+
+            if let Some(ino) = existing
+                && self.inode_mode(ino).await?.is_some_and(|mode| (mode & S_IFMT) == S_IFLNK)
+            {
+                let target = self
+                    .symlink_target(ino)
+                    .await?
+                    .ok_or_else(|| AgentFsError::InvalidPath("Symlink has no target".to_string()))?;
+                current = if target.starts_with('/') {
+                    self.validate_and_normalize_path(&target)?
+                } else {
+                    self.normalize_path(&format!("{}/{}", parent_path, target))
+                };
+                continue;
+            }
+
+            return Ok((parent_ino, name.clone(), existing, child_path(&parent_path, &name)));
+        }
+
+        Err(AgentFsError::SymlinkLoop(path.to_string()))
+    }
+
+    /// Create an inode and return its number
+    pub(crate) async fn create_inode(&self, mode: u32, size: i64) -> Result<i64> {
+        self.ensure_schema().await?;
+        self.check_quota("inode quota", 0, 1).await?;
+        let ino = self.insert_inode(mode, size).await?;
+        let bytes = if (mode & S_IFMT) == S_IFREG { size } else { 0 };
+        self.adjust_usage(bytes, bytes, 1).await?;
+        Ok(ino)
+    }
+
+    /// Insert an inode into this volume, outside its quota and usage,
+    /// returning its number
+    pub(crate) async fn insert_inode(&self, mode: u32, size: i64) -> Result<i64> {
+        let (now, nsec) = Self::now_timespec();
+        let (uid, gid) = self.creator();
+        let query = format!(
+            "INSERT INTO fs_inode (mode, uid, gid, size, atime, mtime, ctime, atime_nsec, mtime_nsec, ctime_nsec, root_ino) \
+             VALUES ({mode}, {uid}, {gid}, {size}, {now}, {now}, {now}, {nsec}, {nsec}, {nsec}, {})",
+            self.root_ino
+        );
+        self.db.query(&query, vec![]).await?;
+        self.inserted_ino().await
+    }
+
+    /// Number of the inode inserted last
+    pub(crate) async fn inserted_ino(&self) -> Result<i64> {
+        let query = "SELECT last_insert_rowid() as ino".to_string();
+        let result = self.db.query(&query, vec![]).await?;
+        match result.rows.first() {
+            Some(row) => self.extract_i64(row, "ino"),
+            None => Err(AgentFsError::Database(agentdb::AgentDbError::Backend("Failed to get inode".to_string()))),
+        }
+    }
+
+    /// Create a directory entry
+    pub(crate) async fn create_dentry(&self, parent_ino: i64, name: &str, ino: i64) -> Result<()> {
+        let query = format!(
+            "INSERT INTO fs_dentry (name, parent_ino, ino) VALUES ('{}', {}, {})",
+            name.replace('\'', "''"),
+            parent_ino,
+            ino
+        );
+        self.db.query(&query, vec![]).await?;
+        Ok(())
+    }
+
+    /// Get stats for an inode
+    pub(crate) async fn stat_inode(&self, ino: i64) -> Result<Option<Stats>> {
+        self.ensure_schema().await?;
+        let query = format!(
+            "SELECT {} FROM fs_inode WHERE ino = {}",
+            STATS_COLUMNS,
+            ino
+        );
+        let result = self.db.query(&query, vec![]).await?;
+
+        match result.rows.first() {
+            Some(row) => Ok(Some(self.build_stats(ino, row).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the content of a file inode
+    pub(crate) async fn read_data(&self, ino: i64) -> Result<Vec<u8>> {
+        let ino = self.data_owner(ino).await?;
+        if let Some(hash) = self.blob_ref(ino).await? {
+            return self.read_blob(&hash).await;
+        }
+
+        // Temporary workaround using KV store
+        let mut current = self.current_data_ref(ino).await?.flatten();
+        while let Some(key) = current {
+            if let Some(value) = self.db.get(&key).await? {
+                return Ok(value.as_bytes().to_vec());
+            }
+            // A concurrent write replaced the entry after we looked it up
+            let next = self.current_data_ref(ino).await?.flatten();
+            if next.as_ref() == Some(&key) {
+                return Ok(Vec::new());
+            }
+            current = next;
+        }
+        if let Some(value) = self.db.get(&data_key(ino)).await? {
+            return Ok(value.as_bytes().to_vec());
+        }
+
+        // If no data in KV, try fs_data table
+        let query = format!("SELECT data FROM fs_data WHERE ino = {} ORDER BY offset", ino);
+        let result = self.db.query(&query, vec![]).await?;
+
+        let mut data = Vec::new();
+        for row in &result.rows {
+            if let Some(chunk) = row.get("data") {
+                data.extend_from_slice(chunk.as_bytes());
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Inode whose data holds the content of `ino`
+    ///
+    /// A copy shares its source's data until either of them is written; its
+    /// `data_ino` then names the source. The owner itself never shares
+    /// another inode's data, so this is at most one hop.
+    pub(crate) async fn data_owner(&self, ino: i64) -> Result<i64> {
+        self.ensure_schema().await?;
+        let query = format!("SELECT data_ino FROM fs_inode WHERE ino = {}", ino);
+        let result = self.db.query(&query, vec![]).await?;
+        Ok(result
+            .rows
+            .first()
+            .and_then(|row| self.extract_string_opt(row, "data_ino"))
+            .and_then(|owner| owner.parse().ok())
+            .unwrap_or(ino))
+    }
+
+    /// Stop `ino` from sharing data with other inodes
+    ///
+    /// A copy simply drops its reference. If `ino` owns data that copies
+    /// still share, the data is handed over to the lowest-numbered copy,
+    /// which becomes the new owner. Afterwards `ino`'s own data can be
+    /// replaced or deleted without affecting anyone else.
+    pub(crate) async fn detach_data(&self, ino: i64) -> Result<()> {
+        let owner = self.data_owner(ino).await?;
+        if owner != ino {
+            let query = format!("UPDATE fs_inode SET data_ino = NULL WHERE ino = {}", ino);
+            self.db.query(&query, vec![]).await?;
+            return Ok(());
+        }
+
+        let query = format!("SELECT ino FROM fs_inode WHERE data_ino = {} ORDER BY ino LIMIT 1", ino);
+        let result = self.db.query(&query, vec![]).await?;
+        let Some(row) = result.rows.first() else {
+            return Ok(());
+        };
+        let heir = self.extract_i64(row, "ino")?;
+
+        if let Some(hash) = self.blob_ref(ino).await? {
+            let query = format!("UPDATE fs_inode SET blob_hash = '{}' WHERE ino = {}", hash, heir);
+            self.db.query(&query, vec![]).await?;
+        } else {
+            let data = self.read_data(ino).await?;
+            let key = fresh_data_key(heir);
+            self.db.put(&key, data.as_slice().into()).await?;
+            let query = format!("UPDATE fs_inode SET data_ref = '{}' WHERE ino = {}", key, heir);
+            self.db.query(&query, vec![]).await?;
+        }
+        let query = format!("UPDATE fs_inode SET data_ino = NULL WHERE ino = {}", heir);
+        self.db.query(&query, vec![]).await?;
+        let query = format!("UPDATE fs_inode SET data_ino = {} WHERE data_ino = {}", heir, ino);
+        self.db.query(&query, vec![]).await?;
+        Ok(())
+    }
+
+    /// Delete the data owned by `ino`, which must be detached first
+    ///
+    /// An offloaded blob is left for garbage collection, as other files
+    /// with the same content may refer to it.
+    pub(crate) async fn delete_data(&self, ino: i64) -> Result<()> {
+        let current = self.current_data_ref(ino).await?.flatten();
+        let query = format!("UPDATE fs_inode SET blob_hash = NULL, data_ref = NULL WHERE ino = {}", ino);
+        self.db.query(&query, vec![]).await?;
+        self.release_data(ino, current).await
+    }
+
+    /// Key of the KV entry holding the data owned by `ino`
+    ///
+    /// `Some(None)` means the data predates per-write entries; `None` means
+    /// the inode is gone.
+    async fn current_data_ref(&self, ino: i64) -> Result<Option<Option<String>>> {
+        let query = format!("SELECT data_ref FROM fs_inode WHERE ino = {}", ino);
+        let result = self.db.query(&query, vec![]).await?;
+        Ok(result.rows.first().map(|row| self.extract_string_opt(row, "data_ref")))
+    }
+
+    /// Store content for `ino` where no reader sees it yet
+    ///
+    /// Large content goes to the blob store, which must hold it before the
+    /// inode refers to it; other content gets a KV entry of its own.
+    async fn stage_data(&self, ino: i64, content: &[u8]) -> Result<StagedData> {
+        if let Some(hash) = self.offload(content).await? {
+            return Ok(StagedData { blob_hash: Some(hash), data_ref: None });
+        }
+        // Store data as a KV entry temporarily (workaround for BLOB binding issue)
+        // TODO: Use proper BLOB insertion once we have parameterized queries
+        let key = fresh_data_key(ino);
+        self.db.put(&key, content.into()).await?;
+        Ok(StagedData { blob_hash: None, data_ref: Some(key) })
+    }
+
+    /// Delete staged content that never became current
+    async fn discard_data(&self, staged: StagedData) {
+        if let Some(key) = staged.data_ref {
+            let _ = self.db.delete(&key).await;
+        }
+    }
+
+    /// Delete the inline data `ino` referred to before its data was
+    /// replaced: the entry `replaced`, or data predating per-write entries
+    async fn release_data(&self, ino: i64, replaced: Option<String>) -> Result<()> {
+        match replaced {
+            Some(key) => {
+                let _ = self.db.delete(&key).await;
+            }
+            None => {
+                let query = format!("DELETE FROM fs_data WHERE ino = {}", ino);
+                self.db.query(&query, vec![]).await?;
+                let _ = self.db.delete(&data_key(ino)).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write content to the entry `name` in `parent_ino`, creating the inode
+    /// if `existing` is `None`
+    ///
+    /// The content is stored first, where no reader sees it yet. With a
+    /// precondition, the check, the version bump and the switch to the new
+    /// content then happen in a single `UPDATE`, so of two racing
+    /// conditional writers only one wins, and the version a writer claims
+    /// always names its own content. An expiry is set in the same
+    /// `UPDATE`, so the content is never visible without it. Text is
+    /// embedded for semantic search before anything is changed.
+    pub(crate) async fn write_entry(
+        &self,
+        parent_ino: i64,
+        name: &str,
+        existing: Option<i64>,
+        path: &str,
+        content: &[u8],
+        options: WriteOptions<'_>,
+    ) -> Result<()> {
+        self.ensure_schema().await?;
+        let precondition = options.precondition;
+        let expiry = options.expires_at.map_or(String::new(), |at| format!(", expires_at = {}", at));
+        let failed = || AgentFsError::PreconditionFailed(path.to_string());
+        let info = crate::mime::inspect(name, content);
+
+        let (ino, chunks) = if let Some(ino) = existing {
+            if self.is_directory(ino).await? {
+                return Err(AgentFsError::IsADirectory(path.to_string()));
+            }
+            self.check_access(ino, W_OK, path).await?;
+            let growth = content.len() as i64 - self.replaced_bytes(ino).await?;
+            self.check_quota(path, growth, 0).await?;
+
+            let condition = match precondition {
+                None => String::new(),
+                Some(Precondition::IfNoneMatch) => return Err(failed()),
+                Some(Precondition::IfMatch(etag)) => {
+                    let version = parse_etag(etag)
+                        .filter(|(tag_ino, _)| *tag_ino == ino)
+                        .map(|(_, version)| version)
+                        .ok_or_else(failed)?;
+                    format!(" AND version = {}", version)
+                }
+                Some(Precondition::IfUnmodifiedSince(time)) => format!(" AND mtime <= {}", time),
+            };
+            let chunks = self.embed_content(content, info.encoding.is_some()).await?;
+
+            // Copies sharing this file's data get their own first. A copy
+            // itself stops sharing in the `UPDATE` below, so it keeps its
+            // content if the precondition fails.
+            if self.data_owner(ino).await? == ino {
+                self.detach_data(ino).await?;
+            }
+            let staged = self.stage_data(ino, content).await?;
+            let (now, nsec) = Self::now_timespec();
+            loop {
+                // Only the writer that switches away from an entry releases
+                // it, so the switch also requires the entry to be current
+                let Some(replaced) = self.current_data_ref(ino).await? else {
+                    self.discard_data(staged).await;
+                    return Err(AgentFsError::FileNotFound(path.to_string()));
+                };
+                let current = match &replaced {
+                    Some(key) => format!("data_ref = '{}'", key),
+                    None => "data_ref IS NULL".to_string(),
+                };
+                // The size is part of the condition so the usage counters
+                // learn exactly how much it changed
+                let old_size = self.apparent_size(ino).await?;
+
+                // Update size, times and content metadata, claiming the next version
+                let query = format!(
+                    "UPDATE fs_inode SET size = {}, mtime = {now}, mtime_nsec = {nsec}, ctime = {now}, ctime_nsec = {nsec}, \
+                     version = version + 1, data_ino = NULL, {}, {}{} WHERE ino = {} AND {} AND size = {}{}",
+                    content.len(),
+                    content_columns(&info),
+                    staged.columns(),
+                    expiry,
+                    ino,
+                    current,
+                    old_size,
+                    condition
+                );
+                if self.db.query(&query, vec![]).await?.rows_affected > 0 {
+                    self.adjust_usage(growth, content.len() as i64 - old_size, 0).await?;
+                    self.release_data(ino, replaced).await?;
+                    break;
+                }
+                if precondition.is_some() {
+                    // Whoever replaced the content also bumped the version
+                    self.discard_data(staged).await;
+                    return Err(failed());
+                }
+            }
+            (ino, chunks)
+        } else {
+            if matches!(precondition, Some(Precondition::IfMatch(_) | Precondition::IfUnmodifiedSince(_))) {
+                return Err(failed());
             }
-        }
-        Ok(0)
-    }
-
-    /// Resolve a path to an inode number
-    async fn resolve_path(&self, path: &str) -> Result<Option<i64>> {
-        let components = self.split_path(path);
-        if components.is_empty() {
-            return Ok(Some(ROOT_INO));
-        }
+            self.check_access(parent_ino, W_OK | X_OK, path).await?;
+            self.check_quota(path, content.len() as i64, 0).await?;
+            let chunks = self.embed_content(content, info.encoding.is_some()).await?;
 
-        let mut current_ino = ROOT_INO;
-        for component in components {
+            let ino = self.create_inode(DEFAULT_FILE_MODE, content.len() as i64).await?;
+            let staged = self.stage_data(ino, content).await?;
             let query = format!(
-                "SELECT ino FROM fs_dentry WHERE parent_ino = {} AND name = '{}'",
-                current_ino,
-                component.replace('\'', "''")
+                "UPDATE fs_inode SET {}, {}{} WHERE ino = {}",
+                content_columns(&info),
+                staged.columns(),
+                expiry,
+                ino
             );
-            let result = self.db.query(&query, vec![]).await?;
-
-            if let Some(row) = result.rows.first() {
-                if let Some(ino_val) = row.get("ino") {
-                    let ino_bytes = ino_val.as_bytes();
-                    let ino_str = String::from_utf8_lossy(ino_bytes);
-                    current_ino = ino_str.parse().unwrap_or(0);
-                } else {
-                    return Ok(None);
+            self.db.query(&query, vec![]).await?;
+            if let Err(e) = self.create_dentry(parent_ino, name, ino).await {
+                // Somebody else created the entry first
+                self.db.query(&format!("DELETE FROM fs_inode WHERE ino = {}", ino), vec![]).await?;
+                self.adjust_usage(-(content.len() as i64), -(content.len() as i64), -1).await?;
+                self.discard_data(staged).await;
+                if precondition.is_some() && self.lookup_for_write(parent_ino, name).await?.is_some() {
+                    return Err(failed());
                 }
-            } else {
-                return Ok(None);
+                return Err(e);
             }
-        }
+            self.touch_dir(parent_ino).await?;
+            (ino, chunks)
+        };
+
+        self.store_index(ino, chunks).await?;
+
+        let kind = if existing.is_some() { FsEventKind::Modified } else { FsEventKind::Created };
+        self.record_change(kind, path).await?;
 
-        Ok(Some(current_ino))
+        Ok(())
     }
 
-    /// Build stats from query result
-    async fn build_stats(&self, ino: i64, mode: u32, uid: u32, gid: u32, size: i64, atime: i64, mtime: i64, ctime: i64) -> Result<Stats> {
+    /// Build stats from an `fs_inode` query row
+    async fn build_stats(&self, ino: i64, row: &agentdb::Row) -> Result<Stats> {
         let nlink = self.get_link_count(ino).await?;
         Ok(Stats {
             ino,
-            mode,
+            mode: self.extract_u32(row, "mode")?,
             nlink,
-            uid,
-            gid,
-            size,
-            atime,
-            mtime,
-            ctime,
+            uid: self.extract_u32(row, "uid")?,
+            gid: self.extract_u32(row, "gid")?,
+            size: self.extract_i64(row, "size")?,
+            atime: self.extract_i64(row, "atime")?,
+            mtime: self.extract_i64(row, "mtime")?,
+            ctime: self.extract_i64(row, "ctime")?,
+            atime_nsec: self.extract_u32(row, "atime_nsec")?,
+            mtime_nsec: self.extract_u32(row, "mtime_nsec")?,
+            ctime_nsec: self.extract_u32(row, "ctime_nsec")?,
+            version: self.extract_i64(row, "version")?,
+            content_type: self.extract_string_opt(row, "content_type"),
+            line_count: self.extract_string_opt(row, "line_count").and_then(|n| n.parse().ok()),
+            encoding: self.extract_string_opt(row, "encoding"),
         })
     }
-}
 
-#[async_trait]
-impl FileSystem for DbFileSystem {
-    async fn write_file(&self, path: &str, content: &[u8]) -> Result<()> {
+    /// Read a file without following a symlink in the final component
+    ///
+    /// Like `O_NOFOLLOW`: fails with `AgentFsError::SymlinkLoop` if `path`
+    /// itself is a symlink. Symlinks in parent directories are followed.
+    pub async fn read_file_nofollow(&self, path: &str) -> Result<Option<Vec<u8>>> {
         let path = self.validate_and_normalize_path(path)?;
-        let components = self.split_path(&path);
-
-        if components.is_empty() {
-            return Err(AgentFsError::InvalidPath("Cannot write to root directory".to_string()));
+        let Some(ino) = self.resolve_path(&path).await? else {
+            return Ok(None);
+        };
+        if self.inode_mode(ino).await?.is_some_and(|mode| (mode & S_IFMT) == S_IFLNK) {
+            return Err(AgentFsError::SymlinkLoop(path));
+        }
+        if self.is_directory(ino).await? {
+            return Err(AgentFsError::IsADirectory(path));
         }
+        self.check_access(ino, R_OK, &path).await?;
+        self.touch_atime(ino).await?;
+        Ok(Some(self.read_data(ino).await?))
+    }
 
-        let parent_path = if components.len() == 1 {
-            "/".to_string()
-        } else {
-            format!("/{}", components[..components.len() - 1].join("/"))
-        };
+    /// Write a file without following a symlink in the final component
+    ///
+    /// Like `O_NOFOLLOW`: fails with `AgentFsError::SymlinkLoop` if `path`
+    /// itself is a symlink. Symlinks in parent directories are followed.
+    pub async fn write_file_nofollow(&self, path: &str, content: &[u8]) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
+        if path == "/" {
+            return Err(AgentFsError::IsADirectory(path));
+        }
 
-        let parent_ino = self
-            .resolve_path(&parent_path)
-            .await?
-            .ok_or_else(|| AgentFsError::DirectoryNotFound(parent_path.clone()))?;
+        let (parent_ino, name, parent_path) = self.resolve_parent(&path).await?;
+        let existing = self.lookup_for_write(parent_ino, &name).await?;
+        if let Some(ino) = existing
+            && self.inode_mode(ino).await?.is_some_and(|mode| (mode & S_IFMT) == S_IFLNK)
+        {
+            return Err(AgentFsError::SymlinkLoop(path));
+        }
 
-        let name = components.last().unwrap();
+        let path = child_path(&parent_path, &name);
+        self.write_entry(parent_ino, &name, existing, &path, content, WriteOptions::default()).await
+    }
 
-        // Check if file exists
-        let ino = if let Some(ino) = self.resolve_path(&path).await? {
-            // Delete existing data chunks
-            let query = format!("DELETE FROM fs_data WHERE ino = {}", ino);
-            self.db.query(&query, vec![]).await?;
-            ino
-        } else {
-            // Create new inode
-            let now = Self::now();
-            let query = format!(
-                "INSERT INTO fs_inode (mode, uid, gid, size, atime, mtime, ctime) VALUES ({}, 0, 0, {}, {}, {}, {})",
-                DEFAULT_FILE_MODE, content.len(), now, now, now
-            );
-            self.db.query(&query, vec![]).await?;
+    /// Write a file only if a precondition holds
+    ///
+    /// Fails with `AgentFsError::PreconditionFailed` if it doesn't, leaving
+    /// the file untouched. The check is atomic with respect to other
+    /// writers, so this implements compare-and-swap on file content.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let stats = agent_fs.fs.stat("/state.json").await?.unwrap();
+    /// let state = agent_fs.fs.read_file("/state.json").await?.unwrap();
+    /// // ... modify state ...
+    /// match agent_fs.fs.write_file_if("/state.json", &state, Precondition::IfMatch(stats.etag())).await {
+    ///     Err(AgentFsError::PreconditionFailed(_)) => { /* changed meanwhile, re-read and retry */ }
+    ///     result => result?,
+    /// }
+    /// ```
+    pub async fn write_file_if(&self, path: &str, content: &[u8], precondition: Precondition) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
+        if path == "/" {
+            return Err(AgentFsError::IsADirectory(path));
+        }
 
-            // Get the new inode number
-            let query = "SELECT last_insert_rowid() as ino".to_string();
-            let result = self.db.query(&query, vec![]).await?;
-            let ino = if let Some(row) = result.rows.first() {
-                if let Some(ino_val) = row.get("ino") {
-                    let ino_str = String::from_utf8_lossy(ino_val.as_bytes());
-                    ino_str.parse().unwrap_or(0)
-                } else {
-                    return Err(AgentFsError::Database(agentdb::AgentDbError::Backend("Failed to get inode".to_string())));
-                }
-            } else {
-                return Err(AgentFsError::Database(agentdb::AgentDbError::Backend("Failed to get inode".to_string())));
-            };
+        let (parent_ino, name, existing, canonical) = self.resolve_for_write(&path).await?;
+        let options = WriteOptions { precondition: Some(&precondition), ..Default::default() };
+        self.write_entry(parent_ino, &name, existing, &canonical, content, options).await
+    }
 
-            // Create directory entry
-            let query = format!(
-                "INSERT INTO fs_dentry (name, parent_ino, ino) VALUES ('{}', {}, {})",
-                name.replace('\'', "''"),
-                parent_ino,
-                ino
-            );
-            self.db.query(&query, vec![]).await?;
+    /// Read, transform and conditionally write back a file until no
+    /// concurrent writer gets in the way
+    ///
+    /// `update` maps the current content to the new content and a value
+    /// returned once the write succeeds. An error from `update` leaves the
+    /// file untouched.
+    pub(crate) async fn update_file<T, F>(&self, path: &str, update: F) -> Result<T>
+    where
+        F: Fn(&[u8]) -> Result<(Vec<u8>, T)>,
+    {
+        for _ in 0..UPDATE_ATTEMPTS {
+            let stats = self
+                .stat(path)
+                .await?
+                .ok_or_else(|| AgentFsError::FileNotFound(path.to_string()))?;
+            let content = self
+                .read_file(path)
+                .await?
+                .ok_or_else(|| AgentFsError::FileNotFound(path.to_string()))?;
+            let (content, value) = update(&content)?;
+
+            match self.write_file_if(path, &content, Precondition::IfMatch(stats.etag())).await {
+                Err(AgentFsError::PreconditionFailed(_)) => continue,
+                result => return result.map(|_| value),
+            }
+        }
+        Err(AgentFsError::PreconditionFailed(format!(
+            "{} kept changing while it was being updated",
+            path
+        )))
+    }
 
-            ino
+    /// List a directory together with the stats of every entry
+    ///
+    /// Entries are not followed, so symlinks are reported as symlinks.
+    /// Saves a `stat` round trip per entry when an agent needs to know what
+    /// kind of content each file holds.
+    pub async fn readdir_plus(&self, path: &str) -> Result<Option<Vec<DirEntry>>> {
+        self.ensure_schema().await?;
+        let path = self.validate_and_normalize_path(path)?;
+        let Some((ino, _)) = self.resolve(&path, true).await? else {
+            return Ok(None);
         };
-
-        // Write data chunk
-        if !content.is_empty() {
-            // Store data as a KV entry temporarily (workaround for BLOB binding issue)
-            let data_key = format!("__fs_data:{}:0", ino);
-            self.db.put(&data_key, content.into()).await?;
-
-            // TODO: Use proper BLOB insertion once we have parameterized queries
-            // For now we'll need to retrieve and insert via a workaround
+        if !self.is_directory(ino).await? {
+            return Err(AgentFsError::NotADirectory(path));
         }
+        self.check_access(ino, R_OK, &path).await?;
 
-        // Update size and mtime
-        let now = Self::now();
+        let columns = STATS_COLUMNS
+            .split(", ")
+            .map(|column| format!("i.{}", column))
+            .collect::<Vec<_>>()
+            .join(", ");
         let query = format!(
-            "UPDATE fs_inode SET size = {}, mtime = {} WHERE ino = {}",
-            content.len(),
-            now,
-            ino
+            "SELECT d.name, {} FROM fs_dentry d JOIN fs_inode i ON i.ino = d.ino WHERE d.parent_ino = {} AND {} ORDER BY d.name",
+            columns,
+            ino,
+            unexpired("i")
         );
-        self.db.query(&query, vec![]).await?;
+        let result = self.db.query(&query, vec![]).await?;
 
-        Ok(())
-    }
+        let mut entries = Vec::with_capacity(result.rows.len());
+        for row in &result.rows {
+            let stats = self.build_stats(self.extract_i64(row, "ino")?, row).await?;
+            entries.push(DirEntry { name: self.extract_string_opt(row, "name").unwrap_or_default(), stats });
+        }
 
-    async fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
-        // Follow symlinks to get the final inode
-        let path = self.validate_and_normalize_path(path)?;
-        let mut current_path = path.clone();
-        let max_symlink_depth = 40;
-
-        let ino = 'resolve: loop {
-            for _ in 0..max_symlink_depth {
-                let ino = match self.resolve_path(&current_path).await? {
-                    Some(ino) => ino,
-                    None => return Ok(None),
-                };
+        Ok(Some(entries))
+    }
+}
 
-                // Check if it's a symlink
-                let query = format!(
-                    "SELECT mode FROM fs_inode WHERE ino = {}",
-                    ino
-                );
-                let result = self.db.query(&query, vec![]).await?;
-
-                if let Some(row) = result.rows.first() {
-                    let mode = self.extract_u32(row, "mode")?;
-
-                    if (mode & S_IFMT) == S_IFLNK {
-                        // It's a symlink, follow it
-                        let target = self.readlink(&current_path).await?
-                            .ok_or_else(|| AgentFsError::InvalidPath("Symlink has no target".to_string()))?;
-
-                        // Resolve target path
-                        current_path = if target.starts_with('/') {
-                            target
-                        } else {
-                            let base = Path::new(&current_path);
-                            let parent = base.parent().unwrap_or(Path::new("/"));
-                            let joined = parent.join(&target);
-                            self.normalize_path(&joined.to_string_lossy())
-                        };
-                        continue;
-                    }
+/// SQL assignments storing detected content metadata
+fn content_columns(info: &ContentInfo) -> String {
+    let text = |value: Option<&str>| match value {
+        Some(v) => format!("'{}'", v.replace('\'', "''")),
+        None => "NULL".to_string(),
+    };
+    format!(
+        "content_type = {}, line_count = {}, encoding = {}",
+        text(Some(&info.content_type)),
+        info.line_count.map_or("NULL".to_string(), |n| n.to_string()),
+        text(info.encoding.as_deref())
+    )
+}
 
-                    // Not a symlink, use this inode
-                    break 'resolve ino;
-                } else {
-                    return Ok(None);
-                }
-            }
+/// Split an ETag produced by [`Stats::etag`] into inode and version
+pub(crate) fn parse_etag(etag: &str) -> Option<(i64, i64)> {
+    let (ino, version) = etag.split_once('-')?;
+    Some((ino.parse().ok()?, version.parse().ok()?))
+}
 
-            return Err(AgentFsError::InvalidPath("Too many levels of symbolic links".to_string()));
-        };
+#[async_trait]
+impl FileSystem for DbFileSystem {
+    async fn write_file(&self, path: &str, content: &[u8]) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
 
-        // Read data chunks
-        // Temporary workaround using KV store
-        let data_key = format!("__fs_data:{}:0", ino);
-        if let Some(value) = self.db.get(&data_key).await? {
-            return Ok(Some(value.as_bytes().to_vec()));
+        if path == "/" {
+            return Err(AgentFsError::IsADirectory(path));
         }
 
-        // If no data in KV, try fs_data table
-        let query = format!("SELECT data FROM fs_data WHERE ino = {} ORDER BY offset", ino);
-        let result = self.db.query(&query, vec![]).await?;
-
-        if result.rows.is_empty() {
-            return Ok(Some(Vec::new())); // Empty file
-        }
+        let (parent_ino, name, existing, canonical) = self.resolve_for_write(&path).await?;
+        self.write_entry(parent_ino, &name, existing, &canonical, content, WriteOptions::default()).await
+    }
 
-        let mut data = Vec::new();
-        for row in &result.rows {
-            if let Some(chunk) = row.get("data") {
-                data.extend_from_slice(chunk.as_bytes());
-            }
+    async fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        // Follow symlinks to get the final inode
+        let path = self.validate_and_normalize_path(path)?;
+        let Some((ino, _)) = self.resolve(&path, true).await? else {
+            return Ok(None);
+        };
+        if self.is_directory(ino).await? {
+            return Err(AgentFsError::IsADirectory(path));
         }
+        self.check_access(ino, R_OK, &path).await?;
 
-        Ok(Some(data))
+        self.touch_atime(ino).await?;
+        Ok(Some(self.read_data(ino).await?))
     }
 
     async fn exists(&self, path: &str) -> Result<bool> {
         let path = self.validate_and_normalize_path(path)?;
-        Ok(self.resolve_path(&path).await?.is_some())
+        match self.resolve_path(&path).await {
+            Ok(ino) => Ok(ino.is_some()),
+            Err(AgentFsError::NotADirectory(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
     }
 
     async fn readdir(&self, path: &str) -> Result<Option<Vec<String>>> {
         let path = self.validate_and_normalize_path(path)?;
-        let ino = match self.resolve_path(&path).await? {
-            Some(ino) => ino,
-            None => return Ok(None),
+        let Some((ino, _)) = self.resolve(&path, true).await? else {
+            return Ok(None);
         };
 
+        if !self.is_directory(ino).await? {
+            return Err(AgentFsError::NotADirectory(path));
+        }
+        self.check_access(ino, R_OK, &path).await?;
+
+        self.touch_atime(ino).await?;
         let query = format!(
-            "SELECT name FROM fs_dentry WHERE parent_ino = {} ORDER BY name",
-            ino
+            "SELECT d.name FROM fs_dentry d JOIN fs_inode i ON i.ino = d.ino WHERE d.parent_ino = {} AND {} ORDER BY d.name",
+            ino,
+            unexpired("i")
         );
         let result = self.db.query(&query, vec![]).await?;
 
@@ -463,108 +1377,60 @@ impl FileSystem for DbFileSystem {
 
     async fn mkdir(&self, path: &str) -> Result<()> {
         let path = self.validate_and_normalize_path(path)?;
-        let components = self.split_path(&path);
 
-        if components.is_empty() {
+        if path == "/" {
             return Err(AgentFsError::InvalidPath("Cannot create root directory".to_string()));
         }
 
-        let parent_path = if components.len() == 1 {
-            "/".to_string()
-        } else {
-            format!("/{}", components[..components.len() - 1].join("/"))
-        };
-
-        let parent_ino = self
-            .resolve_path(&parent_path)
-            .await?
-            .ok_or_else(|| AgentFsError::DirectoryNotFound(parent_path))?;
-
-        let name = components.last().unwrap();
+        let (parent_ino, name, parent_path) = self.resolve_parent(&path).await?;
+        self.check_access(parent_ino, W_OK | X_OK, &path).await?;
 
         // Check if already exists
-        if self.resolve_path(&path).await?.is_some() {
+        if self.lookup_for_write(parent_ino, &name).await?.is_some() {
             return Err(AgentFsError::PathExists(path));
         }
 
-        // Create inode
-        let now = Self::now();
-        let query = format!(
-            "INSERT INTO fs_inode (mode, uid, gid, size, atime, mtime, ctime) VALUES ({}, 0, 0, 0, {}, {}, {})",
-            DEFAULT_DIR_MODE, now, now, now
-        );
-        self.db.query(&query, vec![]).await?;
-
-        // Get new inode number
-        let query = "SELECT last_insert_rowid() as ino".to_string();
-        let result = self.db.query(&query, vec![]).await?;
-        let ino = if let Some(row) = result.rows.first() {
-            if let Some(ino_val) = row.get("ino") {
-                let ino_str = String::from_utf8_lossy(ino_val.as_bytes());
-                ino_str.parse().unwrap_or(0)
-            } else {
-                return Err(AgentFsError::Database(agentdb::AgentDbError::Backend("Failed to get inode".to_string())));
-            }
-        } else {
-            return Err(AgentFsError::Database(agentdb::AgentDbError::Backend("Failed to get inode".to_string())));
-        };
+        let ino = self.create_inode(DEFAULT_DIR_MODE, 0).await?;
+        self.create_dentry(parent_ino, &name, ino).await?;
+        self.touch_dir(parent_ino).await?;
 
-        // Create directory entry
-        let query = format!(
-            "INSERT INTO fs_dentry (name, parent_ino, ino) VALUES ('{}', {}, {})",
-            name.replace('\'', "''"),
-            parent_ino,
-            ino
-        );
-        self.db.query(&query, vec![]).await?;
+        self.record_change(FsEventKind::Created, &child_path(&parent_path, &name)).await?;
 
         Ok(())
     }
 
     async fn remove(&self, path: &str) -> Result<()> {
         let path = self.validate_and_normalize_path(path)?;
-        let components = self.split_path(&path);
 
-        if components.is_empty() {
+        if path == "/" {
             return Err(AgentFsError::InvalidPath("Cannot remove root directory".to_string()));
         }
 
+        let (parent_ino, name, parent_path) = self.resolve_parent(&path).await?;
+        self.check_access(parent_ino, W_OK | X_OK, &path).await?;
         let ino = self
-            .resolve_path(&path)
+            .lookup_for_write(parent_ino, &name)
             .await?
             .ok_or_else(|| AgentFsError::FileNotFound(path.clone()))?;
 
-        if ino == ROOT_INO {
+        if ino == self.root_ino {
             return Err(AgentFsError::InvalidPath("Cannot remove root directory".to_string()));
         }
 
-        // Check if directory is empty
+        // Check if directory is empty, once expired entries are gone
+        self.reap_expired_children(ino).await?;
         let query = format!("SELECT COUNT(*) as count FROM fs_dentry WHERE parent_ino = {}", ino);
         let result = self.db.query(&query, vec![]).await?;
-        if let Some(row) = result.rows.first() {
-            if let Some(count_val) = row.get("count") {
-                let count_str = String::from_utf8_lossy(count_val.as_bytes());
-                let count: i64 = count_str.parse().unwrap_or(0);
-                if count > 0 {
-                    return Err(AgentFsError::InvalidPath("Directory not empty".to_string()));
-                }
+        if let Some(row) = result.rows.first()
+            && let Some(count_val) = row.get("count")
+        {
+            let count_str = String::from_utf8_lossy(count_val.as_bytes());
+            let count: i64 = count_str.parse().unwrap_or(0);
+            if count > 0 {
+                return Err(AgentFsError::DirectoryNotEmpty(path));
             }
         }
 
-        // Get parent directory and name
-        let parent_path = if components.len() == 1 {
-            "/".to_string()
-        } else {
-            format!("/{}", components[..components.len() - 1].join("/"))
-        };
-
-        let parent_ino = self
-            .resolve_path(&parent_path)
-            .await?
-            .ok_or_else(|| AgentFsError::DirectoryNotFound(parent_path))?;
-
-        let name = components.last().unwrap();
-
         // Delete the directory entry
         let query = format!(
             "DELETE FROM fs_dentry WHERE parent_ino = {} AND name = '{}'",
@@ -573,184 +1439,66 @@ impl FileSystem for DbFileSystem {
         );
         self.db.query(&query, vec![]).await?;
 
-        // Check if this was the last link
-        let link_count = self.get_link_count(ino).await?;
-        if link_count == 0 {
-            // Delete data chunks
-            let query = format!("DELETE FROM fs_data WHERE ino = {}", ino);
-            self.db.query(&query, vec![]).await?;
-
-            // Delete symlink if exists
-            let query = format!("DELETE FROM fs_symlink WHERE ino = {}", ino);
-            self.db.query(&query, vec![]).await?;
-
-            // Delete inode
-            let query = format!("DELETE FROM fs_inode WHERE ino = {}", ino);
-            self.db.query(&query, vec![]).await?;
-
-            // Clean up temp KV data
-            let data_key = format!("__fs_data:{}:0", ino);
-            let _ = self.db.delete(&data_key).await;
+        if self.trash {
+            // Keep the inode, reachable only from the trash
+            self.move_to_trash(ino, &child_path(&parent_path, &name)).await?;
+        } else {
+            self.purge_unlinked(ino).await?;
         }
 
+        self.touch_dir(parent_ino).await?;
+        self.record_change(FsEventKind::Removed, &child_path(&parent_path, &name)).await?;
+
         Ok(())
     }
 
     async fn stat(&self, path: &str) -> Result<Option<Stats>> {
         let path = self.validate_and_normalize_path(path)?;
-
-        // Follow symlinks with a maximum depth
-        let mut current_path = path;
-        let max_symlink_depth = 40;
-
-        for _ in 0..max_symlink_depth {
-            let ino = match self.resolve_path(&current_path).await? {
-                Some(ino) => ino,
-                None => return Ok(None),
-            };
-
-            let query = format!(
-                "SELECT ino, mode, uid, gid, size, atime, mtime, ctime FROM fs_inode WHERE ino = {}",
-                ino
-            );
-            let result = self.db.query(&query, vec![]).await?;
-
-            if let Some(row) = result.rows.first() {
-                let mode = self.extract_u32(row, "mode")?;
-
-                // Check if symlink
-                if (mode & S_IFMT) == S_IFLNK {
-                    // Read symlink target
-                    let target = self.readlink(&current_path).await?
-                        .ok_or_else(|| AgentFsError::InvalidPath("Symlink has no target".to_string()))?;
-
-                    // Resolve target path
-                    current_path = if target.starts_with('/') {
-                        target
-                    } else {
-                        let base = Path::new(&current_path);
-                        let parent = base.parent().unwrap_or(Path::new("/"));
-                        let joined = parent.join(&target);
-                        self.normalize_path(&joined.to_string_lossy())
-                    };
-                    continue;
-                }
-
-                // Not a symlink, return stats
-                return Ok(Some(self.build_stats(
-                    ino,
-                    mode,
-                    self.extract_u32(row, "uid")?,
-                    self.extract_u32(row, "gid")?,
-                    self.extract_i64(row, "size")?,
-                    self.extract_i64(row, "atime")?,
-                    self.extract_i64(row, "mtime")?,
-                    self.extract_i64(row, "ctime")?,
-                ).await?));
-            } else {
-                return Ok(None);
-            }
+        match self.resolve(&path, true).await? {
+            Some((ino, _)) => self.stat_inode(ino).await,
+            None => Ok(None),
         }
-
-        Err(AgentFsError::InvalidPath("Too many levels of symbolic links".to_string()))
     }
 
     async fn lstat(&self, path: &str) -> Result<Option<Stats>> {
         let path = self.validate_and_normalize_path(path)?;
-        let ino = match self.resolve_path(&path).await? {
-            Some(ino) => ino,
-            None => return Ok(None),
-        };
-
-        let query = format!(
-            "SELECT ino, mode, uid, gid, size, atime, mtime, ctime FROM fs_inode WHERE ino = {}",
-            ino
-        );
-        let result = self.db.query(&query, vec![]).await?;
-
-        if let Some(row) = result.rows.first() {
-            Ok(Some(self.build_stats(
-                ino,
-                self.extract_u32(row, "mode")?,
-                self.extract_u32(row, "uid")?,
-                self.extract_u32(row, "gid")?,
-                self.extract_i64(row, "size")?,
-                self.extract_i64(row, "atime")?,
-                self.extract_i64(row, "mtime")?,
-                self.extract_i64(row, "ctime")?,
-            ).await?))
-        } else {
-            Ok(None)
+        match self.resolve_path(&path).await? {
+            Some(ino) => self.stat_inode(ino).await,
+            None => Ok(None),
         }
     }
 
     async fn symlink(&self, target: &str, linkpath: &str) -> Result<()> {
         let linkpath = self.validate_and_normalize_path(linkpath)?;
-        let components = self.split_path(&linkpath);
 
-        if components.is_empty() {
+        if linkpath == "/" {
             return Err(AgentFsError::InvalidPath("Cannot create symlink at root".to_string()));
         }
 
-        let parent_path = if components.len() == 1 {
-            "/".to_string()
-        } else {
-            format!("/{}", components[..components.len() - 1].join("/"))
-        };
-
-        let parent_ino = self
-            .resolve_path(&parent_path)
-            .await?
-            .ok_or_else(|| AgentFsError::DirectoryNotFound(parent_path))?;
-
-        let name = components.last().unwrap();
+        let (parent_ino, name, parent_path) = self.resolve_parent(&linkpath).await?;
+        self.check_access(parent_ino, W_OK | X_OK, &linkpath).await?;
 
         // Check if already exists
-        if self.resolve_path(&linkpath).await?.is_some() {
+        if self.lookup_for_write(parent_ino, &name).await?.is_some() {
             return Err(AgentFsError::PathExists(linkpath));
         }
 
         // Create inode for symlink
-        let now = Self::now();
         let mode = S_IFLNK | 0o777;
-        let size = target.len() as i64;
-
-        let query = format!(
-            "INSERT INTO fs_inode (mode, uid, gid, size, atime, mtime, ctime) VALUES ({}, 0, 0, {}, {}, {}, {})",
-            mode, size, now, now, now
-        );
-        self.db.query(&query, vec![]).await?;
-
-        // Get new inode
-        let query = "SELECT last_insert_rowid() as ino".to_string();
-        let result = self.db.query(&query, vec![]).await?;
-        let ino = if let Some(row) = result.rows.first() {
-            if let Some(ino_val) = row.get("ino") {
-                let ino_str = String::from_utf8_lossy(ino_val.as_bytes());
-                ino_str.parse().unwrap_or(0)
-            } else {
-                return Err(AgentFsError::Database(agentdb::AgentDbError::Backend("Failed to get inode".to_string())));
-            }
-        } else {
-            return Err(AgentFsError::Database(agentdb::AgentDbError::Backend("Failed to get inode".to_string())));
-        };
+        let ino = self.create_inode(mode, target.len() as i64).await?;
 
         // Store symlink target
         let query = format!(
             "INSERT INTO fs_symlink (ino, target) VALUES ({}, '{}')",
             ino,
-            target.replace('\'', "''")
+            self.path_policy.normalize_target(target).replace('\'', "''")
         );
         self.db.query(&query, vec![]).await?;
 
-        // Create directory entry
-        let query = format!(
-            "INSERT INTO fs_dentry (name, parent_ino, ino) VALUES ('{}', {}, {})",
-            name.replace('\'', "''"),
-            parent_ino,
-            ino
-        );
-        self.db.query(&query, vec![]).await?;
+        self.create_dentry(parent_ino, &name, ino).await?;
+        self.touch_dir(parent_ino).await?;
+
+        self.record_change(FsEventKind::Created, &child_path(&parent_path, &name)).await?;
 
         Ok(())
     }
@@ -763,36 +1511,121 @@ impl FileSystem for DbFileSystem {
         };
 
         // Check if it's a symlink
-        let query = format!("SELECT mode FROM fs_inode WHERE ino = {}", ino);
-        let result = self.db.query(&query, vec![]).await?;
-
-        if let Some(row) = result.rows.first() {
-            let mode = self.extract_u32(row, "mode")?;
-            if (mode & S_IFMT) != S_IFLNK {
-                return Err(AgentFsError::InvalidPath("Not a symbolic link".to_string()));
+        match self.inode_mode(ino).await? {
+            Some(mode) if (mode & S_IFMT) != S_IFLNK => {
+                return Err(AgentFsError::NotASymlink(path));
             }
-        } else {
-            return Ok(None);
+            Some(_) => {}
+            None => return Ok(None),
         }
 
         // Read target from fs_symlink table
-        let query = format!("SELECT target FROM fs_symlink WHERE ino = {}", ino);
-        let result = self.db.query(&query, vec![]).await?;
+        self.symlink_target(ino).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from = self.validate_and_normalize_path(from)?;
+        let to = self.validate_and_normalize_path(to)?;
+
+        if from == "/" || to == "/" {
+            return Err(AgentFsError::InvalidPath("Cannot rename root directory".to_string()));
+        }
+
+        let (from_parent_ino, from_name, from_parent) = self.resolve_parent(&from).await?;
+        let (to_parent_ino, to_name, to_parent) = self.resolve_parent(&to).await?;
+        self.check_access(from_parent_ino, W_OK | X_OK, &from).await?;
+        self.check_access(to_parent_ino, W_OK | X_OK, &to).await?;
+        let from = child_path(&from_parent, &from_name);
+        let to = child_path(&to_parent, &to_name);
+
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(&format!("{}/", from)) {
+            return Err(AgentFsError::InvalidPath(format!(
+                "Cannot move {} into its own subdirectory",
+                from
+            )));
+        }
+
+        let ino = self
+            .lookup_for_write(from_parent_ino, &from_name)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(from.clone()))?;
+
+        let (from_name_sql, to_name_sql) = (from_name.replace('\'', "''"), to_name.replace('\'', "''"));
+        loop {
+            let Some(existing) = self.lookup_for_write(to_parent_ino, &to_name).await? else {
+                let query = format!(
+                    "UPDATE fs_dentry SET parent_ino = {}, name = '{}' WHERE parent_ino = {} AND name = '{}'",
+                    to_parent_ino, to_name_sql, from_parent_ino, from_name_sql
+                );
+                self.db.query(&query, vec![]).await?;
+                break;
+            };
+            if existing == ino {
+                return Ok(());
+            }
+
+            // Replace an existing destination of a compatible type
+            let is_dir = self.is_directory(existing).await?;
+            match (self.is_directory(ino).await?, is_dir) {
+                (true, false) => return Err(AgentFsError::NotADirectory(to)),
+                (false, true) => return Err(AgentFsError::IsADirectory(to)),
+                _ => {}
+            }
+            let empty = if is_dir {
+                self.reap_expired_children(existing).await?;
+                format!(" AND NOT EXISTS (SELECT 1 FROM fs_dentry WHERE parent_ino = {})", existing)
+            } else {
+                String::new()
+            };
 
-        if let Some(row) = result.rows.first() {
-            if let Some(target_val) = row.get("target") {
-                let target = String::from_utf8_lossy(target_val.as_bytes()).to_string();
-                return Ok(Some(target));
+            // Point the destination entry at the source in one statement,
+            // so the destination never goes missing, then drop the source
+            // entry. Somebody else changing the destination meanwhile
+            // makes us look again.
+            let query = format!(
+                "UPDATE fs_dentry SET ino = {} WHERE parent_ino = {} AND name = '{}' AND ino = {}{}",
+                ino, to_parent_ino, to_name_sql, existing, empty
+            );
+            if self.db.query(&query, vec![]).await?.rows_affected == 0 {
+                if is_dir && self.lookup_for_write(to_parent_ino, &to_name).await? == Some(existing) {
+                    return Err(AgentFsError::DirectoryNotEmpty(to));
+                }
+                continue;
             }
+            let query = format!(
+                "DELETE FROM fs_dentry WHERE parent_ino = {} AND name = '{}' AND ino = {}",
+                from_parent_ino, from_name_sql, ino
+            );
+            self.db.query(&query, vec![]).await?;
+            self.purge_unlinked(existing).await?;
+            break;
         }
 
-        Ok(None)
+        self.touch_dir(from_parent_ino).await?;
+        if to_parent_ino != from_parent_ino {
+            self.touch_dir(to_parent_ino).await?;
+        }
+
+        self.record_change(FsEventKind::Renamed { to }, &from).await?;
+
+        Ok(())
+    }
+
+    async fn copy(&self, src: &str, dst: &str) -> Result<()> {
+        self.copy_file(src, dst).await
+    }
+
+    async fn copy_dir(&self, src: &str, dst: &str) -> Result<()> {
+        self.copy_tree(src, dst).await
     }
 }
 
 impl DbFileSystem {
     /// Helper to extract i64 from row
-    fn extract_i64(&self, row: &agentdb::Row, column: &str) -> Result<i64> {
+    pub(crate) fn extract_i64(&self, row: &agentdb::Row, column: &str) -> Result<i64> {
         row.get(column)
             .ok_or_else(|| AgentFsError::Database(agentdb::AgentDbError::Backend(format!("Missing column: {}", column))))
             .and_then(|val| {
@@ -801,8 +1634,16 @@ impl DbFileSystem {
             })
     }
 
+    /// Helper to extract an optional string from row, treating NULL and
+    /// empty values as absent
+    pub(crate) fn extract_string_opt(&self, row: &agentdb::Row, column: &str) -> Option<String> {
+        row.get(column)
+            .map(|val| String::from_utf8_lossy(val.as_bytes()).to_string())
+            .filter(|s| !s.is_empty())
+    }
+
     /// Helper to extract u32 from row
-    fn extract_u32(&self, row: &agentdb::Row, column: &str) -> Result<u32> {
+    pub(crate) fn extract_u32(&self, row: &agentdb::Row, column: &str) -> Result<u32> {
         row.get(column)
             .ok_or_else(|| AgentFsError::Database(agentdb::AgentDbError::Backend(format!("Missing column: {}", column))))
             .and_then(|val| {
@@ -0,0 +1,313 @@
+//! Filesystem integrity checking and repair
+//!
+//! Crashes and partial writes can leave the inode/dentry tables
+//! inconsistent, because a single filesystem operation spans several
+//! statements. `fsck` finds each class of inconsistency and can repair it.
+//!
+//! A check only covers the volume it runs on: the tree below its root and
+//! the entries in its trash. An orphaned inode has no path, so it counts as
+//! part of the volume it was created in, which every inode records. Data
+//! keys no inode refers to belong to no volume and are reported by every
+//! check.
+
+use crate::error::Result;
+use crate::filesystem::{DbFileSystem, ROOT_INO, S_IFLNK, S_IFMT, S_IFREG, data_key, data_key_created};
+use crate::gc::DATA_GRACE_PERIOD;
+use crate::lock::now_millis;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Directory that orphaned inodes are reattached to during repair
+pub const LOST_AND_FOUND: &str = "/lost+found";
+
+/// A directory entry that refers to an inode that doesn't exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DanglingDentry {
+    pub parent_ino: i64,
+    pub name: String,
+    pub ino: i64,
+}
+
+/// A file whose recorded size doesn't match its stored content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeMismatch {
+    pub ino: i64,
+    pub recorded: i64,
+    pub actual: i64,
+}
+
+/// A file whose content is in a blob that the blob store doesn't hold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingBlob {
+    pub ino: i64,
+    pub hash: String,
+}
+
+/// Result of a filesystem check
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    /// Inodes that no directory entry refers to
+    pub orphan_inodes: Vec<i64>,
+    /// Directory entries whose inode or parent inode is missing
+    pub dangling_dentries: Vec<DanglingDentry>,
+    /// `__fs_data` keys no inode refers to
+    pub orphan_data_keys: Vec<String>,
+    /// Symlink inodes without an `fs_symlink` row
+    pub symlinks_without_target: Vec<i64>,
+    /// Regular files whose `size` doesn't match the stored data
+    pub size_mismatches: Vec<SizeMismatch>,
+    /// Regular files whose blob is missing; repair can't bring it back
+    pub missing_blobs: Vec<MissingBlob>,
+    /// Whether the problems were repaired
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    /// Total number of problems found
+    pub fn problem_count(&self) -> usize {
+        self.orphan_inodes.len()
+            + self.dangling_dentries.len()
+            + self.orphan_data_keys.len()
+            + self.symlinks_without_target.len()
+            + self.size_mismatches.len()
+            + self.missing_blobs.len()
+    }
+
+    /// Check whether no problems were found
+    pub fn is_clean(&self) -> bool {
+        self.problem_count() == 0
+    }
+}
+
+impl DbFileSystem {
+    /// Check the filesystem for inconsistencies, optionally repairing them
+    ///
+    /// Repair deletes dangling directory entries and orphaned data, removes
+    /// symlinks whose target was lost, corrects recorded sizes, and moves
+    /// orphaned inodes into `/lost+found` as `#<ino>` so their content can
+    /// be inspected. Files whose blob is missing are only reported, as the
+    /// content has to come back from a copy of the blob store.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let report = agent_fs.fs.fsck(false).await?;
+    /// if !report.is_clean() {
+    ///     agent_fs.fs.fsck(true).await?;
+    /// }
+    /// ```
+    pub async fn fsck(&self, repair: bool) -> Result<FsckReport> {
+        self.ensure_schema().await?;
+        let (size_mismatches, missing_blobs) = self.check_sizes().await?;
+        let mut report = FsckReport {
+            orphan_inodes: self.find_orphan_inodes().await?,
+            dangling_dentries: self.find_dangling_dentries().await?,
+            orphan_data_keys: self.find_orphan_data_keys(DATA_GRACE_PERIOD).await?,
+            symlinks_without_target: self.find_symlinks_without_target().await?,
+            size_mismatches,
+            missing_blobs,
+            repaired: false,
+        };
+
+        if !repair {
+            return Ok(report);
+        }
+
+        for dentry in &report.dangling_dentries {
+            let query = format!(
+                "DELETE FROM fs_dentry WHERE parent_ino = {} AND name = '{}'",
+                dentry.parent_ino,
+                dentry.name.replace('\'', "''")
+            );
+            self.db.query(&query, vec![]).await?;
+        }
+
+        for ino in &report.symlinks_without_target {
+            self.db.query(&format!("DELETE FROM fs_dentry WHERE ino = {}", ino), vec![]).await?;
+            self.db.query(&format!("DELETE FROM fs_inode WHERE ino = {}", ino), vec![]).await?;
+        }
+
+        // Deleting dangling entries can orphan further inodes
+        for ino in self.find_orphan_inodes().await? {
+            if !report.orphan_inodes.contains(&ino) {
+                report.orphan_inodes.push(ino);
+            }
+            self.reattach_orphan(ino).await?;
+        }
+
+        for key in &report.orphan_data_keys {
+            self.db.delete(key).await?;
+        }
+
+        for mismatch in &report.size_mismatches {
+            let query = format!(
+                "UPDATE fs_inode SET size = {} WHERE ino = {}",
+                mismatch.actual, mismatch.ino
+            );
+            self.db.query(&query, vec![]).await?;
+        }
+
+        // Repairs change what the volume holds without adjusting the counters
+        self.reset_usage().await?;
+
+        report.repaired = true;
+        Ok(report)
+    }
+
+    /// Link an orphaned inode into `/lost+found`
+    async fn reattach_orphan(&self, ino: i64) -> Result<()> {
+        let lost_found_ino = match self.resolve(LOST_AND_FOUND, true).await? {
+            Some((ino, _)) => ino,
+            None => {
+                crate::FileSystem::mkdir(self, LOST_AND_FOUND).await?;
+                self.resolve(LOST_AND_FOUND, true).await?.map_or(self.root_ino, |(ino, _)| ino)
+            }
+        };
+
+        let query = format!(
+            "INSERT INTO fs_dentry (name, parent_ino, ino) VALUES ('#{}', {}, {})",
+            ino, lost_found_ino, ino
+        );
+        self.db.query(&query, vec![]).await?;
+        Ok(())
+    }
+
+    async fn find_orphan_inodes(&self) -> Result<Vec<i64>> {
+        let query = format!(
+            "SELECT ino FROM fs_inode WHERE root_ino = {} AND ino <> {} AND ino NOT IN (SELECT ino FROM fs_dentry) \
+             AND ino NOT IN (SELECT root_ino FROM fs_volume) AND ino NOT IN (SELECT ino FROM fs_trash) ORDER BY ino",
+            self.root_ino, ROOT_INO
+        );
+        self.query_inos(&query).await
+    }
+
+    async fn find_dangling_dentries(&self) -> Result<Vec<DanglingDentry>> {
+        let query = format!(
+            "SELECT parent_ino, name, ino FROM fs_dentry
+             WHERE parent_ino IN {}
+               AND (ino NOT IN (SELECT ino FROM fs_inode) OR parent_ino NOT IN (SELECT ino FROM fs_inode))
+             ORDER BY parent_ino, name",
+            self.volume_inodes()
+        );
+        let result = self.db.query(&query, vec![]).await?;
+
+        let mut dentries = Vec::new();
+        for row in &result.rows {
+            dentries.push(DanglingDentry {
+                parent_ino: self.extract_i64(row, "parent_ino")?,
+                name: row
+                    .get("name")
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+                    .unwrap_or_default(),
+                ino: self.extract_i64(row, "ino")?,
+            });
+        }
+        Ok(dentries)
+    }
+
+    /// Find `__fs_data` entries no inode refers to
+    ///
+    /// That is entries of removed inodes, and entries of live inodes that
+    /// are neither their current data nor data predating per-write
+    /// entries. The latter are left from interrupted writes or failed
+    /// cleanups, and only count once older than `grace_period`, as a
+    /// write in progress may be about to refer to them.
+    pub(crate) async fn find_orphan_data_keys(&self, grace_period: Duration) -> Result<Vec<String>> {
+        // List keys before inodes: a file's inode is created before its data,
+        // and its data is stored before the inode refers to it, so a
+        // concurrently written file can't be mistaken for an orphan
+        let keys = self.db.scan("__fs_data:").await?.keys;
+        let result = self.db.query("SELECT ino, data_ref FROM fs_inode", vec![]).await?;
+        let mut inodes = HashMap::new();
+        for row in &result.rows {
+            inodes.insert(self.extract_i64(row, "ino")?, self.extract_string_opt(row, "data_ref"));
+        }
+        let cutoff = now_millis() - grace_period.as_millis() as i64;
+
+        Ok(keys
+            .into_iter()
+            .filter(|key| {
+                let owner = key.split(':').nth(1).and_then(|ino| ino.parse::<i64>().ok());
+                match owner.and_then(|ino| Some((ino, inodes.get(&ino)?))) {
+                    None => true,
+                    Some((ino, data_ref)) => {
+                        data_ref.as_ref() != Some(key)
+                            && *key != data_key(ino)
+                            && data_key_created(key).is_none_or(|created| created < cutoff)
+                    }
+                }
+            })
+            .collect())
+    }
+
+    async fn find_symlinks_without_target(&self) -> Result<Vec<i64>> {
+        let query = format!(
+            "SELECT ino FROM fs_inode WHERE (mode & {}) = {} AND ino NOT IN (SELECT ino FROM fs_symlink) \
+             AND ino IN {} ORDER BY ino",
+            S_IFMT,
+            S_IFLNK,
+            self.volume_inodes()
+        );
+        self.query_inos(&query).await
+    }
+
+    /// Compare recorded sizes with the stored data
+    ///
+    /// Blob-backed files are checked against the blob store's listing
+    /// rather than read, and those whose blob is missing are reported
+    /// separately.
+    async fn check_sizes(&self) -> Result<(Vec<SizeMismatch>, Vec<MissingBlob>)> {
+        let query = format!(
+            "SELECT ino, size, CASE WHEN data_ino IS NULL THEN blob_hash \
+             ELSE (SELECT o.blob_hash FROM fs_inode o WHERE o.ino = fs_inode.data_ino) END AS blob_hash \
+             FROM fs_inode WHERE (mode & {}) = {} AND ino IN {} ORDER BY ino",
+            S_IFMT,
+            S_IFREG,
+            self.volume_inodes()
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        let blob_sizes: HashMap<String, u64> = match self.blob_store() {
+            Some(store) => store.list().await?.into_iter().map(|blob| (blob.hash, blob.size)).collect(),
+            None => HashMap::new(),
+        };
+
+        let mut mismatches = Vec::new();
+        let mut missing = Vec::new();
+        for row in &result.rows {
+            let ino = self.extract_i64(row, "ino")?;
+            let recorded = self.extract_i64(row, "size")?;
+            let actual = match self.extract_string_opt(row, "blob_hash").filter(|hash| !hash.is_empty()) {
+                Some(hash) => match blob_sizes.get(&hash) {
+                    Some(&size) => size as i64,
+                    None => {
+                        missing.push(MissingBlob { ino, hash });
+                        continue;
+                    }
+                },
+                None => self.read_data(ino).await?.len() as i64,
+            };
+            if recorded != actual {
+                mismatches.push(SizeMismatch { ino, recorded, actual });
+            }
+        }
+        Ok((mismatches, missing))
+    }
+
+    /// Subquery selecting the inodes reachable from the volume root or its
+    /// trash, including inodes that entries refer to but which are missing
+    fn volume_inodes(&self) -> String {
+        format!(
+            "(WITH RECURSIVE tree(ino) AS (\
+             SELECT ino FROM fs_inode WHERE ino = {0} OR ino IN (SELECT ino FROM fs_trash WHERE root_ino = {0}) \
+             UNION SELECT d.ino FROM fs_dentry d JOIN tree t ON d.parent_ino = t.ino) \
+             SELECT ino FROM tree)",
+            self.root_ino
+        )
+    }
+
+    /// Run a query returning an `ino` column
+    async fn query_inos(&self, query: &str) -> Result<Vec<i64>> {
+        let result = self.db.query(query, vec![]).await?;
+        result.rows.iter().map(|row| self.extract_i64(row, "ino")).collect()
+    }
+}
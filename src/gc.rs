@@ -0,0 +1,242 @@
+//! Garbage collection and compaction
+//!
+//! Removing a file deletes its data on a best-effort basis, and crashes can
+//! interrupt multi-statement operations, so unreferenced data slowly piles
+//! up. `gc` finds and deletes it; `compact` returns the freed space to the
+//! operating system.
+
+use crate::error::Result;
+use crate::filesystem::DbFileSystem;
+use crate::lock::now_millis;
+use crate::AgentFS;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// Number of records deleted between time budget checks
+const GC_BATCH_SIZE: usize = 256;
+
+/// How long unreferenced data entries of live files are kept by default
+pub(crate) const DATA_GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// Garbage collection settings
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    /// Stop after roughly this long, leaving the rest for the next run
+    pub budget: Option<Duration>,
+    /// Drop change log entries older than this
+    pub change_log_retention: Option<Duration>,
+    /// Drop tool call records older than this (kept forever by default)
+    pub tool_call_retention: Option<Duration>,
+    /// Drop provenance records older than this (kept forever by default)
+    pub provenance_retention: Option<Duration>,
+    /// Keep unreferenced blobs younger than this, as a write may be about
+    /// to refer to them
+    pub blob_grace_period: Duration,
+    /// Keep unreferenced data entries of existing files younger than this,
+    /// as a write may be about to refer to them
+    pub data_grace_period: Duration,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            budget: None,
+            change_log_retention: Some(Duration::from_secs(24 * 60 * 60)),
+            tool_call_retention: None,
+            provenance_retention: None,
+            blob_grace_period: Duration::from_secs(10 * 60),
+            data_grace_period: DATA_GRACE_PERIOD,
+        }
+    }
+}
+
+/// What a garbage collection run reclaimed
+#[derive(Debug, Clone, Default)]
+pub struct GcReport {
+    /// `__fs_data` entries no inode refers to
+    pub orphan_data_keys: usize,
+    /// `fs_data` chunks whose inode no longer exists
+    pub dead_chunks: usize,
+    /// Offloaded blobs no file refers to
+    pub orphan_blobs: usize,
+    /// `fs_symlink` rows whose inode no longer exists
+    pub dead_symlinks: usize,
+    /// Expired advisory lock leases
+    pub expired_locks: usize,
+    /// Change log entries past their retention
+    pub trimmed_changes: usize,
+    /// Tool call records past their retention
+    pub trimmed_tool_calls: usize,
+    /// Provenance records past their retention
+    pub trimmed_provenance: usize,
+    /// Bytes of file data deleted
+    pub bytes_reclaimed: u64,
+    /// Whether everything was collected, or the time budget ran out first
+    pub complete: bool,
+}
+
+/// Result of compacting the database
+#[derive(Debug, Clone, Default)]
+pub struct CompactReport {
+    /// Database size before compaction, if the backend reports it
+    pub bytes_before: Option<u64>,
+    /// Database size after compaction, if the backend reports it
+    pub bytes_after: Option<u64>,
+}
+
+impl CompactReport {
+    /// Bytes returned to the operating system, if known
+    pub fn bytes_reclaimed(&self) -> Option<u64> {
+        Some(self.bytes_before?.saturating_sub(self.bytes_after?))
+    }
+}
+
+impl AgentFS {
+    /// Delete unreferenced data with default options
+    pub async fn gc(&self) -> Result<GcReport> {
+        self.fs.collect_garbage(&GcOptions::default()).await
+    }
+
+    /// Delete unreferenced data
+    ///
+    /// With a time budget the run stops early once it is used up and
+    /// reports `complete == false`; the next run continues where it left
+    /// off, so large stores can be collected incrementally.
+    pub async fn gc_with_options(&self, options: &GcOptions) -> Result<GcReport> {
+        self.fs.collect_garbage(options).await
+    }
+
+    /// Rebuild the database file to release free space
+    ///
+    /// Uses `VACUUM`, which needs exclusive access on SQLite and may take a
+    /// while on large databases.
+    pub async fn compact(&self) -> Result<CompactReport> {
+        let bytes_before = self.fs.database_size().await;
+        self.fs.db.query("VACUUM", vec![]).await?;
+        let bytes_after = self.fs.database_size().await;
+        Ok(CompactReport { bytes_before, bytes_after })
+    }
+
+    /// Run garbage collection in the background every `interval`
+    ///
+    /// Errors are ignored and retried on the next run. Abort the returned
+    /// handle to stop collecting.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let options = GcOptions { budget: Some(Duration::from_millis(50)), ..Default::default() };
+    /// let gc = agent_fs.spawn_gc(Duration::from_secs(60), options);
+    /// // ...
+    /// gc.abort();
+    /// ```
+    pub fn spawn_gc(&self, interval: Duration, options: GcOptions) -> JoinHandle<()> {
+        let fs = self.fs.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let _ = fs.collect_garbage(&options).await;
+            }
+        })
+    }
+}
+
+impl DbFileSystem {
+    pub(crate) async fn collect_garbage(&self, options: &GcOptions) -> Result<GcReport> {
+        self.ensure_schema().await?;
+        let started = Instant::now();
+        let out_of_time = || options.budget.is_some_and(|budget| started.elapsed() >= budget);
+        let mut report = GcReport::default();
+
+        // Cheap bulk deletes first
+        let query = format!("DELETE FROM fs_lock WHERE expires_at <= {}", now_millis());
+        report.expired_locks = self.db.query(&query, vec![]).await?.rows_affected;
+
+        if let Some(retention) = options.change_log_retention {
+            let query = format!(
+                "DELETE FROM fs_change WHERE changed_at < {}",
+                Self::now() - retention.as_secs() as i64
+            );
+            report.trimmed_changes = self.db.query(&query, vec![]).await?.rows_affected;
+        }
+
+        if let Some(retention) = options.tool_call_retention {
+            let query = format!(
+                "DELETE FROM tool_calls WHERE started_at < {}",
+                Self::now() - retention.as_secs() as i64
+            );
+            report.trimmed_tool_calls = self.db.query(&query, vec![]).await?.rows_affected;
+        }
+
+        if let Some(retention) = options.provenance_retention {
+            let query = format!(
+                "DELETE FROM fs_provenance WHERE recorded_at < {}",
+                Self::now() - retention.as_secs() as i64
+            );
+            report.trimmed_provenance = self.db.query(&query, vec![]).await?.rows_affected;
+        }
+
+        let query = "DELETE FROM fs_symlink WHERE ino NOT IN (SELECT ino FROM fs_inode)";
+        report.dead_symlinks = self.db.query(query, vec![]).await?.rows_affected;
+
+        for batch in self.find_orphan_data_keys(options.data_grace_period).await?.chunks(GC_BATCH_SIZE) {
+            if out_of_time() {
+                return Ok(report);
+            }
+            for key in batch {
+                if let Some(value) = self.db.get(key).await? {
+                    report.bytes_reclaimed += value.as_bytes().len() as u64;
+                }
+                self.db.delete(key).await?;
+                report.orphan_data_keys += 1;
+            }
+        }
+
+        if let Some(store) = self.blob_store() {
+            for batch in self.find_orphan_blobs(options.blob_grace_period).await?.chunks(GC_BATCH_SIZE) {
+                if out_of_time() {
+                    return Ok(report);
+                }
+                for blob in batch {
+                    store.delete(&blob.hash).await?;
+                    report.bytes_reclaimed += blob.size;
+                    report.orphan_blobs += 1;
+                }
+            }
+        }
+
+        loop {
+            if out_of_time() {
+                return Ok(report);
+            }
+            let query = format!(
+                "SELECT id, size FROM fs_data WHERE ino NOT IN (SELECT ino FROM fs_inode) LIMIT {}",
+                GC_BATCH_SIZE
+            );
+            let result = self.db.query(&query, vec![]).await?;
+            if result.rows.is_empty() {
+                break;
+            }
+
+            let mut ids = Vec::with_capacity(result.rows.len());
+            for row in &result.rows {
+                ids.push(self.extract_i64(row, "id")?.to_string());
+                report.bytes_reclaimed += self.extract_i64(row, "size")?.max(0) as u64;
+            }
+            let query = format!("DELETE FROM fs_data WHERE id IN ({})", ids.join(", "));
+            self.db.query(&query, vec![]).await?;
+            report.dead_chunks += ids.len();
+        }
+
+        report.complete = true;
+        Ok(report)
+    }
+
+    /// Size of the database in bytes (SQLite only)
+    async fn database_size(&self) -> Option<u64> {
+        // Only statements starting with SELECT return rows
+        let query = "SELECT page_count * page_size AS size FROM pragma_page_count(), pragma_page_size()";
+        let result = self.db.query(query, vec![]).await.ok()?;
+        Some(self.extract_i64(result.rows.first()?, "size").ok()? as u64)
+    }
+}
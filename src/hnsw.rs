@@ -0,0 +1,218 @@
+//! Hierarchical navigable small world graph
+//!
+//! An approximate nearest neighbour index (Malkov & Yashunin, 2016) over
+//! unit vectors, used by semantic search once a corpus is too large to
+//! scan. Each node is linked to its closest neighbours on a random number
+//! of layers; a search descends greedily through the sparse upper layers
+//! and then explores the dense bottom layer.
+//!
+//! Nodes are identified by their position in the vector slice passed to
+//! every call, and must be inserted in order. Removal is left to the
+//! caller, which filters results and rebuilds the graph once too many
+//! nodes are stale.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+
+/// Links per node on the upper layers; the bottom layer gets twice as many
+const M: usize = 16;
+/// Candidates considered when linking a new node
+const EF_CONSTRUCTION: usize = 100;
+
+/// A node and its distance to the vector being searched for
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    distance: f32,
+    node: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then(self.node.cmp(&other.node))
+    }
+}
+
+/// Cosine distance between unit vectors
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 1.0;
+    }
+    1.0 - a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>()
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Hnsw {
+    /// Neighbours of each node, per layer from the bottom up
+    links: Vec<Vec<Vec<usize>>>,
+    /// Node on the top layer that searches start from
+    entry: Option<usize>,
+    /// State of the xorshift generator drawing node layers
+    seed: u64,
+}
+
+impl Hnsw {
+    pub(crate) fn new() -> Self {
+        Self { links: Vec::new(), entry: None, seed: 0x9e3779b97f4a7c15 }
+    }
+
+    /// Number of nodes in the graph
+    pub(crate) fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    /// Add the next node, whose vector is `vectors[self.len()]`
+    pub(crate) fn insert(&mut self, vectors: &[Vec<f32>]) {
+        let node = self.links.len();
+        let vector = &vectors[node];
+        let level = self.random_level();
+        self.links.push(vec![Vec::new(); level + 1]);
+
+        let Some(entry) = self.entry else {
+            self.entry = Some(node);
+            return;
+        };
+        let top = self.links[entry].len() - 1;
+        let mut nearest = Candidate { distance: distance(vector, &vectors[entry]), node: entry };
+        for layer in (level + 1..=top).rev() {
+            nearest = self.greedy(vector, vectors, nearest, layer);
+        }
+
+        let mut entries = vec![nearest];
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(vector, vectors, &entries, EF_CONSTRUCTION, layer, &|_| true);
+            let max_links = if layer == 0 { 2 * M } else { M };
+            let neighbours: Vec<usize> = found.iter().take(M).map(|c| c.node).collect();
+            for &neighbour in &neighbours {
+                let links = &mut self.links[neighbour][layer];
+                links.push(node);
+                if links.len() > max_links {
+                    // Keep the neighbour's closest links
+                    let own = &vectors[neighbour];
+                    let mut ranked: Vec<Candidate> = links
+                        .iter()
+                        .map(|&n| Candidate { distance: distance(own, &vectors[n]), node: n })
+                        .collect();
+                    ranked.sort();
+                    *links = ranked.into_iter().take(max_links).map(|c| c.node).collect();
+                }
+            }
+            self.links[node][layer] = neighbours;
+            entries = found;
+        }
+
+        if level > top {
+            self.entry = Some(node);
+        }
+    }
+
+    /// Up to `k` nodes accepted by `filter`, closest to `query` first, with
+    /// their distances
+    ///
+    /// `ef` bounds the candidates explored on the bottom layer; larger
+    /// values find the true neighbours more reliably but take longer.
+    pub(crate) fn search(
+        &self,
+        query: &[f32],
+        vectors: &[Vec<f32>],
+        k: usize,
+        ef: usize,
+        filter: &dyn Fn(usize) -> bool,
+    ) -> Vec<(usize, f32)> {
+        let Some(entry) = self.entry else {
+            return Vec::new();
+        };
+        let mut nearest = Candidate { distance: distance(query, &vectors[entry]), node: entry };
+        for layer in (1..self.links[entry].len()).rev() {
+            nearest = self.greedy(query, vectors, nearest, layer);
+        }
+        self.search_layer(query, vectors, &[nearest], ef.max(k), 0, filter)
+            .into_iter()
+            .take(k)
+            .map(|c| (c.node, c.distance))
+            .collect()
+    }
+
+    /// Follow links on `layer` to the node closest to `query`
+    fn greedy(&self, query: &[f32], vectors: &[Vec<f32>], mut nearest: Candidate, layer: usize) -> Candidate {
+        loop {
+            let closer = self.links[nearest.node][layer]
+                .iter()
+                .map(|&n| Candidate { distance: distance(query, &vectors[n]), node: n })
+                .filter(|c| c.distance < nearest.distance)
+                .min();
+            match closer {
+                Some(closer) => nearest = closer,
+                None => return nearest,
+            }
+        }
+    }
+
+    /// Best-first search of one layer, returning up to `ef` nodes accepted
+    /// by `filter`, closest first
+    ///
+    /// Rejected nodes are still explored, so a selective filter costs time
+    /// rather than results.
+    fn search_layer(
+        &self,
+        query: &[f32],
+        vectors: &[Vec<f32>],
+        entries: &[Candidate],
+        ef: usize,
+        layer: usize,
+        filter: &dyn Fn(usize) -> bool,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entries.iter().map(|c| c.node).collect();
+        // Nodes to expand, closest first, and the best accepted nodes so far
+        let mut candidates: BinaryHeap<Reverse<Candidate>> = entries.iter().copied().map(Reverse).collect();
+        let mut results: BinaryHeap<Candidate> = entries.iter().copied().filter(|c| filter(c.node)).collect();
+
+        while let Some(Reverse(current)) = candidates.pop() {
+            let worst = results.peek().map_or(f32::INFINITY, |c| c.distance);
+            if results.len() >= ef && current.distance > worst {
+                break;
+            }
+            for &neighbour in &self.links[current.node][layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let candidate = Candidate { distance: distance(query, &vectors[neighbour]), node: neighbour };
+                let worst = results.peek().map_or(f32::INFINITY, |c| c.distance);
+                if results.len() >= ef && candidate.distance >= worst {
+                    continue;
+                }
+                candidates.push(Reverse(candidate));
+                if filter(neighbour) {
+                    results.push(candidate);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        results.into_sorted_vec()
+    }
+
+    /// Top layer of a new node, each layer up being M times less likely
+    fn random_level(&mut self) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        let uniform = (self.seed >> 11) as f64 / (1u64 << 53) as f64;
+        let level = -(1.0 - uniform).ln() / (M as f64).ln();
+        level as usize
+    }
+}
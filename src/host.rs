@@ -0,0 +1,505 @@
+//! Filesystem backed by a directory on the host
+//!
+//! `HostFileSystem` stores files as ordinary files under a root directory,
+//! so existing tools can work on them, while giving agents the same
+//! `FileSystem` semantics and sandbox as the database-backed filesystem.
+//!
+//! # Security
+//!
+//! Paths are validated with the same rules as `DbFileSystem`, and then
+//! resolved one component at a time with directory file descriptors: each
+//! directory is opened relative to its parent with `O_NOFOLLOW`, and every
+//! operation happens relative to the descriptor of the directory holding
+//! the entry. The host kernel never follows a symlink, not even in a
+//! directory component. Instead symlink targets are interpreted inside the
+//! mount, exactly like `DbFileSystem::resolve` does, so a symlink pointing
+//! at `/etc/passwd` or `../../..` resolves to a path under the root. A
+//! directory swapped for a symlink while a path is being resolved makes
+//! the operation fail rather than escape, as the descriptors held keep
+//! referring to the directories that were checked.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::{FileSystem, MAX_SYMLINK_DEPTH, Stats};
+use crate::path::{PathPolicy, child_path};
+use async_trait::async_trait;
+use rustix::fs::{AtFlags, Dir, FileType, Mode, OFlags, Stat};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+
+/// Filesystem stored in a host directory
+///
+/// Inode numbers, link counts, owners and timestamps are those of the host
+/// files. The host doesn't track content versions, so `Stats::version` is
+/// always 1, and content metadata (`content_type` and friends) is not
+/// detected.
+#[derive(Debug, Clone)]
+pub struct HostFileSystem {
+    root: PathBuf,
+    mount_path: String,
+    path_policy: PathPolicy,
+}
+
+/// An entry found by resolving a path: the directory holding it, its name
+/// there, its canonical path and what it is
+///
+/// The root is held by itself under the name `.`.
+struct Entry {
+    dir: OwnedFd,
+    name: String,
+    canonical: String,
+    stat: Stat,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        is_dir(&self.stat)
+    }
+}
+
+impl HostFileSystem {
+    /// Create a filesystem rooted at an existing host directory
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let fs = HostFileSystem::new("/srv/agents/researcher", "/agent")?;
+    /// let agent_fs = AgentFS::from_parts(fs, MemKvStore::new(), MemToolRecorder::new(), "researcher", "/agent");
+    /// ```
+    pub fn new(root: impl AsRef<Path>, mount_path: impl Into<String>) -> Result<Self> {
+        let root = root.as_ref();
+        let root = fs::canonicalize(root)
+            .map_err(|_| AgentFsError::DirectoryNotFound(root.display().to_string()))?;
+        if !root.is_dir() {
+            return Err(AgentFsError::NotADirectory(root.display().to_string()));
+        }
+        Ok(Self { root, mount_path: mount_path.into(), path_policy: PathPolicy::default() })
+    }
+
+    /// Set the rules paths are validated against
+    pub fn with_path_policy(mut self, policy: PathPolicy) -> Self {
+        self.path_policy = policy;
+        self
+    }
+
+    /// The host directory files are stored in
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn validate_and_normalize_path(&self, path: &str) -> Result<String> {
+        crate::path::validate_and_normalize(&self.mount_path, &self.path_policy, path)
+    }
+
+    /// Open the root directory
+    fn open_root(&self) -> Result<OwnedFd> {
+        rustix::fs::open(&self.root, OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC, Mode::empty())
+            .map_err(|e| map_io_error(e.into(), "/"))
+    }
+
+    /// Resolve a path, following symlinks inside the mount like
+    /// `DbFileSystem::resolve`
+    fn resolve(&self, path: &str, follow_last: bool) -> Result<Option<Entry>> {
+        let mut pending: VecDeque<String> = crate::path::split(path).into();
+        // Directories from the root down to the current one, and their names
+        let mut dirs = vec![self.open_root()?];
+        let mut resolved: Vec<String> = Vec::new();
+        let mut last: Option<(String, Stat)> = None;
+        let mut links_followed = 0;
+
+        while let Some(name) = pending.pop_front() {
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    if resolved.pop().is_some() {
+                        dirs.pop();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            let dir = dirs.last().expect("the root is never popped");
+            let candidate = child_path(&format!("/{}", resolved.join("/")), &name);
+            let Some(stat) = lstat_at(dir, &name, &candidate)? else {
+                return Ok(None);
+            };
+            let is_last = pending.is_empty();
+
+            if is_symlink(&stat) && (follow_last || !is_last) {
+                links_followed += 1;
+                if links_followed > MAX_SYMLINK_DEPTH {
+                    return Err(AgentFsError::SymlinkLoop(path.to_string()));
+                }
+
+                let mut target = link_target_at(dir, &name, &candidate)?;
+                if target.starts_with('/') {
+                    dirs.truncate(1);
+                    resolved.clear();
+                    target = self.validate_and_normalize_path(&target)?;
+                }
+                for component in target.split('/').filter(|c| !c.is_empty()).rev() {
+                    pending.push_front(component.to_string());
+                }
+                continue;
+            }
+
+            if is_last {
+                last = Some((name, stat));
+                break;
+            }
+            if !is_dir(&stat) {
+                return Err(AgentFsError::NotADirectory(path.to_string()));
+            }
+            dirs.push(open_dir_at(dir, &name, &candidate)?);
+            resolved.push(name);
+        }
+
+        let parent = format!("/{}", resolved.join("/"));
+        if let Some((name, stat)) = last {
+            let dir = dirs.pop().expect("the root is never popped");
+            return Ok(Some(Entry { dir, canonical: child_path(&parent, &name), name, stat }));
+        }
+
+        // The path ends at a directory that was opened on the way
+        let dir = dirs.pop().expect("the root is never popped");
+        let stat = rustix::fs::fstat(&dir).map_err(|e| map_io_error(e.into(), &parent))?;
+        match resolved.pop() {
+            Some(name) => {
+                let dir = dirs.pop().expect("a resolved directory has a parent");
+                Ok(Some(Entry { dir, name, canonical: parent, stat }))
+            }
+            None => Ok(Some(Entry { dir, name: ".".to_string(), canonical: parent, stat })),
+        }
+    }
+
+    /// Open the parent directory of a path, returning it along with the
+    /// final name and the canonical parent path
+    fn resolve_parent(&self, path: &str) -> Result<(OwnedFd, String, String)> {
+        let components = crate::path::split(path);
+        let name = components
+            .last()
+            .cloned()
+            .ok_or_else(|| AgentFsError::InvalidPath("Root directory has no parent".to_string()))?;
+
+        let parent_path = format!("/{}", components[..components.len() - 1].join("/"));
+        let parent = self
+            .resolve(&parent_path, true)?
+            .ok_or_else(|| AgentFsError::DirectoryNotFound(parent_path.clone()))?;
+        if !parent.is_dir() {
+            return Err(AgentFsError::NotADirectory(parent_path));
+        }
+
+        let dir = open_dir_at(&parent.dir, &parent.name, &parent.canonical)?;
+        Ok((dir, name, parent.canonical))
+    }
+
+    /// Find where a write to `path` lands, following final symlinks like
+    /// `open(O_CREAT)`; returns the directory holding it, its name and
+    /// canonical path, and what is there now
+    fn resolve_for_write(&self, path: &str) -> Result<(OwnedFd, String, String, Option<Stat>)> {
+        let mut current = path.to_string();
+        for _ in 0..=MAX_SYMLINK_DEPTH {
+            let (dir, name, parent_path) = self.resolve_parent(&current)?;
+            let canonical = child_path(&parent_path, &name);
+            let existing = lstat_at(&dir, &name, &canonical)?;
+
+            if existing.as_ref().is_some_and(is_symlink) {
+                let target = link_target_at(&dir, &name, &canonical)?;
+                current = if target.starts_with('/') {
+                    self.validate_and_normalize_path(&target)?
+                } else {
+                    crate::path::normalize(&format!("{}/{}", parent_path, target))
+                };
+                continue;
+            }
+
+            return Ok((dir, name, canonical, existing));
+        }
+
+        Err(AgentFsError::SymlinkLoop(path.to_string()))
+    }
+
+    /// Remove the entry at a normalized path
+    fn remove_entry(&self, path: &str) -> Result<()> {
+        if path == "/" {
+            return Err(AgentFsError::InvalidPath("Cannot remove root directory".to_string()));
+        }
+
+        let (dir, name, parent_path) = self.resolve_parent(path)?;
+        let canonical = child_path(&parent_path, &name);
+        let stat = lstat_at(&dir, &name, &canonical)?.ok_or_else(|| AgentFsError::FileNotFound(path.to_string()))?;
+
+        let flags = if is_dir(&stat) { AtFlags::REMOVEDIR } else { AtFlags::empty() };
+        rustix::fs::unlinkat(&dir, name.as_str(), flags).map_err(|e| map_io_error(e.into(), path))
+    }
+
+    /// Run blocking host I/O off the async runtime
+    async fn blocking<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Self) -> Result<R> + Send + 'static,
+    {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || f(&this))
+            .await
+            .map_err(|e| AgentFsError::Other(Box::new(e)))?
+    }
+}
+
+/// `lstat` the entry `name` in `dir`, returning `None` if nothing is there
+fn lstat_at(dir: &OwnedFd, name: &str, canonical: &str) -> Result<Option<Stat>> {
+    match rustix::fs::statat(dir, name, AtFlags::SYMLINK_NOFOLLOW) {
+        Ok(stat) => Ok(Some(stat)),
+        Err(rustix::io::Errno::NOENT) => Ok(None),
+        Err(e) => Err(map_io_error(e.into(), canonical)),
+    }
+}
+
+/// Open the directory `name` in `dir`, failing if it is a symlink
+fn open_dir_at(dir: &OwnedFd, name: &str, canonical: &str) -> Result<OwnedFd> {
+    let flags = OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+    rustix::fs::openat(dir, name, flags, Mode::empty()).map_err(|e| map_io_error(e.into(), canonical))
+}
+
+/// Open the file `name` in `dir`, failing if it is a symlink
+fn open_file_at(dir: &OwnedFd, name: &str, flags: OFlags, canonical: &str) -> Result<File> {
+    let flags = flags | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+    let fd = rustix::fs::openat(dir, name, flags, Mode::from_raw_mode(0o666))
+        .map_err(|e| map_io_error(e.into(), canonical))?;
+    Ok(File::from(fd))
+}
+
+/// Read the target of the symlink `name` in `dir`
+fn link_target_at(dir: &OwnedFd, name: &str, canonical: &str) -> Result<String> {
+    let target = rustix::fs::readlinkat(dir, name, Vec::new()).map_err(|e| map_io_error(e.into(), canonical))?;
+    target
+        .into_string()
+        .map_err(|_| AgentFsError::InvalidPath(format!("Symlink target of {} is not UTF-8", canonical)))
+}
+
+fn is_dir(stat: &Stat) -> bool {
+    FileType::from_raw_mode(stat.st_mode) == FileType::Directory
+}
+
+fn is_symlink(stat: &Stat) -> bool {
+    FileType::from_raw_mode(stat.st_mode) == FileType::Symlink
+}
+
+/// Translate a host I/O error into the matching filesystem error
+fn map_io_error(error: std::io::Error, path: &str) -> AgentFsError {
+    let path = path.to_string();
+    match error.kind() {
+        ErrorKind::NotFound => AgentFsError::FileNotFound(path),
+        ErrorKind::AlreadyExists => AgentFsError::PathExists(path),
+        ErrorKind::NotADirectory => AgentFsError::NotADirectory(path),
+        ErrorKind::IsADirectory => AgentFsError::IsADirectory(path),
+        ErrorKind::DirectoryNotEmpty => AgentFsError::DirectoryNotEmpty(path),
+        ErrorKind::PermissionDenied => AgentFsError::PermissionDenied(path),
+        _ if error.raw_os_error() == Some(libc::ELOOP) => AgentFsError::SymlinkLoop(path),
+        _ => AgentFsError::Io(error),
+    }
+}
+
+/// Build `Stats` from a host `stat`
+fn host_stats(stat: &Stat) -> Stats {
+    Stats {
+        ino: stat.st_ino as i64,
+        mode: stat.st_mode,
+        nlink: stat.st_nlink as u32,
+        uid: stat.st_uid,
+        gid: stat.st_gid,
+        size: stat.st_size,
+        atime: stat.st_atime,
+        mtime: stat.st_mtime,
+        ctime: stat.st_ctime,
+        atime_nsec: stat.st_atime_nsec as u32,
+        mtime_nsec: stat.st_mtime_nsec as u32,
+        ctime_nsec: stat.st_ctime_nsec as u32,
+        version: 1,
+        content_type: None,
+        line_count: None,
+        encoding: None,
+    }
+}
+
+#[async_trait]
+impl FileSystem for HostFileSystem {
+    async fn write_file(&self, path: &str, content: &[u8]) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
+        if path == "/" {
+            return Err(AgentFsError::IsADirectory(path));
+        }
+
+        let content = content.to_vec();
+        self.blocking(move |fs| {
+            let (dir, name, canonical, existing) = fs.resolve_for_write(&path)?;
+            if existing.as_ref().is_some_and(is_dir) {
+                return Err(AgentFsError::IsADirectory(canonical));
+            }
+
+            let flags = OFlags::WRONLY | OFlags::CREATE | OFlags::TRUNC;
+            let mut file = open_file_at(&dir, &name, flags, &canonical)?;
+            file.write_all(&content)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.validate_and_normalize_path(path)?;
+        self.blocking(move |fs| {
+            let Some(entry) = fs.resolve(&path, true)? else {
+                return Ok(None);
+            };
+            if entry.is_dir() {
+                return Err(AgentFsError::IsADirectory(path));
+            }
+
+            let mut file = open_file_at(&entry.dir, &entry.name, OFlags::RDONLY, &entry.canonical)?;
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            Ok(Some(data))
+        })
+        .await
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        let path = self.validate_and_normalize_path(path)?;
+        self.blocking(move |fs| match fs.resolve(&path, false) {
+            Ok(entry) => Ok(entry.is_some()),
+            Err(AgentFsError::NotADirectory(_)) => Ok(false),
+            Err(e) => Err(e),
+        })
+        .await
+    }
+
+    async fn readdir(&self, path: &str) -> Result<Option<Vec<String>>> {
+        let path = self.validate_and_normalize_path(path)?;
+        self.blocking(move |fs| {
+            let Some(entry) = fs.resolve(&path, true)? else {
+                return Ok(None);
+            };
+            if !entry.is_dir() {
+                return Err(AgentFsError::NotADirectory(path));
+            }
+
+            let dir = open_dir_at(&entry.dir, &entry.name, &entry.canonical)?;
+            let listing = Dir::new(dir).map_err(|e| map_io_error(e.into(), &entry.canonical))?;
+            let mut names = Vec::new();
+            for dirent in listing {
+                let dirent = dirent.map_err(|e| map_io_error(e.into(), &entry.canonical))?;
+                // Names that aren't valid UTF-8 can't be addressed through the trait
+                if let Ok(name) = dirent.file_name().to_str()
+                    && name != "."
+                    && name != ".."
+                {
+                    names.push(name.to_string());
+                }
+            }
+            names.sort();
+            Ok(Some(names))
+        })
+        .await
+    }
+
+    async fn mkdir(&self, path: &str) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
+        if path == "/" {
+            return Err(AgentFsError::InvalidPath("Cannot create root directory".to_string()));
+        }
+
+        self.blocking(move |fs| {
+            let (dir, name, _) = fs.resolve_parent(&path)?;
+            rustix::fs::mkdirat(&dir, name.as_str(), Mode::from_raw_mode(0o777))
+                .map_err(|e| map_io_error(e.into(), &path))
+        })
+        .await
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
+        self.blocking(move |fs| fs.remove_entry(&path)).await
+    }
+
+    async fn stat(&self, path: &str) -> Result<Option<Stats>> {
+        let path = self.validate_and_normalize_path(path)?;
+        self.blocking(move |fs| Ok(fs.resolve(&path, true)?.map(|entry| host_stats(&entry.stat))))
+            .await
+    }
+
+    async fn lstat(&self, path: &str) -> Result<Option<Stats>> {
+        let path = self.validate_and_normalize_path(path)?;
+        self.blocking(move |fs| Ok(fs.resolve(&path, false)?.map(|entry| host_stats(&entry.stat))))
+            .await
+    }
+
+    async fn symlink(&self, target: &str, linkpath: &str) -> Result<()> {
+        let linkpath = self.validate_and_normalize_path(linkpath)?;
+        if linkpath == "/" {
+            return Err(AgentFsError::InvalidPath("Cannot create symlink at root".to_string()));
+        }
+
+        let target = self.path_policy.normalize_target(target);
+        self.blocking(move |fs| {
+            let (dir, name, _) = fs.resolve_parent(&linkpath)?;
+            rustix::fs::symlinkat(target.as_str(), &dir, name.as_str()).map_err(|e| map_io_error(e.into(), &linkpath))
+        })
+        .await
+    }
+
+    async fn readlink(&self, path: &str) -> Result<Option<String>> {
+        let path = self.validate_and_normalize_path(path)?;
+        self.blocking(move |fs| {
+            let Some(entry) = fs.resolve(&path, false)? else {
+                return Ok(None);
+            };
+            if !is_symlink(&entry.stat) {
+                return Err(AgentFsError::NotASymlink(path));
+            }
+            Ok(Some(link_target_at(&entry.dir, &entry.name, &entry.canonical)?))
+        })
+        .await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from = self.validate_and_normalize_path(from)?;
+        let to = self.validate_and_normalize_path(to)?;
+        if from == "/" || to == "/" {
+            return Err(AgentFsError::InvalidPath("Cannot rename root directory".to_string()));
+        }
+
+        self.blocking(move |fs| {
+            let (from_dir, from_name, from_parent) = fs.resolve_parent(&from)?;
+            let (to_dir, to_name, to_parent) = fs.resolve_parent(&to)?;
+            let from = child_path(&from_parent, &from_name);
+            let to = child_path(&to_parent, &to_name);
+
+            if from == to {
+                return Ok(());
+            }
+            if to.starts_with(&format!("{}/", from)) {
+                return Err(AgentFsError::InvalidPath(format!(
+                    "Cannot move {} into its own subdirectory",
+                    from
+                )));
+            }
+
+            let source =
+                lstat_at(&from_dir, &from_name, &from)?.ok_or_else(|| AgentFsError::FileNotFound(from.clone()))?;
+            if let Some(existing) = lstat_at(&to_dir, &to_name, &to)? {
+                match (is_dir(&source), is_dir(&existing)) {
+                    (true, false) => return Err(AgentFsError::NotADirectory(to)),
+                    (false, true) => return Err(AgentFsError::IsADirectory(to)),
+                    _ => {}
+                }
+            }
+
+            rustix::fs::renameat(&from_dir, from_name.as_str(), &to_dir, to_name.as_str())
+                .map_err(|e| map_io_error(e.into(), &to))
+        })
+        .await
+    }
+}
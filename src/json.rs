@@ -0,0 +1,236 @@
+//! Typed JSON documents
+//!
+//! Most of what agents write are JSON documents. These helpers serialize
+//! them, and update them in place with a JSON Patch (RFC 6902) or a JSON
+//! Merge Patch (RFC 7386) instead of rewriting the whole file by hand.
+//!
+//! Patches are applied with compare-and-swap: the document is read, patched
+//! in memory and written back only if nobody changed it in the meantime,
+//! retrying otherwise. A patch that doesn't fit the document fails with
+//! `AgentFsError::Serialization` and leaves the file untouched.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::{DbFileSystem, FileSystem};
+use serde::Serialize;
+use serde::de::{DeserializeOwned, Error as _};
+use serde_json::{Map, Value};
+
+impl DbFileSystem {
+    /// Read a JSON file into a typed value, or `None` if it doesn't exist
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let config: Option<Config> = agent_fs.fs.read_json("/config.json").await?;
+    /// ```
+    pub async fn read_json<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>> {
+        match self.read_file(path).await? {
+            Some(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Write a value as a pretty-printed JSON file
+    pub async fn write_json<T: Serialize + ?Sized>(&self, path: &str, value: &T) -> Result<()> {
+        let content = serde_json::to_vec_pretty(value)?;
+        self.write_file(path, &content).await
+    }
+
+    /// Apply a JSON Patch (RFC 6902) to a JSON file, returning the patched
+    /// document
+    ///
+    /// All operations apply, or none do.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let patch = json!([
+    ///     { "op": "test", "path": "/status", "value": "running" },
+    ///     { "op": "replace", "path": "/status", "value": "done" },
+    ///     { "op": "add", "path": "/results/-", "value": 42 }
+    /// ]);
+    /// agent_fs.fs.patch_json("/task.json", &patch).await?;
+    /// ```
+    pub async fn patch_json(&self, path: &str, patch: &Value) -> Result<Value> {
+        let operations = patch
+            .as_array()
+            .ok_or_else(|| patch_error("a JSON Patch must be an array of operations"))?;
+        self.update_json(path, |document| {
+            for (i, operation) in operations.iter().enumerate() {
+                apply_operation(document, operation)
+                    .map_err(|e| patch_error(format!("operation {}: {}", i, e)))?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Apply a JSON Merge Patch (RFC 7386) to a JSON file, returning the
+    /// patched document
+    ///
+    /// Objects in the patch are merged recursively, `null` removes a
+    /// member and any other value replaces it.
+    pub async fn merge_patch(&self, path: &str, patch: &Value) -> Result<Value> {
+        self.update_json(path, |document| {
+            merge(document, patch);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Read, modify and conditionally write back a JSON file until no
+    /// concurrent writer gets in the way
+    async fn update_json<F>(&self, path: &str, update: F) -> Result<Value>
+    where
+        F: Fn(&mut Value) -> Result<()>,
+    {
+        self.update_file(path, |content| {
+            let mut document: Value = serde_json::from_slice(content)?;
+            update(&mut document)?;
+            Ok((serde_json::to_vec_pretty(&document)?, document))
+        })
+        .await
+    }
+}
+
+fn patch_error(message: impl std::fmt::Display) -> AgentFsError {
+    AgentFsError::Serialization(serde_json::Error::custom(message))
+}
+
+/// Apply a single JSON Patch operation
+fn apply_operation(document: &mut Value, operation: &Value) -> std::result::Result<(), String> {
+    let member = |name: &str| {
+        operation
+            .get(name)
+            .ok_or_else(|| format!("missing \"{}\"", name))
+    };
+    let pointer = |name: &str| -> std::result::Result<&str, String> {
+        member(name)?
+            .as_str()
+            .ok_or_else(|| format!("\"{}\" must be a string", name))
+    };
+
+    let path = pointer("path")?;
+    match pointer("op")? {
+        "add" => add(document, path, member("value")?.clone()),
+        "remove" => remove(document, path).map(|_| ()),
+        "replace" => {
+            let target = document
+                .pointer_mut(path)
+                .ok_or_else(|| format!("{} does not exist", path))?;
+            *target = member("value")?.clone();
+            Ok(())
+        }
+        "move" => {
+            let from = pointer("from")?;
+            if path.starts_with(from) && path[from.len()..].starts_with('/') {
+                return Err(format!("cannot move {} into itself", from));
+            }
+            let value = remove(document, from)?;
+            add(document, path, value)
+        }
+        "copy" => {
+            let from = pointer("from")?;
+            let value = document
+                .pointer(from)
+                .ok_or_else(|| format!("{} does not exist", from))?
+                .clone();
+            add(document, path, value)
+        }
+        "test" => {
+            let actual = document.pointer(path).ok_or_else(|| format!("{} does not exist", path))?;
+            if actual != member("value")? {
+                return Err(format!("test failed: {} is {}", path, actual));
+            }
+            Ok(())
+        }
+        op => Err(format!("unknown op \"{}\"", op)),
+    }
+}
+
+/// Split a JSON pointer into its parent pointer and unescaped last token
+fn split_pointer(path: &str) -> std::result::Result<(&str, String), String> {
+    let Some(slash) = path.rfind('/') else {
+        return Err(format!("invalid JSON pointer \"{}\"", path));
+    };
+    let token = path[slash + 1..].replace("~1", "/").replace("~0", "~");
+    Ok((&path[..slash], token))
+}
+
+/// Array index named by a pointer token, up to `max`
+fn array_index(token: &str, max: usize) -> std::result::Result<usize, String> {
+    let index: usize = token
+        .parse()
+        .map_err(|_| format!("\"{}\" is not an array index", token))?;
+    if index > max || (token.len() > 1 && token.starts_with('0')) {
+        return Err(format!("array index {} is out of bounds", token));
+    }
+    Ok(index)
+}
+
+fn add(document: &mut Value, path: &str, value: Value) -> std::result::Result<(), String> {
+    if path.is_empty() {
+        *document = value;
+        return Ok(());
+    }
+    let (parent, token) = split_pointer(path)?;
+    match document.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.insert(token, value);
+            Ok(())
+        }
+        Some(Value::Array(items)) => {
+            let index = if token == "-" { items.len() } else { array_index(&token, items.len())? };
+            items.insert(index, value);
+            Ok(())
+        }
+        Some(other) => Err(format!("{} is {}, not an object or array", parent, type_name(other))),
+        None => Err(format!("{} does not exist", parent)),
+    }
+}
+
+fn remove(document: &mut Value, path: &str) -> std::result::Result<Value, String> {
+    let (parent, token) = split_pointer(path)?;
+    match document.pointer_mut(parent) {
+        Some(Value::Object(map)) => map.remove(&token).ok_or_else(|| format!("{} does not exist", path)),
+        Some(Value::Array(items)) if !items.is_empty() => {
+            let index = array_index(&token, items.len() - 1)?;
+            Ok(items.remove(index))
+        }
+        Some(Value::Array(_)) => Err(format!("{} does not exist", path)),
+        Some(other) => Err(format!("{} is {}, not an object or array", parent, type_name(other))),
+        None => Err(format!("{} does not exist", parent)),
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Apply a JSON Merge Patch
+fn merge(document: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *document = patch.clone();
+        return;
+    };
+    if !document.is_object() {
+        *document = Value::Object(Map::new());
+    }
+    let Value::Object(map) = document else {
+        return;
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            map.remove(key);
+        } else {
+            merge(map.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
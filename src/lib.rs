@@ -8,6 +8,28 @@
 //! - **Filesystem**: POSIX-like file and directory operations
 //! - **KV Store**: Key-value storage for agent state
 //! - **Tool Recording**: Audit trail for agent tool calls
+//! - **Advisory Locks**: Shared/exclusive leases on paths across processes
+//! - **Change Notifications**: Watch paths for created/modified/removed/renamed entries
+//! - **Integrity Checking**: `fsck` finds and repairs orphaned or dangling filesystem records
+//! - **Conditional Writes**: ETags and compare-and-swap via `write_file_if`
+//! - **Garbage Collection**: Reclaim unreferenced data incrementally and compact storage
+//! - **Content Metadata**: MIME type, line count and encoding detected on write
+//! - **Permissions**: Optional POSIX permission checks per caller identity
+//! - **In-Memory Storage**: Database-free implementations for tests and scratch agents
+//! - **Host Storage**: Keep files in a sandboxed host directory with `HostFileSystem`
+//! - **Copy-on-Write Copies**: `copy` and `copy_dir` share file data until copies diverge
+//! - **Extended Attributes**: Name/value metadata attached to files
+//! - **Disk Usage**: `du`, `statfs`, largest and recently modified file reports, and quotas
+//! - **Agent Volumes**: Agents sharing a database each get an isolated filesystem tree
+//! - **Trash**: Optionally keep removed entries so they can be restored until purged
+//! - **Expiring Files**: Give scratch files and caches a TTL; a background sweeper reclaims them
+//! - **Blob Offload**: Keep large file contents in a pluggable blob store instead of the database
+//! - **Semantic Search**: Find related text with a pluggable embedder, exactly or through an HNSW index
+//! - **JSON Documents**: Typed reads and writes, JSON Patch and Merge Patch updates
+//! - **Provenance**: Trace every file change to the agent and tool call that made it
+//! - **Line Editing**: Read, replace, insert and delete lines, and replace unique strings, atomically
+//! - **Patches**: Apply and generate multi-file unified diffs; not crash-atomic, rollback is best-effort
+//! - **Mounts**: Combine filesystems in one namespace with `MountFs`, optionally read-only
 //! - **Backend Agnostic**: Works with any AgentDB backend (SQL, KV, Graph)
 //!
 //! ## Example
@@ -40,10 +62,35 @@
 //! }
 //! ```
 
+pub mod blob;
+pub mod copy;
+pub mod edit;
 pub mod error;
+pub mod expiry;
 pub mod filesystem;
+pub mod fsck;
+pub mod gc;
+mod hnsw;
+#[cfg(unix)]
+pub mod host;
+pub mod json;
 pub mod kvstore;
+pub mod lock;
+pub mod memory;
+pub mod mime;
+pub mod mount;
+pub mod patch;
+pub mod path;
+pub mod permissions;
+pub mod provenance;
+mod schema;
+pub mod semantic;
 pub mod tools;
+pub mod trash;
+pub mod usage;
+pub mod volume;
+pub mod watch;
+pub mod xattr;
 
 /// Rig.rs integration module
 ///
@@ -56,25 +103,49 @@ pub mod rig_integration;
 #[cfg(not(feature = "rig-integration"))]
 pub mod rig_integration;
 
+pub use blob::{BlobInfo, BlobStore, LocalBlobStore};
+pub use edit::EditPreview;
 pub use error::{AgentFsError, Result};
-pub use filesystem::{DbFileSystem, FileSystem, Stats};
+pub use filesystem::{AtimePolicy, DbFileSystem, DirEntry, FileSystem, Precondition, Stats};
+pub use fsck::{DanglingDentry, FsckReport, MissingBlob, SizeMismatch};
+pub use gc::{CompactReport, GcOptions, GcReport};
+#[cfg(unix)]
+pub use host::HostFileSystem;
 pub use kvstore::{DbKvStore, KvStore};
+pub use lock::{LockGuard, LockMode};
+pub use memory::{MemFileSystem, MemKvStore, MemToolRecorder};
+pub use mime::ContentInfo;
+pub use mount::{MountFs, MountInfo};
+pub use patch::{FilePatchReport, HunkReport, PatchChange, PatchReport};
+pub use path::PathPolicy;
+pub use permissions::Credentials;
+pub use provenance::Provenance;
+pub use semantic::{Embedder, HashEmbedder, SearchHit};
 pub use tools::{DbToolRecorder, ToolCall, ToolCallStats, ToolCallStatus, ToolRecorder};
+pub use trash::TrashEntry;
+pub use usage::{DiskUsage, Quota, StatFs, UsageEntry};
+pub use volume::VolumeInfo;
+pub use watch::{FsEvent, FsEventKind, FsWatcher};
 
 use agentdb::AgentDB;
 use std::path::PathBuf;
 use std::sync::Arc;
 
 /// Main AgentFS struct providing filesystem, KV store, and tool recording
-pub struct AgentFS {
+///
+/// The storage behind each part is pluggable. By default all three are
+/// backed by an `AgentDB` database; [`AgentFS::in_memory`] keeps everything
+/// in memory instead, and [`AgentFS::from_parts`] combines any
+/// implementations of the traits.
+pub struct AgentFS<F = DbFileSystem, K = DbKvStore, T = DbToolRecorder> {
     /// Filesystem operations
-    pub fs: DbFileSystem,
+    pub fs: F,
 
     /// Key-value store
-    pub kv: DbKvStore,
+    pub kv: K,
 
     /// Tool call recorder
-    pub tools: DbToolRecorder,
+    pub tools: T,
 
     /// Agent identifier
     pub agent_id: String,
@@ -86,6 +157,11 @@ pub struct AgentFS {
 impl AgentFS {
     /// Create a new AgentFS instance
     ///
+    /// The filesystem is the agent's own volume, so agents sharing a
+    /// database don't see each other's files. Files written before volumes
+    /// existed only show up once [`DbFileSystem::adopt_legacy_tree`] has
+    /// bound them to the agent's volume.
+    ///
     /// # Arguments
     ///
     /// * `db` - Database backend implementing AgentDB trait
@@ -110,7 +186,9 @@ impl AgentFS {
         let db_arc = Arc::new(db);
 
         Ok(Self {
-            fs: DbFileSystem::new(db_arc.clone(), mount_path.to_string_lossy().to_string()),
+            fs: DbFileSystem::open_volume(db_arc.clone(), &agent_id, mount_path.to_string_lossy().to_string())
+                .await?
+                .with_agent_id(agent_id.clone()),
             kv: DbKvStore::new(db_arc.clone(), agent_id.clone()),
             tools: DbToolRecorder::new(db_arc),
             agent_id,
@@ -156,6 +234,43 @@ impl AgentFS {
 
         Self::new(Box::new(backend), agent_id, "/agent").await
     }
+}
+
+/// AgentFS with every part kept in process memory
+pub type MemAgentFS = AgentFS<MemFileSystem, MemKvStore, MemToolRecorder>;
+
+impl MemAgentFS {
+    /// Create an AgentFS that keeps everything in memory, without a database
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let agent_fs = AgentFS::in_memory("scratch-agent");
+    /// agent_fs.fs.write_file("/notes.txt", b"ephemeral").await?;
+    /// ```
+    pub fn in_memory(agent_id: impl Into<String>) -> Self {
+        let mount_path = PathBuf::from("/agent");
+        Self::from_parts(
+            MemFileSystem::new(mount_path.to_string_lossy()),
+            MemKvStore::new(),
+            MemToolRecorder::new(),
+            agent_id,
+            mount_path,
+        )
+    }
+}
+
+impl<F: FileSystem, K: KvStore, T: ToolRecorder> AgentFS<F, K, T> {
+    /// Assemble an AgentFS from individual implementations
+    pub fn from_parts(
+        fs: F,
+        kv: K,
+        tools: T,
+        agent_id: impl Into<String>,
+        mount_path: impl Into<PathBuf>,
+    ) -> Self {
+        Self { fs, kv, tools, agent_id: agent_id.into(), mount_path: mount_path.into() }
+    }
 
     /// Get the agent ID
     pub fn agent_id(&self) -> &str {
@@ -0,0 +1,181 @@
+//! Advisory file locks
+//!
+//! Locks are stored in the `fs_lock` table, so they are visible to every
+//! process and host sharing the database. Each lock is a lease: it carries
+//! an expiry time and is ignored once that time has passed, so a crashed
+//! holder can never block other agents forever.
+//!
+//! Locks are advisory. Filesystem operations don't check them; cooperating
+//! agents take a lock around the operations they want to serialize.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::DbFileSystem;
+use agentdb::AgentDB;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Lock mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Any number of shared holders, but no exclusive holder
+    Shared,
+    /// A single holder and nobody else
+    Exclusive,
+}
+
+impl LockMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LockMode::Shared => "shared",
+            LockMode::Exclusive => "exclusive",
+        }
+    }
+}
+
+/// A held advisory lock
+///
+/// The lock is released when the guard is dropped. Dropping needs a Tokio
+/// runtime to issue the release; without one the lease simply expires.
+/// Use [`LockGuard::release`] to release explicitly and observe errors.
+pub struct LockGuard {
+    db: Arc<Box<dyn AgentDB>>,
+    id: String,
+    path: String,
+    mode: LockMode,
+    released: bool,
+}
+
+impl LockGuard {
+    /// The (normalized) path this lock protects
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The mode this lock was acquired with
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Extend the lease to `ttl` from now
+    ///
+    /// Fails with `AgentFsError::Locked` if the lease already expired and
+    /// was taken over by another holder.
+    pub async fn renew(&self, ttl: Duration) -> Result<()> {
+        let query = format!(
+            "UPDATE fs_lock SET expires_at = {} WHERE id = '{}'",
+            expiry_after(now_millis(), ttl),
+            self.id
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        if result.rows_affected == 0 {
+            return Err(AgentFsError::Locked(self.path.clone()));
+        }
+        Ok(())
+    }
+
+    /// Release the lock now
+    pub async fn release(mut self) -> Result<()> {
+        self.released = true;
+        self.db.query(&release_query(&self.id), vec![]).await?;
+        Ok(())
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let db = self.db.clone();
+            let query = release_query(&self.id);
+            handle.spawn(async move {
+                let _ = db.query(&query, vec![]).await;
+            });
+        }
+    }
+}
+
+impl DbFileSystem {
+    /// Acquire an advisory lock on a path
+    ///
+    /// The path doesn't need to exist, so a lock can guard file creation.
+    /// The lease expires after `ttl` unless renewed with
+    /// [`LockGuard::renew`]. Returns `AgentFsError::Locked` immediately if
+    /// a conflicting lock is held.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let guard = agent_fs.fs.lock("/shared/state.json", LockMode::Exclusive, Duration::from_secs(30)).await?;
+    /// // ... read, modify and write /shared/state.json ...
+    /// guard.release().await?;
+    /// ```
+    pub async fn lock(&self, path: &str, mode: LockMode, ttl: Duration) -> Result<LockGuard> {
+        self.ensure_schema().await?;
+        let path = self.validate_and_normalize_path(path)?;
+        let escaped_path = path.replace('\'', "''");
+        let now = now_millis();
+
+        // Drop leases that have run out
+        let query = format!(
+            "DELETE FROM fs_lock WHERE path = '{}' AND root_ino = {} AND expires_at <= {}",
+            escaped_path, self.root_ino, now
+        );
+        self.db.query(&query, vec![]).await?;
+
+        // Register our claim first, then look for conflicting claims. Two
+        // racing claimants may both back off, but never both succeed.
+        let id = Uuid::new_v4().to_string();
+        let query = format!(
+            "INSERT INTO fs_lock (id, path, mode, expires_at, root_ino) VALUES ('{}', '{}', '{}', {}, {})",
+            id,
+            escaped_path,
+            mode.as_str(),
+            expiry_after(now, ttl),
+            self.root_ino
+        );
+        self.db.query(&query, vec![]).await?;
+
+        let mode_filter = match mode {
+            LockMode::Shared => " AND mode = 'exclusive'",
+            LockMode::Exclusive => "",
+        };
+        let query = format!(
+            "SELECT COUNT(*) as count FROM fs_lock WHERE path = '{}' AND root_ino = {} AND id <> '{}' AND expires_at > {}{}",
+            escaped_path, self.root_ino, id, now, mode_filter
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        let conflicts = match result.rows.first() {
+            Some(row) => self.extract_i64(row, "count")?,
+            None => 0,
+        };
+
+        if conflicts > 0 {
+            self.db.query(&release_query(&id), vec![]).await?;
+            return Err(AgentFsError::Locked(path));
+        }
+
+        Ok(LockGuard { db: self.db.clone(), id, path, mode, released: false })
+    }
+}
+
+/// Query deleting a lock row
+fn release_query(id: &str) -> String {
+    format!("DELETE FROM fs_lock WHERE id = '{}'", id)
+}
+
+/// Expiry time in milliseconds of a lease of `ttl` starting at `now`,
+/// capped rather than overflowing for huge TTLs
+fn expiry_after(now: i64, ttl: Duration) -> i64 {
+    now.saturating_add(i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX))
+}
+
+/// Get current Unix timestamp in milliseconds
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
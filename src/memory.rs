@@ -0,0 +1,742 @@
+//! In-memory implementations of the AgentFS traits
+//!
+//! `MemFileSystem`, `MemKvStore` and `MemToolRecorder` keep everything in
+//! process memory, without any database. They follow the same semantics as
+//! the database-backed implementations (inode numbering, symlink
+//! resolution, link counts, errors), which makes them suitable for unit
+//! tests and short-lived scratch agents. Clones share the same state.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::{
+    DEFAULT_DIR_MODE, DEFAULT_FILE_MODE, DbFileSystem, FileSystem, MAX_SYMLINK_DEPTH, ROOT_INO, S_IFDIR,
+    S_IFLNK, S_IFMT, Stats,
+};
+use crate::kvstore::KvStore;
+use crate::mime::ContentInfo;
+use crate::path::{PathPolicy, child_path};
+use crate::tools::{ToolCall, ToolCallStats, ToolCallStatus, ToolRecorder};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// An inode of the in-memory filesystem
+#[derive(Debug, Clone)]
+struct Inode {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    atime: (i64, u32),
+    mtime: (i64, u32),
+    ctime: (i64, u32),
+    version: i64,
+    /// Content, shared between copies until one of them is written
+    data: Arc<[u8]>,
+    target: Option<String>,
+    content: Option<ContentInfo>,
+}
+
+impl Inode {
+    fn new(mode: u32) -> Self {
+        let now = DbFileSystem::now_timespec();
+        Self {
+            mode,
+            uid: 0,
+            gid: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            version: 1,
+            data: Arc::from(Vec::new()),
+            target: None,
+            content: None,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        (self.mode & S_IFMT) == S_IFDIR
+    }
+
+    fn is_symlink(&self) -> bool {
+        (self.mode & S_IFMT) == S_IFLNK
+    }
+}
+
+#[derive(Debug)]
+struct MemState {
+    inodes: HashMap<i64, Inode>,
+    /// Directory entries keyed by (parent inode, name)
+    dentries: BTreeMap<(i64, String), i64>,
+    next_ino: i64,
+}
+
+impl MemState {
+    fn lookup(&self, parent_ino: i64, name: &str) -> Option<i64> {
+        self.dentries.get(&(parent_ino, name.to_string())).copied()
+    }
+
+    fn children(&self, ino: i64) -> impl Iterator<Item = (&String, &i64)> {
+        self.dentries
+            .range((ino, String::new())..(ino + 1, String::new()))
+            .map(|((_, name), child)| (name, child))
+    }
+
+    fn inode(&self, ino: i64) -> Result<&Inode> {
+        self.inodes
+            .get(&ino)
+            .ok_or_else(|| AgentFsError::FileNotFound(format!("inode {}", ino)))
+    }
+
+    fn is_dir(&self, ino: i64) -> bool {
+        self.inodes.get(&ino).is_some_and(Inode::is_dir)
+    }
+
+    fn create_inode(&mut self, inode: Inode) -> i64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes.insert(ino, inode);
+        ino
+    }
+
+    fn touch_dir(&mut self, ino: i64) {
+        if let Some(dir) = self.inodes.get_mut(&ino) {
+            let now = DbFileSystem::now_timespec();
+            dir.mtime = now;
+            dir.ctime = now;
+        }
+    }
+
+    fn stats(&self, ino: i64) -> Result<Stats> {
+        let inode = self.inode(ino)?;
+        let content = inode.content.as_ref();
+        Ok(Stats {
+            ino,
+            mode: inode.mode,
+            nlink: self.dentries.values().filter(|&&i| i == ino).count() as u32,
+            uid: inode.uid,
+            gid: inode.gid,
+            size: match &inode.target {
+                Some(target) => target.len() as i64,
+                None => inode.data.len() as i64,
+            },
+            atime: inode.atime.0,
+            mtime: inode.mtime.0,
+            ctime: inode.ctime.0,
+            atime_nsec: inode.atime.1,
+            mtime_nsec: inode.mtime.1,
+            ctime_nsec: inode.ctime.1,
+            version: inode.version,
+            content_type: content.map(|c| c.content_type.clone()),
+            line_count: content.and_then(|c| c.line_count),
+            encoding: content.and_then(|c| c.encoding.clone()),
+        })
+    }
+}
+
+/// In-memory filesystem
+#[derive(Clone)]
+pub struct MemFileSystem {
+    state: Arc<Mutex<MemState>>,
+    mount_path: String,
+    path_policy: PathPolicy,
+}
+
+impl MemFileSystem {
+    /// Create an empty in-memory filesystem
+    pub fn new(mount_path: impl Into<String>) -> Self {
+        let mut inodes = HashMap::new();
+        inodes.insert(ROOT_INO, Inode::new(DEFAULT_DIR_MODE));
+        Self {
+            state: Arc::new(Mutex::new(MemState {
+                inodes,
+                dentries: BTreeMap::new(),
+                next_ino: ROOT_INO + 1,
+            })),
+            mount_path: mount_path.into(),
+            path_policy: PathPolicy::default(),
+        }
+    }
+
+    /// Set the rules paths are validated against
+    pub fn with_path_policy(mut self, policy: PathPolicy) -> Self {
+        self.path_policy = policy;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn validate_and_normalize_path(&self, path: &str) -> Result<String> {
+        crate::path::validate_and_normalize(&self.mount_path, &self.path_policy, path)
+    }
+
+    /// Resolve a path to an inode, following symlinks like
+    /// `DbFileSystem::resolve`
+    fn resolve(&self, state: &MemState, path: &str, follow_last: bool) -> Result<Option<(i64, String)>> {
+        let mut pending: VecDeque<String> = crate::path::split(path).into();
+        let mut resolved: Vec<(String, i64)> = Vec::new();
+        let mut links_followed = 0;
+
+        while let Some(name) = pending.pop_front() {
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    resolved.pop();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let parent_ino = resolved.last().map_or(ROOT_INO, |(_, ino)| *ino);
+            let Some(ino) = state.lookup(parent_ino, &name) else {
+                return Ok(None);
+            };
+            let inode = state.inode(ino)?;
+            let is_last = pending.is_empty();
+
+            if inode.is_symlink() && (follow_last || !is_last) {
+                links_followed += 1;
+                if links_followed > MAX_SYMLINK_DEPTH {
+                    return Err(AgentFsError::SymlinkLoop(path.to_string()));
+                }
+
+                let mut target = inode
+                    .target
+                    .clone()
+                    .ok_or_else(|| AgentFsError::InvalidPath("Symlink has no target".to_string()))?;
+                if target.starts_with('/') {
+                    resolved.clear();
+                    target = self.validate_and_normalize_path(&target)?;
+                }
+                for component in target.split('/').filter(|c| !c.is_empty()).rev() {
+                    pending.push_front(component.to_string());
+                }
+                continue;
+            }
+
+            if !is_last && !inode.is_dir() {
+                return Err(AgentFsError::NotADirectory(path.to_string()));
+            }
+            resolved.push((name, ino));
+        }
+
+        let ino = resolved.last().map_or(ROOT_INO, |(_, ino)| *ino);
+        let names: Vec<&str> = resolved.iter().map(|(name, _)| name.as_str()).collect();
+        Ok(Some((ino, format!("/{}", names.join("/")))))
+    }
+
+    /// Resolve the parent directory of a path, returning the parent inode,
+    /// the final name and the canonical parent path
+    fn resolve_parent(&self, state: &MemState, path: &str) -> Result<(i64, String, String)> {
+        let components = crate::path::split(path);
+        let name = components
+            .last()
+            .cloned()
+            .ok_or_else(|| AgentFsError::InvalidPath("Root directory has no parent".to_string()))?;
+
+        let parent_path = format!("/{}", components[..components.len() - 1].join("/"));
+        let (parent_ino, parent_canonical) = self
+            .resolve(state, &parent_path, true)?
+            .ok_or_else(|| AgentFsError::DirectoryNotFound(parent_path.clone()))?;
+        if !state.is_dir(parent_ino) {
+            return Err(AgentFsError::NotADirectory(parent_path));
+        }
+
+        Ok((parent_ino, name, parent_canonical))
+    }
+
+    /// Find where a write to `path` lands, following final symlinks like
+    /// `open(O_CREAT)`
+    fn resolve_for_write(&self, state: &MemState, path: &str) -> Result<(i64, String, Option<i64>, String)> {
+        let mut current = path.to_string();
+        for _ in 0..=MAX_SYMLINK_DEPTH {
+            let (parent_ino, name, parent_path) = self.resolve_parent(state, &current)?;
+            let existing = state.lookup(parent_ino, &name);
+
+            if let Some(ino) = existing
+                && let Some(target) = &state.inode(ino)?.target
+            {
+                current = if target.starts_with('/') {
+                    self.validate_and_normalize_path(target)?
+                } else {
+                    crate::path::normalize(&format!("{}/{}", parent_path, target))
+                };
+                continue;
+            }
+
+            return Ok((parent_ino, name.clone(), existing, child_path(&parent_path, &name)));
+        }
+
+        Err(AgentFsError::SymlinkLoop(path.to_string()))
+    }
+
+    /// Remove an entry from an already locked state
+    fn remove_locked(&self, state: &mut MemState, path: &str) -> Result<()> {
+        if path == "/" {
+            return Err(AgentFsError::InvalidPath("Cannot remove root directory".to_string()));
+        }
+
+        let (parent_ino, name, _) = self.resolve_parent(state, path)?;
+        let ino = state
+            .lookup(parent_ino, &name)
+            .ok_or_else(|| AgentFsError::FileNotFound(path.to_string()))?;
+
+        if state.children(ino).next().is_some() {
+            return Err(AgentFsError::DirectoryNotEmpty(path.to_string()));
+        }
+
+        state.dentries.remove(&(parent_ino, name));
+        if !state.dentries.values().any(|&i| i == ino) {
+            state.inodes.remove(&ino);
+        }
+        state.touch_dir(parent_ino);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FileSystem for MemFileSystem {
+    async fn write_file(&self, path: &str, content: &[u8]) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
+        if path == "/" {
+            return Err(AgentFsError::IsADirectory(path));
+        }
+
+        let mut state = self.state();
+        let (parent_ino, name, existing, canonical) = self.resolve_for_write(&state, &path)?;
+        let info = crate::mime::inspect(&name, content);
+
+        match existing {
+            Some(ino) => {
+                if state.is_dir(ino) {
+                    return Err(AgentFsError::IsADirectory(canonical));
+                }
+                let now = DbFileSystem::now_timespec();
+                let inode = state.inodes.get_mut(&ino).expect("resolved inode exists");
+                inode.data = Arc::from(content);
+                inode.mtime = now;
+                inode.ctime = now;
+                inode.version += 1;
+                inode.content = Some(info);
+            }
+            None => {
+                let mut inode = Inode::new(DEFAULT_FILE_MODE);
+                inode.data = Arc::from(content);
+                inode.content = Some(info);
+                let ino = state.create_inode(inode);
+                state.dentries.insert((parent_ino, name), ino);
+                state.touch_dir(parent_ino);
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.validate_and_normalize_path(path)?;
+        let state = self.state();
+        let Some((ino, _)) = self.resolve(&state, &path, true)? else {
+            return Ok(None);
+        };
+        if state.is_dir(ino) {
+            return Err(AgentFsError::IsADirectory(path));
+        }
+        Ok(Some(state.inode(ino)?.data.to_vec()))
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        let path = self.validate_and_normalize_path(path)?;
+        match self.resolve(&self.state(), &path, false) {
+            Ok(ino) => Ok(ino.is_some()),
+            Err(AgentFsError::NotADirectory(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn readdir(&self, path: &str) -> Result<Option<Vec<String>>> {
+        let path = self.validate_and_normalize_path(path)?;
+        let state = self.state();
+        let Some((ino, _)) = self.resolve(&state, &path, true)? else {
+            return Ok(None);
+        };
+        if !state.is_dir(ino) {
+            return Err(AgentFsError::NotADirectory(path));
+        }
+        Ok(Some(state.children(ino).map(|(name, _)| name.clone()).collect()))
+    }
+
+    async fn mkdir(&self, path: &str) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
+        if path == "/" {
+            return Err(AgentFsError::InvalidPath("Cannot create root directory".to_string()));
+        }
+
+        let mut state = self.state();
+        let (parent_ino, name, _) = self.resolve_parent(&state, &path)?;
+        if state.lookup(parent_ino, &name).is_some() {
+            return Err(AgentFsError::PathExists(path));
+        }
+
+        let ino = state.create_inode(Inode::new(DEFAULT_DIR_MODE));
+        state.dentries.insert((parent_ino, name), ino);
+        state.touch_dir(parent_ino);
+        Ok(())
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
+        self.remove_locked(&mut self.state(), &path)
+    }
+
+    async fn stat(&self, path: &str) -> Result<Option<Stats>> {
+        let path = self.validate_and_normalize_path(path)?;
+        let state = self.state();
+        match self.resolve(&state, &path, true)? {
+            Some((ino, _)) => Ok(Some(state.stats(ino)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn lstat(&self, path: &str) -> Result<Option<Stats>> {
+        let path = self.validate_and_normalize_path(path)?;
+        let state = self.state();
+        match self.resolve(&state, &path, false)? {
+            Some((ino, _)) => Ok(Some(state.stats(ino)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn symlink(&self, target: &str, linkpath: &str) -> Result<()> {
+        let linkpath = self.validate_and_normalize_path(linkpath)?;
+        if linkpath == "/" {
+            return Err(AgentFsError::InvalidPath("Cannot create symlink at root".to_string()));
+        }
+
+        let mut state = self.state();
+        let (parent_ino, name, _) = self.resolve_parent(&state, &linkpath)?;
+        if state.lookup(parent_ino, &name).is_some() {
+            return Err(AgentFsError::PathExists(linkpath));
+        }
+
+        let mut inode = Inode::new(S_IFLNK | 0o777);
+        inode.target = Some(self.path_policy.normalize_target(target));
+        let ino = state.create_inode(inode);
+        state.dentries.insert((parent_ino, name), ino);
+        state.touch_dir(parent_ino);
+        Ok(())
+    }
+
+    async fn readlink(&self, path: &str) -> Result<Option<String>> {
+        let path = self.validate_and_normalize_path(path)?;
+        let state = self.state();
+        let Some((ino, _)) = self.resolve(&state, &path, false)? else {
+            return Ok(None);
+        };
+        match &state.inode(ino)?.target {
+            Some(target) => Ok(Some(target.clone())),
+            None => Err(AgentFsError::NotASymlink(path)),
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from = self.validate_and_normalize_path(from)?;
+        let to = self.validate_and_normalize_path(to)?;
+        if from == "/" || to == "/" {
+            return Err(AgentFsError::InvalidPath("Cannot rename root directory".to_string()));
+        }
+
+        let mut state = self.state();
+        let (from_parent_ino, from_name, from_parent) = self.resolve_parent(&state, &from)?;
+        let (to_parent_ino, to_name, to_parent) = self.resolve_parent(&state, &to)?;
+        let from = child_path(&from_parent, &from_name);
+        let to = child_path(&to_parent, &to_name);
+
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(&format!("{}/", from)) {
+            return Err(AgentFsError::InvalidPath(format!(
+                "Cannot move {} into its own subdirectory",
+                from
+            )));
+        }
+
+        let ino = state
+            .lookup(from_parent_ino, &from_name)
+            .ok_or_else(|| AgentFsError::FileNotFound(from.clone()))?;
+
+        // Replace an existing destination of a compatible type
+        if let Some(existing) = state.lookup(to_parent_ino, &to_name) {
+            if existing == ino {
+                return Ok(());
+            }
+            match (state.is_dir(ino), state.is_dir(existing)) {
+                (true, false) => return Err(AgentFsError::NotADirectory(to)),
+                (false, true) => return Err(AgentFsError::IsADirectory(to)),
+                _ => {}
+            }
+            self.remove_locked(&mut state, &to)?;
+        }
+
+        state.dentries.remove(&(from_parent_ino, from_name));
+        state.dentries.insert((to_parent_ino, to_name), ino);
+        state.touch_dir(from_parent_ino);
+        state.touch_dir(to_parent_ino);
+        Ok(())
+    }
+
+    async fn copy(&self, src: &str, dst: &str) -> Result<()> {
+        let src = self.validate_and_normalize_path(src)?;
+        let dst = self.validate_and_normalize_path(dst)?;
+
+        let mut state = self.state();
+        let (src_ino, _) = self
+            .resolve(&state, &src, true)?
+            .ok_or_else(|| AgentFsError::FileNotFound(src.clone()))?;
+        if state.is_dir(src_ino) {
+            return Err(AgentFsError::IsADirectory(src));
+        }
+        if dst == "/" {
+            return Err(AgentFsError::IsADirectory(dst));
+        }
+        let source = state.inode(src_ino)?.clone();
+
+        let (parent_ino, name, existing, canonical) = self.resolve_for_write(&state, &dst)?;
+        match existing {
+            Some(ino) if ino == src_ino => {}
+            Some(ino) => {
+                if state.is_dir(ino) {
+                    return Err(AgentFsError::IsADirectory(canonical));
+                }
+                let now = DbFileSystem::now_timespec();
+                let inode = state.inodes.get_mut(&ino).expect("resolved inode exists");
+                inode.data = source.data;
+                inode.content = source.content;
+                inode.mtime = now;
+                inode.ctime = now;
+                inode.version += 1;
+            }
+            None => {
+                let ino = state.create_inode(copied_inode(&source));
+                state.dentries.insert((parent_ino, name), ino);
+                state.touch_dir(parent_ino);
+            }
+        }
+        Ok(())
+    }
+
+    async fn copy_dir(&self, src: &str, dst: &str) -> Result<()> {
+        let src = self.validate_and_normalize_path(src)?;
+        let dst = self.validate_and_normalize_path(dst)?;
+
+        let mut state = self.state();
+        let (src_ino, src_canonical) = self
+            .resolve(&state, &src, true)?
+            .ok_or_else(|| AgentFsError::FileNotFound(src.clone()))?;
+        if !state.is_dir(src_ino) {
+            return Err(AgentFsError::NotADirectory(src));
+        }
+        if dst == "/" {
+            return Err(AgentFsError::PathExists(dst));
+        }
+
+        let (parent_ino, name, parent_path) = self.resolve_parent(&state, &dst)?;
+        if state.lookup(parent_ino, &name).is_some() {
+            return Err(AgentFsError::PathExists(dst));
+        }
+        let dst_canonical = child_path(&parent_path, &name);
+        if src_canonical == "/" || dst_canonical.starts_with(&format!("{}/", src_canonical)) {
+            return Err(AgentFsError::InvalidPath(format!("Cannot copy {} into itself", src)));
+        }
+
+        let copy = copied_inode(state.inode(src_ino)?);
+        let root = state.create_inode(copy);
+        state.dentries.insert((parent_ino, name), root);
+        state.touch_dir(parent_ino);
+
+        let mut pending = vec![(src_ino, root)];
+        while let Some((from, to)) = pending.pop() {
+            let children: Vec<(String, i64)> = state.children(from).map(|(name, &ino)| (name.clone(), ino)).collect();
+            for (name, child) in children {
+                let copy = copied_inode(state.inode(child)?);
+                let copy = state.create_inode(copy);
+                state.dentries.insert((to, name), copy);
+                if state.is_dir(child) {
+                    pending.push((child, copy));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A new inode with the metadata and (shared) content of `source`
+fn copied_inode(source: &Inode) -> Inode {
+    Inode { ctime: DbFileSystem::now_timespec(), version: 1, ..source.clone() }
+}
+
+/// In-memory key-value store
+#[derive(Clone, Default)]
+pub struct MemKvStore {
+    entries: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemKvStore {
+    /// Create an empty in-memory KV store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> MutexGuard<'_, BTreeMap<String, Vec<u8>>> {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[async_trait]
+impl KvStore for MemKvStore {
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.entries().insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries().get(key).cloned())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.entries().remove(key);
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.entries().contains_key(key))
+    }
+
+    async fn scan(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self
+            .entries()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+}
+
+/// In-memory tool recorder
+#[derive(Clone, Default)]
+pub struct MemToolRecorder {
+    calls: Arc<Mutex<Vec<ToolCall>>>,
+}
+
+impl MemToolRecorder {
+    /// Create an empty in-memory tool recorder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn calls(&self) -> MutexGuard<'_, Vec<ToolCall>> {
+        self.calls.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Mark a pending call as finished
+    fn complete(&self, id: i64, status: ToolCallStatus, result: Option<serde_json::Value>, error: Option<&str>) -> Result<()> {
+        let mut calls = self.calls();
+        let call = calls
+            .iter_mut()
+            .find(|call| call.id == id)
+            .ok_or_else(|| {
+                AgentFsError::Database(agentdb::AgentDbError::Backend("Tool call not found".to_string()))
+            })?;
+
+        let completed_at = DbFileSystem::now();
+        call.status = status;
+        call.result = result;
+        call.error = error.map(str::to_string);
+        call.completed_at = Some(completed_at);
+        call.duration_ms = Some((completed_at - call.started_at) * 1000);
+        Ok(())
+    }
+
+    fn push(&self, mut call: ToolCall) -> i64 {
+        let mut calls = self.calls();
+        call.id = calls.len() as i64 + 1;
+        let id = call.id;
+        calls.push(call);
+        id
+    }
+}
+
+#[async_trait]
+impl ToolRecorder for MemToolRecorder {
+    async fn start(&self, name: &str, parameters: Option<serde_json::Value>) -> Result<i64> {
+        Ok(self.push(ToolCall {
+            id: 0,
+            name: name.to_string(),
+            parameters,
+            result: None,
+            error: None,
+            status: ToolCallStatus::Pending,
+            started_at: DbFileSystem::now(),
+            completed_at: None,
+            duration_ms: None,
+        }))
+    }
+
+    async fn success(&self, id: i64, result: Option<serde_json::Value>) -> Result<()> {
+        self.complete(id, ToolCallStatus::Success, result, None)
+    }
+
+    async fn error(&self, id: i64, error: &str) -> Result<()> {
+        self.complete(id, ToolCallStatus::Error, None, Some(error))
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<ToolCall>> {
+        Ok(self.calls().iter().find(|call| call.id == id).cloned())
+    }
+
+    async fn stats_for(&self, tool_name: &str) -> Result<Option<ToolCallStats>> {
+        let calls = self.calls();
+        let matching: Vec<&ToolCall> = calls.iter().filter(|call| call.name == tool_name).collect();
+        if matching.is_empty() {
+            return Ok(None);
+        }
+
+        let count = |status: ToolCallStatus| matching.iter().filter(|call| call.status == status).count() as i64;
+        let total_duration: i64 = matching.iter().map(|call| call.duration_ms.unwrap_or(0)).sum();
+        Ok(Some(ToolCallStats {
+            name: tool_name.to_string(),
+            total_calls: matching.len() as i64,
+            successful: count(ToolCallStatus::Success),
+            failed: count(ToolCallStatus::Error),
+            avg_duration_ms: total_duration as f64 / matching.len() as f64,
+        }))
+    }
+
+    async fn record(
+        &self,
+        name: &str,
+        started_at: i64,
+        completed_at: i64,
+        parameters: Option<serde_json::Value>,
+        result: Option<serde_json::Value>,
+        error: Option<&str>,
+    ) -> Result<i64> {
+        Ok(self.push(ToolCall {
+            id: 0,
+            name: name.to_string(),
+            parameters,
+            result,
+            error: error.map(str::to_string),
+            status: if error.is_some() { ToolCallStatus::Error } else { ToolCallStatus::Success },
+            started_at,
+            completed_at: Some(completed_at),
+            duration_ms: Some((completed_at - started_at) * 1000),
+        }))
+    }
+
+    async fn list(&self, limit: Option<usize>) -> Result<Vec<ToolCall>> {
+        let mut calls = self.calls().clone();
+        calls.sort_by(|a, b| b.started_at.cmp(&a.started_at).then(b.id.cmp(&a.id)));
+        calls.truncate(limit.unwrap_or(usize::MAX));
+        Ok(calls)
+    }
+}
@@ -0,0 +1,123 @@
+//! Content type detection
+//!
+//! Files are classified when they are written, from their leading bytes
+//! first and their extension second, so agents can tell images, documents
+//! and text apart without reading them back. Text files also get their
+//! line count and encoding; all three are returned in `Stats` and by
+//! `readdir_plus`.
+
+/// Content type of files that are neither recognized nor text
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Metadata derived from a file's content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentInfo {
+    /// MIME type, e.g. `image/png` or `text/plain`
+    pub content_type: String,
+    /// Number of lines, for text files
+    pub line_count: Option<i64>,
+    /// Text encoding (`utf-8`), for text files
+    pub encoding: Option<String>,
+}
+
+/// Signatures checked against the start of the content
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x00asm", "application/wasm"),
+    (b"\x7fELF", "application/x-elf"),
+    (b"SQLite format 3\x00", "application/vnd.sqlite3"),
+];
+
+/// Sizes of the DIB headers that follow a BMP file header, one per
+/// Windows and OS/2 version
+const BMP_DIB_HEADER_SIZES: &[u32] = &[12, 16, 40, 52, 56, 64, 108, 124];
+
+/// Whether `content` starts with a BMP file header and a DIB header
+///
+/// `BM` alone is too common a prefix to go by: it starts plenty of text.
+fn is_bmp(content: &[u8]) -> bool {
+    content.len() >= 26
+        && content.starts_with(b"BM")
+        && BMP_DIB_HEADER_SIZES.contains(&u32::from_le_bytes([content[14], content[15], content[16], content[17]]))
+}
+
+/// Types recognized by file extension
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("json", "application/json"),
+    ("jsonl", "application/jsonl"),
+    ("md", "text/markdown"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    ("js", "text/javascript"),
+    ("ts", "text/x-typescript"),
+    ("py", "text/x-python"),
+    ("rs", "text/x-rust"),
+    ("sh", "application/x-sh"),
+    ("svg", "image/svg+xml"),
+    ("txt", "text/plain"),
+    ("docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+    ("xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+];
+
+/// Detect the content type and text metadata of a file
+///
+/// Magic bytes win over the extension, except for ZIP-based formats such as
+/// `.docx` whose extension is more specific. Valid UTF-8 without NUL bytes
+/// is treated as text.
+pub fn inspect(name: &str, content: &[u8]) -> ContentInfo {
+    let by_extension = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .and_then(|ext| EXTENSIONS.iter().find(|(e, _)| *e == ext).map(|(_, t)| *t));
+    let by_magic = if content.len() >= 12 && &content[..4] == b"RIFF" && &content[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if is_bmp(content) {
+        Some("image/bmp")
+    } else {
+        MAGIC.iter().find(|(magic, _)| content.starts_with(magic)).map(|(_, t)| *t)
+    };
+
+    let text = std::str::from_utf8(content).ok().filter(|s| !s.contains('\0'));
+
+    let content_type = match (by_magic, by_extension) {
+        (Some("application/zip"), Some(ext_type)) if ext_type.starts_with("application/vnd.") => ext_type,
+        (Some(magic_type), _) => magic_type,
+        (None, Some(ext_type)) => ext_type,
+        (None, None) if text.is_some_and(looks_like_json) => "application/json",
+        (None, None) if text.is_some() => "text/plain",
+        (None, None) => OCTET_STREAM,
+    };
+
+    match text.filter(|_| by_magic.is_none()) {
+        Some(text) => ContentInfo {
+            content_type: content_type.to_string(),
+            line_count: Some(count_lines(text)),
+            encoding: Some("utf-8".to_string()),
+        },
+        None => ContentInfo { content_type: content_type.to_string(), line_count: None, encoding: None },
+    }
+}
+
+/// Count lines, including a final line without a trailing newline
+fn count_lines(text: &str) -> i64 {
+    let newlines = text.matches('\n').count() as i64;
+    if text.is_empty() || text.ends_with('\n') { newlines } else { newlines + 1 }
+}
+
+fn looks_like_json(text: &str) -> bool {
+    let trimmed = text.trim_start();
+    (trimmed.starts_with('{') || trimmed.starts_with('['))
+        && serde_json::from_str::<serde_json::Value>(text).is_ok()
+}
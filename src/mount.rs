@@ -0,0 +1,324 @@
+//! Several filesystems in one namespace
+//!
+//! `MountFs` keeps a mount table mapping paths such as `/agent`, `/shared`
+//! and `/host` to other `FileSystem` implementations, and dispatches every
+//! operation to the filesystem mounted at the longest matching prefix. A
+//! mount can be read-only, for instance to expose another agent's volume;
+//! writes to it fail with `AgentFsError::ReadOnly` (`EROFS`).
+//!
+//! Backends see paths relative to their mount point, so create them with
+//! the mount path `/`. Each backend resolves symlinks within itself: a
+//! symlink never leads into another mount. Directories above the mount
+//! points (such as `/` when nothing is mounted there) are read-only
+//! placeholders listing the mount points below them.
+//!
+//! Like `rename(2)` across filesystems, a rename from one mount to another
+//! fails with `AgentFsError::CrossDevice` (`EXDEV`); `copy` works across
+//! mounts.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::{DEFAULT_DIR_MODE, FileSystem, Stats};
+use crate::path::PathPolicy;
+use async_trait::async_trait;
+use std::collections::BTreeSet;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+/// A filesystem in the mount table
+#[derive(Clone)]
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+    read_only: bool,
+}
+
+/// An entry of the mount table, returned by [`MountFs::mounts`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    /// Mount point
+    pub path: String,
+    /// Whether writes through the mount are rejected
+    pub read_only: bool,
+}
+
+/// Filesystem dispatching paths to the filesystems mounted on them
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mounts = MountFs::new();
+/// mounts.mount("/agent", DbFileSystem::open_volume(db.clone(), "alice", "/".into()).await?)?;
+/// mounts.mount_read_only("/shared", DbFileSystem::open_volume(db.clone(), "bob", "/".into()).await?)?;
+/// mounts.mount("/host", HostFileSystem::new("/srv/workspace", "/")?)?;
+///
+/// let agent_fs = AgentFS::from_parts(mounts, MemKvStore::new(), MemToolRecorder::new(), "alice", "/");
+/// agent_fs.fs.copy("/shared/plan.md", "/agent/plan.md").await?;
+/// ```
+#[derive(Clone, Default)]
+pub struct MountFs {
+    mounts: Arc<RwLock<Vec<Mount>>>,
+}
+
+impl MountFs {
+    /// Create a namespace with nothing mounted
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mount a filesystem at `path`
+    pub fn mount(&self, path: &str, fs: impl FileSystem + 'static) -> Result<()> {
+        self.add_mount(path, Arc::new(fs), false)
+    }
+
+    /// Mount a filesystem at `path`, rejecting writes with
+    /// `AgentFsError::ReadOnly`
+    pub fn mount_read_only(&self, path: &str, fs: impl FileSystem + 'static) -> Result<()> {
+        self.add_mount(path, Arc::new(fs), true)
+    }
+
+    /// Remove the filesystem mounted at `path` from the namespace
+    pub fn unmount(&self, path: &str) -> Result<()> {
+        let path = normalize(path)?;
+        let mut mounts = self.mounts.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let before = mounts.len();
+        mounts.retain(|mount| mount.path != path);
+        if mounts.len() == before {
+            return Err(AgentFsError::InvalidPath(format!("Nothing is mounted at {}", path)));
+        }
+        Ok(())
+    }
+
+    /// The mount table, ordered by mount point
+    pub fn mounts(&self) -> Vec<MountInfo> {
+        let mut mounts: Vec<MountInfo> = self
+            .table()
+            .iter()
+            .map(|mount| MountInfo { path: mount.path.clone(), read_only: mount.read_only })
+            .collect();
+        mounts.sort_by(|a, b| a.path.cmp(&b.path));
+        mounts
+    }
+
+    fn add_mount(&self, path: &str, fs: Arc<dyn FileSystem>, read_only: bool) -> Result<()> {
+        let path = normalize(path)?;
+        let mut mounts = self.mounts.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if mounts.iter().any(|mount| mount.path == path) {
+            return Err(AgentFsError::PathExists(path));
+        }
+        mounts.push(Mount { path, fs, read_only });
+        // Longest prefix first, so lookups take the innermost mount
+        mounts.sort_by_key(|mount| std::cmp::Reverse(mount.path.len()));
+        Ok(())
+    }
+
+    fn table(&self) -> RwLockReadGuard<'_, Vec<Mount>> {
+        self.mounts.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The mount holding a normalized path, and the path inside it
+    fn route(&self, path: &str) -> Option<(Mount, String)> {
+        self.table().iter().find_map(|mount| {
+            let inner = if mount.path == "/" {
+                path
+            } else if path == mount.path {
+                "/"
+            } else {
+                path.strip_prefix(&mount.path).filter(|rest| rest.starts_with('/'))?
+            };
+            Some((mount.clone(), inner.to_string()))
+        })
+    }
+
+    /// The mount holding a normalized path, if it accepts writes there
+    fn route_for_write(&self, path: &str) -> Result<(Mount, String)> {
+        match self.route(path) {
+            Some((mount, _)) if mount.read_only => Err(AgentFsError::ReadOnly(path.to_string())),
+            Some(route) => Ok(route),
+            None => Err(AgentFsError::ReadOnly(path.to_string())),
+        }
+    }
+
+    /// Names of the mount points directly below a normalized path
+    fn mount_points_in(&self, path: &str) -> BTreeSet<String> {
+        let prefix = if path == "/" { "/".to_string() } else { format!("{}/", path) };
+        self.table()
+            .iter()
+            .filter_map(|mount| mount.path.strip_prefix(&prefix))
+            .filter(|rest| !rest.is_empty())
+            .map(|rest| rest.split('/').next().unwrap_or(rest).to_string())
+            .collect()
+    }
+
+    /// Whether a normalized path is a placeholder directory above a mount
+    /// point
+    fn is_placeholder(&self, path: &str) -> bool {
+        path == "/" || !self.mount_points_in(path).is_empty()
+    }
+
+    /// Fail if a normalized path is a mount point
+    fn check_not_mount_point(&self, path: &str) -> Result<()> {
+        if self.table().iter().any(|mount| mount.path == path) {
+            return Err(AgentFsError::InvalidPath(format!("{} is a mount point", path)));
+        }
+        Ok(())
+    }
+}
+
+fn normalize(path: &str) -> Result<String> {
+    crate::path::validate_and_normalize("/", &PathPolicy::default(), path)
+}
+
+/// Stats of a placeholder directory
+fn placeholder_stats() -> Stats {
+    Stats {
+        ino: 0,
+        mode: DEFAULT_DIR_MODE,
+        nlink: 2,
+        uid: 0,
+        gid: 0,
+        size: 0,
+        atime: 0,
+        mtime: 0,
+        ctime: 0,
+        atime_nsec: 0,
+        mtime_nsec: 0,
+        ctime_nsec: 0,
+        version: 1,
+        content_type: None,
+        line_count: None,
+        encoding: None,
+    }
+}
+
+#[async_trait]
+impl FileSystem for MountFs {
+    async fn write_file(&self, path: &str, content: &[u8]) -> Result<()> {
+        let path = normalize(path)?;
+        if self.route(&path).is_none() && self.is_placeholder(&path) {
+            return Err(AgentFsError::IsADirectory(path));
+        }
+        let (mount, inner) = self.route_for_write(&path)?;
+        mount.fs.write_file(&inner, content).await
+    }
+
+    async fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let path = normalize(path)?;
+        match self.route(&path) {
+            Some((mount, inner)) => mount.fs.read_file(&inner).await,
+            None if self.is_placeholder(&path) => Err(AgentFsError::IsADirectory(path)),
+            None => Ok(None),
+        }
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        let path = normalize(path)?;
+        match self.route(&path) {
+            Some((mount, inner)) => mount.fs.exists(&inner).await,
+            None => Ok(self.is_placeholder(&path)),
+        }
+    }
+
+    async fn readdir(&self, path: &str) -> Result<Option<Vec<String>>> {
+        let path = normalize(path)?;
+        let mut names = self.mount_points_in(&path);
+        if let Some((mount, inner)) = self.route(&path) {
+            let Some(entries) = mount.fs.readdir(&inner).await? else {
+                return Ok(None);
+            };
+            names.extend(entries);
+        } else if !self.is_placeholder(&path) {
+            return Ok(None);
+        }
+        Ok(Some(names.into_iter().collect()))
+    }
+
+    async fn mkdir(&self, path: &str) -> Result<()> {
+        let path = normalize(path)?;
+        if self.is_placeholder(&path) || self.table().iter().any(|mount| mount.path == path) {
+            return Err(AgentFsError::PathExists(path));
+        }
+        let (mount, inner) = self.route_for_write(&path)?;
+        mount.fs.mkdir(&inner).await
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        let path = normalize(path)?;
+        self.check_not_mount_point(&path)?;
+        let (mount, inner) = self.route_for_write(&path)?;
+        mount.fs.remove(&inner).await
+    }
+
+    async fn stat(&self, path: &str) -> Result<Option<Stats>> {
+        let path = normalize(path)?;
+        match self.route(&path) {
+            Some((mount, inner)) => mount.fs.stat(&inner).await,
+            None => Ok(self.is_placeholder(&path).then(placeholder_stats)),
+        }
+    }
+
+    async fn lstat(&self, path: &str) -> Result<Option<Stats>> {
+        let path = normalize(path)?;
+        match self.route(&path) {
+            Some((mount, inner)) => mount.fs.lstat(&inner).await,
+            None => Ok(self.is_placeholder(&path).then(placeholder_stats)),
+        }
+    }
+
+    async fn symlink(&self, target: &str, linkpath: &str) -> Result<()> {
+        let linkpath = normalize(linkpath)?;
+        let (mount, inner) = self.route_for_write(&linkpath)?;
+        mount.fs.symlink(target, &inner).await
+    }
+
+    async fn readlink(&self, path: &str) -> Result<Option<String>> {
+        let path = normalize(path)?;
+        match self.route(&path) {
+            Some((mount, inner)) => mount.fs.readlink(&inner).await,
+            None if self.is_placeholder(&path) => Err(AgentFsError::NotASymlink(path)),
+            None => Ok(None),
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from = normalize(from)?;
+        let to = normalize(to)?;
+        self.check_not_mount_point(&from)?;
+        self.check_not_mount_point(&to)?;
+        let (from_mount, from_inner) = self.route_for_write(&from)?;
+        let (to_mount, to_inner) = self.route_for_write(&to)?;
+        if from_mount.path != to_mount.path {
+            return Err(AgentFsError::CrossDevice(format!(
+                "Cannot rename {} to {} on another mount",
+                from, to
+            )));
+        }
+        from_mount.fs.rename(&from_inner, &to_inner).await
+    }
+
+    async fn copy(&self, src: &str, dst: &str) -> Result<()> {
+        let src = normalize(src)?;
+        let dst = normalize(dst)?;
+        let (src_mount, src_inner) = self
+            .route(&src)
+            .ok_or_else(|| AgentFsError::FileNotFound(src.clone()))?;
+        let (dst_mount, dst_inner) = self.route_for_write(&dst)?;
+        if src_mount.path == dst_mount.path {
+            // Keep the backend's own copy, which may share data
+            return src_mount.fs.copy(&src_inner, &dst_inner).await;
+        }
+
+        let stats = src_mount
+            .fs
+            .stat(&src_inner)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(src.clone()))?;
+        if stats.is_directory() {
+            return Err(AgentFsError::IsADirectory(src));
+        }
+        let content = src_mount
+            .fs
+            .read_file(&src_inner)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(src.clone()))?;
+        dst_mount.fs.write_file(&dst_inner, &content).await
+    }
+}
@@ -0,0 +1,862 @@
+//! Unified diffs
+//!
+//! [`DbFileSystem::apply_patch`] applies the multi-file unified diffs that
+//! agents and `git diff` produce: modifications, new files (`--- /dev/null`),
+//! deletions (`+++ /dev/null`) and git-style renames (`rename from` /
+//! `rename to`). Paths are taken relative to the filesystem root after
+//! dropping git's `a/` and `b/` prefixes.
+//!
+//! Like GNU `patch`, a hunk that no longer matches at its recorded line is
+//! looked for nearby, and then with up to two lines of its leading and
+//! trailing context ignored (the "fuzz"). Every hunk of every file is
+//! matched before anything is written, and [`DbFileSystem::check_patch`]
+//! reports which hunks would fail without writing.
+//!
+//! Applying a patch is not crash-atomic, and rollback is best-effort.
+//! Files are written one at a time, each with a precondition. If a write
+//! fails, the changes already made are reverted, except in files somebody
+//! else changed since; removed files come back with their inode and
+//! metadata. What couldn't be reverted is listed in
+//! `AgentFsError::PatchRolledBackPartially`. A crash midway leaves the
+//! patch partly applied.
+//!
+//! [`DbFileSystem::make_patch`] produces a unified diff between two files.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::{DbFileSystem, FileSystem, Precondition};
+use crate::path::normalize;
+use std::collections::HashSet;
+
+/// Lines of context around changes in generated patches
+const CONTEXT_LINES: usize = 3;
+
+/// Context lines a hunk may ignore at each end to apply
+const MAX_FUZZ: usize = 2;
+
+/// Edit distance up to which generated patches are minimal
+///
+/// The search keeps a row of state per step, so its memory grows with the
+/// square of the distance. Files further apart are diffed by deleting and
+/// reinserting everything between their common prefix and suffix.
+const MAX_EDIT_DISTANCE: isize = 1024;
+
+/// What a patch does to a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchChange {
+    /// The file is created
+    Create,
+    /// The file's content changes
+    Modify,
+    /// The file is removed
+    Delete,
+    /// The file is moved from another path, possibly with changes
+    Rename { from: String },
+}
+
+/// Outcome of one hunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HunkReport {
+    /// Line the hunk was recorded at in the original file
+    pub old_start: usize,
+    /// Line the hunk matched at, or `None` if it doesn't apply
+    pub applied_at: Option<usize>,
+    /// Context lines ignored at each end to make the hunk match
+    pub fuzz: usize,
+}
+
+/// Outcome of the changes to one file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePatchReport {
+    /// Path of the file after the patch
+    pub path: String,
+    /// What happens to the file
+    pub change: PatchChange,
+    /// Outcome of each hunk, in patch order
+    pub hunks: Vec<HunkReport>,
+    /// Why the file can't be patched at all, such as a missing file
+    pub error: Option<String>,
+}
+
+impl FilePatchReport {
+    /// Check whether every hunk of the file applies
+    pub fn is_clean(&self) -> bool {
+        self.error.is_none() && self.hunks.iter().all(|hunk| hunk.applied_at.is_some())
+    }
+}
+
+/// Outcome of a patch, returned by [`DbFileSystem::apply_patch`] and
+/// [`DbFileSystem::check_patch`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchReport {
+    /// One report per file in the patch, in patch order
+    pub files: Vec<FilePatchReport>,
+}
+
+impl PatchReport {
+    /// Check whether the whole patch applies
+    pub fn is_clean(&self) -> bool {
+        self.files.iter().all(FilePatchReport::is_clean)
+    }
+
+    /// Human-readable list of what doesn't apply
+    fn failures(&self) -> String {
+        let mut failures = Vec::new();
+        for file in &self.files {
+            if let Some(error) = &file.error {
+                failures.push(format!("{}: {}", file.path, error));
+            }
+            for (i, hunk) in file.hunks.iter().enumerate() {
+                if hunk.applied_at.is_none() {
+                    failures.push(format!("{}: hunk {} at line {} does not apply", file.path, i + 1, hunk.old_start));
+                }
+            }
+        }
+        failures.join("; ")
+    }
+}
+
+/// A hunk parsed from a patch; lines keep their line endings
+#[derive(Debug, Default)]
+struct Hunk {
+    old_start: usize,
+    old_count: usize,
+    old: Vec<String>,
+    new: Vec<String>,
+    /// Context lines before the first change
+    leading: usize,
+    /// Context lines after the last change
+    trailing: usize,
+}
+
+/// The changes to one file parsed from a patch
+#[derive(Debug, Default)]
+struct FileDiff {
+    old_path: Option<String>,
+    new_path: Option<String>,
+    rename: bool,
+    hunks: Vec<Hunk>,
+}
+
+/// A write planned by `prepare_patch`
+enum Step {
+    Mkdir(String),
+    Write { path: String, content: Vec<u8>, previous: Option<Vec<u8>>, precondition: Precondition },
+    Remove { path: String, etag: String },
+    Rename { from: String, to: String, etag: String },
+}
+
+/// How to revert a step that was carried out, if the entry is still as
+/// the step left it
+enum Undo {
+    /// Write back the previous content, if the file has ETag `etag`
+    Restore { path: String, content: Vec<u8>, etag: String },
+    /// Remove a created file, if it has ETag `etag`
+    Remove { path: String, etag: String },
+    /// Remove a created directory, if it is still inode `ino` and empty
+    Rmdir { path: String, ino: i64 },
+    /// Put a removed file back from trash entry `id`, if the path is free
+    Unremove { path: String, id: i64, ino: i64 },
+    /// Rename inode `ino` back from `from` to `to`, if `to` is free
+    Rename { from: String, to: String, ino: i64 },
+}
+
+impl DbFileSystem {
+    /// Apply a unified diff, returning what was done to each file
+    ///
+    /// Fails with `AgentFsError::InvalidEdit` without changing anything if
+    /// any hunk doesn't apply; see [`DbFileSystem::check_patch`] for the
+    /// details.
+    ///
+    /// Not crash-atomic; rollback is best-effort. If a file changed after
+    /// the hunks were matched, the changes already made are reverted where
+    /// possible, and `AgentFsError::PatchRolledBackPartially` lists the
+    /// ones that weren't.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let report = agent_fs.fs.apply_patch(&diff).await?;
+    /// for file in &report.files {
+    ///     println!("{:?} {}", file.change, file.path);
+    /// }
+    /// ```
+    pub async fn apply_patch(&self, patch: &str) -> Result<PatchReport> {
+        let (report, steps) = self.prepare_patch(patch).await?;
+        if !report.is_clean() {
+            return Err(AgentFsError::InvalidEdit(report.failures()));
+        }
+
+        let mut undo = Vec::new();
+        for step in steps {
+            if let Err(cause) = self.apply_step(step, &mut undo).await {
+                let mut failures = Vec::new();
+                for action in undo.into_iter().rev() {
+                    if let Err(failure) = self.undo_step(&action).await {
+                        failures.push(failure.to_string());
+                    }
+                }
+                if failures.is_empty() {
+                    return Err(cause);
+                }
+                return Err(AgentFsError::PatchRolledBackPartially { cause: Box::new(cause), failures });
+            }
+        }
+
+        // Removed files were kept aside in case of a rollback
+        for action in undo {
+            if let Undo::Unremove { path, id, ino } = action && !self.trash_enabled() {
+                self.purge_trash_entry(id, ino, &path).await?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Check which hunks of a unified diff apply, without writing anything
+    pub async fn check_patch(&self, patch: &str) -> Result<PatchReport> {
+        Ok(self.prepare_patch(patch).await?.0)
+    }
+
+    /// Unified diff turning the file at `path_a` into the one at `path_b`
+    ///
+    /// A missing file is diffed as empty, giving a patch that creates or
+    /// deletes the other one. Identical files give an empty patch.
+    pub async fn make_patch(&self, path_a: &str, path_b: &str) -> Result<String> {
+        let path_a = self.validate_and_normalize_path(path_a)?;
+        let path_b = self.validate_and_normalize_path(path_b)?;
+        let a = self.read_file(&path_a).await?;
+        let b = self.read_file(&path_b).await?;
+        if a.is_none() && b.is_none() {
+            return Err(AgentFsError::FileNotFound(path_a));
+        }
+
+        let text_a = as_text(&path_a, a.as_deref().unwrap_or_default())?;
+        let text_b = as_text(&path_b, b.as_deref().unwrap_or_default())?;
+        let lines_a: Vec<&str> = text_a.split_inclusive('\n').collect();
+        let lines_b: Vec<&str> = text_b.split_inclusive('\n').collect();
+        let hunks = unified_hunks(&lines_a, &lines_b);
+        if hunks.is_empty() {
+            return Ok(String::new());
+        }
+
+        let label = |prefix: &str, path: &str, exists: bool| {
+            if exists { format!("{}{}", prefix, path) } else { "/dev/null".to_string() }
+        };
+        Ok(format!(
+            "--- {}\n+++ {}\n{}",
+            label("a", &path_a, a.is_some()),
+            label("b", &path_b, b.is_some()),
+            hunks
+        ))
+    }
+
+    /// Match every hunk of a patch against the current files, returning the
+    /// report and the writes that apply it
+    async fn prepare_patch(&self, patch: &str) -> Result<(PatchReport, Vec<Step>)> {
+        let diffs = parse_patch(patch)?;
+        if diffs.is_empty() {
+            return Err(AgentFsError::InvalidEdit("the patch changes no files".to_string()));
+        }
+
+        let mut report = PatchReport::default();
+        let mut steps = Vec::new();
+        let mut touched = HashSet::new();
+        let mut created_dirs = HashSet::new();
+        for diff in diffs {
+            let (path, change) = match (&diff.old_path, &diff.new_path) {
+                (None, Some(new)) => (new.clone(), PatchChange::Create),
+                (Some(old), None) => (old.clone(), PatchChange::Delete),
+                (Some(old), Some(new)) if diff.rename && old != new => {
+                    (new.clone(), PatchChange::Rename { from: old.clone() })
+                }
+                // Like GNU patch, differing names without rename headers
+                // patch the old file
+                (Some(old), Some(_)) => (old.clone(), PatchChange::Modify),
+                (None, None) => {
+                    return Err(AgentFsError::InvalidEdit("a file in the patch has no path".to_string()));
+                }
+            };
+            let source = match &change {
+                PatchChange::Rename { from } => from.clone(),
+                _ => path.clone(),
+            };
+
+            let mut file = FilePatchReport { path: path.clone(), change: change.clone(), hunks: Vec::new(), error: None };
+            let mut file_steps = Vec::new();
+            let prepared = async {
+                if touched.contains(&source) || touched.contains(&path) {
+                    return Err("touched by more than one file in the patch".to_string());
+                }
+                touched.insert(source.clone());
+                touched.insert(path.clone());
+                let existing = self.stat(&source).await.map_err(|e| e.to_string())?;
+                let (original, etag) = match (&change, existing) {
+                    (PatchChange::Create, Some(_)) => return Err("already exists".to_string()),
+                    (PatchChange::Create, None) => (Vec::new(), None),
+                    (_, None) => return Err(format!("{} does not exist", source)),
+                    (_, Some(stats)) if stats.is_directory() => return Err(format!("{} is a directory", source)),
+                    (_, Some(stats)) => {
+                        let content = self.read_file(&source).await.map_err(|e| e.to_string())?.unwrap_or_default();
+                        (content, Some(stats.etag()))
+                    }
+                };
+                if matches!(change, PatchChange::Rename { .. })
+                    && self.lstat(&path).await.map_err(|e| e.to_string())?.is_some()
+                {
+                    return Err(format!("{} already exists", path));
+                }
+
+                let text = std::str::from_utf8(&original).map_err(|_| format!("{} is not UTF-8 text", source))?;
+                let (patched, hunks) = apply_hunks(text, &diff.hunks);
+                file.hunks = hunks;
+                let Some(patched) = patched else {
+                    return Ok(());
+                };
+
+                if matches!(change, PatchChange::Create | PatchChange::Rename { .. }) {
+                    for dir in parent_dirs(&path) {
+                        if created_dirs.contains(&dir) {
+                            continue;
+                        }
+                        match self.stat(&dir).await.map_err(|e| e.to_string())? {
+                            Some(stats) if stats.is_directory() => {}
+                            Some(_) => return Err(format!("{} is not a directory", dir)),
+                            None => {
+                                created_dirs.insert(dir.clone());
+                                file_steps.push(Step::Mkdir(dir));
+                            }
+                        }
+                    }
+                }
+
+                let etag = etag.unwrap_or_default();
+                match &change {
+                    PatchChange::Create => file_steps.push(Step::Write {
+                        path: path.clone(),
+                        content: patched.into_bytes(),
+                        previous: None,
+                        precondition: Precondition::IfNoneMatch,
+                    }),
+                    PatchChange::Delete => {
+                        if !patched.is_empty() {
+                            return Err("the patch doesn't remove all of the file's content".to_string());
+                        }
+                        file_steps.push(Step::Remove { path: path.clone(), etag });
+                    }
+                    PatchChange::Modify => file_steps.push(Step::Write {
+                        path: path.clone(),
+                        content: patched.into_bytes(),
+                        previous: Some(original),
+                        precondition: Precondition::IfMatch(etag),
+                    }),
+                    PatchChange::Rename { from } => {
+                        file_steps.push(Step::Rename { from: from.clone(), to: path.clone(), etag: etag.clone() });
+                        if patched.as_bytes() != original.as_slice() {
+                            file_steps.push(Step::Write {
+                                path: path.clone(),
+                                content: patched.into_bytes(),
+                                previous: Some(original),
+                                // Renames keep the inode and version, so the ETag too
+                                precondition: Precondition::IfMatch(etag),
+                            });
+                        }
+                    }
+                }
+                Ok(())
+            }
+            .await;
+
+            match prepared {
+                Ok(()) => steps.extend(file_steps),
+                Err(error) => file.error = Some(error),
+            }
+            report.files.push(file);
+        }
+        Ok((report, steps))
+    }
+
+    /// Carry out one planned write, recording how to revert it
+    async fn apply_step(&self, step: Step, undo: &mut Vec<Undo>) -> Result<()> {
+        match step {
+            Step::Mkdir(path) => {
+                self.mkdir(&path).await?;
+                let ino = self.lstat(&path).await?.map_or(0, |stats| stats.ino);
+                undo.push(Undo::Rmdir { path, ino });
+            }
+            Step::Write { path, content, previous, precondition } => {
+                let etag = match &precondition {
+                    // Our write claims the next version
+                    Precondition::IfMatch(etag) => {
+                        crate::filesystem::parse_etag(etag).map(|(ino, version)| format!("{}-{}", ino, version + 1))
+                    }
+                    _ => None,
+                };
+                self.write_file_if(&path, &content, precondition).await?;
+                undo.push(match previous {
+                    Some(content) => Undo::Restore { path, content, etag: etag.unwrap_or_default() },
+                    None => {
+                        // A new file starts at version 1
+                        let ino = self.lstat(&path).await?.map_or(0, |stats| stats.ino);
+                        Undo::Remove { path, etag: format!("{}-1", ino) }
+                    }
+                });
+            }
+            Step::Remove { path, etag } => {
+                self.check_etag(&path, &etag).await?;
+                let ino = crate::filesystem::parse_etag(&etag).map_or(0, |(ino, _)| ino);
+                // Keep the inode in the trash until the patch has applied
+                self.clone().with_trash(true).remove(&path).await?;
+                let id = self.trash_entry_of(ino).await?.unwrap_or_default();
+                undo.push(Undo::Unremove { path, id, ino });
+            }
+            Step::Rename { from, to, etag } => {
+                self.check_etag(&from, &etag).await?;
+                if self.lstat(&to).await?.is_some() {
+                    return Err(AgentFsError::PathExists(to));
+                }
+                self.rename(&from, &to).await?;
+                let ino = crate::filesystem::parse_etag(&etag).map_or(0, |(ino, _)| ino);
+                undo.push(Undo::Rename { from: to, to: from, ino });
+            }
+        }
+        Ok(())
+    }
+
+    /// Revert one step, unless the entry changed since
+    async fn undo_step(&self, action: &Undo) -> Result<()> {
+        // Reverting must not leave anything behind in the trash
+        let fs = self.clone().with_trash(false);
+        match action {
+            Undo::Restore { path, content, etag } => {
+                fs.write_file_if(path, content, Precondition::IfMatch(etag.clone())).await.map_err(|e| match e {
+                    AgentFsError::PreconditionFailed(_) => AgentFsError::PreconditionFailed(format!(
+                        "{} was changed by someone else, keeping their content",
+                        path
+                    )),
+                    e => e,
+                })
+            }
+            Undo::Remove { path, etag } => {
+                fs.check_etag(path, etag).await?;
+                fs.remove(path).await
+            }
+            Undo::Rmdir { path, ino } => {
+                match fs.lstat(path).await? {
+                    Some(stats) if stats.ino == *ino && stats.is_directory() => fs.remove(path).await,
+                    _ => Err(AgentFsError::PreconditionFailed(format!("{} was replaced by someone else", path))),
+                }
+            }
+            Undo::Unremove { path, id, ino } => fs.restore_entry(path, *id, *ino).await,
+            Undo::Rename { from, to, ino } => {
+                match fs.lstat(from).await? {
+                    Some(stats) if stats.ino == *ino => {}
+                    _ => return Err(AgentFsError::PreconditionFailed(format!("{} was replaced by someone else", from))),
+                }
+                if fs.lstat(to).await?.is_some() {
+                    return Err(AgentFsError::PathExists(to.clone()));
+                }
+                fs.rename(from, to).await
+            }
+        }
+    }
+
+    /// Fail unless the file at `path` still has the given ETag
+    async fn check_etag(&self, path: &str, etag: &str) -> Result<()> {
+        match self.stat(path).await? {
+            Some(stats) if stats.etag() == etag => Ok(()),
+            _ => Err(AgentFsError::PreconditionFailed(format!("{} changed while the patch was applied", path))),
+        }
+    }
+}
+
+fn as_text<'a>(path: &str, content: &'a [u8]) -> Result<&'a str> {
+    std::str::from_utf8(content).map_err(|_| AgentFsError::InvalidEdit(format!("{} is not UTF-8 text", path)))
+}
+
+/// Directories above a path, outermost first
+fn parent_dirs(path: &str) -> Vec<String> {
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    (1..components.len())
+        .map(|n| format!("/{}", components[..n].join("/")))
+        .collect()
+}
+
+/// Path named in a `---` / `+++` header, or `None` for `/dev/null`
+fn header_path(header: &str, prefix: &str) -> Option<String> {
+    let path = header.split('\t').next().unwrap_or(header).trim_end();
+    if path == "/dev/null" {
+        return None;
+    }
+    let path = path.strip_prefix(prefix).unwrap_or(path);
+    Some(normalize(path))
+}
+
+/// Parse `@@ -old_start,old_count +new_start,new_count @@`
+fn parse_hunk_header(line: &str) -> Result<(usize, usize, usize)> {
+    let malformed = || AgentFsError::InvalidEdit(format!("malformed hunk header {:?}", line));
+    let mut ranges = line
+        .strip_prefix("@@ ")
+        .and_then(|rest| rest.split(" @@").next())
+        .ok_or_else(malformed)?
+        .split(' ');
+    let mut range = |sign: char| -> Result<(usize, usize)> {
+        let range = ranges.next().and_then(|r| r.strip_prefix(sign)).ok_or_else(malformed)?;
+        let (start, count) = range.split_once(',').unwrap_or((range, "1"));
+        Ok((start.parse().map_err(|_| malformed())?, count.parse().map_err(|_| malformed())?))
+    };
+    let (old_start, old_count) = range('-')?;
+    let (_, new_count) = range('+')?;
+    Ok((old_start, old_count, new_count))
+}
+
+/// Split a patch into its files and hunks
+fn parse_patch(patch: &str) -> Result<Vec<FileDiff>> {
+    let mut lines: Vec<&str> = patch.split('\n').collect();
+    if lines.last() == Some(&"") {
+        lines.pop();
+    }
+
+    let mut diffs: Vec<FileDiff> = Vec::new();
+    let mut current: Option<FileDiff> = None;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+
+        if let Some(paths) = line.strip_prefix("diff --git ") {
+            diffs.extend(current.take());
+            // Paths come from the `---`/`+++` or rename headers when there
+            // are any; this covers diffs without them, like empty new files
+            let (old_path, new_path) = match paths.split_once(" b/") {
+                Some((old, new)) => (header_path(old, "a/"), header_path(new, "")),
+                None => (None, None),
+            };
+            current = Some(FileDiff { old_path, new_path, ..Default::default() });
+        } else if line.starts_with("new file mode") {
+            if let Some(diff) = current.as_mut() {
+                diff.old_path = None;
+            }
+        } else if line.starts_with("deleted file mode") {
+            if let Some(diff) = current.as_mut() {
+                diff.new_path = None;
+            }
+        } else if let Some(path) = line.strip_prefix("rename from ") {
+            let diff = current.get_or_insert_with(FileDiff::default);
+            diff.rename = true;
+            diff.old_path = Some(normalize(path));
+        } else if let Some(path) = line.strip_prefix("rename to ") {
+            let diff = current.get_or_insert_with(FileDiff::default);
+            diff.rename = true;
+            diff.new_path = Some(normalize(path));
+        } else if let Some(header) = line.strip_prefix("--- ")
+            && lines.get(i).is_some_and(|next| next.starts_with("+++ "))
+        {
+            // A `---` header after hunks starts the next file
+            if current.as_ref().is_some_and(|diff| !diff.hunks.is_empty()) {
+                diffs.extend(current.take());
+            }
+            let diff = current.get_or_insert_with(FileDiff::default);
+            let new_header = &lines[i][4..];
+            i += 1;
+            if !diff.rename {
+                diff.old_path = header_path(header, "a/");
+                diff.new_path = header_path(new_header, "b/");
+            }
+        } else if line.starts_with("@@ ") {
+            let diff = current
+                .as_mut()
+                .ok_or_else(|| AgentFsError::InvalidEdit("hunk before any file header".to_string()))?;
+            let (old_start, old_count, new_count) = parse_hunk_header(line)?;
+            let mut hunk = Hunk { old_start, old_count, ..Default::default() };
+            let (mut old_left, mut new_left) = (old_count, new_count);
+            let mut kinds = Vec::new();
+            while (old_left > 0 || new_left > 0 || lines.get(i).is_some_and(|l| l.starts_with('\\'))) && i < lines.len() {
+                let body = lines[i];
+                i += 1;
+                if body.starts_with('\\') {
+                    // "\ No newline at end of file" applies to the line before
+                    match kinds.last() {
+                        Some(' ') => {
+                            trim_newline(hunk.old.last_mut());
+                            trim_newline(hunk.new.last_mut());
+                        }
+                        Some('-') => trim_newline(hunk.old.last_mut()),
+                        Some('+') => trim_newline(hunk.new.last_mut()),
+                        _ => {}
+                    }
+                    continue;
+                }
+                // Some tools strip the space from empty context lines
+                let (kind, text) = match body.chars().next() {
+                    Some(kind @ (' ' | '-' | '+')) => (kind, &body[1..]),
+                    None => (' ', ""),
+                    Some(_) => {
+                        return Err(AgentFsError::InvalidEdit(format!("malformed hunk line {:?}", body)));
+                    }
+                };
+                let text = format!("{}\n", text);
+                match kind {
+                    ' ' if old_left > 0 && new_left > 0 => {
+                        hunk.old.push(text.clone());
+                        hunk.new.push(text);
+                        old_left -= 1;
+                        new_left -= 1;
+                    }
+                    '-' if old_left > 0 => {
+                        hunk.old.push(text);
+                        old_left -= 1;
+                    }
+                    '+' if new_left > 0 => {
+                        hunk.new.push(text);
+                        new_left -= 1;
+                    }
+                    _ => return Err(AgentFsError::InvalidEdit(format!("hunk {:?} has the wrong line count", line))),
+                }
+                kinds.push(kind);
+            }
+            if old_left > 0 || new_left > 0 {
+                return Err(AgentFsError::InvalidEdit(format!("hunk {:?} is truncated", line)));
+            }
+            hunk.leading = kinds.iter().take_while(|&&kind| kind == ' ').count();
+            hunk.trailing = if hunk.leading == kinds.len() {
+                0
+            } else {
+                kinds.iter().rev().take_while(|&&kind| kind == ' ').count()
+            };
+            diff.hunks.push(hunk);
+        } else if line.starts_with("GIT binary patch") || line.starts_with("Binary files ") {
+            return Err(AgentFsError::InvalidEdit("binary patches are not supported".to_string()));
+        }
+        // Other lines (index, mode, commit messages) carry nothing to apply
+    }
+    diffs.extend(current);
+    diffs.retain(|diff| diff.old_path.is_some() || diff.new_path.is_some());
+    Ok(diffs)
+}
+
+fn trim_newline(line: Option<&mut String>) {
+    if let Some(line) = line
+        && line.ends_with('\n')
+    {
+        line.pop();
+    }
+}
+
+/// Apply hunks to a text, returning the result if they all apply and the
+/// outcome of each
+fn apply_hunks(text: &str, hunks: &[Hunk]) -> (Option<String>, Vec<HunkReport>) {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut output: Vec<&str> = Vec::with_capacity(lines.len());
+    let mut reports = Vec::with_capacity(hunks.len());
+    let mut failed = false;
+    // Next unconsumed line, and how far hunks have drifted from their
+    // recorded position
+    let mut position = 0;
+    let mut drift: isize = 0;
+
+    for hunk in hunks {
+        // With no old lines the recorded start is the line to insert after
+        let recorded = if hunk.old_count == 0 { hunk.old_start } else { hunk.old_start.saturating_sub(1) };
+        let mut report = HunkReport { old_start: hunk.old_start, applied_at: None, fuzz: 0 };
+
+        for fuzz in 0..=MAX_FUZZ {
+            if fuzz > 0 && fuzz > hunk.leading.max(hunk.trailing) {
+                break;
+            }
+            let leading = fuzz.min(hunk.leading);
+            let trailing = fuzz.min(hunk.trailing);
+            let old = &hunk.old[leading..hunk.old.len() - trailing];
+            let expected = (recorded as isize + drift + leading as isize).max(position as isize) as usize;
+            let Some(at) = find_lines(&lines, old, expected, position) else {
+                continue;
+            };
+
+            output.extend_from_slice(&lines[position..at]);
+            output.extend(hunk.new[leading..hunk.new.len() - trailing].iter().map(String::as_str));
+            position = at + old.len();
+            drift = at as isize - leading as isize - recorded as isize;
+            report.applied_at = Some((at + 1).saturating_sub(leading).max(1));
+            report.fuzz = fuzz;
+            break;
+        }
+        failed |= report.applied_at.is_none();
+        reports.push(report);
+    }
+
+    if failed {
+        return (None, reports);
+    }
+    output.extend_from_slice(&lines[position..]);
+    (Some(output.concat()), reports)
+}
+
+/// Index of the occurrence of `needle` in `lines` nearest to `expected`,
+/// not before `from`
+fn find_lines(lines: &[&str], needle: &[String], expected: usize, from: usize) -> Option<usize> {
+    if lines.len() < needle.len() {
+        return None;
+    }
+    let last = lines.len() - needle.len();
+    if from > last {
+        return None;
+    }
+    let expected = expected.clamp(from, last);
+    let matches = |at: usize| lines[at..at + needle.len()].iter().zip(needle).all(|(a, b)| *a == b);
+    for distance in 0..=last - from {
+        if let Some(at) = expected.checked_add(distance).filter(|&at| at <= last)
+            && matches(at)
+        {
+            return Some(at);
+        }
+        if let Some(at) = expected.checked_sub(distance).filter(|&at| at >= from && distance > 0)
+            && matches(at)
+        {
+            return Some(at);
+        }
+    }
+    None
+}
+
+/// An edit turning one list of lines into another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Shortest edit script from `a` to `b` (Myers' algorithm)
+fn diff_ops(a: &[&str], b: &[&str]) -> Vec<Op> {
+    // Common prefix and suffix need no search
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut ops = vec![Op::Equal; prefix];
+    ops.extend(myers(a_mid, b_mid));
+    ops.extend(std::iter::repeat_n(Op::Equal, suffix));
+    ops
+}
+
+fn myers(a: &[&str], b: &[&str]) -> Vec<Op> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let replace_all = || {
+        let mut ops = vec![Op::Delete; a.len()];
+        ops.extend(std::iter::repeat_n(Op::Insert, b.len()));
+        ops
+    };
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // `trace[d]` holds diagonals -d..=d of `v` before step d
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    'search: for d in 0..=max {
+        if d > MAX_EDIT_DISTANCE {
+            return replace_all();
+        }
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let i = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) { v[i + 1] } else { v[i - 1] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..trace.len() as isize).rev() {
+        let v = &trace[d as usize];
+        let at = |k: isize| v[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = if d == 0 { 0 } else { at(prev_k) };
+        let prev_y = if d == 0 { 0 } else { prev_x - prev_k };
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            ops.push(if x == prev_x { Op::Insert } else { Op::Delete });
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+/// Unified diff hunks turning `a` into `b`
+fn unified_hunks(a: &[&str], b: &[&str]) -> String {
+    let ops = diff_ops(a, b);
+    // Lines of `a` and `b` before each op
+    let mut before = Vec::with_capacity(ops.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for op in &ops {
+        before.push((i, j));
+        match op {
+            Op::Equal => {
+                i += 1;
+                j += 1;
+            }
+            Op::Delete => i += 1,
+            Op::Insert => j += 1,
+        }
+    }
+    before.push((i, j));
+
+    let mut out = String::new();
+    let mut next = 0;
+    while let Some(first) = (next..ops.len()).find(|&k| ops[k] != Op::Equal) {
+        let start = first.saturating_sub(CONTEXT_LINES);
+        let mut last = first;
+        for (k, op) in ops.iter().enumerate().skip(first) {
+            if *op != Op::Equal {
+                last = k;
+            } else if k - last > 2 * CONTEXT_LINES {
+                break;
+            }
+        }
+        let end = (last + CONTEXT_LINES + 1).min(ops.len());
+
+        let (a_start, b_start) = before[start];
+        let (a_end, b_end) = before[end];
+        let range = |start: usize, count: usize| {
+            let start = if count == 0 { start } else { start + 1 };
+            if count == 1 { start.to_string() } else { format!("{},{}", start, count) }
+        };
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(a_start, a_end - a_start),
+            range(b_start, b_end - b_start)
+        ));
+        for k in start..end {
+            let (i, j) = before[k];
+            let (sign, line) = match ops[k] {
+                Op::Equal => (' ', a[i]),
+                Op::Delete => ('-', a[i]),
+                Op::Insert => ('+', b[j]),
+            };
+            out.push(sign);
+            out.push_str(line);
+            if !line.ends_with('\n') {
+                out.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+        next = end;
+    }
+    out
+}
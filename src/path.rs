@@ -0,0 +1,174 @@
+//! Path validation policy
+//!
+//! By default paths are accepted as given and `..` components that would
+//! climb above the mount root are clamped to it. A [`PathPolicy`] makes
+//! validation stricter: it can reject such traversal outright, limit name
+//! and path lengths, forbid control characters, and normalize names to
+//! Unicode NFC so that visually identical names map to the same entry.
+
+use crate::error::{AgentFsError, Result};
+use unicode_normalization::UnicodeNormalization;
+
+/// Rules applied to every path passed to the filesystem
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathPolicy {
+    /// Fail with `AgentFsError::PathTraversal` instead of clamping `..`
+    /// components that would leave the mount root
+    pub reject_traversal: bool,
+    /// Maximum length of a single name, in bytes
+    pub max_name_len: Option<usize>,
+    /// Maximum length of a whole path, in bytes
+    pub max_path_len: Option<usize>,
+    /// Reject names containing NUL or other control characters
+    pub forbid_control_chars: bool,
+    /// Normalize names to Unicode NFC
+    pub normalize_unicode: bool,
+}
+
+impl PathPolicy {
+    /// A policy enabling every check, with POSIX-like limits of 255 bytes
+    /// per name and 4096 bytes per path
+    pub fn strict() -> Self {
+        Self {
+            reject_traversal: true,
+            max_name_len: Some(255),
+            max_path_len: Some(4096),
+            forbid_control_chars: true,
+            normalize_unicode: true,
+        }
+    }
+
+    /// Check a mount-relative path against the policy
+    ///
+    /// Returns the path with its names normalized; `.` and `..` components
+    /// are kept for the caller to resolve.
+    pub(crate) fn apply(&self, path: &str) -> Result<String> {
+        if let Some(max) = self.max_path_len
+            && path.len() > max
+        {
+            return Err(AgentFsError::InvalidPath(format!("Path longer than {} bytes", max)));
+        }
+
+        let mut depth = 0usize;
+        let mut components = Vec::new();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            match component {
+                "." => {}
+                ".." => {
+                    if depth == 0 && self.reject_traversal {
+                        return Err(AgentFsError::PathTraversal(path.to_string()));
+                    }
+                    depth = depth.saturating_sub(1);
+                }
+                _ => depth += 1,
+            }
+            components.push(self.apply_name(component)?);
+        }
+
+        let prefix = if path.starts_with('/') { "/" } else { "" };
+        Ok(format!("{}{}", prefix, components.join("/")))
+    }
+
+    /// Check and normalize a single name
+    pub(crate) fn apply_name(&self, name: &str) -> Result<String> {
+        if self.forbid_control_chars && name.chars().any(char::is_control) {
+            return Err(AgentFsError::InvalidPath(format!(
+                "Name contains control characters: {:?}",
+                name
+            )));
+        }
+
+        let name = if self.normalize_unicode { name.nfc().collect() } else { name.to_string() };
+
+        if let Some(max) = self.max_name_len
+            && name.len() > max
+        {
+            return Err(AgentFsError::InvalidPath(format!("Name longer than {} bytes", max)));
+        }
+        Ok(name)
+    }
+
+    /// Normalize a symlink target so that it resolves to normalized names
+    pub(crate) fn normalize_target(&self, target: &str) -> String {
+        if self.normalize_unicode { target.nfc().collect() } else { target.to_string() }
+    }
+}
+
+/// Normalize a path: make it absolute and resolve `.` and `..`, which stop
+/// at the root
+pub(crate) fn normalize(path: &str) -> String {
+    let normalized = path.trim_end_matches('/');
+    let normalized = if normalized.is_empty() {
+        "/"
+    } else if normalized.starts_with('/') {
+        normalized
+    } else {
+        return format!("/{}", normalized);
+    };
+
+    // Handle . and .. components
+    let components: Vec<&str> = normalized.split('/').filter(|s| !s.is_empty()).collect();
+    let mut result = Vec::new();
+
+    for component in components {
+        match component {
+            "." => continue,
+            ".." => {
+                if !result.is_empty() {
+                    result.pop();
+                }
+            }
+            _ => result.push(component),
+        }
+    }
+
+    if result.is_empty() {
+        "/".to_string()
+    } else {
+        format!("/{}", result.join("/"))
+    }
+}
+
+/// Turn a caller-supplied path into an internal path inside the mount
+///
+/// Shared by every `FileSystem` implementation so they sandbox paths
+/// identically; see `DbFileSystem::validate_and_normalize_path`.
+pub(crate) fn validate_and_normalize(mount_path: &str, policy: &PathPolicy, path: &str) -> Result<String> {
+    let mount_prefix = mount_path.trim_end_matches('/');
+
+    // Check if path explicitly includes the mount point prefix
+    let path_to_normalize = if path.starts_with(&format!("{}/", mount_prefix)) {
+        // Path explicitly includes mount point, strip it
+        &path[mount_prefix.len()..]
+    } else if path == mount_prefix {
+        // Path is exactly the mount point
+        "/"
+    } else {
+        // Treat path as relative to mount point (even if it starts with /)
+        path
+    };
+
+    let checked = policy.apply(path_to_normalize)?;
+
+    // Normalize the path (this handles .. and . components). The result is
+    // the internal path, which can't escape the root
+    Ok(normalize(&checked))
+}
+
+/// Split a path into its normalized components
+pub(crate) fn split(path: &str) -> Vec<String> {
+    let normalized = normalize(path);
+    if normalized == "/" {
+        return vec![];
+    }
+    normalized
+        .split('/')
+        .filter(|p| !p.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Join a canonical directory path and an entry name
+pub(crate) fn child_path(parent: &str, name: &str) -> String {
+    if parent == "/" { format!("/{}", name) } else { format!("{}/{}", parent, name) }
+}
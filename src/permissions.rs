@@ -0,0 +1,219 @@
+//! POSIX permission checks
+//!
+//! Permissions are only enforced for a `DbFileSystem` obtained through
+//! [`DbFileSystem::as_user`]. Such a handle checks the read, write and
+//! search bits of every inode it touches against the caller's identity,
+//! so sub-agents can be given a restricted view of a shared tree. Handles
+//! without credentials (the default) behave as before and skip all checks.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::{DbFileSystem, S_IFMT};
+use crate::watch::FsEventKind;
+use std::sync::Arc;
+
+/// Read permission bit
+pub(crate) const R_OK: u32 = 4;
+/// Write permission bit
+pub(crate) const W_OK: u32 = 2;
+/// Execute (search, for directories) permission bit
+pub(crate) const X_OK: u32 = 1;
+
+/// Identity that filesystem operations run as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// User ID; 0 bypasses all permission checks
+    pub uid: u32,
+    /// Group IDs, the first one being the primary group new files get
+    pub gids: Vec<u32>,
+}
+
+impl Credentials {
+    /// Credentials for a user with a single group
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self { uid, gids: vec![gid] }
+    }
+
+    /// Add supplementary groups
+    pub fn with_groups(mut self, gids: impl IntoIterator<Item = u32>) -> Self {
+        self.gids.extend(gids);
+        self
+    }
+
+    /// Group assigned to files this identity creates
+    pub fn primary_gid(&self) -> u32 {
+        self.gids.first().copied().unwrap_or(0)
+    }
+
+    fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+}
+
+impl DbFileSystem {
+    /// A handle on the same filesystem that runs operations as `credentials`
+    /// and enforces permissions
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// agent_fs.fs.mkdir("/shared").await?;
+    /// agent_fs.fs.chmod("/shared", 0o777).await?;
+    ///
+    /// let sub_agent = agent_fs.fs.as_user(Credentials::new(1000, 1000));
+    /// sub_agent.write_file("/shared/notes.txt", b"...").await?;
+    /// sub_agent.read_file("/secrets.txt").await; // Err(PermissionDenied)
+    /// ```
+    pub fn as_user(&self, credentials: Credentials) -> Self {
+        let mut fs = self.clone();
+        fs.credentials = Some(Arc::new(credentials));
+        fs
+    }
+
+    /// The identity permissions are checked against, if enforced
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_deref()
+    }
+
+    /// Change the permission bits of a file or directory
+    ///
+    /// Only the owner (or root) may do this. Symlinks are followed.
+    pub async fn chmod(&self, path: &str, mode: u32) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
+        let (ino, _) = self
+            .resolve(&path, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(path.clone()))?;
+        let (current, uid, _) = self.inode_owner(ino).await?;
+
+        if let Some(creds) = self.credentials()
+            && !creds.is_root()
+            && creds.uid != uid
+        {
+            return Err(AgentFsError::PermissionDenied(path));
+        }
+
+        let (now, nsec) = Self::now_timespec();
+        let query = format!(
+            "UPDATE fs_inode SET mode = {}, ctime = {now}, ctime_nsec = {nsec} WHERE ino = {ino}",
+            (current & S_IFMT) | (mode & 0o7777)
+        );
+        self.db.query(&query, vec![]).await?;
+        self.record_inode_change(FsEventKind::AttributesChanged, &path, Some(ino)).await?;
+        Ok(())
+    }
+
+    /// Change the owner and group of a file or directory
+    ///
+    /// Only root may change the owner; the owner may change the group to one
+    /// of their own groups. Symlinks are followed.
+    pub async fn chown(&self, path: &str, uid: u32, gid: u32) -> Result<()> {
+        let path = self.validate_and_normalize_path(path)?;
+        let (ino, _) = self
+            .resolve(&path, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(path.clone()))?;
+        let (_, owner, _) = self.inode_owner(ino).await?;
+
+        if let Some(creds) = self.credentials()
+            && !creds.is_root()
+            && (creds.uid != owner || uid != owner || !creds.gids.contains(&gid))
+        {
+            return Err(AgentFsError::PermissionDenied(path));
+        }
+
+        let (now, nsec) = Self::now_timespec();
+        let query = format!(
+            "UPDATE fs_inode SET uid = {uid}, gid = {gid}, ctime = {now}, ctime_nsec = {nsec} WHERE ino = {ino}"
+        );
+        self.db.query(&query, vec![]).await?;
+        self.record_inode_change(FsEventKind::AttributesChanged, &path, Some(ino)).await?;
+        Ok(())
+    }
+
+    /// Check that the caller may access an inode, if permissions are enforced
+    ///
+    /// `access` is a combination of `R_OK`, `W_OK` and `X_OK`.
+    pub(crate) async fn check_access(&self, ino: i64, access: u32, path: &str) -> Result<()> {
+        let Some(creds) = self.credentials() else {
+            return Ok(());
+        };
+        if creds.is_root() {
+            return Ok(());
+        }
+
+        let (mode, uid, gid) = self.inode_owner(ino).await?;
+        let granted = if uid == creds.uid {
+            (mode >> 6) & 7
+        } else if creds.gids.contains(&gid) {
+            (mode >> 3) & 7
+        } else {
+            mode & 7
+        };
+
+        if granted & access != access {
+            return Err(AgentFsError::PermissionDenied(path.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Whether the caller may reach `path` and access its inode `ino`, if
+    /// permissions are enforced
+    ///
+    /// Like `check_access`, but also checks the search bit of every
+    /// directory on the way, and answers `false` instead of failing.
+    pub(crate) async fn may_access(&self, ino: i64, path: &str, access: u32) -> Result<bool> {
+        if !self.enforces_permissions() {
+            return Ok(true);
+        }
+        match self.resolve(path, false).await {
+            Ok(Some((resolved, _))) if resolved == ino => {}
+            Ok(_) | Err(AgentFsError::PermissionDenied(_)) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        match self.check_access(ino, access, path).await {
+            Ok(()) => Ok(true),
+            Err(AgentFsError::PermissionDenied(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the caller may list the directory `path` is in, if
+    /// permissions are enforced
+    ///
+    /// `path` need not exist, but its directory must.
+    pub(crate) async fn may_list_parent(&self, path: &str) -> Result<bool> {
+        if !self.enforces_permissions() {
+            return Ok(true);
+        }
+        match self.resolve_parent(path).await {
+            Ok((parent_ino, _, parent_path)) => self.may_access(parent_ino, &parent_path, R_OK | X_OK).await,
+            Err(AgentFsError::Database(e)) => Err(e.into()),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Whether permissions are checked for this handle's caller
+    pub(crate) fn enforces_permissions(&self) -> bool {
+        self.credentials().is_some_and(|creds| !creds.is_root())
+    }
+
+    /// Owner of files created through this handle
+    pub(crate) fn creator(&self) -> (u32, u32) {
+        self.credentials().map_or((0, 0), |creds| (creds.uid, creds.primary_gid()))
+    }
+
+    /// Get the mode, uid and gid of an inode
+    async fn inode_owner(&self, ino: i64) -> Result<(u32, u32, u32)> {
+        let query = format!("SELECT mode, uid, gid FROM fs_inode WHERE ino = {}", ino);
+        let result = self.db.query(&query, vec![]).await?;
+        let row = result
+            .rows
+            .first()
+            .ok_or_else(|| AgentFsError::FileNotFound(format!("inode {}", ino)))?;
+        Ok((
+            self.extract_u32(row, "mode")?,
+            self.extract_u32(row, "uid")?,
+            self.extract_u32(row, "gid")?,
+        ))
+    }
+}
@@ -0,0 +1,168 @@
+//! Provenance of file changes
+//!
+//! Every change that reaches the change log is also recorded with the
+//! agent that made it and, when the filesystem handle was obtained with
+//! [`DbFileSystem::for_tool_call`], the tool call it was made in. Records
+//! are keyed by inode as well as path, so a file's history survives
+//! renames, and joining with `tool_calls` answers "which tool call created
+//! this file, and with what parameters?".
+//!
+//! Handles with credentials only get records of entries in directories
+//! they may list, as with [`DbFileSystem::list_trash`].
+//!
+//! Records are kept until garbage collection trims them, which it only does
+//! with [`GcOptions::provenance_retention`](crate::GcOptions) set.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::DbFileSystem;
+use crate::watch::FsEventKind;
+use std::collections::HashMap;
+
+/// A recorded change, returned by [`DbFileSystem::provenance`] and
+/// [`DbFileSystem::files_touched_by`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Provenance {
+    /// Kind of change: `created`, `modified`, `removed`, `renamed`,
+    /// `attributes` or `purged`
+    pub operation: String,
+    /// Path of the entry after the change
+    pub path: String,
+    /// Path before a rename
+    pub previous_path: Option<String>,
+    /// Agent that made the change
+    pub agent_id: Option<String>,
+    /// Tool call the change was made in
+    pub tool_call_id: Option<i64>,
+    /// Name of that tool
+    pub tool_name: Option<String>,
+    /// Parameters the tool was called with
+    pub tool_parameters: Option<serde_json::Value>,
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+}
+
+impl DbFileSystem {
+    /// A handle on the same filesystem that attributes its changes to the
+    /// given tool call
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let call = agent_fs.tools.start("write_report", Some(json!({"topic": "sales"}))).await?;
+    /// agent_fs.fs.for_tool_call(call).write_file("/output/report.md", report).await?;
+    /// agent_fs.tools.success(call, None).await?;
+    ///
+    /// let history = agent_fs.fs.provenance("/output/report.md").await?;
+    /// assert_eq!(history[0].tool_name.as_deref(), Some("write_report"));
+    /// ```
+    pub fn for_tool_call(&self, tool_call_id: i64) -> Self {
+        let mut fs = self.clone();
+        fs.tool_call_id = Some(tool_call_id);
+        fs
+    }
+
+    /// Attribute changes to the given agent
+    pub fn with_agent_id(mut self, agent_id: impl Into<String>) -> Self {
+        self.agent_id = Some(agent_id.into());
+        self
+    }
+
+    /// History of the entry at `path`, oldest first
+    ///
+    /// For an existing entry this follows the inode, across renames; for
+    /// a removed one it lists what happened at the path.
+    pub async fn provenance(&self, path: &str) -> Result<Vec<Provenance>> {
+        self.ensure_schema().await?;
+        let path = self.validate_and_normalize_path(path)?;
+        let (filter, exists) = match self.resolve_path(&path).await? {
+            Some(ino) => (format!("p.ino = {}", ino), true),
+            None => (format!("p.path = '{}'", path.replace('\'', "''")), false),
+        };
+        let records = self.query_provenance(&filter).await?;
+        if records.is_empty() && !exists {
+            return Err(AgentFsError::FileNotFound(path));
+        }
+        Ok(records)
+    }
+
+    /// Every change made in a tool call, oldest first
+    pub async fn files_touched_by(&self, tool_call_id: i64) -> Result<Vec<Provenance>> {
+        self.ensure_schema().await?;
+        self.query_provenance(&format!("p.tool_call_id = {}", tool_call_id)).await
+    }
+
+    /// Record who made a change to inode `ino` that was just written to
+    /// the change log
+    pub(crate) async fn record_provenance(&self, kind: &FsEventKind, path: &str, ino: Option<i64>) -> Result<()> {
+        let (path, previous_path) = match kind {
+            FsEventKind::Renamed { to } => (to.as_str(), format!("'{}'", path.replace('\'', "''"))),
+            _ => (path, "NULL".to_string()),
+        };
+        let query = format!(
+            "INSERT INTO fs_provenance (root_ino, ino, path, previous_path, operation, agent_id, tool_call_id, recorded_at) \
+             VALUES ({}, {}, '{}', {}, '{}', {}, {}, {})",
+            self.root_ino,
+            ino.map_or("NULL".to_string(), |ino| ino.to_string()),
+            path.replace('\'', "''"),
+            previous_path,
+            kind.as_str(),
+            self.agent_id
+                .as_ref()
+                .map_or("NULL".to_string(), |id| format!("'{}'", id.replace('\'', "''"))),
+            self.tool_call_id.map_or("NULL".to_string(), |id| id.to_string()),
+            Self::now()
+        );
+        self.db.query(&query, vec![]).await?;
+        Ok(())
+    }
+
+    async fn query_provenance(&self, filter: &str) -> Result<Vec<Provenance>> {
+        let query = format!(
+            "SELECT p.operation, p.path, p.previous_path, p.agent_id, p.tool_call_id, p.recorded_at, \
+             t.name AS tool_name, t.parameters AS tool_parameters \
+             FROM fs_provenance p LEFT JOIN tool_calls t ON t.id = p.tool_call_id \
+             WHERE p.root_ino = {} AND {} ORDER BY p.id",
+            self.root_ino, filter
+        );
+        let result = self.db.query(&query, vec![]).await?;
+
+        let mut records = Vec::with_capacity(result.rows.len());
+        for row in &result.rows {
+            let text = |column: &str| self.extract_string_opt(row, column).filter(|s| !s.is_empty());
+            records.push(Provenance {
+                operation: text("operation").unwrap_or_default(),
+                path: text("path").unwrap_or_default(),
+                previous_path: text("previous_path"),
+                agent_id: text("agent_id"),
+                tool_call_id: text("tool_call_id").and_then(|id| id.parse().ok()),
+                tool_name: text("tool_name"),
+                tool_parameters: text("tool_parameters").and_then(|s| serde_json::from_str(&s).ok()),
+                timestamp: self.extract_i64(row, "recorded_at")?,
+            });
+        }
+        if self.enforces_permissions() {
+            records = self.visible_records(records).await?;
+        }
+        Ok(records)
+    }
+
+    /// The records whose paths, before and after the change, are in
+    /// directories the caller may list
+    async fn visible_records(&self, records: Vec<Provenance>) -> Result<Vec<Provenance>> {
+        let mut listable = HashMap::new();
+        let mut visible = Vec::with_capacity(records.len());
+        for record in records {
+            let mut keep = true;
+            for path in std::iter::once(&record.path).chain(&record.previous_path) {
+                if !listable.contains_key(path) {
+                    listable.insert(path.clone(), self.may_list_parent(path).await?);
+                }
+                keep &= listable[path];
+            }
+            if keep {
+                visible.push(record);
+            }
+        }
+        Ok(visible)
+    }
+}
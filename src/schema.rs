@@ -0,0 +1,232 @@
+//! Schema for AgentFS-owned tables
+//!
+//! The core tables (`fs_inode`, `fs_dentry`, `fs_data`, `fs_symlink`,
+//! `kv_store`, `tool_calls`) are created by the agentsql migrations.
+//! Tables that only AgentFS itself uses are created here, along with the
+//! columns AgentFS adds to the core tables. Every step is idempotent, so
+//! `migrate` can safely run each time a database is opened.
+//!
+//! The statements have to run on SQLite, PostgreSQL and MySQL. Where their
+//! DDL differs, the SQL dialect is detected first and the statement adapted
+//! to it.
+
+use crate::error::Result;
+use crate::filesystem::ROOT_INO;
+use crate::usage::subtree;
+use agentdb::AgentDB;
+
+/// Table creation statements, executed in order
+///
+/// `{serial}` stands for the dialect's auto-incrementing primary key.
+const TABLES: &[&str] = &[
+    // Advisory locks: one row per lock holder, expired leases are ignored
+    "CREATE TABLE IF NOT EXISTS fs_lock (
+        id VARCHAR(64) PRIMARY KEY,
+        path TEXT NOT NULL,
+        mode VARCHAR(16) NOT NULL,
+        expires_at BIGINT NOT NULL
+    )",
+    // Change log feeding watchers, appended to by every mutation
+    "CREATE TABLE IF NOT EXISTS fs_change (
+        id {serial},
+        kind VARCHAR(16) NOT NULL,
+        path TEXT NOT NULL,
+        new_path TEXT,
+        changed_at BIGINT NOT NULL
+    )",
+    // Named volumes, each an independent tree with its own root inode
+    "CREATE TABLE IF NOT EXISTS fs_volume (
+        name VARCHAR(255) PRIMARY KEY,
+        root_ino BIGINT NOT NULL UNIQUE,
+        created_at BIGINT NOT NULL
+    )",
+    // Removed entries kept until the trash is emptied
+    "CREATE TABLE IF NOT EXISTS fs_trash (
+        id {serial},
+        root_ino BIGINT NOT NULL,
+        ino BIGINT NOT NULL,
+        path TEXT NOT NULL,
+        deleted_at BIGINT NOT NULL
+    )",
+    // Embeddings of text file chunks for semantic search
+    "CREATE TABLE IF NOT EXISTS fs_embedding (
+        ino BIGINT NOT NULL,
+        chunk INTEGER NOT NULL,
+        line BIGINT NOT NULL,
+        text TEXT NOT NULL,
+        vector TEXT NOT NULL,
+        PRIMARY KEY (ino, chunk)
+    )",
+    // Counter bumped on every change to fs_embedding, so processes can
+    // tell that the embeddings they hold in memory are stale
+    "CREATE TABLE IF NOT EXISTS fs_embedding_generation (
+        id INTEGER PRIMARY KEY,
+        generation BIGINT NOT NULL
+    )",
+    // Usage totals of each volume, kept up to date by every change so
+    // quota checks don't have to walk the tree
+    "CREATE TABLE IF NOT EXISTS fs_usage (
+        root_ino BIGINT PRIMARY KEY,
+        used_bytes BIGINT NOT NULL,
+        apparent_bytes BIGINT NOT NULL,
+        inodes BIGINT NOT NULL
+    )",
+    // Who made each change: agent, tool call and operation
+    "CREATE TABLE IF NOT EXISTS fs_provenance (
+        id {serial},
+        root_ino BIGINT NOT NULL,
+        ino BIGINT,
+        path TEXT NOT NULL,
+        previous_path TEXT,
+        operation VARCHAR(16) NOT NULL,
+        agent_id VARCHAR(255),
+        tool_call_id BIGINT,
+        recorded_at BIGINT NOT NULL
+    )",
+];
+
+/// Indexes: (name, table, columns, MySQL columns)
+///
+/// MySQL can only index a prefix of TEXT columns, so its column list may
+/// give key lengths.
+const INDEXES: &[(&str, &str, &str, &str)] = &[
+    ("idx_fs_lock_path", "fs_lock", "path", "path(255)"),
+    ("idx_fs_provenance_ino", "fs_provenance", "ino", "ino"),
+    ("idx_fs_provenance_tool_call", "fs_provenance", "tool_call_id", "tool_call_id"),
+];
+
+/// Columns added to core tables: (table, column, definition)
+const COLUMNS: &[(&str, &str, &str)] = &[
+    // Bumped on every content change, backs ETags and compare-and-swap
+    ("fs_inode", "version", "BIGINT NOT NULL DEFAULT 1"),
+    // Content metadata detected on write, NULL for directories and symlinks
+    ("fs_inode", "content_type", "TEXT"),
+    ("fs_inode", "line_count", "BIGINT"),
+    ("fs_inode", "encoding", "VARCHAR(16)"),
+    // Sub-second part of atime/mtime/ctime
+    ("fs_inode", "atime_nsec", "BIGINT NOT NULL DEFAULT 0"),
+    ("fs_inode", "mtime_nsec", "BIGINT NOT NULL DEFAULT 0"),
+    ("fs_inode", "ctime_nsec", "BIGINT NOT NULL DEFAULT 0"),
+    // Inode whose data a copy shares, NULL once it has its own
+    ("fs_inode", "data_ino", "BIGINT"),
+    // Unix time in milliseconds after which the entry is gone, NULL for never
+    ("fs_inode", "expires_at", "BIGINT"),
+    // Hash of the blob store entry holding the data, NULL if stored inline
+    ("fs_inode", "blob_hash", "VARCHAR(64)"),
+    // KV entry holding the inline data, NULL for data written before
+    // each write got its own entry
+    ("fs_inode", "data_ref", "VARCHAR(255)"),
+    // Root inode of the volume an inode was created in
+    ("fs_inode", "root_ino", "BIGINT"),
+    // Root inode of the volume a change or lock belongs to
+    ("fs_change", "root_ino", "BIGINT NOT NULL DEFAULT 1"),
+    ("fs_lock", "root_ino", "BIGINT NOT NULL DEFAULT 1"),
+];
+
+/// SQL dialect of the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dialect {
+    Sqlite,
+    Postgres,
+    Mysql,
+}
+
+impl Dialect {
+    /// Tell the dialects apart by functions only one of them has
+    async fn detect(db: &dyn AgentDB) -> Dialect {
+        if db.query("SELECT sqlite_version()", vec![]).await.is_ok() {
+            Dialect::Sqlite
+        } else if db.query("SELECT current_setting('server_version')", vec![]).await.is_ok() {
+            Dialect::Postgres
+        } else {
+            Dialect::Mysql
+        }
+    }
+
+    /// Definition of an auto-incrementing 64-bit primary key
+    fn serial_key(self) -> &'static str {
+        match self {
+            Dialect::Sqlite => "INTEGER PRIMARY KEY AUTOINCREMENT",
+            Dialect::Postgres => "BIGSERIAL PRIMARY KEY",
+            Dialect::Mysql => "BIGINT PRIMARY KEY AUTO_INCREMENT",
+        }
+    }
+}
+
+/// Create an index unless it exists
+async fn create_index(db: &dyn AgentDB, dialect: Dialect, index: &(&str, &str, &str, &str)) -> Result<()> {
+    let (name, table, columns, mysql_columns) = *index;
+    let query = match dialect {
+        Dialect::Sqlite | Dialect::Postgres => {
+            format!("CREATE INDEX IF NOT EXISTS {} ON {}({})", name, table, columns)
+        }
+        Dialect::Mysql => {
+            // MySQL has no CREATE INDEX IF NOT EXISTS
+            let probe = format!(
+                "SELECT index_name FROM information_schema.statistics \
+                 WHERE table_schema = DATABASE() AND table_name = '{}' AND index_name = '{}'",
+                table, name
+            );
+            if !db.query(&probe, vec![]).await?.rows.is_empty() {
+                return Ok(());
+            }
+            format!("CREATE INDEX {} ON {}({})", name, table, mysql_columns)
+        }
+    };
+    db.query(&query, vec![]).await?;
+    Ok(())
+}
+
+/// Create all AgentFS-owned tables and columns if they don't exist yet
+pub(crate) async fn migrate(db: &dyn AgentDB) -> Result<()> {
+    let dialect = Dialect::detect(db).await;
+    for statement in TABLES {
+        let statement = statement.replace("{serial}", dialect.serial_key());
+        db.query(&statement, vec![]).await?;
+    }
+    for index in INDEXES {
+        create_index(db, dialect, index).await?;
+    }
+
+    for (table, column, definition) in COLUMNS {
+        // Probe for the column; there is no portable way to list columns
+        let probe = format!("SELECT {} FROM {} LIMIT 1", column, table);
+        if db.query(&probe, vec![]).await.is_err() {
+            let query = format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition);
+            db.query(&query, vec![]).await?;
+            if (*table, *column) == ("fs_inode", "root_ino") {
+                assign_inode_volumes(db).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Record the volume of inodes created before inodes recorded it
+///
+/// An inode belongs to the volume whose tree or trash holds it, inode 1
+/// counting as a volume root even if no volume owns it yet. Orphaned
+/// inodes are assigned to the volume their last recorded change was made
+/// in; those without one stay unassigned.
+async fn assign_inode_volumes(db: &dyn AgentDB) -> Result<()> {
+    let mut roots = vec![ROOT_INO];
+    for row in &db.query("SELECT root_ino FROM fs_volume", vec![]).await?.rows {
+        if let Some(root) = row.get("root_ino").and_then(|v| String::from_utf8_lossy(v.as_bytes()).parse().ok()) {
+            roots.push(root);
+        }
+    }
+    for root in roots {
+        let query = format!(
+            "UPDATE fs_inode SET root_ino = {0} WHERE root_ino IS NULL \
+             AND (ino IN {1} OR ino IN (SELECT ino FROM fs_trash WHERE root_ino = {0}))",
+            root,
+            subtree(root)
+        );
+        db.query(&query, vec![]).await?;
+    }
+
+    let query = "UPDATE fs_inode SET root_ino = (SELECT p.root_ino FROM fs_provenance p \
+                 WHERE p.ino = fs_inode.ino ORDER BY p.id DESC LIMIT 1) WHERE root_ino IS NULL";
+    db.query(query, vec![]).await?;
+    Ok(())
+}
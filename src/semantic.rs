@@ -0,0 +1,497 @@
+//! Semantic search over file contents
+//!
+//! With an [`Embedder`] configured (see [`DbFileSystem::with_embedder`]),
+//! every text file written is split into chunks of whole lines, and each
+//! chunk's embedding is stored in the `fs_embedding` table. Rewriting a
+//! file replaces its chunks, copies share them, and removing the file
+//! drops them.
+//!
+//! [`DbFileSystem::semantic_search`] ranks the chunks under a directory
+//! by cosine similarity to the query. The vectors are parsed once and kept
+//! in memory, shared by every handle cloned from the same filesystem; a
+//! generation counter in the database tells a process when another one
+//! changed the embeddings, so it reloads them. Searches over up to
+//! [`DEFAULT_ANN_THRESHOLD`] chunks (see [`DbFileSystem::with_ann_threshold`])
+//! compare the query with every chunk; larger ones use an HNSW graph,
+//! which is much faster but may miss some of the closest chunks.
+
+use crate::error::{AgentFsError, Result};
+use crate::expiry::unexpired;
+use crate::filesystem::{DbFileSystem, S_IFMT, S_IFREG};
+use crate::hnsw::Hnsw;
+use crate::permissions::R_OK;
+use crate::usage::subtree;
+use async_trait::async_trait;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+/// Approximate number of characters per indexed chunk
+const CHUNK_CHARS: usize = 1000;
+
+/// Default number of chunks above which searches use the HNSW index
+pub const DEFAULT_ANN_THRESHOLD: usize = 20_000;
+
+/// Candidates explored by an HNSW search, at least
+const ANN_EF: usize = 64;
+
+/// Turns text into embedding vectors
+///
+/// Implement this for an embedding API or a local model. [`HashEmbedder`]
+/// is a deterministic stand-in that needs neither.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Embed each of `texts`, returning one vector per text
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+}
+
+/// Deterministic embedder hashing words into a fixed number of dimensions
+///
+/// Texts sharing words get similar vectors, which is enough for tests and
+/// offline use, though it knows nothing about synonyms.
+#[derive(Debug, Clone)]
+pub struct HashEmbedder {
+    dimensions: usize,
+}
+
+impl HashEmbedder {
+    /// Create an embedder producing vectors of `dimensions` components
+    pub fn new(dimensions: usize) -> Self {
+        Self { dimensions: dimensions.max(1) }
+    }
+}
+
+impl Default for HashEmbedder {
+    fn default() -> Self {
+        Self::new(256)
+    }
+}
+
+#[async_trait]
+impl Embedder for HashEmbedder {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts
+            .iter()
+            .map(|text| {
+                let mut vector = vec![0f32; self.dimensions];
+                let words = text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty());
+                for word in words {
+                    // FNV-1a, stable across platforms and releases
+                    let hash = word
+                        .to_lowercase()
+                        .bytes()
+                        .fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+                    vector[(hash % self.dimensions as u64) as usize] += 1.0;
+                }
+                vector
+            })
+            .collect())
+    }
+}
+
+/// A chunk of text and its embedding, ready to be indexed
+pub(crate) struct EmbeddedChunk {
+    line: usize,
+    text: String,
+    vector: Vec<f32>,
+}
+
+/// A chunk found by [`DbFileSystem::semantic_search`]
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    /// Path of the file
+    pub path: String,
+    /// Line the chunk starts at, counting from 1
+    pub line: i64,
+    /// Text of the chunk
+    pub text: String,
+    /// Cosine similarity to the query, from -1 to 1
+    pub score: f32,
+}
+
+impl DbFileSystem {
+    /// Find the `k` chunks of text files under `root` most similar to `query`
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let fs = agent_fs.fs.clone().with_embedder(Arc::new(HashEmbedder::default()));
+    /// for hit in fs.semantic_search("deployment checklist", 5, "/notes").await? {
+    ///     println!("{}:{} ({:.2})", hit.path, hit.line, hit.score);
+    /// }
+    /// ```
+    pub async fn semantic_search(&self, query: &str, k: usize, root: &str) -> Result<Vec<SearchHit>> {
+        self.ensure_schema().await?;
+        let embedder = self.embedder().ok_or(AgentFsError::NoEmbedder)?;
+        let root = self.validate_and_normalize_path(root)?;
+        let (root_ino, _) = self
+            .resolve(&root, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(root.clone()))?;
+        let target = normalized(embedder.embed(&[query.to_string()]).await?.pop().unwrap_or_default());
+
+        // Which chunks the search covers
+        let query = format!(
+            "SELECT e.ino, e.chunk FROM fs_embedding e JOIN fs_inode i ON i.ino = e.ino WHERE e.ino IN {} AND {}",
+            subtree(root_ino),
+            unexpired("i")
+        );
+        let mut covered = HashSet::new();
+        for row in &self.db.query(&query, vec![]).await?.rows {
+            covered.insert((self.extract_i64(row, "ino")?, self.extract_i64(row, "chunk")?));
+        }
+
+        // Leave out files the caller can't read, before ranking
+        let mut paths = HashMap::new();
+        if self.enforces_permissions() {
+            let inos: HashSet<i64> = covered.iter().map(|(ino, _)| *ino).collect();
+            for ino in inos {
+                let path = match self.inode_path(ino).await? {
+                    Some(path) if self.may_access(ino, &path, R_OK).await? => Some(path),
+                    _ => None,
+                };
+                paths.insert(ino, path);
+            }
+            covered.retain(|(ino, _)| paths[ino].is_some());
+        }
+
+        let ranked = {
+            let mut guard = self.vector_index.lock().await;
+            let generation = self.embedding_generation().await?;
+            if guard.as_ref().is_none_or(|index| index.generation != generation) {
+                *guard = Some(self.load_vector_index(generation).await?);
+            }
+            let index = guard.as_mut().expect("vector index was just loaded");
+            if covered.len() > self.ann_threshold() {
+                index.search(&target, k, &covered)
+            } else {
+                index.scan(&target, k, &covered)
+            }
+        };
+
+        let mut hits = Vec::with_capacity(ranked.len());
+        for ((ino, chunk), score) in ranked {
+            if let Entry::Vacant(entry) = paths.entry(ino) {
+                entry.insert(self.inode_path(ino).await?);
+            }
+            let Some(path) = paths[&ino].clone() else {
+                continue;
+            };
+            let query = format!("SELECT line, text FROM fs_embedding WHERE ino = {} AND chunk = {}", ino, chunk);
+            let result = self.db.query(&query, vec![]).await?;
+            let Some(row) = result.rows.first() else {
+                continue;
+            };
+            hits.push(SearchHit {
+                path,
+                line: self.extract_i64(row, "line")?,
+                text: self.extract_string_opt(row, "text").unwrap_or_default(),
+                score,
+            });
+        }
+        Ok(hits)
+    }
+
+    /// Index the text files under `root` that aren't indexed yet, such as
+    /// files written before the embedder was configured
+    ///
+    /// Returns the number of files indexed.
+    pub async fn reindex(&self, root: &str) -> Result<usize> {
+        self.ensure_schema().await?;
+        let root = self.validate_and_normalize_path(root)?;
+        let (root_ino, _) = self
+            .resolve(&root, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(root.clone()))?;
+        let query = format!(
+            "SELECT ino FROM fs_inode WHERE ino IN {} AND (mode & {S_IFMT}) = {S_IFREG} AND encoding IS NOT NULL \
+             AND ino NOT IN (SELECT ino FROM fs_embedding) ORDER BY ino",
+            subtree(root_ino)
+        );
+        let result = self.db.query(&query, vec![]).await?;
+
+        let mut indexed = 0;
+        for row in &result.rows {
+            let ino = self.extract_i64(row, "ino")?;
+            let content = self.read_data(ino).await?;
+            if self.index_content(ino, &content, true).await? {
+                indexed += 1;
+            }
+        }
+        Ok(indexed)
+    }
+
+    /// Replace the indexed chunks of `ino` with those of `content`,
+    /// returning whether anything was indexed
+    ///
+    /// Only text is indexed, and only with an embedder configured; other
+    /// content just drops the old chunks.
+    pub(crate) async fn index_content(&self, ino: i64, content: &[u8], is_text: bool) -> Result<bool> {
+        let chunks = self.embed_content(content, is_text).await?;
+        self.store_index(ino, chunks).await
+    }
+
+    /// Split `content` into chunks and embed them, without touching the index
+    ///
+    /// Writes call this before replacing the content, so an embedder error
+    /// fails the write instead of leaving new content without its chunks.
+    pub(crate) async fn embed_content(&self, content: &[u8], is_text: bool) -> Result<Vec<EmbeddedChunk>> {
+        let (Some(embedder), true) = (self.embedder(), is_text) else {
+            return Ok(Vec::new());
+        };
+        let chunks = chunk_lines(&String::from_utf8_lossy(content));
+        if chunks.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let texts: Vec<String> = chunks.iter().map(|(_, text)| text.clone()).collect();
+        let vectors = embedder.embed(&texts).await?;
+        Ok(chunks
+            .into_iter()
+            .zip(vectors)
+            .map(|((line, text), vector)| EmbeddedChunk { line, text, vector })
+            .collect())
+    }
+
+    /// Replace the indexed chunks of `ino` with `chunks`, returning whether
+    /// anything was indexed
+    pub(crate) async fn store_index(&self, ino: i64, chunks: Vec<EmbeddedChunk>) -> Result<bool> {
+        self.drop_index(ino).await?;
+        if chunks.is_empty() {
+            return Ok(false);
+        }
+
+        for (chunk, embedded) in chunks.iter().enumerate() {
+            let query = format!(
+                "INSERT INTO fs_embedding (ino, chunk, line, text, vector) VALUES ({}, {}, {}, '{}', '{}')",
+                ino,
+                chunk,
+                embedded.line,
+                embedded.text.replace('\'', "''"),
+                format_vector(&embedded.vector)
+            );
+            self.db.query(&query, vec![]).await?;
+        }
+        self.embeddings_changed(|index| {
+            for (chunk, embedded) in chunks.into_iter().enumerate() {
+                index.add((ino, chunk as i64), normalized(embedded.vector));
+            }
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Give inode `to_ino` the indexed chunks of `from_ino`
+    pub(crate) async fn copy_index(&self, from_ino: i64, to_ino: i64) -> Result<()> {
+        self.drop_index(to_ino).await?;
+        let query = format!(
+            "INSERT INTO fs_embedding (ino, chunk, line, text, vector) \
+             SELECT {}, chunk, line, text, vector FROM fs_embedding WHERE ino = {}",
+            to_ino, from_ino
+        );
+        if self.db.query(&query, vec![]).await?.rows_affected > 0 {
+            self.embeddings_changed(|index| {
+                for (chunk, vector) in index.chunks_of(from_ino) {
+                    index.add((to_ino, chunk), vector);
+                }
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Forget the indexed chunks of an inode
+    pub(crate) async fn drop_index(&self, ino: i64) -> Result<()> {
+        self.ensure_schema().await?;
+        let query = format!("DELETE FROM fs_embedding WHERE ino = {}", ino);
+        if self.db.query(&query, vec![]).await?.rows_affected > 0 {
+            self.embeddings_changed(|index| index.remove(ino)).await?;
+        }
+        Ok(())
+    }
+
+    /// Bump the embedding generation after changing `fs_embedding`, and
+    /// apply the same change to the vectors in memory
+    ///
+    /// If the generation moved by more than our own bump, another process
+    /// changed the embeddings too, and the vectors are reloaded on the next
+    /// search instead.
+    async fn embeddings_changed(&self, update: impl FnOnce(&mut VectorIndex)) -> Result<()> {
+        let mut guard = self.vector_index.lock().await;
+        let bump = "UPDATE fs_embedding_generation SET generation = generation + 1 WHERE id = 1";
+        if self.db.query(bump, vec![]).await?.rows_affected == 0 {
+            let insert = "INSERT INTO fs_embedding_generation (id, generation) VALUES (1, 1)";
+            if self.db.query(insert, vec![]).await.is_err() {
+                // Another process created the row first
+                self.db.query(bump, vec![]).await?;
+            }
+        }
+        let generation = self.embedding_generation().await?;
+        match guard.as_mut() {
+            Some(index) if index.generation + 1 == generation => {
+                update(index);
+                index.generation = generation;
+            }
+            _ => *guard = None,
+        }
+        Ok(())
+    }
+
+    async fn embedding_generation(&self) -> Result<i64> {
+        let query = "SELECT generation FROM fs_embedding_generation WHERE id = 1";
+        match self.db.query(query, vec![]).await?.rows.first() {
+            Some(row) => self.extract_i64(row, "generation"),
+            None => Ok(0),
+        }
+    }
+
+    /// Read every embedding in the database
+    async fn load_vector_index(&self, generation: i64) -> Result<VectorIndex> {
+        let result = self.db.query("SELECT ino, chunk, vector FROM fs_embedding", vec![]).await?;
+        let mut index = VectorIndex { generation, ..VectorIndex::default() };
+        for row in &result.rows {
+            let key = (self.extract_i64(row, "ino")?, self.extract_i64(row, "chunk")?);
+            let vector = parse_vector(&self.extract_string_opt(row, "vector").unwrap_or_default());
+            index.add(key, normalized(vector));
+        }
+        Ok(index)
+    }
+}
+
+/// The embeddings of a database, held in memory for searching
+///
+/// Chunks are keyed by inode and chunk number. Removed chunks stay in
+/// place, marked dead, until they make up half the index, as the HNSW graph
+/// can't drop nodes.
+#[derive(Default)]
+pub(crate) struct VectorIndex {
+    /// Generation of `fs_embedding` this reflects
+    generation: i64,
+    keys: Vec<(i64, i64)>,
+    /// Unit vectors, in the order of `keys`
+    vectors: Vec<Vec<f32>>,
+    live: Vec<bool>,
+    by_ino: HashMap<i64, Vec<usize>>,
+    dead: usize,
+    /// Built on the first approximate search, then kept up to date
+    graph: Option<Hnsw>,
+}
+
+impl VectorIndex {
+    fn add(&mut self, key: (i64, i64), vector: Vec<f32>) {
+        self.by_ino.entry(key.0).or_default().push(self.keys.len());
+        self.keys.push(key);
+        self.vectors.push(vector);
+        self.live.push(true);
+        if let Some(graph) = &mut self.graph {
+            graph.insert(&self.vectors);
+        }
+    }
+
+    fn remove(&mut self, ino: i64) {
+        for node in self.by_ino.remove(&ino).unwrap_or_default() {
+            self.live[node] = false;
+            self.dead += 1;
+        }
+        if self.dead * 2 > self.keys.len() {
+            self.compact();
+        }
+    }
+
+    /// The live chunks of an inode
+    fn chunks_of(&self, ino: i64) -> Vec<(i64, Vec<f32>)> {
+        self.by_ino
+            .get(&ino)
+            .into_iter()
+            .flatten()
+            .map(|&node| (self.keys[node].1, self.vectors[node].clone()))
+            .collect()
+    }
+
+    /// Drop dead chunks, and the graph that refers to them by position
+    fn compact(&mut self) {
+        let old = std::mem::take(self);
+        *self = VectorIndex { generation: old.generation, ..VectorIndex::default() };
+        for ((key, vector), live) in old.keys.into_iter().zip(old.vectors).zip(old.live) {
+            if live {
+                self.add(key, vector);
+            }
+        }
+    }
+
+    /// The `k` chunks in `covered` closest to `target`, by comparing with
+    /// each of them
+    fn scan(&self, target: &[f32], k: usize, covered: &HashSet<(i64, i64)>) -> Vec<((i64, i64), f32)> {
+        let mut scored: Vec<((i64, i64), f32)> = (0..self.keys.len())
+            .filter(|&node| self.live[node] && covered.contains(&self.keys[node]))
+            .map(|node| (self.keys[node], cosine(target, &self.vectors[node])))
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(k);
+        scored
+    }
+
+    /// The `k` chunks in `covered` closest to `target`, approximately, from
+    /// the HNSW graph
+    fn search(&mut self, target: &[f32], k: usize, covered: &HashSet<(i64, i64)>) -> Vec<((i64, i64), f32)> {
+        let graph = self.graph.get_or_insert_with(Hnsw::new);
+        while graph.len() < self.vectors.len() {
+            graph.insert(&self.vectors);
+        }
+        let accept = |node: usize| self.live[node] && covered.contains(&self.keys[node]);
+        graph
+            .search(target, &self.vectors, k, ANN_EF, &accept)
+            .into_iter()
+            .map(|(node, distance)| (self.keys[node], 1.0 - distance))
+            .collect()
+    }
+}
+
+/// Split text into chunks of whole lines, each with its first line number
+fn chunk_lines(text: &str) -> Vec<(usize, String)> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut start = 1;
+    for (i, line) in text.lines().enumerate() {
+        if !current.is_empty() && current.len() + line.len() > CHUNK_CHARS {
+            chunks.push((start, std::mem::take(&mut current)));
+            start = i + 1;
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.trim().is_empty() {
+        chunks.push((start, current));
+    }
+    chunks.retain(|(_, text)| !text.trim().is_empty());
+    chunks
+}
+
+fn format_vector(vector: &[f32]) -> String {
+    vector.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",")
+}
+
+fn parse_vector(text: &str) -> Vec<f32> {
+    text.split(',').filter_map(|x| x.parse().ok()).collect()
+}
+
+/// Scale a vector to length 1, leaving zero vectors alone
+fn normalized(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+/// Cosine similarity, 0 for vectors of different length or zero norm
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denominator = norm(a) * norm(b);
+    if denominator == 0.0 { 0.0 } else { dot / denominator }
+}
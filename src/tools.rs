@@ -19,12 +19,12 @@ pub enum ToolCallStatus {
     Error,
 }
 
-impl ToString for ToolCallStatus {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for ToolCallStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ToolCallStatus::Pending => "pending".to_string(),
-            ToolCallStatus::Success => "success".to_string(),
-            ToolCallStatus::Error => "error".to_string(),
+            ToolCallStatus::Pending => write!(f, "pending"),
+            ToolCallStatus::Success => write!(f, "success"),
+            ToolCallStatus::Error => write!(f, "error"),
         }
     }
 }
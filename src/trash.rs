@@ -0,0 +1,170 @@
+//! Trash for removed entries
+//!
+//! With trash enabled (see [`DbFileSystem::with_trash`]), `remove` only
+//! unlinks the entry and records it here with its original path and the
+//! time it was deleted. The inode and its data stay in the database until
+//! the trash is emptied, so a mistaken delete can be undone with
+//! [`DbFileSystem::restore`]. Each volume has its own trash.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::{DbFileSystem, Stats};
+use crate::path::child_path;
+use crate::permissions::{W_OK, X_OK};
+use crate::watch::FsEventKind;
+use std::time::Duration;
+
+/// An entry in the trash, returned by [`DbFileSystem::list_trash`]
+#[derive(Debug, Clone)]
+pub struct TrashEntry {
+    /// Path the entry had when it was removed
+    pub path: String,
+    /// Deletion time, in seconds since the Unix epoch
+    pub deleted_at: i64,
+    /// Metadata of the removed inode
+    pub stats: Stats,
+}
+
+impl DbFileSystem {
+    /// List the trash, most recently deleted first
+    ///
+    /// With permissions enforced, only entries removed from directories
+    /// the caller may list are included.
+    pub async fn list_trash(&self) -> Result<Vec<TrashEntry>> {
+        self.ensure_schema().await?;
+        let query = format!(
+            "SELECT ino, path, deleted_at FROM fs_trash WHERE root_ino = {} ORDER BY deleted_at DESC, id DESC",
+            self.root_ino
+        );
+        let result = self.db.query(&query, vec![]).await?;
+
+        let mut entries = Vec::with_capacity(result.rows.len());
+        for row in &result.rows {
+            let ino = self.extract_i64(row, "ino")?;
+            let path = self.extract_string_opt(row, "path").unwrap_or_default();
+            if !self.may_list_parent(&path).await? {
+                continue;
+            }
+            let Some(stats) = self.stat_inode(ino).await? else {
+                continue;
+            };
+            entries.push(TrashEntry {
+                path,
+                deleted_at: self.extract_i64(row, "deleted_at")?,
+                stats,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Put the most recently deleted entry with the given original path
+    /// back where it was
+    ///
+    /// Fails with `PathExists` if something has been created at the path
+    /// since, and with `DirectoryNotFound` if its parent directory is gone.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let fs = agent_fs.fs.clone().with_trash(true);
+    /// fs.remove("/report.md").await?;
+    /// fs.restore("/report.md").await?;
+    /// ```
+    pub async fn restore(&self, path: &str) -> Result<()> {
+        self.ensure_schema().await?;
+        let path = self.validate_and_normalize_path(path)?;
+        let query = format!(
+            "SELECT id, ino FROM fs_trash WHERE root_ino = {} AND path = '{}' ORDER BY deleted_at DESC, id DESC LIMIT 1",
+            self.root_ino,
+            path.replace('\'', "''")
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        let Some(row) = result.rows.first() else {
+            return Err(AgentFsError::FileNotFound(format!("{} (not in trash)", path)));
+        };
+        let id = self.extract_i64(row, "id")?;
+        let ino = self.extract_i64(row, "ino")?;
+        self.restore_entry(&path, id, ino).await
+    }
+
+    /// Permanently delete trash entries removed at least `older_than` ago,
+    /// returning how many were purged
+    ///
+    /// `Duration::ZERO` empties the whole trash.
+    pub async fn empty_trash(&self, older_than: Duration) -> Result<usize> {
+        self.ensure_schema().await?;
+        let cutoff = Self::now() - older_than.as_secs() as i64;
+        let query = format!(
+            "SELECT id, ino, path FROM fs_trash WHERE root_ino = {} AND deleted_at <= {} ORDER BY id",
+            self.root_ino, cutoff
+        );
+        let result = self.db.query(&query, vec![]).await?;
+
+        for row in &result.rows {
+            let id = self.extract_i64(row, "id")?;
+            let ino = self.extract_i64(row, "ino")?;
+            let path = self.extract_string_opt(row, "path").unwrap_or_default();
+            self.purge_trash_entry(id, ino, &path).await?;
+        }
+        Ok(result.rows.len())
+    }
+
+    /// Put trash entry `id`, holding inode `ino`, back at `path`
+    pub(crate) async fn restore_entry(&self, path: &str, id: i64, ino: i64) -> Result<()> {
+        let (parent_ino, name, parent_path) = self.resolve_parent(path).await?;
+        self.check_access(parent_ino, W_OK | X_OK, path).await?;
+        if self.lookup_for_write(parent_ino, &name).await?.is_some() {
+            return Err(AgentFsError::PathExists(path.to_string()));
+        }
+        self.create_dentry(parent_ino, &name, ino).await?;
+        self.db.query(&format!("DELETE FROM fs_trash WHERE id = {}", id), vec![]).await?;
+
+        self.touch_dir(parent_ino).await?;
+        self.record_change(FsEventKind::Created, &child_path(&parent_path, &name)).await?;
+        Ok(())
+    }
+
+    /// Permanently delete trash entry `id`, holding inode `ino` that was
+    /// removed from `path`
+    pub(crate) async fn purge_trash_entry(&self, id: i64, ino: i64, path: &str) -> Result<()> {
+        self.db.query(&format!("DELETE FROM fs_trash WHERE id = {}", id), vec![]).await?;
+        self.purge_unlinked(ino).await?;
+        self.record_change(FsEventKind::Purged, path).await
+    }
+
+    /// The most recent trash entry holding inode `ino`
+    pub(crate) async fn trash_entry_of(&self, ino: i64) -> Result<Option<i64>> {
+        let query = format!(
+            "SELECT id FROM fs_trash WHERE root_ino = {} AND ino = {} ORDER BY id DESC LIMIT 1",
+            self.root_ino, ino
+        );
+        match self.db.query(&query, vec![]).await?.rows.first() {
+            Some(row) => Ok(Some(self.extract_i64(row, "id")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Record an unlinked inode in the trash under its original path
+    pub(crate) async fn move_to_trash(&self, ino: i64, path: &str) -> Result<()> {
+        self.ensure_schema().await?;
+        let query = format!(
+            "INSERT INTO fs_trash (root_ino, ino, path, deleted_at) VALUES ({}, {}, '{}', {})",
+            self.root_ino,
+            ino,
+            path.replace('\'', "''"),
+            Self::now()
+        );
+        self.db.query(&query, vec![]).await?;
+        Ok(())
+    }
+
+    /// Whether any trash entry still refers to an inode
+    pub(crate) async fn is_trashed(&self, ino: i64) -> Result<bool> {
+        self.ensure_schema().await?;
+        let query = format!("SELECT COUNT(*) as count FROM fs_trash WHERE ino = {}", ino);
+        let result = self.db.query(&query, vec![]).await?;
+        match result.rows.first() {
+            Some(row) => Ok(self.extract_i64(row, "count")? > 0),
+            None => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Disk usage, filesystem statistics and quotas
+//!
+//! `du` and the file reports walk a subtree with a single recursive query
+//! instead of stat'ing every entry. Hard-linked files are counted once.
+//!
+//! Handles with credentials only see what they could find by listing
+//! directories: subtrees below directories they may not list are left out.
+//! Expired entries are left out for everyone.
+//!
+//! Two sizes are distinguished: the *apparent* size is the sum of file
+//! sizes, as `du --apparent-size` reports it, while *stored* bytes count
+//! data shared between copies (see [`crate::copy`]) only once. Quotas apply
+//! to stored bytes, since that is what a copy actually costs.
+//!
+//! Whole-volume totals, as `statfs` and quota checks need them, come from
+//! per-volume counters that every change adjusts, so they cost a single
+//! row lookup. A repairing [`DbFileSystem::fsck`] recounts them.
+
+use crate::error::{AgentFsError, Result};
+use crate::expiry::unexpired;
+use crate::filesystem::{DbFileSystem, S_IFDIR, S_IFMT, S_IFREG, Stats};
+use crate::path::child_path;
+use crate::permissions::{R_OK, X_OK};
+use std::collections::HashMap;
+
+/// Rows fetched at a time by the file reports
+const REPORT_BATCH: usize = 256;
+
+/// Limits on what a filesystem may store
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Maximum stored bytes of file data
+    pub max_bytes: Option<u64>,
+    /// Maximum number of inodes (files, directories and symlinks)
+    pub max_inodes: Option<u64>,
+}
+
+/// Space used by a subtree, returned by [`DbFileSystem::du`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Apparent size of all regular files
+    pub bytes: u64,
+    /// Number of inodes, including the subtree root
+    pub inodes: u64,
+    /// Number of regular files
+    pub files: u64,
+    /// Number of directories, including the subtree root
+    pub directories: u64,
+}
+
+/// Whole-filesystem statistics, returned by [`DbFileSystem::statfs`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatFs {
+    /// Bytes of file data stored, counting shared data once
+    pub used_bytes: u64,
+    /// Apparent size of all regular files
+    pub apparent_bytes: u64,
+    /// Number of inodes
+    pub inodes: u64,
+    /// Byte quota, if any
+    pub max_bytes: Option<u64>,
+    /// Inode quota, if any
+    pub max_inodes: Option<u64>,
+    /// Bytes that can still be stored under the quota
+    pub free_bytes: Option<u64>,
+    /// Inodes that can still be created under the quota
+    pub free_inodes: Option<u64>,
+}
+
+/// A file found by [`DbFileSystem::largest_files`] or
+/// [`DbFileSystem::recently_modified`]
+#[derive(Debug, Clone)]
+pub struct UsageEntry {
+    pub path: String,
+    pub stats: Stats,
+}
+
+/// Subquery selecting the inodes of the subtree rooted at `ino`
+///
+/// `UNION` rather than `UNION ALL` visits each inode once, so hard links
+/// aren't counted twice and can't make the walk loop. The CTE is nested
+/// because the backend only returns rows for statements starting with
+/// `SELECT`.
+pub(crate) fn subtree(ino: i64) -> String {
+    format!(
+        "(WITH RECURSIVE tree(ino) AS (SELECT {} UNION SELECT d.ino FROM fs_dentry d JOIN tree t ON d.parent_ino = t.ino) \
+         SELECT ino FROM tree)",
+        ino
+    )
+}
+
+/// Like [`subtree`], but leaving out expired entries and everything below
+/// them
+fn live_subtree(ino: i64) -> String {
+    format!(
+        "(WITH RECURSIVE tree(ino) AS (SELECT {} UNION SELECT d.ino FROM fs_dentry d JOIN tree t ON d.parent_ino = t.ino \
+         JOIN fs_inode i ON i.ino = d.ino WHERE {}) SELECT ino FROM tree)",
+        ino,
+        unexpired("i")
+    )
+}
+
+/// An entry found by [`DbFileSystem::visible_subtree`]
+struct VisibleEntry {
+    path: String,
+    mode: u32,
+    size: i64,
+}
+
+impl DbFileSystem {
+    /// Measure the space used by a file or directory tree
+    ///
+    /// Symlinks are counted but not followed.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let usage = agent_fs.fs.du("/downloads").await?;
+    /// println!("{} bytes in {} files", usage.bytes, usage.files);
+    /// ```
+    pub async fn du(&self, path: &str) -> Result<DiskUsage> {
+        self.ensure_schema().await?;
+        let (ino, path) = self.resolve_existing(path).await?;
+        if self.enforces_permissions() {
+            let mut usage = DiskUsage::default();
+            for entry in self.visible_subtree(ino, &path).await?.values() {
+                usage.inodes += 1;
+                match entry.mode & S_IFMT {
+                    S_IFREG => {
+                        usage.files += 1;
+                        usage.bytes += entry.size.max(0) as u64;
+                    }
+                    S_IFDIR => usage.directories += 1,
+                    _ => {}
+                }
+            }
+            return Ok(usage);
+        }
+
+        let query = format!(
+            "SELECT COUNT(*) AS inodes, \
+             COALESCE(SUM(CASE WHEN (i.mode & {S_IFMT}) = {S_IFREG} THEN i.size ELSE 0 END), 0) AS bytes, \
+             COALESCE(SUM(CASE WHEN (i.mode & {S_IFMT}) = {S_IFREG} THEN 1 ELSE 0 END), 0) AS files, \
+             COALESCE(SUM(CASE WHEN (i.mode & {S_IFMT}) = {S_IFDIR} THEN 1 ELSE 0 END), 0) AS directories \
+             FROM fs_inode i WHERE i.ino IN {}",
+            live_subtree(ino)
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        let Some(row) = result.rows.first() else {
+            return Ok(DiskUsage::default());
+        };
+        Ok(DiskUsage {
+            bytes: self.extract_i64(row, "bytes")?.max(0) as u64,
+            inodes: self.extract_i64(row, "inodes")?.max(0) as u64,
+            files: self.extract_i64(row, "files")?.max(0) as u64,
+            directories: self.extract_i64(row, "directories")?.max(0) as u64,
+        })
+    }
+
+    /// Report totals for the whole volume, with quota limits and free space
+    pub async fn statfs(&self) -> Result<StatFs> {
+        self.ensure_schema().await?;
+        let (used_bytes, apparent_bytes, inodes) = self.usage_totals().await?;
+        let quota = self.quota().unwrap_or_default();
+        Ok(StatFs {
+            used_bytes,
+            apparent_bytes,
+            inodes,
+            max_bytes: quota.max_bytes,
+            max_inodes: quota.max_inodes,
+            free_bytes: quota.max_bytes.map(|max| max.saturating_sub(used_bytes)),
+            free_inodes: quota.max_inodes.map(|max| max.saturating_sub(inodes)),
+        })
+    }
+
+    /// The `limit` largest files under `path`, largest first
+    pub async fn largest_files(&self, path: &str, limit: usize) -> Result<Vec<UsageEntry>> {
+        self.file_report(path, "i.size DESC", limit).await
+    }
+
+    /// The `limit` most recently modified files under `path`, newest first
+    pub async fn recently_modified(&self, path: &str, limit: usize) -> Result<Vec<UsageEntry>> {
+        self.file_report(path, "i.mtime DESC, i.mtime_nsec DESC", limit).await
+    }
+
+    /// Fail with `QuotaExceeded` if storing `bytes` more bytes and `inodes`
+    /// more inodes would exceed the quota
+    pub(crate) async fn check_quota(&self, path: &str, bytes: i64, inodes: i64) -> Result<()> {
+        let Some(quota) = self.quota() else {
+            return Ok(());
+        };
+        let (used_bytes, _, used_inodes) = self.usage_totals().await?;
+        let over = |used: u64, extra: i64, max: Option<u64>| {
+            extra > 0 && max.is_some_and(|max| used.saturating_add(extra as u64) > max)
+        };
+        if over(used_bytes, bytes, quota.max_bytes) || over(used_inodes, inodes, quota.max_inodes) {
+            return Err(AgentFsError::QuotaExceeded(path.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Stored bytes that rewriting inode `ino` frees: the size of a regular
+    /// file, unless copies share its data
+    pub(crate) async fn replaced_bytes(&self, ino: i64) -> Result<i64> {
+        if self.data_owner(ino).await? != ino {
+            return Ok(0);
+        }
+        let query = format!(
+            "SELECT CASE WHEN (mode & {S_IFMT}) = {S_IFREG} THEN size ELSE 0 END AS size, \
+             (SELECT COUNT(*) FROM fs_inode WHERE data_ino = {ino}) AS sharers FROM fs_inode WHERE ino = {ino}"
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        match result.rows.first() {
+            Some(row) if self.extract_i64(row, "sharers")? == 0 => self.extract_i64(row, "size"),
+            _ => Ok(0),
+        }
+    }
+
+    /// Stored bytes, apparent bytes and inode count of the volume,
+    /// including its trash
+    ///
+    /// Read from the volume's counters, which are set up by counting the
+    /// tree the first time they are needed.
+    async fn usage_totals(&self) -> Result<(u64, u64, u64)> {
+        if let Some(totals) = self.usage_counters().await? {
+            return Ok(totals);
+        }
+        let (used, apparent, inodes) = self.count_usage().await?;
+        let query = format!(
+            "INSERT INTO fs_usage (root_ino, used_bytes, apparent_bytes, inodes) VALUES ({}, {}, {}, {})",
+            self.root_ino, used, apparent, inodes
+        );
+        match self.db.query(&query, vec![]).await {
+            Ok(_) => Ok((used, apparent, inodes)),
+            // Somebody else set them up first
+            Err(e) => self.usage_counters().await?.ok_or_else(|| e.into()),
+        }
+    }
+
+    /// The volume's usage counters, if set up
+    async fn usage_counters(&self) -> Result<Option<(u64, u64, u64)>> {
+        let query = format!(
+            "SELECT used_bytes, apparent_bytes, inodes FROM fs_usage WHERE root_ino = {}",
+            self.root_ino
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        let Some(row) = result.rows.first() else {
+            return Ok(None);
+        };
+        Ok(Some((
+            self.extract_i64(row, "used_bytes")?.max(0) as u64,
+            self.extract_i64(row, "apparent_bytes")?.max(0) as u64,
+            self.extract_i64(row, "inodes")?.max(0) as u64,
+        )))
+    }
+
+    /// Count the usage totals by walking the volume
+    async fn count_usage(&self) -> Result<(u64, u64, u64)> {
+        let query = format!(
+            "SELECT COUNT(*) AS inodes, \
+             COALESCE(SUM(CASE WHEN (mode & {S_IFMT}) = {S_IFREG} AND data_ino IS NULL THEN size ELSE 0 END), 0) AS used, \
+             COALESCE(SUM(CASE WHEN (mode & {S_IFMT}) = {S_IFREG} THEN size ELSE 0 END), 0) AS apparent \
+             FROM fs_inode WHERE ino IN {} OR ino IN (SELECT ino FROM fs_trash WHERE root_ino = {})",
+            subtree(self.root_ino),
+            self.root_ino
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        let Some(row) = result.rows.first() else {
+            return Ok((0, 0, 0));
+        };
+        Ok((
+            self.extract_i64(row, "used")?.max(0) as u64,
+            self.extract_i64(row, "apparent")?.max(0) as u64,
+            self.extract_i64(row, "inodes")?.max(0) as u64,
+        ))
+    }
+
+    /// Add to the volume's usage counters, if they are set up
+    pub(crate) async fn adjust_usage(&self, used_bytes: i64, apparent_bytes: i64, inodes: i64) -> Result<()> {
+        if (used_bytes, apparent_bytes, inodes) == (0, 0, 0) {
+            return Ok(());
+        }
+        let query = format!(
+            "UPDATE fs_usage SET used_bytes = used_bytes + {}, apparent_bytes = apparent_bytes + {}, \
+             inodes = inodes + {} WHERE root_ino = {}",
+            used_bytes, apparent_bytes, inodes, self.root_ino
+        );
+        self.db.query(&query, vec![]).await?;
+        Ok(())
+    }
+
+    /// Drop the volume's usage counters, so they are counted afresh
+    pub(crate) async fn reset_usage(&self) -> Result<()> {
+        let query = format!("DELETE FROM fs_usage WHERE root_ino = {}", self.root_ino);
+        self.db.query(&query, vec![]).await?;
+        Ok(())
+    }
+
+    /// Bytes inode `ino` adds to the apparent size: its size if it is a
+    /// regular file
+    pub(crate) async fn apparent_size(&self, ino: i64) -> Result<i64> {
+        let query = format!(
+            "SELECT CASE WHEN (mode & {S_IFMT}) = {S_IFREG} THEN size ELSE 0 END AS size FROM fs_inode WHERE ino = {ino}"
+        );
+        let result = self.db.query(&query, vec![]).await?;
+        match result.rows.first() {
+            Some(row) => self.extract_i64(row, "size"),
+            None => Ok(0),
+        }
+    }
+
+    /// Regular files under `path` in the given order
+    ///
+    /// Rows are fetched in batches until `limit` entries are found, since
+    /// some may turn out to be unreachable or hidden from the caller.
+    async fn file_report(&self, path: &str, order: &str, limit: usize) -> Result<Vec<UsageEntry>> {
+        self.ensure_schema().await?;
+        let (root, path) = self.resolve_existing(path).await?;
+        let visible = match self.enforces_permissions() {
+            true => Some(self.visible_subtree(root, &path).await?),
+            false => None,
+        };
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while entries.len() < limit {
+            let query = format!(
+                "SELECT i.ino FROM fs_inode i WHERE i.ino IN {} \
+                 AND (i.mode & {S_IFMT}) = {S_IFREG} ORDER BY {order}, i.ino LIMIT {REPORT_BATCH} OFFSET {offset}",
+                live_subtree(root)
+            );
+            let result = self.db.query(&query, vec![]).await?;
+            for row in &result.rows {
+                let ino = self.extract_i64(row, "ino")?;
+                let path = match &visible {
+                    Some(visible) => visible.get(&ino).map(|entry| entry.path.clone()),
+                    None => self.inode_path(ino).await?,
+                };
+                let (Some(path), Some(stats)) = (path, self.stat_inode(ino).await?) else {
+                    continue;
+                };
+                entries.push(UsageEntry { path, stats });
+                if entries.len() == limit {
+                    break;
+                }
+            }
+            if result.rows.len() < REPORT_BATCH {
+                break;
+            }
+            offset += REPORT_BATCH;
+        }
+        Ok(entries)
+    }
+
+    /// The unexpired entries of the subtree rooted at `ino`, at `path`, that
+    /// the caller can reach by listing directories, by inode
+    ///
+    /// Directories the caller may not list are included, but not what is
+    /// in them. Each inode is found once, at the first path it is met at.
+    async fn visible_subtree(&self, ino: i64, path: &str) -> Result<HashMap<i64, VisibleEntry>> {
+        let query = format!("SELECT mode, size FROM fs_inode WHERE ino = {}", ino);
+        let result = self.db.query(&query, vec![]).await?;
+        let Some(row) = result.rows.first() else {
+            return Ok(HashMap::new());
+        };
+        let root = VisibleEntry {
+            path: path.to_string(),
+            mode: self.extract_i64(row, "mode")? as u32,
+            size: self.extract_i64(row, "size")?,
+        };
+
+        let mut pending = Vec::new();
+        if root.mode & S_IFMT == S_IFDIR {
+            pending.push((ino, root.path.clone()));
+        }
+        let mut visible = HashMap::from([(ino, root)]);
+        while let Some((dir, dir_path)) = pending.pop() {
+            // The walk only reaches directories whose ancestors were listable,
+            // so checking the directory itself is enough
+            match self.check_access(dir, R_OK | X_OK, &dir_path).await {
+                Ok(()) => {}
+                Err(AgentFsError::PermissionDenied(_)) => continue,
+                Err(e) => return Err(e),
+            }
+            let query = format!(
+                "SELECT d.name, d.ino, i.mode, i.size FROM fs_dentry d JOIN fs_inode i ON i.ino = d.ino \
+                 WHERE d.parent_ino = {} AND {} ORDER BY d.id",
+                dir,
+                unexpired("i")
+            );
+            let result = self.db.query(&query, vec![]).await?;
+            for row in &result.rows {
+                let child = self.extract_i64(row, "ino")?;
+                let Some(name) = self.extract_string_opt(row, "name") else {
+                    continue;
+                };
+                if visible.contains_key(&child) {
+                    continue;
+                }
+                let entry = VisibleEntry {
+                    path: child_path(&dir_path, &name),
+                    mode: self.extract_i64(row, "mode")? as u32,
+                    size: self.extract_i64(row, "size")?,
+                };
+                if entry.mode & S_IFMT == S_IFDIR {
+                    pending.push((child, entry.path.clone()));
+                }
+                visible.insert(child, entry);
+            }
+        }
+        Ok(visible)
+    }
+
+    /// A path leading to an inode, found by walking its entries up to the root
+    pub(crate) async fn inode_path(&self, mut ino: i64) -> Result<Option<String>> {
+        let mut names = Vec::new();
+        while ino != self.root_ino {
+            let query = format!("SELECT parent_ino, name FROM fs_dentry WHERE ino = {} ORDER BY id LIMIT 1", ino);
+            let result = self.db.query(&query, vec![]).await?;
+            let Some(row) = result.rows.first() else {
+                return Ok(None);
+            };
+            names.push(self.extract_string_opt(row, "name").unwrap_or_default());
+            ino = self.extract_i64(row, "parent_ino")?;
+            // Only a corrupted tree has entries this deep; don't loop on cycles
+            if names.len() > 4096 {
+                return Ok(None);
+            }
+        }
+        Ok(Some(names.iter().rev().fold("/".to_string(), |path, name| child_path(&path, name))))
+    }
+
+    /// Resolve a path that must exist, following symlinks, to its inode
+    /// and normalized path
+    async fn resolve_existing(&self, path: &str) -> Result<(i64, String)> {
+        let path = self.validate_and_normalize_path(path)?;
+        let (ino, _) = self
+            .resolve(&path, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(path.clone()))?;
+        Ok((ino, path))
+    }
+}
@@ -0,0 +1,173 @@
+//! Named volumes
+//!
+//! Several agents can share one database without seeing each other's
+//! files. A volume is a name bound to a root directory inode, and a
+//! filesystem opened on it resolves every path from that root. Change
+//! notifications, locks and usage totals are scoped to the volume too.
+//!
+//! Databases created before volumes existed hold a single tree rooted at
+//! inode 1. Volumes never take over such a tree on their own, since
+//! whichever agent happened to open the database first would get the
+//! files; bind it to a volume once with [`DbFileSystem::adopt_legacy_tree`]
+//! to keep them where the agent left them. Only while that tree is still
+//! empty, as in a new database, does the first volume opened use inode 1
+//! as its root.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::{DEFAULT_DIR_MODE, DbFileSystem, ROOT_INO};
+use agentdb::AgentDB;
+use std::sync::Arc;
+
+/// A volume listed by [`DbFileSystem::list_volumes`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeInfo {
+    /// Name the volume was opened with, usually an agent id
+    pub name: String,
+    /// Inode of the volume's root directory
+    pub root_ino: i64,
+    /// Creation time, in seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+impl DbFileSystem {
+    /// Open the named volume, creating it on first use
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// let db = Arc::new(Box::new(backend) as Box<dyn AgentDB>);
+    /// let alice = DbFileSystem::open_volume(db.clone(), "alice", "/agent".to_string()).await?;
+    /// let bob = DbFileSystem::open_volume(db, "bob", "/agent".to_string()).await?;
+    /// // alice and bob each see only their own files
+    /// ```
+    pub async fn open_volume(db: Arc<Box<dyn AgentDB>>, name: &str, mount_path: String) -> Result<Self> {
+        check_volume_name(name)?;
+        let mut fs = Self::new(db, mount_path);
+        fs.ensure_schema().await?;
+        let escaped = name.replace('\'', "''");
+
+        // Another process may create the same volume between our lookup and
+        // insert; the loser looks again
+        for _ in 0..3 {
+            if let Some(root_ino) = fs.volume_root(&escaped).await? {
+                fs.root_ino = root_ino;
+                return Ok(fs);
+            }
+
+            let reuse = fs.legacy_tree_unused().await?;
+            let root_ino = if reuse { ROOT_INO } else { fs.insert_inode(DEFAULT_DIR_MODE, 0).await? };
+            if fs.insert_volume(&escaped, root_ino).await.is_ok() {
+                fs.root_ino = root_ino;
+                let query = format!("UPDATE fs_inode SET root_ino = {0} WHERE ino = {0}", root_ino);
+                fs.db.query(&query, vec![]).await?;
+                return Ok(fs);
+            }
+            if !reuse {
+                fs.db.query(&format!("DELETE FROM fs_inode WHERE ino = {}", root_ino), vec![]).await?;
+            }
+        }
+        Err(AgentFsError::Database(agentdb::AgentDbError::Backend(format!(
+            "Failed to open volume {}",
+            name
+        ))))
+    }
+
+    /// Bind the tree rooted at inode 1, from before volumes existed, to the
+    /// volume `name` and open it
+    ///
+    /// Only one volume can own that tree. Adopting it again under the same
+    /// name just opens the volume; any other conflict fails with
+    /// `AgentFsError::PathExists`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// // Once, when upgrading: the existing files become alice's
+    /// DbFileSystem::adopt_legacy_tree(db.clone(), "alice", "/agent".to_string()).await?;
+    /// let alice = AgentFS::new(Box::new(backend), "alice", "/agent").await?;
+    /// ```
+    pub async fn adopt_legacy_tree(db: Arc<Box<dyn AgentDB>>, name: &str, mount_path: String) -> Result<Self> {
+        check_volume_name(name)?;
+        let mut fs = Self::new(db, mount_path);
+        fs.ensure_schema().await?;
+        let escaped = name.replace('\'', "''");
+
+        match fs.volume_root(&escaped).await? {
+            Some(ROOT_INO) => {}
+            Some(_) => {
+                return Err(AgentFsError::PathExists(format!("Volume {} already has its own tree", name)));
+            }
+            None => {
+                if let Some(owner) = fs.list_volumes().await?.into_iter().find(|v| v.root_ino == ROOT_INO) {
+                    return Err(AgentFsError::PathExists(format!(
+                        "The legacy tree already belongs to volume {}",
+                        owner.name
+                    )));
+                }
+                // The unique root_ino stops a concurrent adoption
+                fs.insert_volume(&escaped, ROOT_INO).await?;
+            }
+        }
+        fs.root_ino = ROOT_INO;
+        Ok(fs)
+    }
+
+    /// Inode of the root directory this filesystem resolves paths from
+    pub fn root_ino(&self) -> i64 {
+        self.root_ino
+    }
+
+    /// List every volume in the database, by name
+    pub async fn list_volumes(&self) -> Result<Vec<VolumeInfo>> {
+        self.ensure_schema().await?;
+        let query = "SELECT name, root_ino, created_at FROM fs_volume ORDER BY name";
+        let result = self.db.query(query, vec![]).await?;
+
+        let mut volumes = Vec::with_capacity(result.rows.len());
+        for row in &result.rows {
+            volumes.push(VolumeInfo {
+                name: self.extract_string_opt(row, "name").unwrap_or_default(),
+                root_ino: self.extract_i64(row, "root_ino")?,
+                created_at: self.extract_i64(row, "created_at")?,
+            });
+        }
+        Ok(volumes)
+    }
+
+    /// Whether the tree at inode 1 is empty and no volume owns it
+    async fn legacy_tree_unused(&self) -> Result<bool> {
+        let query = format!(
+            "SELECT ino FROM fs_dentry WHERE parent_ino = {0} \
+             UNION ALL SELECT root_ino AS ino FROM fs_volume WHERE root_ino = {0} LIMIT 1",
+            ROOT_INO
+        );
+        Ok(self.db.query(&query, vec![]).await?.rows.is_empty())
+    }
+
+    async fn insert_volume(&self, escaped_name: &str, root_ino: i64) -> Result<()> {
+        let query = format!(
+            "INSERT INTO fs_volume (name, root_ino, created_at) VALUES ('{}', {}, {})",
+            escaped_name,
+            root_ino,
+            Self::now()
+        );
+        self.db.query(&query, vec![]).await?;
+        Ok(())
+    }
+
+    async fn volume_root(&self, escaped_name: &str) -> Result<Option<i64>> {
+        let query = format!("SELECT root_ino FROM fs_volume WHERE name = '{}'", escaped_name);
+        let result = self.db.query(&query, vec![]).await?;
+        match result.rows.first() {
+            Some(row) => Ok(Some(self.extract_i64(row, "root_ino")?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn check_volume_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 255 || name.chars().any(char::is_control) {
+        return Err(AgentFsError::InvalidPath(format!("Invalid volume name: {:?}", name)));
+    }
+    Ok(())
+}
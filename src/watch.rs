@@ -0,0 +1,254 @@
+//! Change notifications for files and directories
+//!
+//! Every mutating filesystem operation appends a row to the `fs_change`
+//! table. Watchers poll that table for new rows, so they see changes made
+//! by any process sharing the database. Changes made through the same
+//! `DbFileSystem` (or one of its clones) also wake watchers immediately
+//! instead of waiting for the next poll.
+
+use crate::error::Result;
+use crate::filesystem::DbFileSystem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// How often watchers poll the change log for changes from other processes
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Kind of filesystem change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsEventKind {
+    /// A file, directory or symlink was created
+    Created,
+    /// A file's content was rewritten
+    Modified,
+    /// An entry was removed
+    Removed,
+    /// An entry was moved to a new path
+    Renamed { to: String },
+    /// Permissions, owner, extended attributes or expiry changed
+    AttributesChanged,
+    /// A removed entry was deleted from the trash for good
+    Purged,
+}
+
+impl FsEventKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            FsEventKind::Created => "created",
+            FsEventKind::Modified => "modified",
+            FsEventKind::Removed => "removed",
+            FsEventKind::Renamed { .. } => "renamed",
+            FsEventKind::AttributesChanged => "attributes",
+            FsEventKind::Purged => "purged",
+        }
+    }
+}
+
+/// A filesystem change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsEvent {
+    /// Position in the change log, increasing with every change
+    pub id: i64,
+    /// What happened
+    pub kind: FsEventKind,
+    /// Path of the affected entry (the old path for renames)
+    pub path: String,
+    /// Unix timestamp of the change
+    pub timestamp: i64,
+}
+
+impl FsEvent {
+    /// Check whether this event concerns `path`, or one of its children
+    /// (`recursive == false`) or descendants (`recursive == true`)
+    pub fn affects(&self, path: &str, recursive: bool) -> bool {
+        let matches = |p: &str| {
+            if p == path {
+                return true;
+            }
+            let prefix = if path == "/" { "/".to_string() } else { format!("{}/", path) };
+            match p.strip_prefix(&prefix) {
+                Some(rest) => recursive || !rest.contains('/'),
+                None => false,
+            }
+        };
+
+        match &self.kind {
+            FsEventKind::Renamed { to } => matches(&self.path) || matches(to),
+            _ => matches(&self.path),
+        }
+    }
+}
+
+/// Stream of filesystem events returned by [`DbFileSystem::watch`]
+///
+/// The stream yields an error and ends if the change log can't be read.
+/// Dropping it stops the background poller.
+pub struct FsWatcher {
+    inner: ReceiverStream<Result<FsEvent>>,
+}
+
+impl Stream for FsWatcher {
+    type Item = Result<FsEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl DbFileSystem {
+    /// Watch a path for changes
+    ///
+    /// Only changes made after this call are reported. With `recursive`
+    /// set, changes anywhere below `path` are included; otherwise only
+    /// `path` itself and its direct children.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// use tokio_stream::StreamExt;
+    ///
+    /// let mut events = agent_fs.fs.watch("/output", true).await?;
+    /// while let Some(event) = events.next().await {
+    ///     let event = event?;
+    ///     println!("{:?} {}", event.kind, event.path);
+    /// }
+    /// ```
+    pub async fn watch(&self, path: &str, recursive: bool) -> Result<FsWatcher> {
+        self.watch_with_interval(path, recursive, DEFAULT_POLL_INTERVAL).await
+    }
+
+    /// Watch a path for changes, polling for changes from other processes
+    /// at the given interval
+    pub async fn watch_with_interval(
+        &self,
+        path: &str,
+        recursive: bool,
+        poll_interval: Duration,
+    ) -> Result<FsWatcher> {
+        self.ensure_schema().await?;
+        let path = self.validate_and_normalize_path(path)?;
+        let mut last_id = self.last_change_id().await?;
+
+        let (tx, rx) = mpsc::channel(64);
+        let fs = self.clone();
+        let mut local_changes = self.changes.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match fs.changes_since(last_id, None).await {
+                    Ok(events) => {
+                        for event in events {
+                            last_id = event.id;
+                            if event.affects(&path, recursive) && tx.send(Ok(event)).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        return;
+                    }
+                }
+
+                tokio::select! {
+                    _ = local_changes.changed() => {}
+                    _ = tokio::time::sleep(poll_interval) => {}
+                    _ = tx.closed() => return,
+                }
+            }
+        });
+
+        Ok(FsWatcher { inner: ReceiverStream::new(rx) })
+    }
+
+    /// Read the change log after the given event id, oldest first
+    ///
+    /// Use `0` to read from the beginning. This is the polling primitive
+    /// behind [`DbFileSystem::watch`], useful for consumers that keep their
+    /// own cursor.
+    pub async fn changes_since(&self, after_id: i64, limit: Option<usize>) -> Result<Vec<FsEvent>> {
+        self.ensure_schema().await?;
+        let limit_clause = limit.map(|l| format!(" LIMIT {}", l)).unwrap_or_default();
+        let query = format!(
+            "SELECT id, kind, path, new_path, changed_at FROM fs_change WHERE id > {} AND root_ino = {} ORDER BY id{}",
+            after_id, self.root_ino, limit_clause
+        );
+        let result = self.db.query(&query, vec![]).await?;
+
+        let mut events = Vec::with_capacity(result.rows.len());
+        for row in &result.rows {
+            let text = |column: &str| {
+                row.get(column)
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+                    .unwrap_or_default()
+            };
+            let kind = match text("kind").as_str() {
+                "created" => FsEventKind::Created,
+                "removed" => FsEventKind::Removed,
+                "renamed" => FsEventKind::Renamed { to: text("new_path") },
+                "attributes" => FsEventKind::AttributesChanged,
+                "purged" => FsEventKind::Purged,
+                _ => FsEventKind::Modified,
+            };
+            events.push(FsEvent {
+                id: self.extract_i64(row, "id")?,
+                kind,
+                path: text("path"),
+                timestamp: self.extract_i64(row, "changed_at")?,
+            });
+        }
+
+        Ok(events)
+    }
+
+    /// Id of the most recent change, or 0 if nothing changed yet
+    async fn last_change_id(&self) -> Result<i64> {
+        let result = self
+            .db
+            .query("SELECT COALESCE(MAX(id), 0) as id FROM fs_change", vec![])
+            .await?;
+        match result.rows.first() {
+            Some(row) => self.extract_i64(row, "id"),
+            None => Ok(0),
+        }
+    }
+
+    /// Append a change to the change log and wake local watchers
+    pub(crate) async fn record_change(&self, kind: FsEventKind, path: &str) -> Result<()> {
+        let ino = match &kind {
+            FsEventKind::Removed | FsEventKind::Purged => None,
+            FsEventKind::Renamed { to } => self.resolve_path(to).await.ok().flatten(),
+            _ => self.resolve_path(path).await.ok().flatten(),
+        };
+        self.record_inode_change(kind, path, ino).await
+    }
+
+    /// Append a change to inode `ino` to the change log and notify watchers
+    ///
+    /// For callers that already resolved the inode, and must not look the
+    /// path up again: the lookup would reap an entry that just expired.
+    pub(crate) async fn record_inode_change(&self, kind: FsEventKind, path: &str, ino: Option<i64>) -> Result<()> {
+        self.ensure_schema().await?;
+        let new_path = match &kind {
+            FsEventKind::Renamed { to } => format!("'{}'", to.replace('\'', "''")),
+            _ => "NULL".to_string(),
+        };
+        let query = format!(
+            "INSERT INTO fs_change (kind, path, new_path, changed_at, root_ino) VALUES ('{}', '{}', {}, {}, {})",
+            kind.as_str(),
+            path.replace('\'', "''"),
+            new_path,
+            Self::now(),
+            self.root_ino
+        );
+        self.db.query(&query, vec![]).await?;
+        self.record_provenance(&kind, path, ino).await?;
+
+        self.changes.send_modify(|counter| *counter += 1);
+        Ok(())
+    }
+}
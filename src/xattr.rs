@@ -0,0 +1,107 @@
+//! Extended attributes
+//!
+//! Small name/value pairs attached to files and directories, for metadata
+//! that doesn't belong in the content, such as the URL a page was fetched
+//! from or a checksum. Like file data they are stored as KV entries, under
+//! `__fs_xattr:<ino>:<name>`, and they follow the inode through renames,
+//! hard links and copies.
+
+use crate::error::{AgentFsError, Result};
+use crate::filesystem::DbFileSystem;
+use crate::permissions::{R_OK, W_OK};
+use crate::watch::FsEventKind;
+
+/// Prefix of the KV keys holding the attributes of an inode
+fn xattr_prefix(ino: i64) -> String {
+    format!("__fs_xattr:{}:", ino)
+}
+
+impl DbFileSystem {
+    /// Set an extended attribute, replacing any previous value
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// agent_fs.fs.set_xattr("/downloads/page.html", "user.source_url", b"https://example.com").await?;
+    /// ```
+    pub async fn set_xattr(&self, path: &str, name: &str, value: &[u8]) -> Result<()> {
+        let (ino, path) = self.xattr_target(path, name, W_OK).await?;
+        self.db.put(&format!("{}{}", xattr_prefix(ino), name), value.into()).await?;
+        let (now, nsec) = Self::now_timespec();
+        let query = format!("UPDATE fs_inode SET ctime = {now}, ctime_nsec = {nsec} WHERE ino = {ino}");
+        self.db.query(&query, vec![]).await?;
+        self.record_inode_change(FsEventKind::AttributesChanged, &path, Some(ino)).await
+    }
+
+    /// Get the value of an extended attribute
+    pub async fn get_xattr(&self, path: &str, name: &str) -> Result<Option<Vec<u8>>> {
+        let (ino, _) = self.xattr_target(path, name, R_OK).await?;
+        let value = self.db.get(&format!("{}{}", xattr_prefix(ino), name)).await?;
+        Ok(value.map(|value| value.as_bytes().to_vec()))
+    }
+
+    /// List the names of a file's extended attributes, sorted
+    pub async fn list_xattrs(&self, path: &str) -> Result<Vec<String>> {
+        let path = self.validate_and_normalize_path(path)?;
+        let (ino, _) = self
+            .resolve(&path, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(path.clone()))?;
+        self.check_access(ino, R_OK, &path).await?;
+        self.xattr_names(ino).await
+    }
+
+    /// Remove an extended attribute; removing a missing one is not an error
+    pub async fn remove_xattr(&self, path: &str, name: &str) -> Result<()> {
+        let (ino, path) = self.xattr_target(path, name, W_OK).await?;
+        let _ = self.db.delete(&format!("{}{}", xattr_prefix(ino), name)).await;
+        self.record_inode_change(FsEventKind::AttributesChanged, &path, Some(ino)).await
+    }
+
+    /// Copy every extended attribute of one inode to another
+    pub(crate) async fn copy_xattrs(&self, from_ino: i64, to_ino: i64) -> Result<()> {
+        for name in self.xattr_names(from_ino).await? {
+            if let Some(value) = self.db.get(&format!("{}{}", xattr_prefix(from_ino), name)).await? {
+                self.db.put(&format!("{}{}", xattr_prefix(to_ino), name), value).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the extended attributes of an inode that is being deleted
+    pub(crate) async fn delete_xattrs(&self, ino: i64) -> Result<()> {
+        for key in self.db.scan(&xattr_prefix(ino)).await?.keys {
+            let _ = self.db.delete(&key).await;
+        }
+        Ok(())
+    }
+
+    async fn xattr_names(&self, ino: i64) -> Result<Vec<String>> {
+        let prefix = xattr_prefix(ino);
+        let mut names: Vec<String> = self
+            .db
+            .scan(&prefix)
+            .await?
+            .keys
+            .into_iter()
+            .filter_map(|key| key.strip_prefix(&prefix).map(str::to_string))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Resolve the inode an attribute operation applies to, following
+    /// symlinks, and check access to it; also returns the normalized path
+    async fn xattr_target(&self, path: &str, name: &str, access: u32) -> Result<(i64, String)> {
+        if name.is_empty() || name.chars().any(char::is_control) {
+            return Err(AgentFsError::InvalidPath(format!("Invalid attribute name: {:?}", name)));
+        }
+        let path = self.validate_and_normalize_path(path)?;
+        let (ino, _) = self
+            .resolve(&path, true)
+            .await?
+            .ok_or_else(|| AgentFsError::FileNotFound(path.clone()))?;
+        self.check_access(ino, access, &path).await?;
+        Ok((ino, path))
+    }
+}
@@ -4,7 +4,12 @@
 //! Specification (SPEC.md) and matches the behavior of the original agentfs-main.
 
 use agentfs::{AgentFS, FileSystem, KvStore, ToolRecorder};
+use agentdb::AgentDB;
+use agentfs::DbFileSystem;
 use agentsql::SqlBackend;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
 /// Helper to create an in-memory SQLite AgentFS instance for testing
 async fn create_test_agentfs() -> AgentFS {
@@ -17,6 +22,132 @@ async fn create_test_agentfs() -> AgentFS {
         .expect("Failed to create AgentFS")
 }
 
+/// Database handle shared between an AgentFS instance and the test, so tests
+/// can inspect or corrupt the underlying tables directly
+struct SharedDb(Arc<SqlBackend>);
+
+#[async_trait::async_trait]
+impl AgentDB for SharedDb {
+    fn family(&self) -> agentdb::BackendFamily {
+        self.0.family()
+    }
+    fn capabilities(&self) -> &dyn agentdb::Capabilities {
+        self.0.capabilities()
+    }
+    async fn put(&self, key: &str, value: agentdb::Value) -> agentdb::Result<()> {
+        self.0.put(key, value).await
+    }
+    async fn get(&self, key: &str) -> agentdb::Result<Option<agentdb::Value>> {
+        self.0.get(key).await
+    }
+    async fn delete(&self, key: &str) -> agentdb::Result<()> {
+        self.0.delete(key).await
+    }
+    async fn exists(&self, key: &str) -> agentdb::Result<bool> {
+        self.0.exists(key).await
+    }
+    async fn query(&self, query: &str, params: Vec<agentdb::Value>) -> agentdb::Result<agentdb::QueryResult> {
+        self.0.query(query, params).await
+    }
+    async fn scan(&self, prefix: &str) -> agentdb::Result<agentdb::ScanResult> {
+        self.0.scan(prefix).await
+    }
+    async fn begin(&self) -> agentdb::Result<Box<dyn agentdb::Transaction>> {
+        self.0.begin().await
+    }
+    async fn close(&self) -> agentdb::Result<()> {
+        self.0.close().await
+    }
+}
+
+/// Work a concurrent writer does in the middle of an operation
+type Interference = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+/// Database handle that lets a concurrent writer in once, right after the
+/// first statement containing a trigger
+struct InterferingDb {
+    inner: SharedDb,
+    hook: Arc<Mutex<Option<(&'static str, Interference)>>>,
+}
+
+#[async_trait::async_trait]
+impl AgentDB for InterferingDb {
+    fn family(&self) -> agentdb::BackendFamily {
+        self.inner.family()
+    }
+    fn capabilities(&self) -> &dyn agentdb::Capabilities {
+        self.inner.capabilities()
+    }
+    async fn put(&self, key: &str, value: agentdb::Value) -> agentdb::Result<()> {
+        self.inner.put(key, value).await
+    }
+    async fn get(&self, key: &str) -> agentdb::Result<Option<agentdb::Value>> {
+        self.inner.get(key).await
+    }
+    async fn delete(&self, key: &str) -> agentdb::Result<()> {
+        self.inner.delete(key).await
+    }
+    async fn exists(&self, key: &str) -> agentdb::Result<bool> {
+        self.inner.exists(key).await
+    }
+    async fn query(&self, query: &str, params: Vec<agentdb::Value>) -> agentdb::Result<agentdb::QueryResult> {
+        let result = self.inner.query(query, params).await?;
+        let interference = {
+            let mut hook = self.hook.lock().unwrap();
+            match hook.as_ref() {
+                Some((trigger, _)) if query.contains(trigger) => hook.take().map(|(_, interference)| interference),
+                _ => None,
+            }
+        };
+        if let Some(interference) = interference {
+            interference().await;
+        }
+        Ok(result)
+    }
+    async fn scan(&self, prefix: &str) -> agentdb::Result<agentdb::ScanResult> {
+        self.inner.scan(prefix).await
+    }
+    async fn begin(&self) -> agentdb::Result<Box<dyn agentdb::Transaction>> {
+        self.inner.begin().await
+    }
+    async fn close(&self) -> agentdb::Result<()> {
+        self.inner.close().await
+    }
+}
+
+/// Helper to open the root volume of an in-memory database twice: once
+/// through an [`InterferingDb`] with the returned hook, and once directly
+/// for the concurrent writer
+async fn create_interfering_test_fs() -> (DbFileSystem, DbFileSystem, Arc<Mutex<Option<(&'static str, Interference)>>>)
+{
+    let backend = Arc::new(
+        SqlBackend::sqlite(":memory:")
+            .await
+            .expect("Failed to create SQLite backend"),
+    );
+    let hook = Arc::new(Mutex::new(None));
+    let db: Arc<Box<dyn AgentDB>> =
+        Arc::new(Box::new(InterferingDb { inner: SharedDb(backend.clone()), hook: hook.clone() }));
+    let fs = DbFileSystem::open_volume(db, "agent", "/".to_string()).await.expect("Failed to open volume");
+    let db: Arc<Box<dyn AgentDB>> = Arc::new(Box::new(SharedDb(backend)));
+    let other = DbFileSystem::open_volume(db, "agent", "/".to_string()).await.expect("Failed to open volume");
+    (fs, other, hook)
+}
+
+/// Helper to create an in-memory AgentFS instance along with raw access to its database
+async fn create_shared_test_agentfs() -> (AgentFS, Arc<SqlBackend>) {
+    let backend = Arc::new(
+        SqlBackend::sqlite(":memory:")
+            .await
+            .expect("Failed to create SQLite backend"),
+    );
+
+    let agentfs = AgentFS::new(Box::new(SharedDb(backend.clone())), "test-agent", "/agent")
+        .await
+        .expect("Failed to create AgentFS");
+    (agentfs, backend)
+}
+
 #[tokio::test]
 async fn test_agentfs_creation() {
     let agentfs = create_test_agentfs().await;
@@ -626,3 +757,1747 @@ async fn test_path_sandboxing() {
     let stats = agentfs.fs.stat("/agent/test.txt").await.unwrap();
     assert!(stats.is_some());
 }
+
+#[tokio::test]
+async fn test_advisory_locks() {
+    use agentfs::LockMode;
+    use std::time::Duration;
+
+    let agentfs = create_test_agentfs().await;
+    let ttl = Duration::from_secs(30);
+
+    // Shared locks coexist, but block an exclusive lock
+    let shared1 = agentfs.fs.lock("/data.txt", LockMode::Shared, ttl).await.unwrap();
+    let shared2 = agentfs.fs.lock("/agent/data.txt", LockMode::Shared, ttl).await.unwrap();
+    let result = agentfs.fs.lock("/data.txt", LockMode::Exclusive, ttl).await;
+    assert!(matches!(result, Err(agentfs::AgentFsError::Locked(_))));
+
+    shared1.release().await.unwrap();
+    shared2.release().await.unwrap();
+
+    // An exclusive lock blocks everyone else
+    let exclusive = agentfs.fs.lock("/data.txt", LockMode::Exclusive, ttl).await.unwrap();
+    assert_eq!(exclusive.path(), "/data.txt");
+    assert!(agentfs.fs.lock("/data.txt", LockMode::Shared, ttl).await.is_err());
+
+    // Other paths are unaffected
+    let other = agentfs.fs.lock("/other.txt", LockMode::Exclusive, ttl).await.unwrap();
+    other.release().await.unwrap();
+
+    // Dropping the guard releases the lock
+    drop(exclusive);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let again = agentfs.fs.lock("/data.txt", LockMode::Exclusive, ttl).await.unwrap();
+    again.release().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_advisory_lock_expiry() {
+    use agentfs::LockMode;
+    use std::time::Duration;
+
+    let agentfs = create_test_agentfs().await;
+
+    let stale = agentfs
+        .fs
+        .lock("/job", LockMode::Exclusive, Duration::from_millis(20))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // The expired lease no longer blocks, and can't be renewed once taken over
+    let fresh = agentfs
+        .fs
+        .lock("/job", LockMode::Exclusive, Duration::from_secs(30))
+        .await
+        .unwrap();
+    assert!(stale.renew(Duration::from_secs(30)).await.is_err());
+    fresh.renew(Duration::from_secs(60)).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_filesystem_rename() {
+    use agentfs::{AgentFsError, FsEventKind};
+
+    let agentfs = create_test_agentfs().await;
+
+    agentfs.fs.mkdir("/src").await.unwrap();
+    agentfs.fs.mkdir("/dst").await.unwrap();
+    agentfs.fs.write_file("/src/a.txt", b"a").await.unwrap();
+    let ino = agentfs.fs.stat("/src/a.txt").await.unwrap().unwrap().ino;
+
+    // Move across directories keeps the inode
+    agentfs.fs.rename("/src/a.txt", "/dst/b.txt").await.unwrap();
+    assert!(!agentfs.fs.exists("/src/a.txt").await.unwrap());
+    let stats = agentfs.fs.stat("/dst/b.txt").await.unwrap().unwrap();
+    assert_eq!(stats.ino, ino);
+    assert_eq!(agentfs.fs.read_file("/dst/b.txt").await.unwrap().unwrap(), b"a");
+
+    // Renaming over an existing file replaces it, as a single change that
+    // deletes the replaced file outright, even with trash enabled
+    let fs = agentfs.fs.clone().with_trash(true);
+    fs.write_file("/dst/c.txt", b"c").await.unwrap();
+    let since = fs.changes_since(0, None).await.unwrap().last().unwrap().id;
+    fs.rename("/dst/c.txt", "/dst/b.txt").await.unwrap();
+    assert_eq!(fs.read_file("/dst/b.txt").await.unwrap().unwrap(), b"c");
+    assert!(!fs.exists("/dst/c.txt").await.unwrap());
+    let kinds: Vec<FsEventKind> = fs.changes_since(since, None).await.unwrap().into_iter().map(|e| e.kind).collect();
+    assert_eq!(kinds, vec![FsEventKind::Renamed { to: "/dst/b.txt".to_string() }]);
+    assert!(fs.list_trash().await.unwrap().is_empty());
+    assert!(fs.fsck(false).await.unwrap().is_clean());
+
+    // Only an empty directory can be replaced
+    fs.mkdir("/full").await.unwrap();
+    fs.write_file("/full/f.txt", b"f").await.unwrap();
+    assert!(matches!(fs.rename("/src", "/full").await, Err(AgentFsError::DirectoryNotEmpty(_))));
+    fs.mkdir("/empty").await.unwrap();
+    fs.rename("/src", "/empty").await.unwrap();
+    assert!(!fs.exists("/src").await.unwrap());
+    assert!(fs.fsck(false).await.unwrap().is_clean());
+    fs.mkdir("/src").await.unwrap();
+
+    // A directory can't be moved into itself or over a file
+    assert!(agentfs.fs.rename("/dst", "/dst/sub").await.is_err());
+    assert!(agentfs.fs.rename("/src", "/dst/b.txt").await.is_err());
+    assert!(agentfs.fs.rename("/missing", "/other").await.is_err());
+}
+
+#[tokio::test]
+async fn test_watch_events() {
+    use agentfs::FsEventKind;
+    use std::time::Duration;
+    use tokio_stream::StreamExt;
+
+    let agentfs = create_test_agentfs().await;
+    agentfs.fs.mkdir("/output").await.unwrap();
+    agentfs.fs.mkdir("/other").await.unwrap();
+
+    let mut events = agentfs.fs.watch("/output", true).await.unwrap();
+
+    agentfs.fs.write_file("/other/ignored.txt", b"x").await.unwrap();
+    agentfs.fs.write_file("/output/report.md", b"v1").await.unwrap();
+    agentfs.fs.write_file("/output/report.md", b"v2").await.unwrap();
+    agentfs.fs.rename("/output/report.md", "/output/final.md").await.unwrap();
+    agentfs.fs.remove("/output/final.md").await.unwrap();
+
+    let mut next = async || {
+        tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .expect("timed out waiting for event")
+            .unwrap()
+            .unwrap()
+    };
+
+    let event = next().await;
+    assert_eq!(event.kind, FsEventKind::Created);
+    assert_eq!(event.path, "/output/report.md");
+    assert_eq!(next().await.kind, FsEventKind::Modified);
+    let event = next().await;
+    assert_eq!(event.kind, FsEventKind::Renamed { to: "/output/final.md".to_string() });
+    assert_eq!(event.path, "/output/report.md");
+    let event = next().await;
+    assert_eq!(event.kind, FsEventKind::Removed);
+    assert_eq!(event.path, "/output/final.md");
+
+    // The change log is also readable directly, e.g. from another process
+    let all = agentfs.fs.changes_since(0, None).await.unwrap();
+    assert!(all.iter().any(|e| e.path == "/other/ignored.txt"));
+    assert!(all.windows(2).all(|w| w[0].id < w[1].id));
+}
+
+#[tokio::test]
+async fn test_intermediate_symlinks() {
+    let agentfs = create_test_agentfs().await;
+
+    agentfs.fs.mkdir("/real").await.unwrap();
+    agentfs.fs.mkdir("/real/sub").await.unwrap();
+    agentfs.fs.write_file("/real/sub/file.txt", b"deep").await.unwrap();
+    agentfs.fs.symlink("/real", "/link_to_dir").await.unwrap();
+    agentfs.fs.symlink("../real/sub", "/real/rel_link").await.unwrap();
+
+    // Symlinks in the middle of a path are followed
+    let data = agentfs.fs.read_file("/link_to_dir/sub/file.txt").await.unwrap().unwrap();
+    assert_eq!(data, b"deep");
+    let data = agentfs.fs.read_file("/real/rel_link/file.txt").await.unwrap().unwrap();
+    assert_eq!(data, b"deep");
+    assert!(agentfs.fs.stat("/link_to_dir/sub").await.unwrap().unwrap().is_directory());
+    let entries = agentfs.fs.readdir("/link_to_dir").await.unwrap().unwrap();
+    assert!(entries.contains(&"sub".to_string()));
+
+    // Creating through a symlinked directory lands in the target
+    agentfs.fs.write_file("/link_to_dir/new.txt", b"new").await.unwrap();
+    assert_eq!(agentfs.fs.read_file("/real/new.txt").await.unwrap().unwrap(), b"new");
+    agentfs.fs.mkdir("/link_to_dir/made").await.unwrap();
+    assert!(agentfs.fs.stat("/real/made").await.unwrap().unwrap().is_directory());
+
+    // lstat only skips the final component
+    assert!(agentfs.fs.lstat("/link_to_dir").await.unwrap().unwrap().is_symlink());
+    assert!(agentfs.fs.lstat("/link_to_dir/sub/file.txt").await.unwrap().unwrap().is_file());
+
+    // Writing through a dangling symlink creates its target
+    agentfs.fs.symlink("/real/created.txt", "/dangling").await.unwrap();
+    agentfs.fs.write_file("/dangling", b"via link").await.unwrap();
+    assert_eq!(agentfs.fs.read_file("/real/created.txt").await.unwrap().unwrap(), b"via link");
+    assert!(agentfs.fs.lstat("/dangling").await.unwrap().unwrap().is_symlink());
+
+    // O_NOFOLLOW-style variants refuse a final symlink
+    let result = agentfs.fs.read_file_nofollow("/dangling").await;
+    assert!(matches!(result, Err(agentfs::AgentFsError::SymlinkLoop(_))));
+    assert!(agentfs.fs.write_file_nofollow("/dangling", b"x").await.is_err());
+    let data = agentfs.fs.read_file_nofollow("/link_to_dir/sub/file.txt").await.unwrap().unwrap();
+    assert_eq!(data, b"deep");
+}
+
+#[tokio::test]
+async fn test_symlink_loops_and_sandboxing() {
+    let agentfs = create_test_agentfs().await;
+
+    // Loops are detected for every operation that follows links
+    agentfs.fs.symlink("/loop_b", "/loop_a").await.unwrap();
+    agentfs.fs.symlink("/loop_a", "/loop_b").await.unwrap();
+    assert!(matches!(
+        agentfs.fs.stat("/loop_a").await,
+        Err(agentfs::AgentFsError::SymlinkLoop(_))
+    ));
+    assert!(agentfs.fs.read_file("/loop_a").await.is_err());
+    assert!(agentfs.fs.read_file("/loop_a/child").await.is_err());
+    assert!(agentfs.fs.write_file("/loop_a", b"x").await.is_err());
+    assert!(agentfs.fs.lstat("/loop_a").await.unwrap().unwrap().is_symlink());
+
+    // Targets can't climb out of the mount root
+    agentfs.fs.mkdir("/etc").await.unwrap();
+    agentfs.fs.write_file("/etc/passwd", b"sandboxed").await.unwrap();
+    agentfs.fs.mkdir("/a").await.unwrap();
+    agentfs.fs.symlink("../../../../etc", "/a/escape").await.unwrap();
+    agentfs.fs.symlink("/agent/etc", "/prefixed").await.unwrap();
+    let data = agentfs.fs.read_file("/a/escape/passwd").await.unwrap().unwrap();
+    assert_eq!(data, b"sandboxed");
+    let data = agentfs.fs.read_file("/prefixed/passwd").await.unwrap().unwrap();
+    assert_eq!(data, b"sandboxed");
+}
+
+#[tokio::test]
+async fn test_fsck_detects_and_repairs() {
+    // A handle that hasn't been used yet checks an empty filesystem
+    let backend = SqlBackend::sqlite(":memory:").await.unwrap();
+    let fresh = DbFileSystem::new(Arc::new(Box::new(backend)), "/".to_string());
+    assert!(fresh.fsck(false).await.unwrap().is_clean());
+
+    let (agentfs, raw) = create_shared_test_agentfs().await;
+
+    agentfs.fs.mkdir("/docs").await.unwrap();
+    agentfs.fs.write_file("/docs/a.txt", b"hello").await.unwrap();
+    agentfs.fs.write_file("/docs/b.txt", b"world").await.unwrap();
+    agentfs.fs.symlink("/docs/a.txt", "/link").await.unwrap();
+    assert!(agentfs.fs.fsck(false).await.unwrap().is_clean());
+
+    // Corrupt the tables behind the filesystem's back
+    let a = agentfs.fs.stat("/docs/a.txt").await.unwrap().unwrap().ino;
+    let b = agentfs.fs.stat("/docs/b.txt").await.unwrap().unwrap().ino;
+    let link = agentfs.fs.lstat("/link").await.unwrap().unwrap().ino;
+    for query in [
+        format!("DELETE FROM fs_dentry WHERE ino = {}", a),
+        format!("UPDATE fs_inode SET size = 99 WHERE ino = {}", b),
+        format!("DELETE FROM fs_symlink WHERE ino = {}", link),
+        "INSERT INTO fs_dentry (name, parent_ino, ino) VALUES ('ghost', 1, 4242)".to_string(),
+    ] {
+        raw.query(&query, vec![]).await.unwrap();
+    }
+    raw.put("__fs_data:4243:0", b"stale".as_slice().into()).await.unwrap();
+
+    let report = agentfs.fs.fsck(false).await.unwrap();
+    assert_eq!(report.orphan_inodes, vec![a]);
+    assert_eq!(report.dangling_dentries.len(), 1);
+    assert_eq!(report.dangling_dentries[0].name, "ghost");
+    assert_eq!(report.orphan_data_keys, vec!["__fs_data:4243:0".to_string()]);
+    assert_eq!(report.symlinks_without_target, vec![link]);
+    assert_eq!(report.size_mismatches.len(), 1);
+    assert_eq!(report.size_mismatches[0].actual, 5);
+    assert!(!report.repaired);
+
+    let report = agentfs.fs.fsck(true).await.unwrap();
+    assert_eq!(report.problem_count(), 5);
+    assert!(report.repaired);
+
+    // The orphan is readable from lost+found and nothing is left to fix
+    let recovered = format!("/lost+found/#{}", a);
+    assert_eq!(agentfs.fs.read_file(&recovered).await.unwrap().unwrap(), b"hello");
+    assert!(!agentfs.fs.exists("/ghost").await.unwrap());
+    assert!(!agentfs.fs.exists("/link").await.unwrap());
+    assert_eq!(agentfs.fs.stat("/docs/b.txt").await.unwrap().unwrap().size, 5);
+    assert!(agentfs.fs.fsck(false).await.unwrap().is_clean());
+}
+
+#[tokio::test]
+async fn test_fsck_is_scoped_to_volume() {
+    use agentfs::DbFileSystem;
+
+    let backend = Arc::new(SqlBackend::sqlite(":memory:").await.unwrap());
+    let db: Arc<Box<dyn AgentDB>> = Arc::new(Box::new(SharedDb(backend.clone())));
+    let alice = DbFileSystem::open_volume(db.clone(), "alice", "/".to_string()).await.unwrap();
+    let bob = DbFileSystem::open_volume(db, "bob", "/".to_string()).await.unwrap();
+    alice.write_file("/a.txt", b"alice").await.unwrap();
+    bob.write_file("/b.txt", b"bob").await.unwrap();
+
+    // Corrupt bob's volume only, as if a crash came before the new file's
+    // entry and change record were written
+    assert_eq!(alice.root_ino(), 1);
+    let b = bob.stat("/b.txt").await.unwrap().unwrap().ino;
+    for query in [
+        format!("DELETE FROM fs_dentry WHERE ino = {}", b),
+        format!("DELETE FROM fs_provenance WHERE ino = {}", b),
+        format!("INSERT INTO fs_dentry (name, parent_ino, ino) VALUES ('ghost', {}, 4242)", bob.root_ino()),
+    ] {
+        backend.query(&query, vec![]).await.unwrap();
+    }
+
+    assert!(alice.fsck(false).await.unwrap().is_clean());
+    let report = bob.fsck(false).await.unwrap();
+    assert_eq!(report.orphan_inodes, vec![b]);
+    assert_eq!(report.dangling_dentries.len(), 1);
+
+    // Alice's repair leaves bob's orphan alone
+    alice.fsck(true).await.unwrap();
+    assert!(!alice.exists("/lost+found").await.unwrap());
+    bob.fsck(true).await.unwrap();
+    let recovered = format!("/lost+found/#{}", b);
+    assert_eq!(bob.read_file(&recovered).await.unwrap().unwrap(), b"bob");
+    assert!(bob.fsck(false).await.unwrap().is_clean());
+}
+
+#[tokio::test]
+async fn test_conditional_writes() {
+    use agentfs::{AgentFsError, Precondition};
+
+    let agentfs = create_test_agentfs().await;
+
+    // IfNoneMatch creates, but only once
+    agentfs.fs.write_file_if("/state.json", b"{}", Precondition::IfNoneMatch).await.unwrap();
+    assert!(matches!(
+        agentfs.fs.write_file_if("/state.json", b"{\"a\":1}", Precondition::IfNoneMatch).await,
+        Err(AgentFsError::PreconditionFailed(_))
+    ));
+
+    // IfMatch succeeds with the current ETag and fails with a stale one
+    let stale = agentfs.fs.stat("/state.json").await.unwrap().unwrap();
+    agentfs
+        .fs
+        .write_file_if("/state.json", b"{\"a\":1}", Precondition::IfMatch(stale.etag()))
+        .await
+        .unwrap();
+    let current = agentfs.fs.stat("/state.json").await.unwrap().unwrap();
+    assert_eq!(current.version, stale.version + 1);
+    assert_ne!(current.etag(), stale.etag());
+    assert!(matches!(
+        agentfs.fs.write_file_if("/state.json", b"lost", Precondition::IfMatch(stale.etag())).await,
+        Err(AgentFsError::PreconditionFailed(_))
+    ));
+    assert_eq!(agentfs.fs.read_file("/state.json").await.unwrap().unwrap(), b"{\"a\":1}");
+
+    // Plain writes bump the version too
+    agentfs.fs.write_file("/state.json", b"{\"a\":2}").await.unwrap();
+    assert!(agentfs
+        .fs
+        .write_file_if("/state.json", b"x", Precondition::IfMatch(current.etag()))
+        .await
+        .is_err());
+
+    // IfUnmodifiedSince compares against mtime and requires the file to exist
+    let mtime = agentfs.fs.stat("/state.json").await.unwrap().unwrap().mtime;
+    agentfs
+        .fs
+        .write_file_if("/state.json", b"{\"a\":3}", Precondition::IfUnmodifiedSince(mtime))
+        .await
+        .unwrap();
+    assert!(matches!(
+        agentfs.fs.write_file_if("/state.json", b"x", Precondition::IfUnmodifiedSince(mtime - 1)).await,
+        Err(AgentFsError::PreconditionFailed(_))
+    ));
+    assert!(matches!(
+        agentfs.fs.write_file_if("/missing", b"x", Precondition::IfUnmodifiedSince(mtime)).await,
+        Err(AgentFsError::PreconditionFailed(_))
+    ));
+    assert!(!agentfs.fs.exists("/missing").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_conditional_write_races() {
+    use agentfs::Precondition;
+
+    let (fs, other, hook) = create_interfering_test_fs().await;
+    fs.write_file("/state.json", b"{}").await.unwrap();
+    let etag = fs.stat("/state.json").await.unwrap().unwrap().etag();
+
+    // Right after our write claims the next version, the other writer sees
+    // our content under it and writes on top
+    *hook.lock().unwrap() = Some((
+        "version = version + 1",
+        Box::new(move || {
+            Box::pin(async move {
+                let stats = other.stat("/state.json").await.unwrap().unwrap();
+                assert_eq!(other.read_file("/state.json").await.unwrap().unwrap(), b"{\"a\":1}");
+                other
+                    .write_file_if("/state.json", b"{\"a\":2}", Precondition::IfMatch(stats.etag()))
+                    .await
+                    .unwrap();
+            })
+        }),
+    ));
+    fs.write_file_if("/state.json", b"{\"a\":1}", Precondition::IfMatch(etag)).await.unwrap();
+
+    // The later write is not lost
+    let stats = fs.stat("/state.json").await.unwrap().unwrap();
+    assert_eq!(stats.version, 3);
+    assert_eq!(fs.read_file("/state.json").await.unwrap().unwrap(), b"{\"a\":2}");
+    assert!(fs.fsck(false).await.unwrap().is_clean());
+}
+
+#[tokio::test]
+async fn test_gc_reclaims_unreferenced_data() {
+    use agentfs::GcOptions;
+    use std::time::Duration;
+
+    let (agentfs, raw) = create_shared_test_agentfs().await;
+
+    agentfs.fs.write_file("/keep.txt", b"keep me").await.unwrap();
+    raw.put("__fs_data:9001:0", b"0123456789".as_slice().into()).await.unwrap();
+    raw.query(
+        "INSERT INTO fs_data (ino, offset, size, data) VALUES (9002, 0, 4, X'DEADBEEF')",
+        vec![],
+    )
+    .await
+    .unwrap();
+
+    // A zero budget stops before touching any data
+    let options = GcOptions { budget: Some(Duration::ZERO), ..Default::default() };
+    let report = agentfs.gc_with_options(&options).await.unwrap();
+    assert!(!report.complete);
+    assert_eq!(report.orphan_data_keys, 0);
+
+    let report = agentfs.gc().await.unwrap();
+    assert!(report.complete);
+    assert_eq!(report.orphan_data_keys, 1);
+    assert_eq!(report.dead_chunks, 1);
+    assert_eq!(report.bytes_reclaimed, 14);
+    assert!(raw.get("__fs_data:9001:0").await.unwrap().is_none());
+    assert_eq!(agentfs.fs.read_file("/keep.txt").await.unwrap().unwrap(), b"keep me");
+
+    // Nothing left on the next run
+    let report = agentfs.gc().await.unwrap();
+    assert_eq!(report.orphan_data_keys + report.dead_chunks, 0);
+
+    // Stale entries of a live file go too, once past the grace period
+    let ino = agentfs.fs.stat("/keep.txt").await.unwrap().unwrap().ino;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
+    let stale = format!("__fs_data:{}:{:012x}70008000000000000000", ino, now - 3_600_000);
+    let staged = format!("__fs_data:{}:{:012x}70008000000000000000", ino, now);
+    raw.put(&stale, b"old".as_slice().into()).await.unwrap();
+    raw.put(&staged, b"new".as_slice().into()).await.unwrap();
+    let report = agentfs.gc().await.unwrap();
+    assert_eq!(report.orphan_data_keys, 1);
+    assert!(raw.get(&stale).await.unwrap().is_none());
+    assert!(raw.get(&staged).await.unwrap().is_some());
+    assert_eq!(agentfs.fs.read_file("/keep.txt").await.unwrap().unwrap(), b"keep me");
+    let options = GcOptions { data_grace_period: Duration::ZERO, ..Default::default() };
+    assert_eq!(agentfs.gc_with_options(&options).await.unwrap().orphan_data_keys, 1);
+    assert_eq!(agentfs.fs.read_file("/keep.txt").await.unwrap().unwrap(), b"keep me");
+
+    // Compaction returns the space of removed files
+    agentfs.fs.write_file("/big.bin", &vec![7u8; 1 << 20]).await.unwrap();
+    agentfs.fs.remove("/big.bin").await.unwrap();
+    agentfs.gc().await.unwrap();
+    let report = agentfs.compact().await.unwrap();
+    assert!(report.bytes_before.is_some() && report.bytes_after.is_some());
+    assert!(report.bytes_reclaimed().unwrap() > 0);
+    assert!(agentfs.fs.fsck(false).await.unwrap().is_clean());
+}
+
+#[tokio::test]
+async fn test_content_metadata() {
+    let agentfs = create_test_agentfs().await;
+
+    agentfs.fs.mkdir("/out").await.unwrap();
+    agentfs.fs.write_file("/out/chart", b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR").await.unwrap();
+    agentfs.fs.write_file("/out/data.json", b"{\"a\": 1}\n").await.unwrap();
+    agentfs.fs.write_file("/out/notes.txt", "first\nsecond\nthird".as_bytes()).await.unwrap();
+    agentfs.fs.write_file("/out/report.pdf", b"%PDF-1.7\n...").await.unwrap();
+    agentfs.fs.write_file("/out/blob", b"\x00\x01\x02\xff").await.unwrap();
+    agentfs.fs.symlink("/out/notes.txt", "/out/link").await.unwrap();
+
+    let stats = agentfs.fs.stat("/out/notes.txt").await.unwrap().unwrap();
+    assert_eq!(stats.content_type.as_deref(), Some("text/plain"));
+    assert_eq!(stats.line_count, Some(3));
+    assert_eq!(stats.encoding.as_deref(), Some("utf-8"));
+
+    let entries = agentfs.fs.readdir_plus("/out").await.unwrap().unwrap();
+    let types: Vec<(&str, Option<&str>)> = entries
+        .iter()
+        .map(|e| (e.name.as_str(), e.stats.content_type.as_deref()))
+        .collect();
+    assert_eq!(
+        types,
+        vec![
+            ("blob", Some("application/octet-stream")),
+            ("chart", Some("image/png")),
+            ("data.json", Some("application/json")),
+            ("link", None),
+            ("notes.txt", Some("text/plain")),
+            ("report.pdf", Some("application/pdf")),
+        ]
+    );
+    assert!(entries[3].stats.is_symlink());
+    assert_eq!(entries[1].stats.line_count, None);
+    assert_eq!(entries[2].stats.line_count, Some(1));
+
+    // Overwriting re-detects the content
+    agentfs.fs.write_file("/out/blob", b"now text\n").await.unwrap();
+    let stats = agentfs.fs.stat("/out/blob").await.unwrap().unwrap();
+    assert_eq!(stats.content_type.as_deref(), Some("text/plain"));
+    assert_eq!(stats.line_count, Some(1));
+
+    // Text starting with "BM" is not a bitmap, but a real BMP header is
+    agentfs.fs.write_file("/out/cars", b"BMW and Audi sales figures\n").await.unwrap();
+    let stats = agentfs.fs.stat("/out/cars").await.unwrap().unwrap();
+    assert_eq!(stats.content_type.as_deref(), Some("text/plain"));
+    let mut bmp = b"BM\x46\x00\x00\x00\x00\x00\x00\x00\x36\x00\x00\x00\x28\x00\x00\x00".to_vec();
+    bmp.resize(70, 0);
+    agentfs.fs.write_file("/out/pixel", &bmp).await.unwrap();
+    let stats = agentfs.fs.stat("/out/pixel").await.unwrap().unwrap();
+    assert_eq!(stats.content_type.as_deref(), Some("image/bmp"));
+}
+
+#[tokio::test]
+async fn test_subsecond_timestamps_and_atime() {
+    use agentfs::AtimePolicy;
+    use std::time::Duration;
+
+    let agentfs = create_test_agentfs().await;
+
+    // Writes within the same second are still ordered by mtime
+    agentfs.fs.write_file("/first.txt", b"1").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    agentfs.fs.write_file("/second.txt", b"2").await.unwrap();
+    let first = agentfs.fs.stat("/first.txt").await.unwrap().unwrap();
+    let second = agentfs.fs.stat("/second.txt").await.unwrap().unwrap();
+    assert!(second.mtime_ns() > first.mtime_ns());
+    assert_eq!(first.mtime, first.mtime_ns() / 1_000_000_000);
+
+    // Creating and removing entries updates the parent directory
+    agentfs.fs.mkdir("/dir").await.unwrap();
+    let before = agentfs.fs.stat("/dir").await.unwrap().unwrap();
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    agentfs.fs.write_file("/dir/file", b"x").await.unwrap();
+    let after_create = agentfs.fs.stat("/dir").await.unwrap().unwrap();
+    assert!(after_create.mtime_ns() > before.mtime_ns());
+    assert!(after_create.ctime_ns() > before.ctime_ns());
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    agentfs.fs.remove("/dir/file").await.unwrap();
+    let after_remove = agentfs.fs.stat("/dir").await.unwrap().unwrap();
+    assert!(after_remove.mtime_ns() > after_create.mtime_ns());
+
+    // noatime leaves atime alone, strictatime updates it on every read
+    let noatime = agentfs.fs.clone().with_atime_policy(AtimePolicy::NoAtime);
+    let atime = agentfs.fs.stat("/first.txt").await.unwrap().unwrap().atime_ns();
+    noatime.read_file("/first.txt").await.unwrap();
+    assert_eq!(agentfs.fs.stat("/first.txt").await.unwrap().unwrap().atime_ns(), atime);
+
+    let strict = agentfs.fs.clone().with_atime_policy(AtimePolicy::StrictAtime);
+    strict.read_file("/first.txt").await.unwrap();
+    let atime1 = agentfs.fs.stat("/first.txt").await.unwrap().unwrap().atime_ns();
+    assert!(atime1 > atime);
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    strict.read_file("/first.txt").await.unwrap();
+    let atime2 = agentfs.fs.stat("/first.txt").await.unwrap().unwrap().atime_ns();
+    assert!(atime2 > atime1);
+
+    // relatime (the default) only updates an atime that isn't newer than mtime
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    agentfs.fs.read_file("/second.txt").await.unwrap();
+    let relatime = agentfs.fs.stat("/second.txt").await.unwrap().unwrap();
+    assert!(relatime.atime_ns() > relatime.mtime_ns());
+    let atime = relatime.atime_ns();
+    agentfs.fs.read_file("/second.txt").await.unwrap();
+    assert_eq!(agentfs.fs.stat("/second.txt").await.unwrap().unwrap().atime_ns(), atime);
+}
+
+#[tokio::test]
+async fn test_file_type_errors() {
+    use agentfs::AgentFsError;
+
+    let agentfs = create_test_agentfs().await;
+    agentfs.fs.mkdir("/dir").await.unwrap();
+    agentfs.fs.write_file("/dir/file.txt", b"data").await.unwrap();
+    agentfs.fs.write_file("/plain.txt", b"data").await.unwrap();
+
+    let err = agentfs.fs.write_file("/dir", b"data").await.unwrap_err();
+    assert!(matches!(err, AgentFsError::IsADirectory(_)));
+    assert_eq!(err.errno(), 21);
+    assert!(matches!(agentfs.fs.read_file("/dir").await, Err(AgentFsError::IsADirectory(_))));
+
+    let err = agentfs.fs.mkdir("/plain.txt/sub").await.unwrap_err();
+    assert!(matches!(err, AgentFsError::NotADirectory(_)));
+    assert_eq!(err.errno(), 20);
+    assert!(matches!(
+        agentfs.fs.write_file("/plain.txt/child", b"x").await,
+        Err(AgentFsError::NotADirectory(_))
+    ));
+    assert!(matches!(agentfs.fs.readdir("/plain.txt").await, Err(AgentFsError::NotADirectory(_))));
+    assert!(matches!(agentfs.fs.stat("/plain.txt/child").await, Err(AgentFsError::NotADirectory(_))));
+    assert!(!agentfs.fs.exists("/plain.txt/child").await.unwrap());
+
+    let err = agentfs.fs.remove("/dir").await.unwrap_err();
+    assert!(matches!(err, AgentFsError::DirectoryNotEmpty(_)));
+    assert_eq!(err.errno(), 39);
+
+    let err = agentfs.fs.readlink("/plain.txt").await.unwrap_err();
+    assert!(matches!(err, AgentFsError::NotASymlink(_)));
+    assert_eq!(err.errno(), 22);
+
+    assert!(matches!(agentfs.fs.rename("/plain.txt", "/dir").await, Err(AgentFsError::IsADirectory(_))));
+    assert!(matches!(agentfs.fs.rename("/dir", "/plain.txt").await, Err(AgentFsError::NotADirectory(_))));
+    assert_eq!(agentfs.fs.read_file("/dir/file.txt").await.unwrap().unwrap(), b"data");
+    assert_eq!(AgentFsError::FileNotFound("/x".to_string()).errno(), 2);
+}
+
+#[tokio::test]
+async fn test_path_policy() {
+    use agentfs::{AgentFsError, PathPolicy};
+
+    let agentfs = create_test_agentfs().await;
+
+    // The default policy clamps traversal and accepts any name
+    agentfs.fs.write_file("/../../clamped.txt", b"x").await.unwrap();
+    assert!(agentfs.fs.exists("/clamped.txt").await.unwrap());
+
+    let fs = agentfs.fs.clone().with_path_policy(PathPolicy::strict());
+    assert!(matches!(fs.read_file("/../../etc/passwd").await, Err(AgentFsError::PathTraversal(_))));
+    assert!(matches!(fs.read_file("/a/../../etc").await, Err(AgentFsError::PathTraversal(_))));
+    assert!(fs.exists("/a/../clamped.txt").await.unwrap());
+    assert!(matches!(fs.write_file("/bad\0name", b"x").await, Err(AgentFsError::InvalidPath(_))));
+    assert!(matches!(fs.mkdir("/tab\tdir").await, Err(AgentFsError::InvalidPath(_))));
+    assert!(matches!(fs.write_file(&format!("/{}", "n".repeat(256)), b"x").await, Err(AgentFsError::InvalidPath(_))));
+    assert!(matches!(fs.stat(&"/d".repeat(2049)).await, Err(AgentFsError::InvalidPath(_))));
+
+    // Composed and decomposed forms of "café" name the same file
+    fs.write_file("/caf\u{e9}.txt", b"nfc").await.unwrap();
+    fs.write_file("/cafe\u{301}.txt", b"nfd").await.unwrap();
+    assert_eq!(fs.readdir("/").await.unwrap().unwrap(), vec!["caf\u{e9}.txt", "clamped.txt"]);
+    assert_eq!(fs.read_file("/caf\u{e9}.txt").await.unwrap().unwrap(), b"nfd");
+}
+
+#[tokio::test]
+async fn test_permission_enforcement() {
+    use agentfs::{AgentFsError, Credentials};
+
+    let agentfs = create_test_agentfs().await;
+    agentfs.fs.mkdir("/shared").await.unwrap();
+    agentfs.fs.chmod("/shared", 0o777).await.unwrap();
+    agentfs.fs.mkdir("/private").await.unwrap();
+    agentfs.fs.chmod("/private", 0o700).await.unwrap();
+    agentfs.fs.write_file("/private/secret.txt", b"secret").await.unwrap();
+    agentfs.fs.write_file("/readme.txt", b"public").await.unwrap();
+
+    let alice = agentfs.fs.as_user(Credentials::new(1000, 1000));
+    let bob = agentfs.fs.as_user(Credentials::new(1001, 1001).with_groups([1000]));
+
+    // Reading world-readable files works, writing root-owned ones doesn't
+    assert_eq!(alice.read_file("/readme.txt").await.unwrap().unwrap(), b"public");
+    assert!(matches!(alice.write_file("/readme.txt", b"x").await, Err(AgentFsError::PermissionDenied(_))));
+    assert!(matches!(alice.write_file("/new.txt", b"x").await, Err(AgentFsError::PermissionDenied(_))));
+
+    // No search permission on /private
+    let err = alice.read_file("/private/secret.txt").await.unwrap_err();
+    assert!(matches!(err, AgentFsError::PermissionDenied(_)));
+    assert_eq!(err.errno(), 13);
+    assert!(alice.stat("/private/secret.txt").await.is_err());
+
+    // New files belong to their creator
+    alice.write_file("/shared/notes.txt", b"alice").await.unwrap();
+    let stats = agentfs.fs.stat("/shared/notes.txt").await.unwrap().unwrap();
+    assert_eq!((stats.uid, stats.gid), (1000, 1000));
+
+    // Group bits apply to group members
+    alice.chmod("/shared/notes.txt", 0o640).await.unwrap();
+    assert_eq!(bob.read_file("/shared/notes.txt").await.unwrap().unwrap(), b"alice");
+    assert!(bob.write_file("/shared/notes.txt", b"bob").await.is_err());
+    assert!(bob.chmod("/shared/notes.txt", 0o666).await.is_err());
+    assert!(bob.chown("/shared/notes.txt", 1001, 1001).await.is_err());
+    alice.chmod("/shared/notes.txt", 0o600).await.unwrap();
+    assert!(bob.read_file("/shared/notes.txt").await.is_err());
+
+    // Root bypasses checks; handles without credentials don't enforce at all
+    let root = agentfs.fs.as_user(Credentials::new(0, 0));
+    assert_eq!(root.read_file("/shared/notes.txt").await.unwrap().unwrap(), b"alice");
+    root.chown("/shared/notes.txt", 1001, 1001).await.unwrap();
+    assert_eq!(bob.read_file("/shared/notes.txt").await.unwrap().unwrap(), b"alice");
+    assert_eq!(agentfs.fs.read_file("/private/secret.txt").await.unwrap().unwrap(), b"secret");
+}
+
+/// Filesystem behavior every `FileSystem` implementation must share
+async fn check_filesystem_semantics<F: FileSystem>(fs: &F) {
+    use agentfs::AgentFsError;
+
+    fs.mkdir("/dir").await.unwrap();
+    fs.write_file("/dir/a.txt", b"hello").await.unwrap();
+    fs.symlink("/dir/a.txt", "/link").await.unwrap();
+    fs.symlink("dir", "/dirlink").await.unwrap();
+
+    let dir = fs.stat("/dir").await.unwrap().unwrap();
+    assert_eq!(dir.ino, fs.stat("/dirlink").await.unwrap().unwrap().ino);
+    assert_ne!(dir.ino, fs.stat("/").await.unwrap().unwrap().ino);
+    assert!(dir.is_directory());
+    let file = fs.stat("/link").await.unwrap().unwrap();
+    assert!(file.is_file());
+    assert_eq!((file.size, file.nlink), (5, 1));
+    assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+    assert!(fs.lstat("/link").await.unwrap().unwrap().is_symlink());
+    assert_eq!(fs.readlink("/link").await.unwrap().unwrap(), "/dir/a.txt");
+    assert_eq!(fs.read_file("/dirlink/a.txt").await.unwrap().unwrap(), b"hello");
+    assert_eq!(fs.readdir("/").await.unwrap().unwrap(), vec!["dir", "dirlink", "link"]);
+
+    // Writes through a symlink land on its target
+    fs.write_file("/link", b"bye").await.unwrap();
+    assert_eq!(fs.read_file("/dir/a.txt").await.unwrap().unwrap(), b"bye");
+    assert_eq!(fs.stat("/dir/a.txt").await.unwrap().unwrap().version, 2);
+
+    // Errors
+    assert!(matches!(fs.mkdir("/dir").await, Err(AgentFsError::PathExists(_))));
+    assert!(matches!(fs.write_file("/dir", b"x").await, Err(AgentFsError::IsADirectory(_))));
+    assert!(matches!(fs.write_file("/missing/x", b"x").await, Err(AgentFsError::DirectoryNotFound(_))));
+    assert!(matches!(fs.mkdir("/dir/a.txt/sub").await, Err(AgentFsError::NotADirectory(_))));
+    assert!(matches!(fs.remove("/dir").await, Err(AgentFsError::DirectoryNotEmpty(_))));
+    assert!(matches!(fs.remove("/nope").await, Err(AgentFsError::FileNotFound(_))));
+    assert!(matches!(fs.readlink("/dir").await, Err(AgentFsError::NotASymlink(_))));
+    fs.symlink("/loop", "/loop").await.unwrap();
+    assert!(matches!(fs.stat("/loop").await, Err(AgentFsError::SymlinkLoop(_))));
+    assert!(fs.read_file("/nope").await.unwrap().is_none());
+    assert!(!fs.exists("/dir/a.txt/child").await.unwrap());
+
+    // Rename and remove
+    fs.rename("/dir/a.txt", "/b.txt").await.unwrap();
+    assert!(fs.exists("/b.txt").await.unwrap());
+    fs.remove("/b.txt").await.unwrap();
+    fs.remove("/dir").await.unwrap();
+    fs.write_file("/c.txt", b"").await.unwrap();
+
+    // A new file gets an inode of its own
+    let c = fs.stat("/c.txt").await.unwrap().unwrap().ino;
+    for path in ["/", "/link", "/dirlink", "/loop"] {
+        assert_ne!(fs.lstat(path).await.unwrap().unwrap().ino, c);
+    }
+}
+
+#[tokio::test]
+async fn test_in_memory_agentfs() {
+    let db = create_test_agentfs().await;
+    check_filesystem_semantics(&db.fs).await;
+
+    let agentfs = AgentFS::in_memory("scratch");
+    check_filesystem_semantics(&agentfs.fs).await;
+    assert_eq!(agentfs.agent_id(), "scratch");
+
+    agentfs.kv.set("user:1", b"alice").await.unwrap();
+    agentfs.kv.set("user:2", b"bob").await.unwrap();
+    agentfs.kv.set("session", b"x").await.unwrap();
+    assert_eq!(agentfs.kv.get("user:1").await.unwrap().unwrap(), b"alice");
+    assert_eq!(agentfs.kv.scan("user:").await.unwrap(), vec!["user:1", "user:2"]);
+    agentfs.kv.delete("session").await.unwrap();
+    assert!(!agentfs.kv.exists("session").await.unwrap());
+
+    let id = agentfs.tools.start("search", None).await.unwrap();
+    agentfs.tools.success(id, Some(serde_json::json!({"hits": 3}))).await.unwrap();
+    agentfs.tools.record("search", 100, 102, None, None, Some("timeout")).await.unwrap();
+    let stats = agentfs.tools.stats_for("search").await.unwrap().unwrap();
+    assert_eq!((stats.total_calls, stats.successful, stats.failed), (2, 1, 1));
+    assert_eq!(agentfs.tools.list(Some(1)).await.unwrap()[0].id, id);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_host_filesystem_sandbox() {
+    use agentfs::{AgentFsError, HostFileSystem};
+
+    let base = std::env::temp_dir().join(format!("agentfs-host-{}", uuid::Uuid::new_v4()));
+    let root = base.join("root");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(base.join("secret.txt"), b"outside").unwrap();
+
+    let fs = HostFileSystem::new(&root, "/agent").unwrap();
+    fs.mkdir("/agent/docs").await.unwrap();
+    fs.write_file("/docs/a.txt", b"hello").await.unwrap();
+    assert_eq!(std::fs::read(root.join("docs/a.txt")).unwrap(), b"hello");
+    assert_eq!(fs.read_file("/docs/a.txt").await.unwrap().unwrap(), b"hello");
+    assert_eq!(fs.readdir("/").await.unwrap().unwrap(), vec!["docs"]);
+    let stats = fs.stat("/docs/a.txt").await.unwrap().unwrap();
+    assert!(stats.is_file());
+    assert_eq!(stats.size, 5);
+
+    // Symlinks resolve inside the root, whatever the host would do with them
+    fs.symlink("/docs/a.txt", "/abs").await.unwrap();
+    assert_eq!(fs.read_file("/abs").await.unwrap().unwrap(), b"hello");
+    std::os::unix::fs::symlink("../secret.txt", root.join("escape")).unwrap();
+    std::os::unix::fs::symlink(base.join("secret.txt"), root.join("escape_abs")).unwrap();
+    assert!(fs.read_file("/escape").await.unwrap().is_none());
+    assert!(fs.read_file("/escape_abs").await.unwrap().is_none());
+    assert!(fs.read_file("/../secret.txt").await.unwrap().is_none());
+
+    // Writing through an escaping symlink creates the file inside the root
+    fs.write_file("/escape", b"contained").await.unwrap();
+    assert_eq!(std::fs::read(base.join("secret.txt")).unwrap(), b"outside");
+    assert_eq!(std::fs::read(root.join("secret.txt")).unwrap(), b"contained");
+
+    // Same errors as the other implementations
+    assert!(matches!(fs.mkdir("/docs").await, Err(AgentFsError::PathExists(_))));
+    assert!(matches!(fs.write_file("/docs", b"x").await, Err(AgentFsError::IsADirectory(_))));
+    assert!(matches!(fs.remove("/docs").await, Err(AgentFsError::DirectoryNotEmpty(_))));
+    assert!(matches!(fs.readlink("/docs").await, Err(AgentFsError::NotASymlink(_))));
+    fs.symlink("/loop", "/loop").await.unwrap();
+    assert!(matches!(fs.stat("/loop").await, Err(AgentFsError::SymlinkLoop(_))));
+
+    fs.rename("/docs/a.txt", "/b.txt").await.unwrap();
+    assert!(root.join("b.txt").exists());
+    fs.remove("/b.txt").await.unwrap();
+    fs.remove("/docs").await.unwrap();
+    assert!(!fs.exists("/docs").await.unwrap());
+
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_host_filesystem_directory_swap() {
+    use agentfs::HostFileSystem;
+    use rustix::fs::{CWD, RenameFlags};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+
+    let base = std::env::temp_dir().join(format!("agentfs-swap-{}", uuid::Uuid::new_v4()));
+    let root = base.join("root");
+    let outside = base.join("outside");
+    // A deep tree, mirrored outside, keeps resolution busy below the
+    // directory that gets swapped
+    let deep = vec!["d"; 32].join("/");
+    std::fs::create_dir_all(root.join("dir").join(&deep)).unwrap();
+    std::fs::create_dir_all(outside.join(&deep)).unwrap();
+    std::fs::write(root.join("dir").join(&deep).join("file.txt"), b"inside").unwrap();
+    std::fs::write(outside.join(&deep).join("file.txt"), b"outside").unwrap();
+    let file = format!("/dir/{}/file.txt", deep);
+    let new = format!("/dir/{}/new.txt", deep);
+
+    // Another process keeps swapping the directory with a symlink leading
+    // out of the root
+    let stop = Arc::new(AtomicBool::new(false));
+    let swapper = {
+        let (root, outside, stop) = (root.clone(), outside.clone(), stop.clone());
+        std::thread::spawn(move || {
+            let (dir, link) = (root.join("dir"), root.join("link"));
+            std::os::unix::fs::symlink(&outside, &link).unwrap();
+            let mut swaps = 0;
+            while !stop.load(Ordering::Relaxed) || swaps % 2 == 1 {
+                rustix::fs::renameat_with(CWD, &dir, CWD, &link, RenameFlags::EXCHANGE).unwrap();
+                swaps += 1;
+                // Waking up interrupts the filesystem at arbitrary points
+                std::thread::sleep(Duration::from_micros(20));
+            }
+        })
+    };
+
+    // Operations may fail while the swap is going on, but never reach outside
+    let fs = HostFileSystem::new(&root, "/agent").unwrap();
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(500) {
+        if let Ok(Some(content)) = fs.read_file(&file).await {
+            assert_eq!(content, b"inside");
+        }
+        let _ = fs.write_file(&new, b"new").await;
+        let _ = fs.remove(&new).await;
+    }
+    stop.store(true, Ordering::Relaxed);
+    swapper.join().unwrap();
+
+    assert!(!outside.join(&deep).join("new.txt").exists());
+    assert_eq!(std::fs::read(outside.join(&deep).join("file.txt")).unwrap(), b"outside");
+    assert_eq!(fs.read_file(&file).await.unwrap().unwrap(), b"inside");
+
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+#[tokio::test]
+async fn test_copy_shares_data() {
+    use agentfs::{AgentFsError, Precondition};
+
+    let (agentfs, raw) = create_shared_test_agentfs().await;
+    let fs = &agentfs.fs;
+    fs.mkdir("/template").await.unwrap();
+    fs.mkdir("/template/src").await.unwrap();
+    fs.write_file("/template/README.md", b"# Task").await.unwrap();
+    fs.write_file("/template/src/main.rs", b"fn main() {}\n").await.unwrap();
+    fs.symlink("src/main.rs", "/template/entry").await.unwrap();
+    fs.chmod("/template/README.md", 0o600).await.unwrap();
+    fs.set_xattr("/template/README.md", "user.origin", b"template").await.unwrap();
+    let data_keys = raw.scan("__fs_data:").await.unwrap().keys.len();
+
+    fs.copy_dir("/template", "/task1").await.unwrap();
+    assert_eq!(fs.readdir("/task1").await.unwrap().unwrap(), vec!["README.md", "entry", "src"]);
+    assert_eq!(fs.read_file("/task1/entry").await.unwrap().unwrap(), b"fn main() {}\n");
+    assert_eq!(fs.readlink("/task1/entry").await.unwrap().unwrap(), "src/main.rs");
+    let original = fs.stat("/template/README.md").await.unwrap().unwrap();
+    let copy = fs.stat("/task1/README.md").await.unwrap().unwrap();
+    assert_ne!(copy.ino, original.ino);
+    assert_eq!((copy.mode, copy.size, copy.mtime), (original.mode, original.size, original.mtime));
+    assert_eq!(copy.content_type.as_deref(), Some("text/markdown"));
+    assert_eq!(fs.get_xattr("/task1/README.md", "user.origin").await.unwrap().unwrap(), b"template");
+    // No content was duplicated
+    assert_eq!(raw.scan("__fs_data:").await.unwrap().keys.len(), data_keys);
+
+    // Writes diverge without affecting the other side
+    fs.write_file("/task1/README.md", b"# Task 1").await.unwrap();
+    assert_eq!(fs.read_file("/template/README.md").await.unwrap().unwrap(), b"# Task");
+    assert_eq!(raw.scan("__fs_data:").await.unwrap().keys.len(), data_keys + 1);
+    fs.write_file("/template/src/main.rs", b"fn main() { todo!() }\n").await.unwrap();
+    assert_eq!(fs.read_file("/task1/src/main.rs").await.unwrap().unwrap(), b"fn main() {}\n");
+
+    // A failed conditional write to a copy keeps the shared content
+    fs.copy("/template/README.md", "/readme-copy.md").await.unwrap();
+    let stale = fs.stat("/readme-copy.md").await.unwrap().unwrap();
+    fs.write_file("/readme-copy.md", b"# Draft").await.unwrap();
+    fs.copy("/template/README.md", "/readme-copy.md").await.unwrap();
+    assert!(matches!(
+        fs.write_file_if("/readme-copy.md", b"lost", Precondition::IfMatch(stale.etag())).await,
+        Err(AgentFsError::PreconditionFailed(_))
+    ));
+    assert_eq!(fs.read_file("/readme-copy.md").await.unwrap().unwrap(), b"# Task");
+    assert_eq!(fs.stat("/readme-copy.md").await.unwrap().unwrap().size, 6);
+    fs.remove("/readme-copy.md").await.unwrap();
+
+    // Removing the original keeps the copy's data
+    fs.copy("/task1/src/main.rs", "/main.rs").await.unwrap();
+    fs.remove("/task1/src/main.rs").await.unwrap();
+    assert_eq!(fs.read_file("/main.rs").await.unwrap().unwrap(), b"fn main() {}\n");
+    assert!(fs.fsck(false).await.unwrap().is_clean());
+
+    // Copying over an existing file keeps its inode
+    let target = fs.stat("/task1/README.md").await.unwrap().unwrap();
+    fs.copy("/template/README.md", "/task1/README.md").await.unwrap();
+    let overwritten = fs.stat("/task1/README.md").await.unwrap().unwrap();
+    assert_eq!(overwritten.ino, target.ino);
+    assert_eq!(overwritten.version, target.version + 1);
+    assert_eq!(fs.read_file("/task1/README.md").await.unwrap().unwrap(), b"# Task");
+
+    assert!(matches!(fs.copy("/template", "/x").await, Err(AgentFsError::IsADirectory(_))));
+    assert!(matches!(fs.copy_dir("/template", "/task1").await, Err(AgentFsError::PathExists(_))));
+    assert!(matches!(fs.copy_dir("/template", "/template/nested").await, Err(AgentFsError::InvalidPath(_))));
+
+    // The in-memory filesystem shares content the same way
+    let mem = AgentFS::in_memory("scratch");
+    mem.fs.mkdir("/a").await.unwrap();
+    mem.fs.write_file("/a/f.txt", b"shared").await.unwrap();
+    mem.fs.copy_dir("/a", "/b").await.unwrap();
+    mem.fs.write_file("/b/f.txt", b"changed").await.unwrap();
+    assert_eq!(mem.fs.read_file("/a/f.txt").await.unwrap().unwrap(), b"shared");
+}
+
+#[tokio::test]
+async fn test_disk_usage_and_quota() {
+    use agentfs::{AgentFsError, Credentials, Quota};
+    use std::time::{Duration, SystemTime};
+
+    let agentfs = create_test_agentfs().await;
+    let fs = &agentfs.fs;
+    fs.mkdir("/data").await.unwrap();
+    fs.mkdir("/data/raw").await.unwrap();
+    fs.write_file("/data/small.txt", b"abc").await.unwrap();
+    fs.write_file("/data/raw/big.bin", &[0u8; 1000]).await.unwrap();
+    fs.write_file("/other.txt", b"0123456789").await.unwrap();
+    fs.symlink("/data/raw/big.bin", "/data/link").await.unwrap();
+
+    let usage = fs.du("/data").await.unwrap();
+    assert_eq!(usage.bytes, 1003);
+    assert_eq!((usage.files, usage.directories, usage.inodes), (2, 2, 5));
+    assert_eq!(fs.du("/").await.unwrap().bytes, 1013);
+
+    let largest = fs.largest_files("/", 2).await.unwrap();
+    let paths: Vec<&str> = largest.iter().map(|entry| entry.path.as_str()).collect();
+    assert_eq!(paths, vec!["/data/raw/big.bin", "/other.txt"]);
+    fs.write_file("/data/small.txt", b"abcd").await.unwrap();
+    let recent = fs.recently_modified("/data", 10).await.unwrap();
+    assert_eq!(recent[0].path, "/data/small.txt");
+    assert_eq!(recent[0].stats.size, 4);
+    assert_eq!(recent.len(), 2);
+
+    // Copies count towards apparent size only
+    fs.copy("/data/raw/big.bin", "/big-copy.bin").await.unwrap();
+    let stats = fs.statfs().await.unwrap();
+    assert_eq!((stats.used_bytes, stats.apparent_bytes), (1014, 2014));
+    assert_eq!(stats.free_bytes, None);
+
+    let limited = fs.clone().with_quota(Quota { max_bytes: Some(1100), max_inodes: Some(10) });
+    let stats = limited.statfs().await.unwrap();
+    assert_eq!(stats.free_bytes, Some(86));
+    assert_eq!(stats.free_inodes, Some(10 - stats.inodes));
+
+    limited.write_file("/fits.txt", &[1u8; 80]).await.unwrap();
+    let err = limited.write_file("/too-big.txt", &[1u8; 80]).await.unwrap_err();
+    assert!(matches!(err, AgentFsError::QuotaExceeded(_)));
+    assert_eq!(err.errno(), 122);
+    assert!(!limited.exists("/too-big.txt").await.unwrap());
+    // Shrinking a file frees its space
+    limited.write_file("/fits.txt", b"x").await.unwrap();
+    limited.write_file("/too-big.txt", &[1u8; 80]).await.unwrap();
+
+    while limited.statfs().await.unwrap().free_inodes != Some(0) {
+        let n = limited.statfs().await.unwrap().inodes;
+        limited.mkdir(&format!("/dir{}", n)).await.unwrap();
+    }
+    assert!(matches!(limited.mkdir("/one-more").await, Err(AgentFsError::QuotaExceeded(_))));
+
+    // The totals follow every change without recounting, as a recount shows
+    fs.write_file("/big-copy.bin", &[2u8; 500]).await.unwrap();
+    fs.copy("/other.txt", "/data/small.txt").await.unwrap();
+    fs.copy("/data/raw/big.bin", "/big-copy2.bin").await.unwrap();
+    fs.remove("/data/raw/big.bin").await.unwrap();
+    fs.remove("/data/link").await.unwrap();
+    fs.remove("/fits.txt").await.unwrap();
+    let counted = fs.statfs().await.unwrap();
+    assert_eq!((counted.used_bytes, counted.apparent_bytes), (1590, 1600));
+    assert!(fs.fsck(true).await.unwrap().is_clean());
+    assert_eq!(fs.statfs().await.unwrap(), counted);
+
+    // Callers don't see below directories they may not list, and nobody
+    // sees expired files
+    fs.mkdir("/reports").await.unwrap();
+    fs.mkdir("/reports/private").await.unwrap();
+    for n in 0..300 {
+        fs.write_file(&format!("/reports/private/{n}.bin"), &[0u8; 100]).await.unwrap();
+    }
+    fs.chmod("/reports/private", 0o700).await.unwrap();
+    fs.write_file("/reports/public.txt", b"public").await.unwrap();
+    fs.write_file("/reports/stale.txt", &[0u8; 50]).await.unwrap();
+    fs.set_expiry("/reports/stale.txt", Some(SystemTime::now() - Duration::from_secs(1))).await.unwrap();
+    let usage = fs.du("/reports").await.unwrap();
+    assert_eq!((usage.bytes, usage.files, usage.directories, usage.inodes), (30006, 301, 2, 303));
+    let agent = fs.as_user(Credentials::new(1000, 1000));
+    let usage = agent.du("/reports").await.unwrap();
+    assert_eq!((usage.bytes, usage.files, usage.directories, usage.inodes), (6, 1, 2, 3));
+    let largest = agent.largest_files("/reports", 2).await.unwrap();
+    let paths: Vec<&str> = largest.iter().map(|entry| entry.path.as_str()).collect();
+    assert_eq!(paths, vec!["/reports/public.txt"]);
+    assert_eq!(fs.largest_files("/reports", 1000).await.unwrap().len(), 301);
+}
+
+#[tokio::test]
+async fn test_agent_volumes_are_isolated() {
+    use agentfs::{DbFileSystem, LockMode};
+    use std::time::Duration;
+
+    let backend = Arc::new(SqlBackend::sqlite(":memory:").await.unwrap());
+
+    // A database written before volumes existed
+    let legacy_db: Arc<Box<dyn AgentDB>> = Arc::new(Box::new(SharedDb(backend.clone())));
+    let legacy = DbFileSystem::new(legacy_db, "/agent".to_string());
+    legacy.write_file("/notes.txt", b"from before").await.unwrap();
+
+    // New volumes never pick up the existing tree on their own
+    let carol = AgentFS::new(Box::new(SharedDb(backend.clone())), "carol", "/agent").await.unwrap();
+    assert_ne!(carol.fs.root_ino(), 1);
+    assert!(carol.fs.read_file("/notes.txt").await.unwrap().is_none());
+
+    // Adopting binds it to a volume, once
+    let db: Arc<Box<dyn AgentDB>> = Arc::new(Box::new(SharedDb(backend.clone())));
+    DbFileSystem::adopt_legacy_tree(db.clone(), "alice", "/agent".to_string()).await.unwrap();
+    DbFileSystem::adopt_legacy_tree(db.clone(), "alice", "/agent".to_string()).await.unwrap();
+    assert!(DbFileSystem::adopt_legacy_tree(db.clone(), "bob", "/agent".to_string()).await.is_err());
+    assert!(DbFileSystem::adopt_legacy_tree(db, "carol", "/agent".to_string()).await.is_err());
+
+    let alice = AgentFS::new(Box::new(SharedDb(backend.clone())), "alice", "/agent").await.unwrap();
+    let bob = AgentFS::new(Box::new(SharedDb(backend.clone())), "bob", "/agent").await.unwrap();
+    assert_eq!(alice.fs.root_ino(), 1);
+    assert_ne!(bob.fs.root_ino(), 1);
+    assert_eq!(alice.fs.read_file("/notes.txt").await.unwrap().unwrap(), b"from before");
+    assert!(bob.fs.read_file("/notes.txt").await.unwrap().is_none());
+
+    alice.fs.mkdir("/shared").await.unwrap();
+    bob.fs.mkdir("/shared").await.unwrap();
+    alice.fs.write_file("/shared/name.txt", b"alice").await.unwrap();
+    bob.fs.write_file("/shared/name.txt", b"bob").await.unwrap();
+    assert_eq!(alice.fs.read_file("/shared/name.txt").await.unwrap().unwrap(), b"alice");
+    assert_eq!(bob.fs.read_file("/shared/name.txt").await.unwrap().unwrap(), b"bob");
+    assert_eq!(bob.fs.readdir("/").await.unwrap().unwrap(), vec!["shared".to_string()]);
+    assert!(bob.fs.remove("/").await.is_err());
+
+    // Changes, locks and usage are per volume
+    let bob_changes = bob.fs.changes_since(0, None).await.unwrap();
+    assert!(bob_changes.iter().all(|event| event.path.starts_with("/shared")));
+    let _guard = alice.fs.lock("/shared/name.txt", LockMode::Exclusive, Duration::from_secs(30)).await.unwrap();
+    bob.fs.lock("/shared/name.txt", LockMode::Exclusive, Duration::from_secs(30)).await.unwrap();
+    assert_eq!(bob.fs.statfs().await.unwrap().apparent_bytes, 3);
+    assert!(bob.fs.fsck(false).await.unwrap().is_clean());
+
+    // Reopening a volume finds the same tree
+    let bob_again = AgentFS::new(Box::new(SharedDb(backend.clone())), "bob", "/agent").await.unwrap();
+    assert_eq!(bob_again.fs.root_ino(), bob.fs.root_ino());
+    assert_eq!(bob_again.fs.read_file("/shared/name.txt").await.unwrap().unwrap(), b"bob");
+
+    let names: Vec<String> = bob.fs.list_volumes().await.unwrap().into_iter().map(|v| v.name).collect();
+    assert_eq!(names, vec!["alice", "bob", "carol"]);
+}
+
+#[tokio::test]
+async fn test_trash_restore_and_purge() {
+    use agentfs::{AgentFsError, Credentials};
+    use std::time::Duration;
+
+    let (agentfs, backend) = create_shared_test_agentfs().await;
+    let fs = agentfs.fs.clone().with_trash(true);
+    fs.mkdir("/docs").await.unwrap();
+    fs.write_file("/docs/plan.md", b"version 1").await.unwrap();
+    fs.write_file("/docs/notes.md", b"notes").await.unwrap();
+
+    fs.remove("/docs/plan.md").await.unwrap();
+    assert!(!fs.exists("/docs/plan.md").await.unwrap());
+    let trash = fs.list_trash().await.unwrap();
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].path, "/docs/plan.md");
+    assert_eq!(trash[0].stats.size, 9);
+    assert!(fs.fsck(false).await.unwrap().is_clean());
+
+    // Restoring puts the same inode back, unless the path was reused
+    fs.write_file("/docs/plan.md", b"version 2").await.unwrap();
+    assert!(matches!(fs.restore("/docs/plan.md").await, Err(AgentFsError::PathExists(_))));
+    fs.remove("/docs/plan.md").await.unwrap();
+    fs.restore("/docs/plan.md").await.unwrap();
+    assert_eq!(fs.read_file("/docs/plan.md").await.unwrap().unwrap(), b"version 2");
+    assert_eq!(fs.list_trash().await.unwrap()[0].stats.size, 9);
+    assert!(matches!(fs.restore("/docs/missing.md").await, Err(AgentFsError::FileNotFound(_))));
+
+    // Nothing is purged before it is old enough
+    fs.remove("/docs/notes.md").await.unwrap();
+    assert_eq!(fs.empty_trash(Duration::from_secs(3600)).await.unwrap(), 0);
+    let data_keys = backend.scan("__fs_data:").await.unwrap().keys.len();
+    assert_eq!(fs.empty_trash(Duration::ZERO).await.unwrap(), 2);
+    assert!(fs.list_trash().await.unwrap().is_empty());
+    assert_eq!(backend.scan("__fs_data:").await.unwrap().keys.len(), data_keys - 2);
+    assert!(fs.fsck(false).await.unwrap().is_clean());
+
+    // Without trash mode, remove stays permanent
+    agentfs.fs.remove("/docs/plan.md").await.unwrap();
+    assert!(agentfs.fs.list_trash().await.unwrap().is_empty());
+
+    // Callers only see entries removed from directories they may list
+    fs.write_file("/docs/draft.md", b"draft").await.unwrap();
+    fs.remove("/docs/draft.md").await.unwrap();
+    fs.mkdir("/private").await.unwrap();
+    fs.write_file("/private/key.txt", b"secret").await.unwrap();
+    fs.chmod("/private", 0o700).await.unwrap();
+    fs.remove("/private/key.txt").await.unwrap();
+    assert_eq!(fs.list_trash().await.unwrap().len(), 2);
+    let agent = fs.as_user(Credentials::new(1000, 1000));
+    let visible: Vec<String> = agent.list_trash().await.unwrap().into_iter().map(|entry| entry.path).collect();
+    assert_eq!(visible, vec!["/docs/draft.md"]);
+}
+
+#[tokio::test]
+async fn test_expiring_files() {
+    use agentfs::FsEventKind;
+    use std::time::{Duration, SystemTime};
+
+    let (agentfs, backend) = create_shared_test_agentfs().await;
+    let fs = &agentfs.fs;
+    fs.mkdir("/cache").await.unwrap();
+    fs.write_file_with_ttl("/cache/page.html", b"<html>", Duration::from_secs(3600)).await.unwrap();
+    fs.write_file("/cache/keep.txt", b"keep").await.unwrap();
+    assert!(fs.expiry("/cache/page.html").await.unwrap().unwrap() > SystemTime::now());
+    assert_eq!(fs.expiry("/cache/keep.txt").await.unwrap(), None);
+
+    // A TTL past the end of time is refused without writing anything
+    assert!(matches!(
+        fs.write_file_with_ttl("/cache/forever", b"x", Duration::MAX).await,
+        Err(agentfs::AgentFsError::InvalidArgument(_))
+    ));
+    assert!(!fs.exists("/cache/forever").await.unwrap());
+
+    // Once expired, an entry is invisible and its name can be reused
+    let past = SystemTime::now() - Duration::from_secs(1);
+    fs.set_expiry("/cache/page.html", Some(past)).await.unwrap();
+    assert!(fs.stat("/cache/page.html").await.unwrap().is_none());
+    assert!(fs.read_file("/cache/page.html").await.unwrap().is_none());
+    assert_eq!(fs.readdir("/cache").await.unwrap().unwrap(), vec!["keep.txt".to_string()]);
+    // Reads don't write, so the entry is still stored until reused
+    let stored = "SELECT COUNT(*) AS n FROM fs_dentry WHERE name = 'page.html'";
+    assert_eq!(backend.query(stored, vec![]).await.unwrap().rows[0].get("n").unwrap().as_bytes(), b"1");
+    fs.write_file("/cache/page.html", b"fresh").await.unwrap();
+    assert_eq!(fs.read_file("/cache/page.html").await.unwrap().unwrap(), b"fresh");
+    assert_eq!(fs.expiry("/cache/page.html").await.unwrap(), None);
+
+    // The sweeper reclaims entries nobody reuses, whole directories included
+    fs.mkdir("/tmp").await.unwrap();
+    fs.write_file("/tmp/a.txt", b"a").await.unwrap();
+    fs.write_file("/cache/old.txt", b"old").await.unwrap();
+    fs.set_expiry("/tmp", Some(past)).await.unwrap();
+    fs.set_expiry("/cache/old.txt", Some(past)).await.unwrap();
+    let data_keys = backend.scan("__fs_data:").await.unwrap().keys.len();
+    let last_change = fs.changes_since(0, None).await.unwrap().last().unwrap().id;
+
+    let sweeper = agentfs.spawn_sweeper(Duration::from_millis(10));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    sweeper.abort();
+
+    assert_eq!(backend.scan("__fs_data:").await.unwrap().keys.len(), data_keys - 2);
+    let removed: Vec<String> = fs
+        .changes_since(last_change, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|event| event.kind == FsEventKind::Removed)
+        .map(|event| event.path)
+        .collect();
+    assert_eq!(removed.len(), 2);
+    assert!(removed.contains(&"/tmp".to_string()) && removed.contains(&"/cache/old.txt".to_string()));
+    assert!(fs.fsck(false).await.unwrap().is_clean());
+    assert_eq!(fs.sweep_expired().await.unwrap(), 0);
+    assert!(fs.set_expiry("/", Some(past)).await.is_err());
+}
+
+#[tokio::test]
+async fn test_blob_offload() {
+    use agentfs::{BlobStore, GcOptions, LocalBlobStore};
+    use std::time::Duration;
+
+    let dir = std::env::temp_dir().join(format!("agentfs-blobs-{}", uuid::Uuid::new_v4()));
+    let store = Arc::new(LocalBlobStore::new(&dir).unwrap());
+    let (mut agentfs, backend) = create_shared_test_agentfs().await;
+    agentfs.fs = agentfs.fs.clone().with_blob_store(store.clone(), 16);
+    let fs = &agentfs.fs;
+
+    let big = vec![7u8; 1000];
+    fs.write_file("/small.txt", b"tiny").await.unwrap();
+    fs.write_file("/big.bin", &big).await.unwrap();
+    fs.write_file("/same.bin", &big).await.unwrap();
+    assert_eq!(fs.read_file("/big.bin").await.unwrap().unwrap(), big);
+    assert_eq!(fs.stat("/big.bin").await.unwrap().unwrap().size, 1000);
+
+    // Only the small file stays in the database; identical content is stored once
+    assert_eq!(backend.scan("__fs_data:").await.unwrap().keys.len(), 1);
+    let blobs = store.list().await.unwrap();
+    assert_eq!(blobs.len(), 1);
+    assert_eq!(blobs[0].hash, agentfs::blob::blob_hash(&big));
+    assert_eq!(blobs[0].size, 1000);
+
+    // Copies and overwrites
+    fs.copy("/big.bin", "/copy.bin").await.unwrap();
+    fs.write_file("/big.bin", b"small now").await.unwrap();
+    assert_eq!(fs.read_file("/copy.bin").await.unwrap().unwrap(), big);
+    assert_eq!(fs.read_file("/big.bin").await.unwrap().unwrap(), b"small now");
+    assert!(fs.fsck(false).await.unwrap().is_clean());
+
+    // Unreferenced blobs are collected once past the grace period
+    fs.remove("/copy.bin").await.unwrap();
+    fs.remove("/same.bin").await.unwrap();
+    assert_eq!(store.list().await.unwrap().len(), 1);
+    let report = agentfs.gc().await.unwrap();
+    assert_eq!(report.orphan_blobs, 0);
+    let options = GcOptions { blob_grace_period: Duration::ZERO, ..Default::default() };
+    let report = agentfs.gc_with_options(&options).await.unwrap();
+    assert_eq!(report.orphan_blobs, 1);
+    assert!(store.list().await.unwrap().is_empty());
+
+    // Without the store, offloaded content can't be read
+    fs.write_file("/again.bin", &big).await.unwrap();
+    let plain = AgentFS::new(Box::new(SharedDb(backend.clone())), "test-agent", "/agent").await.unwrap().fs;
+    assert!(plain.read_file("/again.bin").await.is_err());
+    assert_eq!(plain.read_file("/small.txt").await.unwrap().unwrap(), b"tiny");
+
+    // fsck takes blob-backed sizes from the store, and reports lost blobs
+    let again = fs.stat("/again.bin").await.unwrap().unwrap().ino;
+    backend.query(&format!("UPDATE fs_inode SET size = 5 WHERE ino = {}", again), vec![]).await.unwrap();
+    let report = fs.fsck(false).await.unwrap();
+    assert_eq!(report.size_mismatches, vec![agentfs::SizeMismatch { ino: again, recorded: 5, actual: 1000 }]);
+    let hash = agentfs::blob::blob_hash(&big);
+    store.delete(&hash).await.unwrap();
+    let report = fs.fsck(true).await.unwrap();
+    assert!(report.size_mismatches.is_empty());
+    assert_eq!(report.missing_blobs, vec![agentfs::MissingBlob { ino: again, hash }]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_semantic_search() {
+    use agentfs::{Credentials, HashEmbedder};
+
+    let mut agentfs = create_test_agentfs().await;
+    agentfs.fs.mkdir("/notes").await.unwrap();
+    agentfs.fs.write_file("/notes/old.md", b"kubernetes cluster upgrade runbook").await.unwrap();
+    agentfs.fs = agentfs.fs.clone().with_embedder(Arc::new(HashEmbedder::default()));
+    let fs = &agentfs.fs;
+
+    fs.write_file("/notes/deploy.md", b"# Deploy\nRun the deployment checklist before each release.\n")
+        .await
+        .unwrap();
+    fs.write_file("/notes/recipes.md", b"Pancakes need flour, eggs and milk.").await.unwrap();
+    fs.write_file("/notes/image.png", b"\x89PNG\r\n\x1a\nrelease deployment checklist").await.unwrap();
+    fs.mkdir("/other").await.unwrap();
+    fs.write_file("/other/deploy.md", b"deployment checklist release").await.unwrap();
+
+    let hits = fs.semantic_search("release checklist for deployment", 2, "/notes").await.unwrap();
+    assert_eq!(hits[0].path, "/notes/deploy.md");
+    assert_eq!(hits[0].line, 1);
+    assert!(hits[0].text.contains("deployment checklist"));
+    assert!(hits[0].score > hits[1].score);
+    assert!(hits.iter().all(|hit| hit.path.starts_with("/notes/") && hit.path != "/notes/image.png"));
+
+    // Files written before the embedder was set are indexed on request
+    assert!(fs.semantic_search("kubernetes upgrade", 5, "/").await.unwrap().iter().all(|h| h.path != "/notes/old.md"));
+    assert_eq!(fs.reindex("/").await.unwrap(), 1);
+    assert_eq!(fs.semantic_search("kubernetes upgrade", 1, "/").await.unwrap()[0].path, "/notes/old.md");
+
+    // Rewrites, copies and removals keep the index in sync
+    fs.write_file("/notes/recipes.md", b"kubernetes upgrade pancakes").await.unwrap();
+    fs.copy("/notes/deploy.md", "/notes/deploy-copy.md").await.unwrap();
+    fs.remove("/notes/deploy.md").await.unwrap();
+    let hits = fs.semantic_search("deployment checklist", 10, "/notes").await.unwrap();
+    assert_eq!(hits[0].path, "/notes/deploy-copy.md");
+    assert!(hits.iter().all(|hit| hit.path != "/notes/deploy.md"));
+    assert!(hits.iter().find(|hit| hit.path == "/notes/recipes.md").unwrap().text.contains("pancakes"));
+
+    // Files the caller can't read or reach are left out before the top k
+    fs.write_file("/notes/secret.md", b"deployment checklist").await.unwrap();
+    fs.chmod("/notes/secret.md", 0o600).await.unwrap();
+    fs.mkdir("/notes/private").await.unwrap();
+    fs.write_file("/notes/private/deploy.md", b"deployment checklist").await.unwrap();
+    fs.chmod("/notes/private", 0o700).await.unwrap();
+    assert_eq!(fs.semantic_search("deployment checklist", 1, "/notes").await.unwrap().len(), 1);
+    let agent = fs.as_user(Credentials::new(1000, 1000));
+    let hits = agent.semantic_search("deployment checklist", 1, "/notes").await.unwrap();
+    assert_eq!(hits[0].path, "/notes/deploy-copy.md");
+    let hits = agent.semantic_search("deployment checklist", 10, "/").await.unwrap();
+    assert!(hits.iter().all(|hit| hit.path != "/notes/secret.md" && !hit.path.starts_with("/notes/private/")));
+
+    // An embedder failure fails the write before anything changes
+    struct Offline;
+    #[async_trait::async_trait]
+    impl agentfs::Embedder for Offline {
+        async fn embed(&self, _texts: &[String]) -> agentfs::Result<Vec<Vec<f32>>> {
+            Err(agentfs::AgentFsError::InvalidArgument("embedder offline".to_string()))
+        }
+    }
+    let offline = fs.clone().with_embedder(Arc::new(Offline));
+    assert!(offline.write_file("/notes/deploy-copy.md", b"rewritten").await.is_err());
+    assert!(fs.read_file("/notes/deploy-copy.md").await.unwrap().unwrap().starts_with(b"# Deploy"));
+    assert_eq!(agent.semantic_search("deployment checklist", 1, "/notes").await.unwrap()[0].path, "/notes/deploy-copy.md");
+
+    let plain = create_test_agentfs().await;
+    assert!(matches!(
+        plain.fs.semantic_search("anything", 1, "/").await,
+        Err(agentfs::AgentFsError::NoEmbedder)
+    ));
+}
+
+#[tokio::test]
+async fn test_semantic_search_index() {
+    use agentfs::HashEmbedder;
+
+    let (agentfs, backend) = create_shared_test_agentfs().await;
+    let embedder = Arc::new(HashEmbedder::new(64));
+    let exact = agentfs.fs.clone().with_embedder(embedder.clone()).with_ann_threshold(usize::MAX);
+    let approximate = exact.clone().with_ann_threshold(0);
+    exact.mkdir("/notes").await.unwrap();
+    for i in 0..300 {
+        let content = format!("note{} about topic{} and subject{}", i, i % 37, i % 11);
+        exact.write_file(&format!("/notes/{}.md", i), content.as_bytes()).await.unwrap();
+    }
+
+    // The index finds the same best matches as the exhaustive scan
+    for i in (0..300).step_by(23) {
+        let query = format!("note{} topic{} subject{}", i, i % 37, i % 11);
+        let expected = exact.semantic_search(&query, 3, "/").await.unwrap();
+        let found = approximate.semantic_search(&query, 3, "/").await.unwrap();
+        assert_eq!(found[0].path, format!("/notes/{}.md", i));
+        assert_eq!(found[0].path, expected[0].path);
+        assert!((found[0].score - expected[0].score).abs() < 1e-4);
+    }
+
+    // Removals, rewrites and scoping apply to indexed searches too
+    approximate.remove("/notes/5.md").await.unwrap();
+    approximate.write_file("/notes/6.md", b"pancakes").await.unwrap();
+    approximate.mkdir("/other").await.unwrap();
+    approximate.write_file("/other/note7.md", b"note7 topic7 subject7").await.unwrap();
+    let hits = approximate.semantic_search("note5 topic5 subject5", 10, "/notes").await.unwrap();
+    assert!(hits.iter().all(|hit| hit.path != "/notes/5.md"));
+    assert_eq!(approximate.semantic_search("pancakes", 1, "/").await.unwrap()[0].path, "/notes/6.md");
+    let hits = approximate.semantic_search("note7 topic7 subject7", 3, "/notes").await.unwrap();
+    assert!(hits.iter().all(|hit| hit.path.starts_with("/notes/")));
+
+    // Changes made by another process are picked up
+    let other = AgentFS::new(Box::new(SharedDb(backend)), "test-agent", "/agent").await.unwrap();
+    let other = other.fs.with_embedder(embedder);
+    other.write_file("/notes/new.md", b"waffles with syrup").await.unwrap();
+    assert_eq!(approximate.semantic_search("waffles syrup", 1, "/").await.unwrap()[0].path, "/notes/new.md");
+}
+
+#[tokio::test]
+async fn test_json_documents() {
+    use agentfs::AgentFsError;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Task {
+        status: String,
+        results: Vec<i64>,
+    }
+
+    let agentfs = create_test_agentfs().await;
+    let fs = &agentfs.fs;
+    let task = Task { status: "running".to_string(), results: vec![1] };
+    fs.write_json("/task.json", &task).await.unwrap();
+    assert_eq!(fs.read_json::<Task>("/task.json").await.unwrap(), Some(task));
+    assert_eq!(fs.read_json::<Task>("/missing.json").await.unwrap(), None);
+
+    let patched = fs
+        .patch_json(
+            "/task.json",
+            &json!([
+                { "op": "test", "path": "/status", "value": "running" },
+                { "op": "replace", "path": "/status", "value": "done" },
+                { "op": "add", "path": "/results/-", "value": 2 },
+                { "op": "copy", "from": "/results/0", "path": "/results/0" },
+                { "op": "add", "path": "/meta", "value": { "a~b": 1 } },
+                { "op": "move", "from": "/meta/a~0b", "path": "/meta/c" }
+            ]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(patched, json!({ "status": "done", "results": [1, 1, 2], "meta": { "c": 1 } }));
+    let version = fs.stat("/task.json").await.unwrap().unwrap().version;
+
+    // A failing operation leaves the document untouched
+    for patch in [
+        json!([{ "op": "replace", "path": "/status", "value": "x" }, { "op": "test", "path": "/status", "value": "done" }]),
+        json!([{ "op": "add", "path": "/status/inner", "value": 1 }]),
+        json!([{ "op": "remove", "path": "/results/3" }]),
+        json!([{ "op": "frobnicate", "path": "/status" }]),
+        json!({ "op": "remove", "path": "/status" }),
+    ] {
+        let err = fs.patch_json("/task.json", &patch).await.unwrap_err();
+        assert!(matches!(err, AgentFsError::Serialization(_)), "{:?}", err);
+    }
+    assert_eq!(fs.stat("/task.json").await.unwrap().unwrap().version, version);
+    assert!(matches!(fs.read_json::<Vec<i64>>("/task.json").await, Err(AgentFsError::Serialization(_))));
+
+    let merged = fs
+        .merge_patch("/task.json", &json!({ "status": "archived", "meta": null, "tags": { "x": true } }))
+        .await
+        .unwrap();
+    assert_eq!(merged, json!({ "status": "archived", "results": [1, 1, 2], "tags": { "x": true } }));
+    assert_eq!(fs.read_json::<Task>("/task.json").await.unwrap().unwrap().status, "archived");
+    assert!(matches!(fs.merge_patch("/nope.json", &json!({})).await, Err(AgentFsError::FileNotFound(_))));
+}
+
+#[tokio::test]
+async fn test_provenance() {
+    use agentfs::Credentials;
+    use serde_json::json;
+
+    let agentfs = create_test_agentfs().await;
+    agentfs.fs.mkdir("/output").await.unwrap();
+
+    let call = agentfs.tools.start("write_report", Some(json!({ "topic": "sales" }))).await.unwrap();
+    let tool_fs = agentfs.fs.for_tool_call(call);
+    tool_fs.write_file("/output/draft.md", b"# Sales").await.unwrap();
+    tool_fs.rename("/output/draft.md", "/output/report.md").await.unwrap();
+    agentfs.tools.success(call, None).await.unwrap();
+    agentfs.fs.write_file("/output/report.md", b"# Sales, edited").await.unwrap();
+
+    let history = agentfs.fs.provenance("/output/report.md").await.unwrap();
+    let operations: Vec<&str> = history.iter().map(|record| record.operation.as_str()).collect();
+    assert_eq!(operations, vec!["created", "renamed", "modified"]);
+    assert_eq!(history[0].tool_call_id, Some(call));
+    assert_eq!(history[0].tool_name.as_deref(), Some("write_report"));
+    assert_eq!(history[0].tool_parameters, Some(json!({ "topic": "sales" })));
+    assert_eq!(history[0].agent_id.as_deref(), Some("test-agent"));
+    assert_eq!(history[1].previous_path.as_deref(), Some("/output/draft.md"));
+    assert_eq!(history[2].tool_call_id, None);
+
+    let touched = agentfs.fs.files_touched_by(call).await.unwrap();
+    let paths: Vec<&str> = touched.iter().map(|record| record.path.as_str()).collect();
+    assert_eq!(paths, vec!["/output/draft.md", "/output/report.md"]);
+
+    // Removed files keep their history at the path
+    agentfs.fs.for_tool_call(call).remove("/output/report.md").await.unwrap();
+    let history = agentfs.fs.provenance("/output/report.md").await.unwrap();
+    assert_eq!(history.last().unwrap().operation, "removed");
+    assert_eq!(agentfs.fs.files_touched_by(call).await.unwrap().len(), 3);
+    assert!(agentfs.fs.provenance("/never.md").await.is_err());
+
+    // Callers only get records of entries in directories they may list
+    agentfs.fs.mkdir("/private").await.unwrap();
+    agentfs.fs.chmod("/private", 0o700).await.unwrap();
+    let call = agentfs.tools.start("stash_key", None).await.unwrap();
+    let tool_fs = agentfs.fs.for_tool_call(call);
+    tool_fs.write_file("/private/key.txt", b"secret").await.unwrap();
+    tool_fs.write_file("/output/summary.md", b"done").await.unwrap();
+    tool_fs.rename("/private/key.txt", "/output/key.txt").await.unwrap();
+    assert_eq!(agentfs.fs.files_touched_by(call).await.unwrap().len(), 3);
+    let agent = agentfs.fs.as_user(Credentials::new(1000, 1000));
+    let touched = agent.files_touched_by(call).await.unwrap();
+    let paths: Vec<&str> = touched.iter().map(|record| record.path.as_str()).collect();
+    assert_eq!(paths, vec!["/output/summary.md"]);
+    assert!(agent.provenance("/output/key.txt").await.unwrap().is_empty());
+    assert_eq!(agentfs.fs.provenance("/output/key.txt").await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_provenance_of_metadata_changes() {
+    use std::time::Duration;
+
+    let agentfs = create_test_agentfs().await;
+    let fs = agentfs.fs.clone().with_trash(true);
+    fs.write_file("/notes.txt", b"notes").await.unwrap();
+    fs.chmod("/notes.txt", 0o600).await.unwrap();
+    fs.chown("/notes.txt", 1000, 1000).await.unwrap();
+    fs.set_xattr("/notes.txt", "user.source", b"web").await.unwrap();
+    fs.remove_xattr("/notes.txt", "user.source").await.unwrap();
+    fs.set_expiry("/notes.txt", None).await.unwrap();
+
+    let history = fs.provenance("/notes.txt").await.unwrap();
+    let operations: Vec<&str> = history.iter().map(|record| record.operation.as_str()).collect();
+    assert_eq!(operations, vec!["created", "attributes", "attributes", "attributes", "attributes", "attributes"]);
+
+    fs.remove("/notes.txt").await.unwrap();
+    fs.empty_trash(Duration::ZERO).await.unwrap();
+    let history = fs.provenance("/notes.txt").await.unwrap();
+    assert_eq!(history.last().unwrap().operation, "purged");
+
+    // Provenance is kept unless a retention is set
+    assert_eq!(agentfs.gc().await.unwrap().trimmed_provenance, 0);
+}
+
+#[tokio::test]
+async fn test_mount_table() {
+    use agentfs::{AgentFsError, DbFileSystem, MemFileSystem, MountFs};
+
+    let backend = Arc::new(SqlBackend::sqlite(":memory:").await.unwrap());
+    let db: Arc<Box<dyn AgentDB>> = Arc::new(Box::new(SharedDb(backend.clone())));
+    let alice = DbFileSystem::open_volume(db.clone(), "alice", "/".to_string()).await.unwrap();
+    let bob = DbFileSystem::open_volume(db.clone(), "bob", "/".to_string()).await.unwrap();
+    bob.write_file("/plan.md", b"# Plan").await.unwrap();
+
+    let mounts = MountFs::new();
+    mounts.mount("/agent", alice.clone()).unwrap();
+    mounts.mount_read_only("/shared", bob.clone()).unwrap();
+    mounts.mount("/agent/scratch", MemFileSystem::new("/")).unwrap();
+    assert!(matches!(mounts.mount("/agent", MemFileSystem::new("/")), Err(AgentFsError::PathExists(_))));
+
+    // Paths dispatch to the innermost mount
+    assert_eq!(mounts.readdir("/").await.unwrap().unwrap(), vec!["agent", "shared"]);
+    assert!(mounts.stat("/").await.unwrap().unwrap().is_directory());
+    mounts.write_file("/agent/notes.txt", b"mine").await.unwrap();
+    mounts.write_file("/agent/scratch/tmp.txt", b"scratch").await.unwrap();
+    assert_eq!(alice.read_file("/notes.txt").await.unwrap().unwrap(), b"mine");
+    assert!(alice.read_file("/scratch/tmp.txt").await.unwrap().is_none());
+    assert_eq!(mounts.readdir("/agent").await.unwrap().unwrap(), vec!["notes.txt", "scratch"]);
+    assert_eq!(mounts.read_file("/shared/plan.md").await.unwrap().unwrap(), b"# Plan");
+
+    // Read-only mounts and placeholder directories reject writes
+    let err = mounts.write_file("/shared/plan.md", b"mine now").await.unwrap_err();
+    assert!(matches!(err, AgentFsError::ReadOnly(_)));
+    assert_eq!(err.errno(), 30);
+    assert!(matches!(mounts.mkdir("/other").await, Err(AgentFsError::ReadOnly(_))));
+    assert!(mounts.remove("/agent").await.is_err());
+
+    // Renames stay within a mount; copies may cross
+    let err = mounts.rename("/agent/notes.txt", "/agent/scratch/notes.txt").await.unwrap_err();
+    assert!(matches!(err, AgentFsError::CrossDevice(_)));
+    assert_eq!(err.errno(), 18);
+    mounts.rename("/agent/notes.txt", "/agent/renamed.txt").await.unwrap();
+    mounts.copy("/shared/plan.md", "/agent/plan.md").await.unwrap();
+    assert_eq!(alice.read_file("/plan.md").await.unwrap().unwrap(), b"# Plan");
+
+    mounts.unmount("/agent/scratch").unwrap();
+    assert!(mounts.read_file("/agent/scratch/tmp.txt").await.unwrap().is_none());
+    let paths: Vec<String> = mounts.mounts().into_iter().map(|mount| mount.path).collect();
+    assert_eq!(paths, vec!["/agent", "/shared"]);
+}
+
+#[tokio::test]
+async fn test_line_editing() {
+    use agentfs::AgentFsError;
+
+    let agentfs = create_test_agentfs().await;
+    let fs = &agentfs.fs;
+    fs.write_file("/main.rs", b"fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}").await.unwrap();
+
+    assert_eq!(fs.read_lines("/main.rs", 2..=3).await.unwrap(), vec!["    let x = 1;", "    println!(\"{}\", x);"]);
+    assert_eq!(fs.read_lines("/main.rs", 4..=80).await.unwrap(), vec!["}"]);
+
+    let preview = fs.replace_lines("/main.rs", 2, 2, "    let x = 2;\n    let y = 3;").await.unwrap();
+    assert_eq!((preview.start_line, preview.end_line, preview.total_lines), (2, 3, 5));
+    assert!(preview.snippet.starts_with("     1\tfn main() {\n     2\t    let x = 2;"));
+
+    // Appending after a last line without a line ending keeps lines apart
+    fs.insert_lines("/main.rs", 5, "// end").await.unwrap();
+    fs.insert_lines("/main.rs", 0, "use std::fmt;\n").await.unwrap();
+    let preview = fs.delete_lines("/main.rs", 4, 4).await.unwrap();
+    assert_eq!((preview.start_line, preview.end_line), (4, 3));
+    assert_eq!(
+        fs.read_file("/main.rs").await.unwrap().unwrap(),
+        b"use std::fmt;\nfn main() {\n    let x = 2;\n    println!(\"{}\", x);\n}\n// end"
+    );
+
+    // str_replace needs exactly one match
+    let preview = fs.str_replace("/main.rs", "let x = 2", "let x = 42").await.unwrap();
+    assert_eq!(preview.start_line, 3);
+    assert!(matches!(fs.str_replace("/main.rs", "x", "z").await, Err(AgentFsError::InvalidEdit(_))));
+    assert!(matches!(fs.str_replace("/main.rs", "missing", "z").await, Err(AgentFsError::InvalidEdit(_))));
+
+    // Failed edits leave the file untouched
+    let before = fs.read_file("/main.rs").await.unwrap().unwrap();
+    assert!(matches!(fs.replace_lines("/main.rs", 5, 9, "").await, Err(AgentFsError::InvalidEdit(_))));
+    assert!(matches!(fs.insert_lines("/main.rs", 7, "x").await, Err(AgentFsError::InvalidEdit(_))));
+    assert_eq!(fs.read_file("/main.rs").await.unwrap().unwrap(), before);
+    assert!(matches!(fs.delete_lines("/nope.rs", 1, 1).await, Err(AgentFsError::FileNotFound(_))));
+}
+
+#[tokio::test]
+async fn test_unified_patches() {
+    use agentfs::{AgentFsError, PatchChange};
+
+    let agentfs = create_test_agentfs().await;
+    let fs = &agentfs.fs;
+    let original: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+    fs.mkdir("/src").await.unwrap();
+    fs.write_file("/src/lib.rs", original.as_bytes()).await.unwrap();
+    fs.write_file("/src/lib.new.rs", original.replace("line 4\n", "line four\n").replace("line 18\n", "").as_bytes())
+        .await
+        .unwrap();
+    fs.write_file("/old.txt", b"bye\n").await.unwrap();
+    fs.write_file("/notes.txt", b"a\nb").await.unwrap();
+
+    // make_patch output applies back onto the original
+    let diff = fs.make_patch("/src/lib.rs", "/src/lib.new.rs").await.unwrap();
+    assert!(diff.starts_with("--- a/src/lib.rs\n+++ b/src/lib.new.rs\n@@ -1,7 +1,7 @@\n"));
+    assert_eq!(diff.matches("@@ -").count(), 2);
+    assert!(fs.make_patch("/src/lib.rs", "/src/lib.rs").await.unwrap().is_empty());
+    assert!(fs.make_patch("/notes.txt", "/old.txt").await.unwrap().contains("\\ No newline at end of file"));
+
+    // Lines added at the top since the diff was made shift every hunk
+    let shifted = format!("// header\n{}", original);
+    fs.write_file("/src/lib.rs", shifted.as_bytes()).await.unwrap();
+    let patch = format!(
+        "{}diff --git a/new.txt b/new.txt\nnew file mode 100644\n--- /dev/null\n+++ b/docs/new.txt\n@@ -0,0 +1,2 @@\n+hello\n+world\n\
+         diff --git a/old.txt b/old.txt\ndeleted file mode 100644\n--- a/old.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-bye\n\
+         diff --git a/notes.txt b/notes/todo.txt\nrename from notes.txt\nrename to notes/todo.txt\n\
+         --- a/notes.txt\n+++ b/notes/todo.txt\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+c\n",
+        diff.replace("+++ b/src/lib.new.rs", "+++ b/src/lib.rs")
+    );
+    let report = fs.check_patch(&patch).await.unwrap();
+    assert!(report.is_clean());
+    assert_eq!(report.files[0].hunks[0].applied_at, Some(2));
+
+    let report = fs.apply_patch(&patch).await.unwrap();
+    let changes: Vec<&PatchChange> = report.files.iter().map(|file| &file.change).collect();
+    assert_eq!(
+        changes,
+        vec![
+            &PatchChange::Modify,
+            &PatchChange::Create,
+            &PatchChange::Delete,
+            &PatchChange::Rename { from: "/notes.txt".to_string() }
+        ]
+    );
+    let expected = format!("// header\n{}", fs.read_file("/src/lib.new.rs").await.unwrap().map(String::from_utf8).unwrap().unwrap());
+    assert_eq!(fs.read_file("/src/lib.rs").await.unwrap().unwrap(), expected.as_bytes());
+    assert_eq!(fs.read_file("/docs/new.txt").await.unwrap().unwrap(), b"hello\nworld\n");
+    assert!(!fs.exists("/old.txt").await.unwrap());
+    assert!(!fs.exists("/notes.txt").await.unwrap());
+    assert_eq!(fs.read_file("/notes/todo.txt").await.unwrap().unwrap(), b"a\nc\n");
+
+    // Context that changed a little is matched with fuzz
+    let fuzzy = "--- a/docs/new.txt\n+++ b/docs/new.txt\n@@ -1,2 +1,2 @@\n hi\n-world\n+there\n";
+    let report = fs.apply_patch(fuzzy).await.unwrap();
+    assert_eq!(report.files[0].hunks[0].fuzz, 1);
+    assert_eq!(fs.read_file("/docs/new.txt").await.unwrap().unwrap(), b"hello\nthere\n");
+
+    // A failing hunk is reported by the dry run, and nothing is applied
+    let broken = "--- a/docs/new.txt\n+++ b/docs/new.txt\n@@ -1 +1 @@\n-hello\n+howdy\n\
+                  --- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -3,3 +3,3 @@\n-nope 1\n-nope 2\n-nope 3\n+x\n+y\n+z\n";
+    let report = fs.check_patch(broken).await.unwrap();
+    assert!(!report.is_clean());
+    assert!(report.files[0].is_clean());
+    assert_eq!(report.files[1].hunks[0].applied_at, None);
+    assert!(matches!(fs.apply_patch(broken).await, Err(AgentFsError::InvalidEdit(_))));
+    assert_eq!(fs.read_file("/docs/new.txt").await.unwrap().unwrap(), b"hello\nthere\n");
+
+    // Large files with nothing in common are replaced in a single hunk
+    let before: String = (0..10_000).map(|i| format!("old {}\n", i)).collect();
+    let after: String = (0..10_000).map(|i| format!("new {}\n", i)).collect();
+    fs.write_file("/big.txt", before.as_bytes()).await.unwrap();
+    fs.write_file("/big.new.txt", after.as_bytes()).await.unwrap();
+    let diff = fs.make_patch("/big.txt", "/big.new.txt").await.unwrap();
+    assert!(diff.contains("\n@@ -1,10000 +1,10000 @@\n"));
+    assert_eq!(diff.matches("@@ -").count(), 1);
+    fs.apply_patch(&diff.replace("+++ b/big.new.txt", "+++ b/big.txt")).await.unwrap();
+    assert_eq!(fs.read_file("/big.txt").await.unwrap().unwrap(), after.as_bytes());
+}
+
+#[tokio::test]
+async fn test_patch_rollback_with_concurrent_writer() {
+    use agentfs::AgentFsError;
+
+    let (fs, other, hook) = create_interfering_test_fs().await;
+    fs.write_file("/a.txt", b"one\n").await.unwrap();
+    fs.write_file("/b.txt", b"two\n").await.unwrap();
+    fs.write_file("/c.txt", b"three\n").await.unwrap();
+    fs.chmod("/c.txt", 0o600).await.unwrap();
+    fs.set_xattr("/c.txt", "user.origin", b"test").await.unwrap();
+    let c = fs.stat("/c.txt").await.unwrap().unwrap().ino;
+
+    let patch = "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-one\n+ONE\n\
+                 --- a/c.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-three\n\
+                 --- a/b.txt\n+++ b/b.txt\n@@ -1 +1 @@\n-two\n+TWO\n";
+    // The other writer writes files while c is being removed
+    let write = |paths: &'static [&'static str]| -> Interference {
+        let other = other.clone();
+        Box::new(move || {
+            Box::pin(async move {
+                for path in paths {
+                    other.write_file(path, b"theirs\n").await.unwrap();
+                }
+            })
+        })
+    };
+
+    // b's precondition fails, and a and c are put back as they were, c with
+    // the same inode and metadata
+    *hook.lock().unwrap() = Some(("DELETE FROM fs_dentry", write(&["/b.txt"])));
+    assert!(matches!(fs.apply_patch(patch).await, Err(AgentFsError::PreconditionFailed(_))));
+    assert_eq!(fs.read_file("/a.txt").await.unwrap().unwrap(), b"one\n");
+    assert_eq!(fs.read_file("/b.txt").await.unwrap().unwrap(), b"theirs\n");
+    let restored = fs.stat("/c.txt").await.unwrap().unwrap();
+    assert_eq!((restored.ino, restored.mode & 0o777), (c, 0o600));
+    assert_eq!(fs.get_xattr("/c.txt", "user.origin").await.unwrap().unwrap(), b"test");
+    assert!(fs.list_trash().await.unwrap().is_empty());
+
+    // If a changes too, reverting it would overwrite the other writer, so
+    // the rollback reports it instead
+    fs.write_file("/b.txt", b"two\n").await.unwrap();
+    *hook.lock().unwrap() = Some(("DELETE FROM fs_dentry", write(&["/a.txt", "/b.txt"])));
+    match fs.apply_patch(patch).await {
+        Err(AgentFsError::PatchRolledBackPartially { cause, failures }) => {
+            assert!(matches!(*cause, AgentFsError::PreconditionFailed(_)));
+            assert_eq!(failures.len(), 1);
+            assert!(failures[0].contains("/a.txt"));
+        }
+        other => panic!("expected a partial rollback, got {:?}", other),
+    }
+    assert_eq!(fs.read_file("/a.txt").await.unwrap().unwrap(), b"theirs\n");
+    assert_eq!(fs.stat("/c.txt").await.unwrap().unwrap().ino, c);
+
+    // Without interference the removed file is gone for good
+    fs.write_file("/a.txt", b"one\n").await.unwrap();
+    fs.write_file("/b.txt", b"two\n").await.unwrap();
+    fs.apply_patch(patch).await.unwrap();
+    assert!(!fs.exists("/c.txt").await.unwrap());
+    assert!(fs.list_trash().await.unwrap().is_empty());
+}